@@ -15,15 +15,58 @@ extern crate proc_macro;
 use cc_use_common::CcUse;
 use proc_macro2::{Span, TokenStream};
 use quote::quote;
-use std::env;
+use std::{env, path::PathBuf};
 use syn::{parse_macro_input, punctuated::Punctuated, Ident, Token};
 
 #[proc_macro]
 pub fn cc_use(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let mac = parse_macro_input!(input as CcUse);
+    if let Err(err) = check_headers(&mac, source_dir()) {
+        return err.to_compile_error().into();
+    }
     cc_use_impl(mac).into()
 }
 
+/// Returns the directory to look for quoted headers in first.
+///
+/// This is the directory of the invoking file when the compiler knows it, the
+/// same one the bindings generator searches. Otherwise, as for code that
+/// another macro generated, it's `CARGO_MANIFEST_DIR`.
+fn source_dir() -> Option<PathBuf> {
+    proc_macro::Span::call_site()
+        .local_file()
+        .and_then(|file| file.parent().map(|dir| dir.to_owned()))
+        .or_else(|| env::var_os("CARGO_MANIFEST_DIR").map(Into::into))
+}
+
+/// Checks that each quoted header can be found, the same way the bindings
+/// generator looks for it: in `source_dir`, then in each directory in
+/// `MOSAIC_INCLUDE_PATH`.
+fn check_headers(input: &CcUse, source_dir: Option<PathBuf>) -> syn::Result<()> {
+    let source_dir = match source_dir {
+        Some(dir) => dir,
+        // Without a directory to start from, leave it to the bindings generator.
+        None => return Ok(()),
+    };
+    let include_path = env::var_os("MOSAIC_INCLUDE_PATH").unwrap_or_default();
+    let search_dirs: Vec<_> = std::iter::once(source_dir)
+        .chain(env::split_paths(&include_path))
+        .collect();
+    for hdr in input.headers.iter().filter(|hdr| !hdr.is_system) {
+        if cc_use_common::find_header(&hdr.path, &search_dirs).is_none() {
+            return Err(syn::Error::new(
+                hdr.span,
+                format!(
+                    "header not found: `{}`; {}",
+                    hdr.path,
+                    cc_use_common::describe_search(&search_dirs)
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn cc_use_impl(input: CcUse) -> TokenStream {
     // FIXME: We should allow setting the name of the binding crate explicitly since external build
     // systems will have to care about that.
@@ -33,14 +76,22 @@ fn cc_use_impl(input: CcUse) -> TokenStream {
         .to_owned();
     let bind_crate_name = Ident::new(&(crate_name + "_bind"), Span::call_site());
     let paths: Punctuated<_, Token![,]> = input
-        .cc_paths
+        .imports
         .iter()
-        .map(|path| -> Punctuated<syn::PathSegment, Token![::]> {
-            // We put all exported items in a module named `export`.
+        .map(|import| -> Punctuated<syn::PathSegment, Token![::]> {
+            // We put all imported items in a module named `export`, nested
+            // under the same path they were imported with. Renamed items are
+            // exported under their new name.
             let export = Ident::new("export", Span::call_site());
-            [export.into(), path.segments.last().unwrap().clone()]
-                .iter()
-                .cloned()
+            let segments = &import.path.segments;
+            let parents = segments.iter().take(segments.len() - 1).cloned();
+            let last = match &import.alias {
+                Some(alias) => alias.clone().into(),
+                None => segments.last().unwrap().clone(),
+            };
+            std::iter::once(export.into())
+                .chain(parents)
+                .chain(std::iter::once(last))
                 .collect()
         })
         .collect();
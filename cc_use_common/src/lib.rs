@@ -8,7 +8,8 @@
 
 //! Types and parsing code shared between mosaic and the cc_use macro.
 
-use proc_macro2::Span;
+use proc_macro2::{Span, TokenTree};
+use std::path::{Path, PathBuf};
 use syn::{
     self,
     parse::{self, Parse, ParseStream},
@@ -18,11 +19,11 @@ use syn::{
 };
 
 pub struct CcUse {
-    pub header: Header,
-    #[allow(unused)]
+    /// The headers to import from, without duplicates. These are parsed
+    /// together, in order, so items in one can refer to items in another.
+    pub headers: Vec<Header>,
     pub lib_name: Option<LibName>,
-    #[allow(unused)]
-    pub cc_paths: Vec<CcPath>,
+    pub imports: Vec<CcImport>,
 }
 
 pub struct Header {
@@ -31,6 +32,30 @@ pub struct Header {
     pub span: Span,
 }
 
+/// Finds a quoted header in the first of `dirs` that contains it.
+///
+/// The driver and the cc_use macro both search the directory of the Rust file
+/// that names the header first, followed by the include directories.
+pub fn find_header(path: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter().map(|dir| dir.join(path)).find(|p| p.is_file())
+}
+
+/// Describes the directories searched by [`find_header`], for errors.
+pub fn describe_search(dirs: &[PathBuf]) -> String {
+    let dirs: Vec<_> = dirs
+        .iter()
+        .map(|dir| format!("`{}`", display_dir(dir)))
+        .collect();
+    format!("searched {}", dirs.join(", "))
+}
+
+fn display_dir(dir: &Path) -> std::path::Display<'_> {
+    match dir.as_os_str().is_empty() {
+        true => Path::new(".").display(),
+        false => dir.display(),
+    }
+}
+
 // TODO: This is to support automatic passing of flags from a sys crate's build script to us.
 #[derive(Clone)]
 pub struct LibName {
@@ -40,6 +65,36 @@ pub struct LibName {
 
 pub type CcPath = syn::Path;
 
+/// One item imported by a cc_use, like `Foo::Bar` or `Widget as CWidget`.
+pub struct CcImport {
+    pub path: CcPath,
+    pub alias: Option<syn::Ident>,
+}
+
+impl CcImport {
+    /// The name the item is imported as.
+    pub fn name(&self) -> &syn::Ident {
+        match &self.alias {
+            Some(alias) => alias,
+            None => &self.path.segments.last().unwrap().ident,
+        }
+    }
+}
+
+// Foo::Bar [as Baz]
+impl Parse for CcImport {
+    fn parse(input: ParseStream) -> parse::Result<Self> {
+        let path = input.parse()?;
+        let alias = if input.peek(Token![as]) {
+            input.parse::<Token![as]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        Ok(CcImport { path, alias })
+    }
+}
+
 impl Parse for Header {
     fn parse(input: ParseStream) -> parse::Result<Self> {
         let lookahead = input.lookahead1();
@@ -57,26 +112,10 @@ impl Parse for Header {
             })
         } else if lookahead.peek(syn::token::Lt) {
             let lt = input.parse::<syn::token::Lt>()?;
-            let parse_hdr = || -> parse::Result<_> {
-                // For now we only support single-word names, like those in the standard library,
-                // in bare angle brackets. TODO: Ideally we'd support any valid filename.
-                let hdr: syn::Ident = input.parse()?;
-                input.parse::<syn::token::Gt>()?;
-                Ok(hdr)
-            };
-            let hdr = match parse_hdr() {
-                Ok(hdr) => hdr,
-                Err(mut e) => {
-                    e.combine(syn::Error::new(
-                        lt.span(),
-                        "Try putting system paths inside quotes: \"<foo/bar.h>\"",
-                    ));
-                    return Err(e);
-                }
-            };
+            let (path, gt) = parse_system_header(input, lt.span())?;
             Ok(Header {
-                path: hdr.to_string(),
-                span: hdr.span(),
+                path,
+                span: lt.span().join(gt).unwrap_or_else(|| lt.span()),
                 is_system: true,
             })
         } else {
@@ -85,41 +124,153 @@ impl Parse for Header {
     }
 }
 
-// in "foo"
+/// Parses either a single header or a bracketed list like `["a.h", <b.h>]`.
+/// Headers named more than once are only kept the first time.
+fn parse_headers(input: ParseStream) -> parse::Result<Vec<Header>> {
+    if !input.peek(syn::token::Bracket) {
+        return Ok(vec![input.parse()?]);
+    }
+    let content;
+    let bracket = syn::bracketed!(content in input);
+    let list: Punctuated<Header, Token![,]> = content.parse_terminated(Header::parse)?;
+    if list.is_empty() {
+        return Err(syn::Error::new(
+            bracket.span,
+            "expected at least one header",
+        ));
+    }
+    let mut headers: Vec<Header> = Vec::with_capacity(list.len());
+    for hdr in list {
+        if !headers
+            .iter()
+            .any(|prev| prev.path == hdr.path && prev.is_system == hdr.is_system)
+        {
+            headers.push(hdr);
+        }
+    }
+    Ok(headers)
+}
+
+/// Parses the name of a system header like `<foo/bar.h>`, after the opening
+/// `<`, up to and including the closing `>`. Returns the name and the span
+/// of the `>`.
+///
+/// The name is reassembled from the tokens between the brackets, so it may
+/// only contain identifiers, integers, and the punctuation `.`, `/`, `-` and
+/// `+`.
+fn parse_system_header(input: ParseStream, lt: Span) -> parse::Result<(String, Span)> {
+    let mut path = String::new();
+    loop {
+        if input.peek(Token![>]) {
+            let gt = input.parse::<Token![>]>()?;
+            if path.is_empty() {
+                return Err(syn::Error::new(gt.span(), "expected a header name"));
+            }
+            return Ok((path, gt.span()));
+        }
+        if input.is_empty() || input.peek(Token![,]) {
+            return Err(syn::Error::new(
+                lt,
+                "unterminated header name; expected `>`",
+            ));
+        }
+        let tt: TokenTree = input.parse()?;
+        match &tt {
+            TokenTree::Ident(ident) => path += &ident.to_string(),
+            TokenTree::Punct(punct) if ['.', '/', '-', '+'].contains(&punct.as_char()) => {
+                path.push(punct.as_char())
+            }
+            TokenTree::Literal(lit) if lit.to_string().bytes().all(|c| c.is_ascii_digit()) => {
+                path += &lit.to_string()
+            }
+            _ => {
+                return Err(syn::Error::new(
+                    tt.span(),
+                    "invalid header name; put unusual paths inside quotes: \"<foo/bar.h>\"",
+                ))
+            }
+        }
+    }
+}
+
+// in "foo" or in foo
 impl Parse for LibName {
     fn parse(input: ParseStream) -> parse::Result<Self> {
         input.parse::<Token![in]>()?;
-        let name: syn::LitStr = input.parse()?;
-        Ok(LibName {
-            name: name.value(),
-            span: name.span(),
-        })
+        let lookahead = input.lookahead1();
+        if lookahead.peek(syn::LitStr) {
+            let name: syn::LitStr = input.parse()?;
+            if name.value().is_empty() {
+                return Err(syn::Error::new(name.span(), "library name cannot be empty"));
+            }
+            Ok(LibName {
+                name: name.value(),
+                span: name.span(),
+            })
+        } else if lookahead.peek(syn::Ident) {
+            let name: syn::Ident = input.parse()?;
+            Ok(LibName {
+                name: name.to_string(),
+                span: name.span(),
+            })
+        } else {
+            Err(lookahead.error())
+        }
     }
 }
 
-// cc_use!("foo.h" [in "foo"], item1[, item2[, ...]])
+// cc_use!("foo.h" [in "foo"], item1 [as name1][, item2[, ...]])
+// cc_use!(["foo.h", "bar.h"] [in "foo"], item1[, ...])
 impl Parse for CcUse {
     fn parse(input: ParseStream) -> parse::Result<Self> {
-        let header = input.parse()?;
+        let headers = parse_headers(input)?;
 
         let lookahead = input.lookahead1();
         let lib_name = if lookahead.peek(Token![in]) {
             Some(input.parse()?)
         } else if lookahead.peek(Token![,]) {
             None
+        } else if input.peek(syn::Ident) && (input.peek2(syn::LitStr) || input.peek2(syn::Ident)) {
+            // Something like `"foo.h" on "libfoo"`.
+            let keyword: syn::Ident = input.parse()?;
+            return Err(syn::Error::new(
+                keyword.span(),
+                format!("unknown keyword `{}`; use `in` to name a library", keyword),
+            ));
         } else {
             return Err(lookahead.error());
         };
 
         input.parse::<Token![,]>()?;
+        if input.peek(Token![in]) {
+            let in_token = input.parse::<Token![in]>()?;
+            return Err(syn::Error::new(
+                in_token.span(),
+                "the library must come before the comma, as in `\"foo.h\" in \"libfoo\", Item`",
+            ));
+        }
 
-        let paths: Punctuated<CcPath, Token![,]> = input.parse_terminated(CcPath::parse)?;
-        let cc_paths = paths.iter().cloned().collect();
+        let imports: Punctuated<CcImport, Token![,]> = input.parse_terminated(CcImport::parse)?;
+        let imports: Vec<_> = imports.into_iter().collect();
+        for (idx, import) in imports.iter().enumerate() {
+            let name = import.name();
+            if let Some(prev) = imports[..idx].iter().find(|prev| prev.name() == name) {
+                let mut err = syn::Error::new(
+                    name.span(),
+                    format!("the name `{}` is imported more than once", name),
+                );
+                err.combine(syn::Error::new(
+                    prev.name().span(),
+                    format!("previous import of `{}` here", name),
+                ));
+                return Err(err);
+            }
+        }
 
         Ok(CcUse {
-            header,
+            headers,
             lib_name,
-            cc_paths,
+            imports,
         })
     }
 }
@@ -8,7 +8,7 @@
 // except according to those terms.
 
 #![allow(dead_code)]
-#![allow(non_camel_case_types)]
+#![allow(deprecated, non_camel_case_types)]
 
 /// Notes about how (not) to generate bindings, as code.
 
@@ -0,0 +1,150 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Build script support for mosaic bindings.
+//!
+//! Runs the bindings generator from a `build.rs`, compiles the C++ glue it
+//! emits, and tells cargo how to link it:
+//!
+//! ```no_run
+//! mosaic_build::bridge()
+//!     .header("widget.h")
+//!     .include("vendor/include")
+//!     .compile("widget_glue");
+//! ```
+//!
+//! The Rust side of the bindings is then written to `$OUT_DIR/<name>.rs`,
+//! and can be pulled into the root of the crate with
+//! `include!(concat!(env!("OUT_DIR"), "/widget_glue.rs"));`.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Creates a new [`Bridge`] with no header set.
+pub fn bridge() -> Bridge {
+    Bridge::default()
+}
+
+/// Generates and builds the bindings for a single header.
+#[derive(Clone, Debug, Default)]
+pub struct Bridge {
+    header: Option<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    mosaic: Option<PathBuf>,
+}
+
+impl Bridge {
+    /// Sets the file to generate bindings for.
+    ///
+    /// This is either a C++ header with a `rust_export` namespace, or a Rust
+    /// file using `cc_use!`.
+    pub fn header(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.header = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a directory to search for included headers, both when generating
+    /// the bindings and when compiling the glue.
+    pub fn include(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        self.include_dirs.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the path to the `mosaic` binary.
+    ///
+    /// Defaults to `$MOSAIC`, or `mosaic` on the `PATH` if that isn't set.
+    pub fn mosaic(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.mosaic = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Generates the bindings into `$OUT_DIR/<name>.rs`, and compiles and
+    /// links the C++ glue as a static library called `name`.
+    ///
+    /// Prints the cargo directives to rerun the build script when any of the
+    /// headers or the files they include change, and to link the libraries
+    /// named in `cc_use!`.
+    ///
+    /// # Panics
+    ///
+    /// Like the `cc` crate, this is meant to be called from a build script, and
+    /// panics if anything goes wrong.
+    pub fn compile(&self, name: &str) {
+        let header = self
+            .header
+            .as_ref()
+            .expect("no header was set; call `header()` before `compile()`");
+        let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+
+        let mosaic = self.mosaic.clone().unwrap_or_else(|| {
+            println!("cargo:rerun-if-env-changed=MOSAIC");
+            env::var_os("MOSAIC")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("mosaic"))
+        });
+        let mut cmd = Command::new(&mosaic);
+        cmd.arg("--out-dir")
+            .arg(&out_dir)
+            .arg("--crate-name")
+            .arg(name)
+            .arg("--inline-crate");
+        for dir in &self.include_dirs {
+            cmd.arg("-I").arg(dir);
+        }
+        cmd.arg(header);
+        let status = cmd
+            .status()
+            .unwrap_or_else(|err| panic!("failed to run {}: {}", mosaic.display(), err));
+        if !status.success() {
+            panic!("{} failed with {}", mosaic.display(), status);
+        }
+
+        // Lets cc_use! find headers in the include directories too. Build
+        // scripts run in the package directory, but rustc may not.
+        let package_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default());
+        let include_path =
+            env::join_paths(self.include_dirs.iter().map(|dir| package_dir.join(dir)))
+                .expect("include directories cannot contain the path separator");
+        println!(
+            "cargo:rustc-env=MOSAIC_INCLUDE_PATH={}",
+            include_path.to_string_lossy()
+        );
+
+        println!("cargo:rerun-if-changed={}", header.display());
+        let manifest_path = out_dir.join(name).with_extension("manifest");
+        let manifest = fs::read_to_string(&manifest_path)
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", manifest_path.display(), err));
+        for line in manifest.lines() {
+            match line.split_once(' ') {
+                Some(("header", path)) | Some(("depends", path)) => {
+                    println!("cargo:rerun-if-changed={}", path)
+                }
+                Some(("link-lib", lib)) => println!("cargo:rustc-link-lib={}", lib),
+                // The ABI of the bindings, for `mosaic --abi-baseline`.
+                Some(("struct", _)) | Some(("field", _)) | Some(("method", _)) => {}
+                _ => panic!("unexpected line in {}: {}", manifest_path.display(), line),
+            }
+        }
+
+        let mut build = cc::Build::new();
+        build.cpp(true).flag_if_supported("-std=c++17");
+        // The glue includes the header by its file name.
+        let header_dir = match header.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        build
+            .include(header_dir)
+            .includes(&self.include_dirs)
+            .file(out_dir.join(name).with_extension("cc"))
+            .compile(name);
+    }
+}
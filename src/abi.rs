@@ -0,0 +1,326 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Records the ABI of the exported items in the manifest, and compares it
+//! with the manifest of an earlier run for `--abi-baseline`.
+//!
+//! Code built against the earlier bindings keeps assuming the old sizes,
+//! offsets and signatures, so each difference that would break it is
+//! reported. Items that were only added can't break anything and are just
+//! noted.
+
+use crate::{
+    diagnostics::{Diagnostic, Diagnostics, Severity},
+    exports::{Bindings, FieldInfo, MethodInfo, StructInfo},
+};
+
+/// Writes the manifest lines that describe the ABI of `bindings`:
+///
+/// * `struct <path> <size> <align>` for each exported struct.
+/// * `field <path> <name> <offset> <type>` for each of its fields.
+/// * `method <path> <name> <signature>` for each of its methods, with the
+///   signature written like `(i32, &Pod) -> bool`.
+///
+/// * `symbol <name>` for each shim defined by the generated C++.
+///
+/// Types come last since they may contain spaces.
+pub(crate) fn manifest_lines(bindings: &Bindings) -> String {
+    let mut out = String::new();
+    for st in bindings.structs() {
+        out += &format!("struct {} {} {}\n", st.path, st.size, st.align);
+        for field in &st.fields {
+            out += &format!(
+                "field {} {} {} {}\n",
+                st.path, field.name, field.offset, field.ty
+            );
+        }
+        for meth in &st.methods {
+            out += &format!("method {} {} {}\n", st.path, meth.name, signature(meth));
+        }
+    }
+    for symbol in bindings.generated_symbols() {
+        out += &format!("symbol {}\n", symbol);
+    }
+    out
+}
+
+/// Reads the exported structs back from a manifest written by an earlier
+/// run. Lines for anything other than the ABI are skipped.
+pub(crate) fn parse_manifest(text: &str) -> Result<Bindings, String> {
+    let mut structs: Vec<StructInfo> = vec![];
+    let mut symbols = vec![];
+    for (line_no, line) in text.lines().enumerate() {
+        let invalid = || format!("line {}: invalid ABI entry `{}`", line_no + 1, line);
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        // The last argument takes the rest of the line.
+        let arg_count = match directive {
+            "symbol" => 1,
+            "struct" | "method" => 3,
+            "field" => 4,
+            _ => continue,
+        };
+        let mut args = rest.splitn(arg_count, ' ');
+        let mut arg = || args.next().ok_or_else(invalid);
+        match directive {
+            "struct" => {
+                let path = arg()?.to_owned();
+                let size = arg()?.parse().map_err(|_| invalid())?;
+                let align = arg()?.parse().map_err(|_| invalid())?;
+                structs.push(StructInfo {
+                    path,
+                    size,
+                    align,
+                    fields: vec![],
+                    methods: vec![],
+                });
+            }
+            "field" | "method" => {
+                let path = arg()?;
+                let st = structs
+                    .iter_mut()
+                    .rev()
+                    .find(|st| st.path == path)
+                    .ok_or_else(|| format!("line {}: unknown struct `{}`", line_no + 1, path))?;
+                let name = arg()?.to_owned();
+                if directive == "field" {
+                    let offset = arg()?.parse().map_err(|_| invalid())?;
+                    let ty = arg()?.to_owned();
+                    st.fields.push(FieldInfo { name, ty, offset });
+                } else {
+                    let sig = arg()?;
+                    st.methods
+                        .push(parse_signature(name, sig).ok_or_else(invalid)?);
+                }
+            }
+            "symbol" => symbols.push(arg()?.to_owned()),
+            _ => unreachable!(),
+        }
+    }
+    Ok(Bindings::new(structs, symbols))
+}
+
+fn signature(meth: &MethodInfo) -> String {
+    format!("({}) -> {}", meth.param_tys.join(", "), meth.return_ty)
+}
+
+fn parse_signature(name: String, sig: &str) -> Option<MethodInfo> {
+    let (params, return_ty) = sig.strip_prefix('(')?.rsplit_once(") -> ")?;
+    let param_tys = match params {
+        "" => vec![],
+        // Types like `(i32, i32)` have commas of their own, so only split
+        // at the top level.
+        _ => {
+            let (mut tys, mut depth, mut start) = (vec![], 0, 0);
+            for (i, c) in params.char_indices() {
+                match c {
+                    '(' | '[' | '<' => depth += 1,
+                    ')' | ']' | '>' => depth -= 1,
+                    ',' if depth == 0 => {
+                        tys.push(params[start..i].trim().to_owned());
+                        start = i + 1;
+                    }
+                    _ => {}
+                }
+            }
+            tys.push(params[start..].trim().to_owned());
+            tys
+        }
+    };
+    Some(MethodInfo {
+        name,
+        param_tys,
+        return_ty: return_ty.to_owned(),
+    })
+}
+
+/// Compares the ABI of `current` with `baseline`.
+///
+/// Differences that break code built against the baseline are warnings, or
+/// errors if `strict` is set. Added items are always notes.
+pub(crate) fn compare(baseline: &Bindings, current: &Bindings, strict: bool) -> Diagnostics {
+    let severity = match strict {
+        true => Severity::Error,
+        false => Severity::Warning,
+    };
+    let mut diags = Diagnostics::new();
+    let mut report = |msg: String| diags.add(Diagnostic::new(severity, msg));
+    for old in baseline.structs() {
+        let new = match current.get_struct(&old.path) {
+            Some(new) => new,
+            None => {
+                report(format!("struct `{}` was removed", old.path));
+                continue;
+            }
+        };
+        if old.size != new.size {
+            report(format!(
+                "the size of `{}` changed from {} to {} bytes",
+                old.path, old.size, new.size
+            ));
+        }
+        if old.align != new.align {
+            report(format!(
+                "the alignment of `{}` changed from {} to {} bytes",
+                old.path, old.align, new.align
+            ));
+        }
+        for old_field in &old.fields {
+            let new_field = match new.fields.iter().find(|f| f.name == old_field.name) {
+                Some(field) => field,
+                None => {
+                    report(format!(
+                        "field `{}::{}` was removed",
+                        old.path, old_field.name
+                    ));
+                    continue;
+                }
+            };
+            if old_field.offset != new_field.offset {
+                report(format!(
+                    "field `{}::{}` moved from offset {} to {}",
+                    old.path, old_field.name, old_field.offset, new_field.offset
+                ));
+            }
+            if old_field.ty != new_field.ty {
+                report(format!(
+                    "the type of field `{}::{}` changed from `{}` to `{}`",
+                    old.path, old_field.name, old_field.ty, new_field.ty
+                ));
+            }
+        }
+        for old_meth in &old.methods {
+            match new.methods.iter().find(|m| m.name == old_meth.name) {
+                None => report(format!(
+                    "method `{}::{}` was removed",
+                    old.path, old_meth.name
+                )),
+                Some(new_meth) if new_meth != old_meth => report(format!(
+                    "the signature of method `{}::{}` changed from `fn{}` to `fn{}`",
+                    old.path,
+                    old_meth.name,
+                    signature(old_meth),
+                    signature(new_meth)
+                )),
+                Some(_) => {}
+            }
+        }
+    }
+    for new in current.structs() {
+        if baseline.get_struct(&new.path).is_none() {
+            diags.add(Diagnostic::new(
+                Severity::Note,
+                format!("struct `{}` was added", new.path),
+            ));
+        }
+    }
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    fn bindings(src: &str) -> Bindings {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.h");
+        std::fs::write(&path, src).unwrap();
+        let mut sess = Session::test();
+        sess.parse_header(&path).unwrap();
+        sess.generate(vec![], vec![]).unwrap()
+    }
+
+    fn messages(diags: &Diagnostics) -> Vec<(Severity, &str)> {
+        diags.iter().map(|d| (d.severity(), d.message())).collect()
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let bindings = bindings(
+            "
+            struct Pod {
+                int a;
+                double b;
+                int sum(int x, const Pod& other) const;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        ",
+        );
+        let text = manifest_lines(&bindings);
+        assert!(text.starts_with("struct export::Pod 16 8\n"), "{}", text);
+        assert!(text.contains("field export::Pod b 8 f64\n"), "{}", text);
+        assert!(text.ends_with("symbol _bind_Pod__sum\n"), "{}", text);
+        assert_eq!(parse_manifest(&text), Ok(bindings));
+    }
+
+    #[test]
+    fn compare_with_baseline() {
+        let baseline = "
+            header test.h
+            struct export::Pod 8 4
+            field export::Pod a 0 i32
+            field export::Pod b 4 i32
+            method export::Pod sum (i32) -> i32
+            struct export::Gone 1 1
+        ";
+        let baseline: String = baseline
+            .lines()
+            .map(|l| l.trim().to_owned() + "\n")
+            .collect();
+        let baseline = parse_manifest(&baseline).unwrap();
+        let current = bindings(
+            "
+            struct Pod {
+                int a;
+                long long b;
+                int sum(int x, int y) const;
+            };
+            struct Added { int x; };
+            namespace rust_export {
+                using ::Pod;
+                using ::Added;
+            }
+        ",
+        );
+
+        let diags = compare(&baseline, &current, false);
+        assert_eq!(
+            messages(&diags),
+            [
+                (
+                    Severity::Warning,
+                    "the size of `export::Pod` changed from 8 to 16 bytes"
+                ),
+                (
+                    Severity::Warning,
+                    "the alignment of `export::Pod` changed from 4 to 8 bytes"
+                ),
+                (
+                    Severity::Warning,
+                    "field `export::Pod::b` moved from offset 4 to 8"
+                ),
+                (
+                    Severity::Warning,
+                    "the type of field `export::Pod::b` changed from `i32` to `i64`"
+                ),
+                (
+                    Severity::Warning,
+                    "the signature of method `export::Pod::sum` changed from `fn(i32) -> i32` \
+                     to `fn(i32, i32) -> i32`"
+                ),
+                (Severity::Warning, "struct `export::Gone` was removed"),
+                (Severity::Note, "struct `export::Added` was added"),
+            ]
+        );
+        assert!(!diags.has_errors());
+        assert!(compare(&baseline, &current, true).has_errors());
+        assert!(compare(&current, &current, true).is_empty());
+    }
+}
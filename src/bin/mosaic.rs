@@ -7,6 +7,13 @@
 // except according to those terms.
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    clang_sys::load().expect("Couldn't find libclang");
+    if let Err(err) = clang_sys::load() {
+        eprintln!("Error: could not load libclang: {}", err);
+        eprintln!(
+            "Set LIBCLANG_PATH to the directory containing the libclang shared library, \
+             or put llvm-config on PATH."
+        );
+        std::process::exit(1);
+    }
     std::process::exit(mosaic::main()?)
 }
@@ -7,7 +7,6 @@
 // except according to those terms.
 
 use cc_crate as cc;
-use itertools::Itertools;
 use std::{
     env,
     error::Error,
@@ -15,7 +14,6 @@ use std::{
     path::{Path, PathBuf},
     process::{self, Command, Output},
 };
-use trybuild as tb;
 
 struct Opts {
     nocapture: bool,
@@ -50,16 +48,6 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     println!("");
 
-    let ui_failed = std::panic::catch_unwind(|| {
-        let strategy = RunGenerator {
-            source_root: source_root.into(),
-        };
-        let t = trybuild::TestCases::new_with_strategy(strategy);
-        t.compile_fail("test/ui/*.cc");
-        t.compile_fail("test/ui/*.rs");
-    })
-    .is_err();
-
     let mut failures = vec![];
     let mut passes = 0usize;
     for test in source_root.join("test").join("run-make").read_dir()? {
@@ -108,7 +96,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    let failed = !failures.is_empty() || ui_failed;
+    let failed = !failures.is_empty();
     let result = if failed { "FAILED" } else { "ok" };
     println!("");
     println!(
@@ -125,59 +113,6 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-#[derive(Debug)]
-struct RunGenerator {
-    source_root: PathBuf,
-}
-impl tb::Strategy for RunGenerator {
-    fn prepare(&mut self, _tests: &[tb::Test]) -> tb::Result<tb::Project> {
-        let mut project = tb::Project::new()?;
-        project.source_dir = self.source_root.clone();
-        Ok(project)
-    }
-
-    fn build(&self, test: &tb::Test) -> tb::Result<Command> {
-        let mut cmd = if cfg!(windows) {
-            Command::new(self.source_root.join("target\\debug\\mosaic.exe"))
-        } else {
-            Command::new(self.source_root.join("target/debug/mosaic"))
-        };
-        cmd.arg(test.path()).env("TERM", "dumb");
-        Ok(cmd)
-    }
-
-    fn run(&self, _test: &tb::Test) -> tb::Result<Command> {
-        unimplemented!()
-    }
-
-    fn normalize(&self, _project: &tb::Project, test: &tb::Test, output: &str) -> String {
-        output
-            .lines()
-            .map(|line| {
-                const PAT: &str = "┌─ ";
-                if line.trim_start().starts_with(PAT) {
-                    let cut_start = line.find(PAT).unwrap() + PAT.len();
-                    if let Some(cut_end) = line.rfind(&['/', '\\'][..]) {
-                        return String::from(&line[..cut_start]) + "$DIR/" + &line[cut_end + 1..];
-                    }
-                }
-                line.trim_end().replace(&test.crate_name(), "$CRATE")
-            })
-            .join("\n")
-    }
-
-    fn matches(
-        &self,
-        project: &tb::Project,
-        test: &tb::Test,
-        expected: &str,
-        actual: &str,
-    ) -> bool {
-        let actual = self.normalize(project, test, actual);
-        expected == actual
-    }
-}
-
 enum TestResult {
     Ok,
     Failed(Option<Output>),
@@ -13,16 +13,18 @@ use crate::{
         db::{FileId, SourceFileCache},
         Diagnostic, Diagnostics, Outcome,
     },
-    ir, libclang,
+    ir::{self, DefIr},
+    libclang, Options,
 };
 use cc_use_common::{CcPath, CcUse};
 use gen_macro::{snippet, write_gen, Snippet};
 use proc_macro2::Span;
 use std::fs::File;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     io::{self, Read},
-    path::Path,
+    iter,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 use syn::{self, spanned::Spanned};
@@ -39,7 +41,7 @@ pub trait RsSource: SourceFileCache {
 
 /// Generates IR for `cc_use!` imports in Rust source.
 #[salsa::query_group(RsImportIrStorage)]
-pub trait RsImportIr: RsSource {
+pub trait RsImportIr: RsSource + DefIr + Options {
     #[doc(hidden)]
     fn headers_with_imports(&self) -> Arc<Outcome<Vec<HeaderInfo>>>;
 
@@ -57,15 +59,18 @@ pub trait RsImportIr: RsSource {
 
     /// The set of (top-level) imports in the Rust crate for a given `ModuleId`.
     fn imports_for(&self, mdl: ir::bindings::ModuleId) -> Outcome<Arc<[ir::bindings::Import]>>;
+
+    /// The libraries named with `in "lib"` in the Rust crate, without duplicates.
+    fn link_libs(&self) -> Outcome<Arc<[String]>>;
 }
 
-/// Parses the C++ header file for the given `ModuleId`.
+/// Parses the C++ headers for the given `ModuleId`.
 // This should probably be a query, but the libclang types don't easily go in salsa.
 pub(crate) fn cc_module_from_rs(
     db: &impl RsImportIr,
     index: &libclang::Index,
     module_id: ir::bindings::ModuleId,
-) -> (libclang::ModuleContext, libclang::ParseErrors) {
+) -> libclang::ParseResult {
     let rs_source_path = db.rs_source_root().unwrap().name(db);
     let hdrs = db.headers_with_imports();
     // skip_errs okay since the user must go through an outcome with these errors to get module_id.
@@ -74,62 +79,152 @@ pub(crate) fn cc_module_from_rs(
         db,
         index,
         Path::new(&rs_source_path),
-        &hdr.header,
+        &hdr.headers,
         hdr.module_id,
     );
     parse
 }
 
+/// Parses the C++ headers for each of `module_ids`, on up to
+/// [`SessionOptions::jobs`](crate::SessionOptions::jobs) threads.
+///
+/// If any module fails to parse, every failure is reported and `None` is
+/// returned, since the modules can't be lowered without all of them.
+pub(crate) fn cc_modules_from_rs(
+    db: &crate::Database,
+    module_ids: &[ir::bindings::ModuleId],
+) -> Result<Outcome<Option<Vec<libclang::ParsedModule>>>, rayon::ThreadPoolBuildError> {
+    let jobs = db.options().jobs;
+    let parsed: Vec<_> = if jobs <= 1 {
+        let index = libclang::create_index();
+        module_ids
+            .iter()
+            .map(|id| Ok(cc_module_from_rs(db, &index, *id)))
+            .collect()
+    } else {
+        libclang::parse_in_parallel(db, jobs, module_ids, |db, index, id| {
+            cc_module_from_rs(db, index, *id)
+        })?
+    };
+    let hdrs = db.headers_with_imports();
+    let hdrs = hdrs.to_ref().skip_errs();
+    let mut modules = vec![];
+    let mut errs = Diagnostics::new();
+    for (id, result) in module_ids.iter().zip(parsed) {
+        match result {
+            Ok(Ok(module)) => modules.push(module),
+            Ok(Err(diag)) => errs.add(diag),
+            Err(msg) => {
+                const MESSAGE: &str = "failed to parse headers";
+                let span = hdrs[id.as_usize()]
+                    .headers
+                    .iter()
+                    .find_map(|hdr| hdr.span.as_ref());
+                let diag = match span {
+                    Some(span) => Diagnostic::error(
+                        MESSAGE,
+                        span.label("clang could not parse the headers imported here"),
+                    ),
+                    None => Diagnostic::new(diagnostics::Severity::Error, MESSAGE),
+                };
+                errs.add(diag.with_note(msg));
+            }
+        }
+    }
+    let modules = if errs.is_empty() { Some(modules) } else { None };
+    Ok(Outcome::from_parts(modules, errs))
+}
+
 #[doc(hidden)]
 #[derive(Debug, Eq, PartialEq)]
 pub struct HeaderInfo {
     module_id: ir::bindings::ModuleId,
-    header: ir::bindings::Header,
+    /// The headers parsed together for this module, in the order they're
+    /// included.
+    headers: Vec<ir::bindings::Header>,
     imports: Vec<ir::bindings::Import>,
+    libs: BTreeSet<String>,
 }
 
-// Parse Rust file and return IR imports grouped by header.
+// Parse Rust file and return IR imports grouped by the set of headers they import from. Each set
+// of headers is parsed as one module.
 fn headers_with_imports(db: &impl RsImportIr) -> Arc<Outcome<Vec<HeaderInfo>>> {
     let file_id = db.rs_source_root().unwrap();
+    let rs_path = file_id.name(db);
+    let search_dirs: Vec<_> = iter::once(Path::new(&rs_path).parent().unwrap_or(Path::new("")))
+        .map(Path::to_owned)
+        .chain(db.options().include_dirs.iter().cloned())
+        .collect();
     let headers = parse_rs_file(db, &file_id.contents(db), file_id).then(|macros| {
+        let mut errs = Diagnostics::new();
         let mut header_map = BTreeMap::new();
         for mac in &macros {
-            header_map
-                .entry((&mac.header.path, mac.header.is_system))
-                .or_insert((mac.header.span, Vec::new()))
-                .1
-                .push(&mac.cc_paths);
+            let mut paths = Vec::with_capacity(mac.headers.len());
+            for hdr in &mac.headers {
+                let (path, err) = resolve_header(db, file_id, &search_dirs, hdr).split();
+                errs.append(err);
+                paths.extend(path);
+            }
+            // The imports can't be found without the header, so don't report each of them too.
+            if paths.len() != mac.headers.len() {
+                continue;
+            }
+            // Each header is included once, even if it's named more than once, since a header
+            // without an include guard can't be included twice.
+            let mut key = Vec::with_capacity(paths.len());
+            let mut spans = Vec::with_capacity(paths.len());
+            for (path, hdr) in paths.into_iter().zip(&mac.headers) {
+                let path = (path, hdr.is_system);
+                if !key.contains(&path) {
+                    key.push(path);
+                    spans.push(hdr.span);
+                }
+            }
+            let entry = header_map
+                .entry(key)
+                .or_insert((spans, Vec::new(), BTreeSet::new()));
+            entry.1.push(&mac.imports);
+            entry
+                .2
+                .extend(mac.lib_name.iter().map(|lib| lib.name.clone()));
         }
-        let mut errs = Diagnostics::new();
         let headers = header_map
             .iter()
             .enumerate()
-            .map(|(id, (hdr, macros))| {
+            .map(|(id, (hdrs, macros))| {
                 let module_id = ir::bindings::ModuleId::new(id as _);
-                let header = ir::bindings::Header {
-                    path: hdr.0.clone(),
-                    is_system: hdr.1,
-                    span: Some(span(db, file_id, macros.0)),
-                };
+                let headers = hdrs
+                    .iter()
+                    .zip(&macros.0)
+                    .map(|((path, is_system), hdr_span)| ir::bindings::Header {
+                        path: path.clone(),
+                        is_system: *is_system,
+                        span: Some(span(db, file_id, *hdr_span)),
+                    })
+                    .collect();
                 let imports = macros
                     .1
                     .iter()
                     .copied()
                     .flatten()
-                    .flat_map(|cc_path| {
-                        let (path, err) = convert_path(db, file_id, cc_path).split();
+                    .flat_map(|import| {
+                        let (path, err) = convert_path(db, file_id, &import.path).split();
                         errs.append(err);
                         path.map(|ir_path| ir::bindings::Import {
                             mdl: module_id,
                             path: ir_path,
-                            span: span(db, file_id, cc_path.span()),
+                            alias: import.alias.as_ref().map(|alias| {
+                                ir::bindings::Ident::new_mangled(db, &alias.to_string())
+                            }),
+                            span: span(db, file_id, import.path.span()),
                         })
                     })
                     .collect();
                 HeaderInfo {
                     module_id,
-                    header,
+                    headers,
                     imports,
+                    libs: macros.2.clone(),
                 }
             })
             .collect();
@@ -138,6 +233,35 @@ fn headers_with_imports(db: &impl RsImportIr) -> Arc<Outcome<Vec<HeaderInfo>>> {
     Arc::new(headers)
 }
 
+/// Resolves a quoted header to an absolute path, so the generated code finds the
+/// same file no matter where it's compiled from. System headers are left for the
+/// C++ compiler to find.
+fn resolve_header(
+    db: &impl SourceFileCache,
+    file_id: FileId,
+    search_dirs: &[PathBuf],
+    hdr: &cc_use_common::Header,
+) -> Outcome<Option<String>> {
+    if hdr.is_system {
+        return Outcome::from_ok(Some(hdr.path.clone()));
+    }
+    match cc_use_common::find_header(&hdr.path, search_dirs) {
+        Some(path) => {
+            let path = path.canonicalize().unwrap_or(path);
+            Outcome::from_ok(Some(path.to_string_lossy().into_owned()))
+        }
+        None => Outcome::from_err(
+            None,
+            Diagnostic::error(
+                format!("header not found: `{}`", hdr.path),
+                span(db, file_id, hdr.span)
+                    .label("not found next to this file or in the include directories"),
+            )
+            .with_note(cc_use_common::describe_search(search_dirs)),
+        ),
+    }
+}
+
 fn module_ids(db: &impl RsImportIr) -> Outcome<Arc<[ir::bindings::ModuleId]>> {
     db.headers_with_imports().to_ref().map(|hdrs| {
         (0..(hdrs.len() as _))
@@ -147,9 +271,14 @@ fn module_ids(db: &impl RsImportIr) -> Outcome<Arc<[ir::bindings::ModuleId]>> {
 }
 
 fn headers(db: &impl RsImportIr) -> Outcome<Arc<[ir::bindings::Header]>> {
-    db.headers_with_imports()
-        .to_ref()
-        .map(|data| data.iter().map(|hdr| hdr.header.clone()).collect())
+    db.headers_with_imports().to_ref().map(|data| {
+        let mut seen = BTreeSet::new();
+        data.iter()
+            .flat_map(|info| info.headers.iter())
+            .filter(|hdr| seen.insert((&hdr.path, hdr.is_system)))
+            .cloned()
+            .collect()
+    })
 }
 
 fn imports(db: &impl RsImportIr) -> Outcome<Arc<[ir::bindings::Import]>> {
@@ -178,6 +307,19 @@ fn imports_for(
     })
 }
 
+fn link_libs(db: &impl RsImportIr) -> Outcome<Arc<[String]>> {
+    if db.rs_source_root().is_none() {
+        return Outcome::from_ok(Arc::new([]));
+    }
+    db.headers_with_imports().to_ref().map(|data| {
+        data.iter()
+            .flat_map(|hdr| hdr.libs.iter().cloned())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    })
+}
+
 fn parse_rs_file(
     db: &impl SourceFileCache,
     contents: &str,
@@ -215,41 +357,36 @@ fn parse_rs_file(
 
 #[rustfmt::skip::macros(write_gen)]
 fn load_cc_module(
-    db: &impl SourceFileCache,
+    db: &(impl SourceFileCache + Options),
     index: &libclang::Index,
     rs_src_path: &Path,
-    header: &ir::bindings::Header,
+    headers: &[ir::bindings::Header],
     module_id: ir::bindings::ModuleId,
-) -> (libclang::ModuleContext, libclang::ParseErrors) {
-    let (ctx, errs) = libclang::parse_with(db, index, module_id, |index| {
-        let mut code = Vec::new();
-        let writer = &mut CodeWriter::new(&mut code);
+) -> libclang::ParseResult {
+    let mut code = Vec::new();
+    let writer = &mut CodeWriter::new(&mut code);
 
+    // Each header gets one line, so errors can be traced back to it.
+    for header in headers {
         let path: Snippet = header.path.clone().into();
         let quoted_path = if header.is_system {
             snippet!(db, "<$path>")
         } else {
             snippet!(db, r#""$path""#)
         };
-        // TODO: This line directive doesn't have the intended effect. We can either figure
-        // out a way to use the "presumed location" libclang gives us, or find a way to present
-        // a better error ourselves.
-        let line: Snippet = header.span.as_ref().map(|_| 1).unwrap().to_string().into(); // TODO
         write_gen!(db, writer, "
-            #line $line
             #include $quoted_path
         ")
         .unwrap();
+    }
 
-        let code = std::str::from_utf8(&code).unwrap();
+    let code = String::from_utf8(code).unwrap();
 
-        // Use the rust source as the path so it shows up in "not found" errors
-        let mut parser = libclang::configure(index.parser(rs_src_path));
-        let unsaved = clang::Unsaved::new(rs_src_path, code);
-        // unwrap is okay because we know the "file" exists.
-        parser.unsaved(&[unsaved]).parse().unwrap()
-    });
-    (ctx, errs)
+    // Errors in the generated file, like a missing header, are reported on the header named in
+    // the cc_use! instead.
+    let (ctx, errs) = libclang::parse_with(db, index, module_id, rs_src_path, Some(code))?;
+    let spans = headers.iter().flat_map(|hdr| hdr.span.clone()).collect();
+    Ok((ctx, errs.attribute_to(spans)))
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -304,7 +441,7 @@ fn error(db: &impl SourceFileCache, file_id: FileId, input: syn::Error) -> Diagn
 }
 
 fn convert_path(
-    db: &impl SourceFileCache,
+    db: &(impl SourceFileCache + DefIr),
     file_id: FileId,
     other: &CcPath,
 ) -> Outcome<Option<ir::bindings::Path>> {
@@ -342,10 +479,360 @@ fn convert_path(
             }
             syn::PathArguments::Parenthesized(args) => return invalid_arg(args.span()),
         }
+        // Raw identifiers like `r#type` can't name a C++ item.
+        let name = match ir::bindings::Ident::new(db, &segment.ident.to_string()) {
+            Ok(name) => name,
+            Err(invalid) => {
+                let sp = span(db, file_id, segment.ident.span());
+                return Outcome::from_err(None, invalid.to_diagnostic(&sp, "path segment"));
+            }
+        };
         components.push(ir::bindings::PathComponent {
-            name: ir::bindings::Ident::from(segment.ident.to_string()),
+            name,
             args: component_args,
         });
     }
     Outcome::from_ok(Some(ir::bindings::Path::from(components)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    /// Writes `contents` to a `lib.rs` in a new directory, along with an empty
+    /// file for each of `headers`, and makes it the source root.
+    fn set_rs_source(sess: &mut Session, contents: &str, headers: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for hdr in headers {
+            let path = dir.path().join(hdr);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let rs_path = dir.path().join("lib.rs");
+        std::fs::write(&rs_path, contents).unwrap();
+        let file_id = SourceFile::intern_from_path(&sess.db, &rs_path).unwrap();
+        sess.db.set_rs_source_root(Some(file_id));
+        dir
+    }
+
+    /// The absolute path a header at `path` is resolved to.
+    fn resolved(path: PathBuf) -> String {
+        path.canonicalize().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn link_libs() {
+        let mut sess = Session::test();
+        let _dir = set_rs_source(
+            &mut sess,
+            r#"
+            cc_use!("a.h" in "libfoo", A);
+            cc_use!("b.h" in libfoo, B);
+            cc_use!("b.h" in "libbar", C);
+            cc_use!("c.h", D);
+            "#,
+            &["a.h", "b.h", "c.h"],
+        );
+        let (libs, errs) = sess.db.link_libs().split();
+        assert!(errs.is_empty());
+        assert_eq!(&*libs, &["libbar".to_string(), "libfoo".to_string()]);
+    }
+
+    /// Lowers `rs_src` importing from `header`, and returns each reexport in
+    /// the bindings crate along with the lowering errors.
+    fn lower_imports(header: &str, rs_src: &str) -> (Vec<String>, Vec<String>) {
+        lower_imports_from(&[("test.h", header)], rs_src)
+    }
+
+    /// Like `lower_imports`, but with each `(name, contents)` in `headers`.
+    fn lower_imports_from(headers: &[(&str, &str)], rs_src: &str) -> (Vec<String>, Vec<String>) {
+        let (sess, krate, errs) = lower_crate(headers, rs_src);
+        let mut paths = vec![];
+        reexports(&sess.db, krate.root, "", &mut paths);
+        let errs = errs.iter().map(|diag| diag.message().to_string()).collect();
+        (paths, errs)
+    }
+
+    /// Lowers `rs_src` importing from `headers`, and returns the session it
+    /// was lowered in, the bindings crate and the lowering diagnostics.
+    fn lower_crate(
+        headers: &[(&str, &str)],
+        rs_src: &str,
+    ) -> (Session, ir::rs::BindingsCrate, Diagnostics) {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, contents) in headers {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+        let rs_path = dir.path().join("lib.rs");
+        std::fs::write(&rs_path, rs_src).unwrap();
+
+        let mut sess = Session::test();
+        let file_id = SourceFile::intern_from_path(&sess.db, &rs_path).unwrap();
+        sess.db.set_rs_source_root(Some(file_id));
+        let index = libclang::create_index();
+        let (asts, parse_errs): (Vec<_>, Vec<_>) = sess
+            .db
+            .module_ids()
+            .skip_errs()
+            .iter()
+            .map(|mdl| cc_module_from_rs(&sess.db, &index, *mdl).unwrap())
+            .unzip();
+        let (krate, errs) = libclang::set_ast(&mut sess.db, asts, |db| {
+            crate::lower_bindings(db, parse_errs)
+        })
+        .split();
+        (sess, krate, errs)
+    }
+
+    /// Lists each reexport in `mdl` and its submodules as `path => target`.
+    fn reexports(db: &crate::Database, mdl: ir::rs::ModuleId, prefix: &str, out: &mut Vec<String>) {
+        let mdl = mdl.lookup(db);
+        let prefix = format!("{}{}::", prefix, mdl.name.as_str(db));
+        for child in &mdl.children {
+            match child {
+                ir::rs::ItemKind::Module(id) => reexports(db, *id, &prefix, out),
+                ir::rs::ItemKind::Reexport(path, name, _) => out.push(format!(
+                    "{}{} => {}",
+                    prefix,
+                    name.as_str(db),
+                    path.lookup(db).display(db)
+                )),
+                ir::rs::ItemKind::Struct(_)
+                | ir::rs::ItemKind::Opaque(_)
+                | ir::rs::ItemKind::Alias(_) => (),
+            }
+        }
+    }
+
+    /// Lists each struct in `mdl` and its submodules by path.
+    fn structs(db: &crate::Database, mdl: ir::rs::ModuleId, prefix: &str, out: &mut Vec<String>) {
+        let mdl = mdl.lookup(db);
+        let prefix = format!("{}{}::", prefix, mdl.name.as_str(db));
+        for child in &mdl.children {
+            match child {
+                ir::rs::ItemKind::Module(id) => structs(db, *id, &prefix, out),
+                ir::rs::ItemKind::Struct(id) => {
+                    out.push(format!("{}{}", prefix, id.lookup(db).name.as_str(db)))
+                }
+                ir::rs::ItemKind::Reexport(..)
+                | ir::rs::ItemKind::Opaque(_)
+                | ir::rs::ItemKind::Alias(_) => (),
+            }
+        }
+    }
+
+    #[test]
+    fn nested_imports() {
+        let (paths, errs) = lower_imports(
+            "struct C { int c; }; namespace Foo { struct C { char c; }; }",
+            r#"cc_use!("test.h", C, Foo::C);"#,
+        );
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(
+            paths,
+            vec![
+                "::export::C => crate::bind::C",
+                "::export::Foo::C => crate::bind::Foo::C",
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_imports() {
+        let (paths, errs) = lower_imports(
+            "struct Widget { int w; }; namespace ns { struct Widget { char c; }; }",
+            r#"cc_use!("test.h", Widget, ns::Widget as NsWidget, Widget as CWidget);"#,
+        );
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(
+            paths,
+            vec![
+                "::export::Widget => crate::bind::Widget",
+                "::export::CWidget => crate::bind::Widget",
+                "::export::ns::NsWidget => crate::bind::ns::Widget",
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_conflicts_with_import() {
+        let (_, errs) = lower_imports(
+            "struct A { int a; }; struct B { int b; };",
+            r#"
+            cc_use!("test.h", A as B);
+            cc_use!("test.h", B);
+            "#,
+        );
+        assert_eq!(errs, vec!["conflicting exports named `B`"]);
+    }
+
+    #[test]
+    fn missing_header() {
+        let mut sess = Session::test();
+        let _dir = set_rs_source(&mut sess, r#"cc_use!("missing.h", A, B);"#, &[]);
+        let (headers, errs) = sess.db.headers().split();
+        assert!(headers.is_empty());
+        let errs: Vec<_> = errs.iter().map(|diag| diag.message()).collect();
+        assert_eq!(errs, vec!["header not found: `missing.h`"]);
+    }
+
+    #[test]
+    fn header_search_dirs() {
+        let include_dir = tempfile::tempdir().unwrap();
+        for hdr in &["b.h", "c.h"] {
+            std::fs::write(include_dir.path().join(hdr), "").unwrap();
+        }
+        let mut sess = Session::with_options(crate::SessionOptions {
+            include_dirs: vec![include_dir.path().to_owned()],
+            ..Default::default()
+        });
+        let dir = set_rs_source(
+            &mut sess,
+            r#"
+            cc_use!("sub/a.h", A);
+            cc_use!("b.h", B);
+            cc_use!("c.h", C);
+            "#,
+            &["sub/a.h", "b.h"],
+        );
+        let (headers, errs) = sess.db.headers().split();
+        assert!(errs.is_empty());
+        let headers: Vec<_> = headers.iter().map(|hdr| hdr.path.clone()).collect();
+        let mut expected = vec![
+            resolved(dir.path().join("sub/a.h")),
+            // Headers next to the Rust file take precedence.
+            resolved(dir.path().join("b.h")),
+            resolved(include_dir.path().join("c.h")),
+        ];
+        expected.sort();
+        assert_eq!(headers, expected);
+    }
+
+    #[test]
+    fn unknown_item() {
+        let (_, _, errs) = lower_crate(
+            &[("test.h", "struct Widget { int w; };")],
+            r#"cc_use!("test.h", Widget, Wdget);"#,
+        );
+        let errs: Vec<_> = errs.iter().collect();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].message(), "item not found: `Wdget`");
+        assert_eq!(errs[0].notes(), ["did you mean `Widget`?"]);
+    }
+
+    #[test]
+    fn multiple_headers() {
+        let mut sess = Session::test();
+        let dir = set_rs_source(
+            &mut sess,
+            r#"
+            cc_use!(["a.h", "a.h", <b.h>], A);
+            cc_use!("a.h", B);
+            "#,
+            &["a.h"],
+        );
+        let (headers, errs) = sess.db.headers().split();
+        assert!(errs.is_empty());
+        let headers: Vec<_> = headers
+            .iter()
+            .map(|hdr| (hdr.path.clone(), hdr.is_system))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                (resolved(dir.path().join("a.h")), false),
+                ("b.h".to_string(), true)
+            ]
+        );
+        assert_eq!(sess.db.module_ids().skip_errs().len(), 2);
+    }
+
+    #[test]
+    fn field_type_from_another_header() {
+        let (paths, errs) = lower_imports_from(
+            &[
+                ("inner.h", "struct Inner { int i; };"),
+                ("outer.h", "struct Outer { Inner inner; };"),
+            ],
+            r#"cc_use!(["inner.h", "outer.h"], Outer, Inner);"#,
+        );
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(
+            paths,
+            vec![
+                "::export::Outer => crate::bind::Outer",
+                "::export::Inner => crate::bind::Inner",
+            ]
+        );
+    }
+
+    /// Headers that both include `shared.h`, which defines `Shared`.
+    const DIAMOND: &[(&str, &str)] = &[
+        (
+            "shared.h",
+            "#pragma once\n#warning parsing shared.h\nstruct Shared { int s; };\n",
+        ),
+        (
+            "guarded.h",
+            "#ifndef GUARDED_H\n#define GUARDED_H\n#include \"shared.h\"\n#endif\n",
+        ),
+        (
+            "left.h",
+            "#include \"guarded.h\"\nstruct Left { Shared s; };\n",
+        ),
+        (
+            "right.h",
+            "#include \"guarded.h\"\nstruct Right { Shared s; };\n",
+        ),
+    ];
+
+    #[test]
+    fn diamond_includes() {
+        let (sess, krate, errs) = lower_crate(
+            DIAMOND,
+            r#"cc_use!(["left.h", "right.h", "shared.h"], Left, Right, Shared);"#,
+        );
+        let errs: Vec<_> = errs.iter().map(|diag| diag.message()).collect();
+        assert_eq!(errs, vec!["parsing shared.h"]);
+        let mut paths = vec![];
+        reexports(&sess.db, krate.root, "", &mut paths);
+        assert_eq!(
+            paths,
+            vec![
+                "::export::Left => crate::bind::Left",
+                "::export::Right => crate::bind::Right",
+                "::export::Shared => crate::bind::Shared",
+            ]
+        );
+        let mut names = vec![];
+        structs(&sess.db, krate.root, "", &mut names);
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["::bind::Left", "::bind::Right", "::bind::Shared"]
+        );
+    }
+
+    #[test]
+    fn header_named_twice() {
+        let (paths, errs) = lower_imports_from(
+            &[("unguarded.h", "struct A { int a; };")],
+            r#"cc_use!(["unguarded.h", "unguarded.h"], A);"#,
+        );
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(paths, vec!["::export::A => crate::bind::A"]);
+    }
+
+    #[test]
+    fn conflicting_definitions_across_headers() {
+        let (_, errs) = lower_imports_from(
+            &[
+                ("a.h", "struct A { int a; };"),
+                ("b.h", "struct A { char c; };"),
+            ],
+            r#"cc_use!(["a.h", "b.h"], A);"#,
+        );
+        assert_eq!(errs, vec!["redefinition of 'A'"]);
+    }
+}
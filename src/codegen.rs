@@ -11,15 +11,18 @@
 #![cfg_attr(rustfmt, rustfmt::skip::macros(write_gen))]
 
 use crate::{
+    diagnostics::{Diagnostic, Diagnostics},
     ir::{
         bindings,
         cc::{self, RsTargetIr},
         rs::{self, RsTargetBindings},
+        DefIr,
     },
     libclang::CcSourceIr,
 };
 use gen_macro::{snippet, write_gen, write_gen_if, Gen, Snippet};
 use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
 
 pub(crate) use gen_macro::CodeWriter;
@@ -64,17 +67,36 @@ pub(crate) fn perform_codegen(
 
     if !skip_header {
         if let Some(rs) = out.rs.as_mut() {
+            if !db.options().inline_crate {
+                write_gen!(db, rs, "
+                    #![allow(deprecated, non_camel_case_types)]
+                ")?;
+                if db.options().no_std {
+                    write_gen!(db, rs, "
+                        #![no_std]
+                    ")?;
+                }
+            }
             write_gen!(db, rs, "
-                #![allow(non_camel_case_types)]
                 extern crate core;
 
             ")?;
+            // Libraries named in cc_use! are linked by the bindings crate, so
+            // each one is linked once no matter how many invocations name it.
+            for lib in db.link_libs().skip_errs().iter() {
+                let name: Snippet = link_name(lib).into();
+                write_gen!(db, rs, r#"
+                    #[link(name = "$name")]
+                    extern "C" {}
+
+                "#)?;
+            }
         }
         if let Some(cc) = out.cc.as_mut() {
             for hdr in headers {
                 let include_path = match hdr.is_system {
-                    true => Snippet::from(format!(r#""{}""#, hdr.path)),
-                    false => Snippet::from(format!(r#"<{}>"#, hdr.path)),
+                    true => Snippet::from(format!(r#"<{}>"#, hdr.path)),
+                    false => Snippet::from(format!(r#""{}""#, hdr.path)),
                 };
                 write_gen!(db, cc, r#"
                     #include $include_path
@@ -88,17 +110,104 @@ pub(crate) fn perform_codegen(
     Ok(())
 }
 
+/// Returns the name to link a library by, as in `-lfoo` for `libfoo`.
+pub(crate) fn link_name(lib: &str) -> String {
+    match lib.strip_prefix("lib") {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => lib.to_string(),
+    }
+}
+
+/// Returns the macro that enables the C++ shims of a config set, like
+/// `MOSAIC_CONFIG_use_double` for `use_double`.
+fn config_guard(feature: &str) -> Snippet {
+    format!("MOSAIC_CONFIG_{}", feature).into()
+}
+
+/// Returns a Rust type as it's spelled in the generated code.
+pub(crate) fn ty_to_string(db: &impl RsTargetIr, ty: rs::Ty) -> String {
+    snippet!(db, "$ty").to_string()
+}
+
+/// Returns the type a parameter is passed as in the generated Rust.
+///
+/// C++ references to structs become Rust references, which the shim receives
+/// as pointers and dereferences again. A `const T&` to a scalar is passed as
+/// `T` instead, since the callee can't tell the difference, but `T&` to a
+/// scalar is still `&mut T` so the caller sees what's written to it. With
+/// `raw_pointer_refs`, every reference is passed as a raw pointer.
+pub(crate) fn param_ty(db: &impl RsTargetIr, ty: rs::Ty) -> rs::Ty {
+    match ty {
+        rs::Ty::Ref { pointee, mutable } if db.options().raw_pointer_refs => {
+            rs::Ty::Ptr { pointee, mutable }
+        }
+        rs::Ty::Ref {
+            pointee,
+            mutable: false,
+        } if pointee.as_rs(db).skip_errs().is_scalar() => pointee.as_rs(db).skip_errs(),
+        ty => ty,
+    }
+}
+
+/// Returns the type a return value is passed as through the shim and the
+/// extension trait. Returned references become raw pointers, since nothing
+/// says how long they live.
+pub(crate) fn return_ty(ty: rs::Ty) -> rs::Ty {
+    match ty {
+        rs::Ty::Ref { pointee, mutable } => rs::Ty::Ptr { pointee, mutable },
+        ty => ty,
+    }
+}
+
+/// Returns the type a method's wrapper on the struct returns.
+///
+/// A returned reference is assumed to point into the object, so the wrapper
+/// returns it borrowed from `self`. Const methods take `&self`, so they can
+/// only return shared references.
+fn wrapper_return_ty(db: &impl RsTargetIr, meth: &rs::Method) -> rs::Ty {
+    match meth.return_ty(db) {
+        rs::Ty::Ref { pointee, mutable } => rs::Ty::Ref {
+            pointee,
+            mutable: mutable && !meth.func().is_const,
+        },
+        ty => return_ty(ty),
+    }
+}
+
 #[rustfmt::skip::macros(write_gen, write_gen_if)]
 fn gen_module_contents(
     db: &impl RsTargetBindings,
     mdl: &rs::Module,
     out: &mut Outputs<'_, '_>,
 ) -> io::Result<()> {
-    for item in &mdl.children {
+    let children = dependency_order(db, &mdl.children);
+    let mut bundles = children
+        .iter()
+        .map(|item| item_bundle(db, item))
+        .collect::<io::Result<Vec<_>>>()?;
+    let two_layer = db.options().two_layer && bundles.iter().any(Option::is_some);
+    if two_layer {
+        gen_sys_module(&bundles, out)?;
+    }
+    let mut externs = vec![];
+    for (item, bundle) in children.iter().zip(&mut bundles) {
         match item {
             rs::ItemKind::Module(id) => {
                 let inner = id.lookup(db);
                 let (name, vis) = (&inner.name, &inner.vis);
+                // The C++ half of a feature-gated module is only valid with
+                // the definitions of its config set, so it's guarded by a
+                // macro the build defines along with them.
+                if let Some(feature) = &inner.feature {
+                    let guard = config_guard(feature);
+                    let feature: Snippet = feature.as_str().into();
+                    write_gen_if!(db, out.rs, r#"
+                        #[cfg(feature = "$feature")]
+                    "#)?;
+                    write_gen_if!(db, out.cc, "
+                        #ifdef $guard
+                    ")?;
+                }
                 write_gen_if!(db, out.rs, "
                     ${vis}mod $name {
                 ")?;
@@ -106,21 +215,233 @@ fn gen_module_contents(
                 write_gen_if!(db, out.rs, "
                     }
                 ")?;
+                if inner.feature.is_some() {
+                    write_gen_if!(db, out.cc, "
+                        #endif
+                    ")?;
+                }
             }
-            rs::ItemKind::Reexport(path_id, _) => {
+            rs::ItemKind::Reexport(path_id, name, _) => {
                 if let Some(rs) = out.rs.as_mut() {
                     let path = path_id.lookup(db);
-                    write_gen!(db, rs, "
-                        pub use $path;
-                    ")?;
+                    if path.last() == name {
+                        write_gen!(db, rs, "
+                            pub use $path;
+                        ")?;
+                    } else {
+                        write_gen!(db, rs, "
+                            pub use $path as $name;
+                        ")?;
+                    }
                 }
             }
-            rs::ItemKind::Struct(st) => gen_struct(db, &st.lookup(db), out)?,
+            rs::ItemKind::Struct(_) | rs::ItemKind::Opaque(_) | rs::ItemKind::Alias(_) => (),
+        }
+        let bundle = match bundle {
+            Some(bundle) => bundle,
+            None => continue,
+        };
+        if two_layer {
+            if let Some(rs) = out.rs.as_mut() {
+                let (vis, name) = (&bundle.vis, &bundle.name);
+                write_gen!(db, rs, "
+                    ${vis}use self::sys::$name;
+                ")?;
+            }
+            bundle.write_impls(db, out)?;
+        } else {
+            if db.options().coalesce_externs {
+                externs.append(&mut bundle.externs);
+            }
+            bundle.write(db, out)?;
         }
     }
+    if let Some(rs) = out.rs.as_mut() {
+        gen_extern_block(rs, &externs)?;
+    }
     Ok(())
 }
 
+/// Orders the items of a module so that each struct comes after the structs
+/// in the same module that its fields hold by value, and otherwise keeps the
+/// items in the order they were found in.
+///
+/// Pointers don't need the type they point to to come first, so they don't
+/// order anything. Structs that point to each other stay in their original
+/// order.
+fn dependency_order<'a>(db: &impl RsTargetIr, items: &'a [rs::ItemKind]) -> Vec<&'a rs::ItemKind> {
+    let index: HashMap<rs::StructId, usize> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, item)| match item {
+            rs::ItemKind::Struct(id) => Some((*id, idx)),
+            _ => None,
+        })
+        .collect();
+    // A depth-first search that places each item after its dependencies.
+    // Struct fields can't hold each other by value, so there are no cycles.
+    fn visit<'a>(
+        db: &impl RsTargetIr,
+        items: &'a [rs::ItemKind],
+        index: &HashMap<rs::StructId, usize>,
+        idx: usize,
+        visited: &mut [bool],
+        order: &mut Vec<&'a rs::ItemKind>,
+    ) {
+        if visited[idx] {
+            return;
+        }
+        visited[idx] = true;
+        if let rs::ItemKind::Struct(id) = &items[idx] {
+            for field in &id.lookup(db).fields {
+                if let Some(&dep) = held_struct(db, field.ty(db)).and_then(|st| index.get(&st)) {
+                    visit(db, items, index, dep, visited, order);
+                }
+            }
+        }
+        order.push(&items[idx]);
+    }
+    let mut visited = vec![false; items.len()];
+    let mut order = Vec::with_capacity(items.len());
+    for idx in 0..items.len() {
+        visit(db, items, &index, idx, &mut visited, &mut order);
+    }
+    order
+}
+
+/// Returns the struct a field of type `ty` holds by value, directly or in an
+/// array.
+fn held_struct(db: &impl RsTargetIr, ty: rs::Ty) -> Option<rs::StructId> {
+    match ty {
+        rs::Ty::Struct(id) => Some(id),
+        rs::Ty::Array { elem, .. } => held_struct(db, elem.as_rs(db).skip_errs()),
+        _ => None,
+    }
+}
+
+/// Generates the code of an item that's a type, or returns `None` for
+/// modules and reexports.
+fn item_bundle(db: &impl RsTargetBindings, item: &rs::ItemKind) -> io::Result<Option<ItemBundle>> {
+    let bundle = match item {
+        rs::ItemKind::Struct(id) => gen_struct(db, &id.lookup(db))?,
+        rs::ItemKind::Opaque(id) => gen_opaque(db, *id)?,
+        rs::ItemKind::Alias(id) => gen_alias(db, *id)?,
+        rs::ItemKind::Module(_) | rs::ItemKind::Reexport(..) => return Ok(None),
+    };
+    Ok(Some(bundle))
+}
+
+/// Generates the `sys` module of a module in two-layer mode. It holds the
+/// raw types and the extern declarations of the shims, which the wrappers in
+/// the parent module call.
+fn gen_sys_module(bundles: &[Option<ItemBundle>], out: &mut Outputs<'_, '_>) -> io::Result<()> {
+    let rs = match out.rs.as_mut() {
+        Some(rs) => rs,
+        None => return Ok(()),
+    };
+    writeln!(rs, "pub mod sys {{")?;
+    rs.with_indent(|rs| -> io::Result<()> {
+        for bundle in bundles.iter().flatten() {
+            bundle.write_def(rs)?;
+        }
+        let externs: Vec<u8> = bundles
+            .iter()
+            .flatten()
+            .flat_map(|bundle| bundle.externs.iter().copied())
+            .collect();
+        gen_extern_block(rs, &externs)
+    })?;
+    writeln!(rs, "}}")
+}
+
+/// The code generated for one type, in sections that are written out
+/// together in a fixed order: the definition, assertions of its layout, its
+/// inherent impl, its trait impls and the extern block declaring its shims.
+/// Keeping each type's code in one place makes the output easy to read and
+/// to diff.
+///
+/// In two-layer mode the definition and layout go in the `sys` module and
+/// the impls in its parent. The extern blocks of a module can also be
+/// coalesced into one at its end, with `coalesce_externs`.
+struct ItemBundle {
+    name: rs::Ident,
+    vis: rs::Visibility,
+    def: Vec<u8>,
+    layout: Vec<u8>,
+    /// The body of the type's `impl` block, which is only written if it's
+    /// not empty.
+    inherent: Vec<u8>,
+    traits: Vec<u8>,
+    /// The declarations in the extern block.
+    externs: Vec<u8>,
+    /// The C++ shims.
+    cc: Vec<u8>,
+}
+
+#[rustfmt::skip::macros(write_gen)]
+impl ItemBundle {
+    fn new(name: rs::Ident, vis: rs::Visibility) -> ItemBundle {
+        ItemBundle {
+            name,
+            vis,
+            def: vec![],
+            layout: vec![],
+            inherent: vec![],
+            traits: vec![],
+            externs: vec![],
+            cc: vec![],
+        }
+    }
+
+    /// Writes every section, with the extern block unless it was taken to
+    /// be coalesced.
+    fn write(&self, db: &impl RsTargetIr, out: &mut Outputs<'_, '_>) -> io::Result<()> {
+        if let Some(rs) = out.rs.as_mut() {
+            self.write_def(rs)?;
+        }
+        self.write_impls(db, out)?;
+        match out.rs.as_mut() {
+            Some(rs) => gen_extern_block(rs, &self.externs),
+            None => Ok(()),
+        }
+    }
+
+    fn write_def(&self, rs: &mut CodeWriter<'_>) -> io::Result<()> {
+        rs.write_all(&self.def)?;
+        rs.write_all(&self.layout)
+    }
+
+    fn write_impls(&self, db: &impl RsTargetIr, out: &mut Outputs<'_, '_>) -> io::Result<()> {
+        if let Some(rs) = out.rs.as_mut() {
+            if !self.inherent.is_empty() {
+                let name = &self.name;
+                write_gen!(db, rs, "
+                    impl $name {
+                ")?;
+                rs.with_indent(|rs| rs.write_all(&self.inherent))?;
+                write_gen!(db, rs, "
+                    }
+                ")?;
+            }
+            rs.write_all(&self.traits)?;
+        }
+        if let Some(cc) = out.cc.as_mut() {
+            cc.write_all(&self.cc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes an extern block with the given declarations, if there are any.
+fn gen_extern_block(rs: &mut CodeWriter<'_>, decls: &[u8]) -> io::Result<()> {
+    if decls.is_empty() {
+        return Ok(());
+    }
+    writeln!(rs, r#"extern "C" {{"#)?;
+    rs.with_indent(|rs| rs.write_all(decls))?;
+    writeln!(rs, "}}")
+}
+
 impl<DB: RsTargetIr> Gen<DB> for rs::Visibility {
     fn gen(&self, _db: &DB, f: &mut CodeWriter) -> io::Result<()> {
         Ok(match self {
@@ -131,30 +452,97 @@ impl<DB: RsTargetIr> Gen<DB> for rs::Visibility {
     }
 }
 
+fn gen_struct(db: &impl RsTargetBindings, st: &rs::Struct) -> io::Result<ItemBundle> {
+    let mut bundle = ItemBundle::new(st.name, st.vis);
+    gen_struct_def(db, st, &mut bundle)?;
+    gen_struct_impls(db, st, &mut bundle)?;
+    Ok(bundle)
+}
+
 #[rustfmt::skip::macros(write_gen)]
-fn gen_struct(
+fn gen_struct_def(
     db: &impl RsTargetBindings,
     st: &rs::Struct,
-    out: &mut Outputs<'_, '_>,
+    bundle: &mut ItemBundle,
 ) -> io::Result<()> {
-    if let Some(rs) = out.rs.as_mut() {
-        let rs::Struct {
-            vis,
-            name,
-            layout: rs::StructLayout { align, .. },
-            ..
-        } = st;
+    let rs::Struct {
+        vis,
+        name,
+        layout: rs::StructLayout { size, align, .. },
+        repr,
+        packed,
+        ..
+    } = st;
+    let rs = &mut CodeWriter::new(&mut bundle.def);
+    if let Some(alias) = doc_alias(db, *name, st.cc_id.lookup(db).name) {
         write_gen!(db, rs, "
+            $alias
+        ")?;
+    }
+    if let Some(deprecated) = deprecated_attr(st.deprecated.as_ref()) {
+        write_gen!(db, rs, "
+            $deprecated
+        ")?;
+    }
+    match (repr, packed) {
+        (rs::Repr::Transparent, _) => write_gen!(db, rs, "
+            #[repr(transparent)]
+        ")?,
+        (_, Some(packed)) => write_gen!(db, rs, "
+            #[repr(C, packed($packed))]
+        ")?,
+        _ => write_gen!(db, rs, "
             #[repr(C, align($align))]
-            ${vis}struct $name {
+        ")?,
+    }
+    let struct_vis = raw_vis(db, *vis);
+    write_gen!(db, rs, "
+        ${struct_vis}struct $name {
+    ")?;
+    let mut unions = vec![];
+    rs.with_indent(|rs| -> io::Result<()> {
+        // The blob is only bytes, so an opaque struct gets its alignment from
+        // `repr(align)` above, even one that's over-aligned with `alignas`.
+        // That keeps it aligned in a `Box`, in other structs, and when a shim
+        // returns it by value.
+        if let rs::Repr::Opaque = repr {
+            let size: Snippet = size.get().to_string().into();
+            let field_vis = raw_vis(db, rs::Visibility::Private);
+            write_gen!(db, rs, "
+                ${field_vis}_opaque: [u8; $size],
+            ")?;
+        }
+        for field in &st.fields {
+            match field.union_group {
+                // The members of an anonymous union go in a Rust union,
+                // which takes the place of the first member.
+                Some(group) if unions.last() == Some(&group) => continue,
+                Some(group) => {
+                    unions.push(group);
+                    let (field_name, union_name) = union_names(db, name, group);
+                    write_gen!(db, rs, "
+                        ${struct_vis}$field_name: $union_name,
+                    ")?;
+                    continue;
+                }
+                None => (),
+            }
+            gen_field(db, field, rs)?;
+        }
+        Ok(())
+    })?;
+    write_gen!(db, rs, "
+        }
+    ")?;
+    for group in unions {
+        let (_, union_name) = union_names(db, name, group);
+        write_gen!(db, rs, "
+            #[repr(C)]
+            ${struct_vis}union $union_name {
         ")?;
         rs.with_indent(|rs| -> io::Result<()> {
-            for field in &st.fields {
-                let rs::Field { vis, name, .. } = field;
-                let ty = field.ty(db);
-                write_gen!(db, rs, "
-                    ${vis}$name: $ty,
-                ")?;
+            for field in st.fields.iter().filter(|f| f.union_group == Some(group)) {
+                gen_field(db, field, rs)?;
             }
             Ok(())
         })?;
@@ -162,12 +550,305 @@ fn gen_struct(
             }
         ")?;
     }
+    gen_layout_asserts(db, st, &mut CodeWriter::new(&mut bundle.layout))?;
+    let traits = &mut CodeWriter::new(&mut bundle.traits);
+    gen_thread_safe_impls(db, &st.cc_id.lookup(db).path(db), name, traits)
+}
+
+/// Asserts that Rust lays out a struct with the size and alignment it has
+/// in C++. The alignment of a packed struct is left to the compiler.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_layout_asserts(
+    db: &impl RsTargetIr,
+    st: &rs::Struct,
+    rs: &mut CodeWriter<'_>,
+) -> io::Result<()> {
+    let name = &st.name;
+    let size: Snippet = st.layout.size.get().to_string().into();
+    write_gen!(db, rs, "
+        const _: () = assert!(::core::mem::size_of::<$name>() == $size);
+    ")?;
+    if st.packed.is_none() {
+        let align = &st.layout.align;
+        write_gen!(db, rs, "
+            const _: () = assert!(::core::mem::align_of::<$name>() == $align);
+        ")?;
+    }
+    Ok(())
+}
+
+/// Returns the name of the field holding an anonymous union of a struct, and
+/// the name of the union's type.
+fn union_names(db: &impl DefIr, struct_name: &rs::Ident, group: u32) -> (Snippet, Snippet) {
+    (
+        format!("union{}", group).into(),
+        format!("{}_union{}", struct_name.as_str(db), group).into(),
+    )
+}
+
+#[rustfmt::skip::macros(write_gen)]
+fn gen_field(db: &impl RsTargetIr, field: &rs::Field, rs: &mut CodeWriter<'_>) -> io::Result<()> {
+    let rs::Field { vis, name, .. } = field;
+    let vis = raw_vis(db, *vis);
+    // Rust can't stop the field from being written, so the docs say when C++
+    // code doesn't expect it to be.
+    if field.is_const_member {
+        write_gen!(db, rs, "
+            /// `const` in C++: it must not be changed after construction.
+        ")?;
+    }
+    if field.is_mutable {
+        write_gen!(db, rs, "
+            /// `mutable` in C++: const methods may change it.
+        ")?;
+    }
+    if let Some(alias) = doc_alias(db, *name, field.cc_name) {
+        write_gen!(db, rs, "
+            $alias
+        ")?;
+    }
+    if let Some(deprecated) = deprecated_attr(field.deprecated.as_ref()) {
+        write_gen!(db, rs, "
+            $deprecated
+        ")?;
+    }
+    let ty = field.ty(db);
+    write_gen!(db, rs, "
+        ${vis}$name: $ty,
+    ")
+}
+
+/// Implements `Send` and `Sync` for a type, if its C++ path is listed in
+/// [`SessionOptions::thread_safe`](crate::SessionOptions::thread_safe).
+#[rustfmt::skip::macros(write_gen)]
+fn gen_thread_safe_impls(
+    db: &impl RsTargetIr,
+    cc_path: &cc::Path,
+    name: &rs::Ident,
+    rs: &mut CodeWriter<'_>,
+) -> io::Result<()> {
+    let cc_path = cc_path.display(db).to_string();
+    let cc_path = cc_path.trim_start_matches("::");
+    if !db.options().thread_safe.iter().any(|path| path == cc_path) {
+        return Ok(());
+    }
+    write_gen!(db, rs, "
+        unsafe impl Send for $name {}
+        unsafe impl Sync for $name {}
+    ")
+}
+
+/// Returns the visibility of a struct or field where it's defined. In
+/// two-layer mode, private items in the sys module are still visible to the
+/// wrappers in its parent.
+fn raw_vis(db: &impl RsTargetIr, vis: rs::Visibility) -> Snippet {
+    match vis {
+        rs::Visibility::Private if db.options().two_layer => "pub(super) ".into(),
+        vis => snippet!(db, "$vis"),
+    }
+}
+
+/// Generates the conversions and methods of a struct.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_struct_impls(
+    db: &impl RsTargetBindings,
+    st: &rs::Struct,
+    bundle: &mut ItemBundle,
+) -> io::Result<()> {
+    {
+        let rs = &mut CodeWriter::new(&mut bundle.traits);
+        let (name, repr) = (&st.name, &st.repr);
+        if let (rs::Repr::Transparent, [field]) = (repr, st.fields.as_slice()) {
+            let field_name = &field.name;
+            let ty = field.ty(db);
+            write_gen!(db, rs, "
+                impl From<$ty> for $name {
+                    fn from(value: $ty) -> Self {
+                        $name { $field_name: value }
+                    }
+                }
+                impl From<$name> for $ty {
+                    fn from(wrapper: $name) -> Self {
+                        wrapper.$field_name
+                    }
+                }
+            ")?;
+        }
+        if let rs::Repr::C = repr {
+            if db.options().tuple_conversions {
+                gen_tuple_conversions(db, st, rs)?;
+            }
+        }
+    }
 
     for method in &st.methods {
-        gen_method(db, st, method, out)?;
+        gen_method(db, st, method, bundle)?;
     }
+    gen_index_impls(db, st, &mut CodeWriter::new(&mut bundle.traits))
+}
 
-    Ok(())
+/// Implements `Index<usize>` for a struct with a const `operator[]` taking a
+/// `size_t` and returning a reference, and `IndexMut<usize>` if it also has a
+/// non-const one returning a mutable reference to the same type.
+///
+/// The impls call the same methods on `NonNull<Self>` as the inherent
+/// wrappers, so the references are tied to `self` the same way.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_index_impls(
+    db: &impl RsTargetBindings,
+    st: &rs::Struct,
+    rs: &mut CodeWriter<'_>,
+) -> io::Result<()> {
+    let index_op = |is_const: bool| {
+        st.methods.iter().find_map(|meth| {
+            let func = meth.func();
+            if func.operator != Some(cc::Operator::Index) || func.is_const != is_const {
+                return None;
+            }
+            let params: Vec<_> = meth.param_tys(db).collect();
+            match (params.as_slice(), wrapper_return_ty(db, meth)) {
+                ([rs::Ty::USize], rs::Ty::Ref { pointee, mutable }) if mutable != is_const => {
+                    Some((meth, pointee.as_rs(db).skip_errs()))
+                }
+                _ => None,
+            }
+        })
+    };
+    let (index, output) = match index_op(true) {
+        Some(op) => op,
+        None => return Ok(()),
+    };
+    let struct_name = &st.name;
+    let index_name = &index.name;
+    write_gen!(db, rs, "
+        impl ::core::ops::Index<usize> for $struct_name {
+            type Output = $output;
+            fn index(&self, index: usize) -> &$output {
+                unsafe { &*::core::ptr::NonNull::from(self).$index_name(index) }
+            }
+        }
+    ")?;
+    let index_mut = match index_op(false) {
+        Some((meth, pointee)) if pointee == output => meth,
+        _ => return Ok(()),
+    };
+    let index_mut_name = &index_mut.name;
+    write_gen!(db, rs, "
+        impl ::core::ops::IndexMut<usize> for $struct_name {
+            fn index_mut(&mut self, index: usize) -> &mut $output {
+                unsafe { &mut *::core::ptr::NonNull::from(self).$index_mut_name(index) }
+            }
+        }
+    ")
+}
+
+/// The most fields a struct can have and still be converted from and to a
+/// tuple. The standard library stops implementing traits for tuples here.
+const MAX_TUPLE_ARITY: usize = 12;
+
+/// Generates conversions between `st` and a tuple of its fields, in order.
+///
+/// A struct with any non-public field is skipped, since the conversions
+/// would let anyone read and set it.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_tuple_conversions(
+    db: &impl RsTargetBindings,
+    st: &rs::Struct,
+    rs: &mut CodeWriter<'_>,
+) -> io::Result<()> {
+    let fields = &st.fields;
+    if fields.is_empty()
+        || fields.len() > MAX_TUPLE_ARITY
+        || fields
+            .iter()
+            .any(|field| !field.vis.is_public() || field.union_group.is_some())
+    {
+        return Ok(());
+    }
+    // A one-element tuple needs a trailing comma.
+    let tuple = |items: Vec<String>| match items.len() {
+        1 => format!("({},)", items[0]),
+        _ => format!("({})", items.join(", ")),
+    };
+    let names: Vec<_> = fields
+        .iter()
+        .map(|field| field.name.to_rust_ident(db))
+        .collect();
+    let tuple_ty: Snippet = tuple(
+        fields
+            .iter()
+            .map(|field| ty_to_string(db, field.ty(db)))
+            .collect(),
+    )
+    .into();
+    let tuple_pat: Snippet = tuple(names.clone()).into();
+    let tuple_expr: Snippet =
+        tuple(names.iter().map(|name| format!("value.{}", name)).collect()).into();
+    let field_names: Snippet = names.join(", ").into();
+    let name = &st.name;
+    write_gen!(db, rs, "
+        impl From<$tuple_ty> for $name {
+            fn from($tuple_pat: $tuple_ty) -> Self {
+                $name { $field_names }
+            }
+        }
+        impl From<$name> for $tuple_ty {
+            fn from(value: $name) -> Self {
+                $tuple_expr
+            }
+        }
+    ")
+}
+
+/// Generates the marker type for an opaque C++ type. It has no fields that
+/// can be set, so it can't be constructed and is only used behind pointers.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_opaque(db: &impl RsTargetBindings, id: cc::OpaqueId) -> io::Result<ItemBundle> {
+    let opaque = id.lookup(db);
+    let name = opaque.name;
+    let mut bundle = ItemBundle::new(name, rs::Visibility::Public);
+    let rs = &mut CodeWriter::new(&mut bundle.def);
+    // The marker makes the type `!Send` and `!Sync` unless it's listed as
+    // thread-safe.
+    write_gen!(db, rs, "
+        #[repr(C)]
+        pub struct $name {
+            _private: [u8; 0],
+            _marker: ::core::marker::PhantomData<(*mut u8, ::core::marker::PhantomPinned)>,
+        }
+    ")?;
+    let traits = &mut CodeWriter::new(&mut bundle.traits);
+    gen_thread_safe_impls(db, &opaque.path(db), &name, traits)?;
+    Ok(bundle)
+}
+
+/// Generates the Rust type for an exported C++ alias of a builtin type: a
+/// type alias, or a `#[repr(transparent)]` newtype if it's listed in
+/// `newtypes`. The newtype passes through the shims like the builtin type.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_alias(db: &impl RsTargetBindings, id: cc::AliasId) -> io::Result<ItemBundle> {
+    let alias = id.lookup(db);
+    let (name, target) = (alias.name, alias.rs_target(db));
+    let mut bundle = ItemBundle::new(name, rs::Visibility::Public);
+    let rs = &mut CodeWriter::new(&mut bundle.def);
+    if !alias.newtype {
+        write_gen!(db, rs, "
+            pub type $name = $target;
+        ")?;
+        return Ok(bundle);
+    }
+    // Floats have no total order, so they only get the partial traits.
+    let derives: Snippet = match target {
+        rs::Ty::F32 | rs::Ty::F64 => "Clone, Copy, Debug, Default, PartialEq, PartialOrd",
+        _ => "Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash",
+    }
+    .into();
+    write_gen!(db, rs, "
+        #[repr(transparent)]
+        #[derive($derives)]
+        pub struct $name(pub $target);
+    ")?;
+    Ok(bundle)
 }
 
 #[rustfmt::skip::macros(write_gen)]
@@ -175,89 +856,599 @@ fn gen_method(
     db: &impl RsTargetBindings,
     st: &rs::Struct,
     meth: &rs::Method,
-    out: &mut Outputs<'_, '_>,
+    bundle: &mut ItemBundle,
 ) -> io::Result<()> {
     // TODO handle visibility (don't generate bindings for private methods)
     // TODO handle constness
     let func = meth.func();
     debug_assert_eq!(func.param_tys.len(), func.param_names.len());
-    assert!(func.is_method, "static methods aren't supported yet");
-
-    let mangled_path = st.name.to_string().replace("::", "__");
-    let c_func_name: Snippet = format!("_bind_{}__{}", mangled_path, func.name).into();
+    if !func.is_method {
+        return gen_static_method(db, st, meth, bundle);
+    }
 
-    if let Some(rs) = out.rs.as_mut() {
-        let arg_names = arg_names(meth);
-        let args_sig: Snippet = arg_names
-            .iter()
-            .zip(meth.param_tys(db))
-            .map(|(name, ty)| snippet!(db, "$name: $ty").to_string())
-            .join(", ")
-            .into();
-        let arg_names: Snippet = arg_names.iter().join(", ").into();
-        let ret_ty = meth.return_ty(db);
+    let c_func_name = c_func_name(db, st, meth);
+    let shim = shim_path(db, &c_func_name);
+    let args_sig = rs_args_sig(db, meth);
+    let arg_names: Snippet = rs_arg_names(db, meth)
+        .iter()
+        .map(Snippet::to_string)
+        .join(", ")
+        .into();
+    let ret_ty = return_ty(meth.return_ty(db));
+    let func_name = &meth.name;
+    let struct_name = &st.name;
 
-        let func_name = &func.name;
-        let struct_name = &st.name;
+    {
+        let rs = &mut CodeWriter::new(&mut bundle.traits);
         let trait_name = snippet!(db, "${struct_name}_${func_name}_Ext");
 
-        // Create an extension trait for our method.
+        // Create an extension trait for our method, and impl it for
+        // NonNull<Struct>.
         write_gen!(db, rs, "
             pub trait $trait_name {
                 fn $func_name(self, $args_sig) -> $ret_ty;
             }
-        ")?;
-
-        // impl the extension trait for NonNull<Struct>.
-        write_gen!(db, rs, r#"
             impl $trait_name for ::core::ptr::NonNull<$struct_name> {
                 fn $func_name(self, $args_sig) -> $ret_ty {
-                    extern "C" { fn $c_func_name(this: *mut $struct_name, $args_sig) -> $ret_ty; }
-                    unsafe { $c_func_name(self.as_ptr(), $arg_names) }
+                    unsafe { $shim(self.as_ptr(), $arg_names) }
                 }
             }
-        "#)?;
+        ")?;
+    }
 
-        // Create a convenience wrapper for &mut self.
+    {
+        let rs = &mut CodeWriter::new(&mut bundle.externs);
+        let extern_vis = extern_vis(db);
         write_gen!(db, rs, "
-            impl $struct_name {
-                pub fn $func_name(&mut self, $args_sig) -> $ret_ty {
-                    ::core::ptr::NonNull::from(self).$func_name($arg_names)
-                }
-            }
+            ${extern_vis}fn $c_func_name(this: *mut $struct_name, $args_sig) -> $ret_ty;
         ")?;
     }
 
-    if let Some(cc) = out.cc.as_mut() {
-        let func = meth.cc_func(db);
-        let arg_names = arg_names(meth);
-        let args_sig: Snippet = arg_names
-            .iter()
-            .zip(func.param_tys(db))
-            .map(|(name, ty)| snippet!(db, "$ty $name").to_string())
-            .join(", ")
-            .into();
-        let arg_names: Snippet = arg_names.iter().join(", ").into();
-        let cc_st = st.cc_id.lookup(db);
-        let st_path = cc_st.path(db);
-        let func_name = &func.name;
-        let ret_ty = func.return_ty(db);
-        write_gen!(db, cc, r#"
-            extern "C" $ret_ty $c_func_name($st_path* self, $args_sig) {
-                return self->$st_path::$func_name($arg_names);
+    {
+        // Create a convenience wrapper for &mut self.
+        let rs = &mut CodeWriter::new(&mut bundle.inherent);
+        if let Some(alias) = doc_alias(db, meth.name, func.name) {
+            write_gen!(db, rs, "
+                $alias
+            ")?;
+        }
+        if let Some(deprecated) = deprecated_attr(func.attrs.deprecated.as_ref()) {
+            write_gen!(db, rs, "
+                $deprecated
+            ")?;
+        }
+        match wrapper_return_ty(db, meth) {
+            // The shim returns a pointer, which lives as long as the object
+            // it came from.
+            rs::Ty::Ref { pointee, mutable } => {
+                let self_param: Snippet = match func.is_const {
+                    true => "&self".into(),
+                    false => "&mut self".into(),
+                };
+                let ref_ty = rs::Ty::Ref { pointee, mutable };
+                let deref: Snippet = match mutable {
+                    true => "&mut *".into(),
+                    false => "&*".into(),
+                };
+                write_gen!(db, rs, "
+                    pub fn $func_name($self_param, $args_sig) -> $ref_ty {
+                        unsafe { ${deref}::core::ptr::NonNull::from(self).$func_name($arg_names) }
+                    }
+                ")?;
+            }
+            _ => {
+                // Getters only read the field, and setters of `mutable`
+                // fields are const in C++, so they don't need a unique
+                // borrow.
+                let self_param: Snippet = match func.accessor {
+                    Some(_) if func.is_const => "&self".into(),
+                    _ => "&mut self".into(),
+                };
+                write_gen!(db, rs, "
+                    pub fn $func_name($self_param, $args_sig) -> $ret_ty {
+                        ::core::ptr::NonNull::from(self).$func_name($arg_names)
+                    }
+                ")?;
             }
-        "#)?;
+        }
+        gen_c_str_wrappers(db, st, meth, rs)?;
     }
 
-    Ok(())
-}
-
-fn arg_names(meth: &rs::Method) -> Vec<rs::Ident> {
-    meth.func()
-        .param_names
+    let cc = &mut CodeWriter::new(&mut bundle.cc);
+    let func = meth.cc_func(db);
+    let (args_sig, args) = shim_args(db, meth);
+    let cc_st = st.cc_id.lookup(db);
+    let st_path = cc_st.path(db);
+    // Inherited methods are called through the base that declares them.
+    let class_path = func.inherited_from.as_ref().unwrap_or(&st_path);
+    let func_name = &func.name;
+    let (ret_ty, addr_of) = shim_return_ty(db, &func);
+    // Operators can be overloaded on constness, so a const one is called
+    // through a const pointer to pick the right overload.
+    let this: Snippet = match (func.operator, func.is_const) {
+        (Some(_), true) => snippet!(db, "static_cast<const $st_path*>(self)"),
+        _ => "self".into(),
+    };
+    let body = match func.accessor {
+        None => snippet!(
+            db,
+            "return ${addr_of}$this->$class_path::$func_name($args);"
+        ),
+        Some(cc::Accessor::Get(field)) => snippet!(db, "return self->$field;"),
+        Some(cc::Accessor::Set(field)) => snippet!(db, "self->$field = $args;"),
+    };
+    write_gen!(db, cc, r#"
+        extern "C" $ret_ty $c_func_name($st_path* self, $args_sig) {
+            $body
+        }
+    "#)
+}
+
+/// Generates a static method as an associated function. A `constexpr` one
+/// whose result clang could evaluate becomes a `const fn` returning it, and
+/// needs no shim.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_static_method(
+    db: &impl RsTargetBindings,
+    st: &rs::Struct,
+    meth: &rs::Method,
+    bundle: &mut ItemBundle,
+) -> io::Result<()> {
+    let func = meth.func();
+    let c_func_name = c_func_name(db, st, meth);
+    let ret_ty = return_ty(meth.return_ty(db));
+    let func_name = &meth.name;
+
+    let rs = &mut CodeWriter::new(&mut bundle.inherent);
+    if let Some(alias) = doc_alias(db, meth.name, func.name) {
+        write_gen!(db, rs, "
+            $alias
+        ")?;
+    }
+    if let Some(deprecated) = deprecated_attr(func.attrs.deprecated.as_ref()) {
+        write_gen!(db, rs, "
+            $deprecated
+        ")?;
+    }
+    if let Some(value) = const_fn_value(db, meth) {
+        return write_gen!(db, rs, "
+            pub const fn $func_name() -> $ret_ty {
+                $value
+            }
+        ");
+    }
+    let args_sig = rs_args_sig(db, meth);
+    let arg_names: Snippet = rs_arg_names(db, meth)
+        .iter()
+        .map(Snippet::to_string)
+        .join(", ")
+        .into();
+    let shim = shim_path(db, &c_func_name);
+    write_gen!(db, rs, "
+        pub fn $func_name($args_sig) -> $ret_ty {
+            unsafe { $shim($arg_names) }
+        }
+    ")?;
+
+    let rs = &mut CodeWriter::new(&mut bundle.externs);
+    let extern_vis = extern_vis(db);
+    write_gen!(db, rs, "
+        ${extern_vis}fn $c_func_name($args_sig) -> $ret_ty;
+    ")?;
+
+    let cc = &mut CodeWriter::new(&mut bundle.cc);
+    let func = meth.cc_func(db);
+    let (args_sig, args) = shim_args(db, meth);
+    let st_path = st.cc_id.lookup(db).path(db);
+    let class_path = func.inherited_from.as_ref().unwrap_or(&st_path);
+    let func_name = &func.name;
+    let (ret_ty, addr_of) = shim_return_ty(db, &func);
+    write_gen!(db, cc, r#"
+        extern "C" $ret_ty $c_func_name($args_sig) {
+            return ${addr_of}$class_path::$func_name($args);
+        }
+    "#)
+}
+
+/// Returns how the wrappers call a shim. In two-layer mode it's declared in
+/// the `sys` module.
+fn shim_path(db: &impl RsTargetIr, c_func_name: &Snippet) -> Snippet {
+    match db.options().two_layer {
+        true => format!("sys::{}", c_func_name.to_string()).into(),
+        false => c_func_name.to_string().into(),
+    }
+}
+
+/// Returns the value a `constexpr` static method returns, if it's generated
+/// as a `const fn` instead of calling a shim.
+fn const_fn_value(db: &impl RsTargetIr, meth: &rs::Method) -> Option<Snippet> {
+    let func = meth.func();
+    if func.is_method {
+        return None;
+    }
+    let ret_ty = return_ty(meth.return_ty(db));
+    func.const_value.and_then(|val| const_literal(val, &ret_ty))
+}
+
+/// Returns the names of the shims defined by the C++ half of the bindings,
+/// in the order they're generated.
+///
+/// The shims are the only symbols the bindings add, so this is what a linker
+/// or version script needs to list to keep them visible.
+pub(crate) fn shim_symbols(db: &impl RsTargetIr, krate: &rs::BindingsCrate) -> Vec<String> {
+    let mut symbols = vec![];
+    add_shim_symbols(db, &krate.root.lookup(db), &mut symbols);
+    symbols
+}
+
+fn add_shim_symbols(db: &impl RsTargetIr, mdl: &rs::Module, symbols: &mut Vec<String>) {
+    for item in dependency_order(db, &mdl.children) {
+        match item {
+            rs::ItemKind::Module(id) => add_shim_symbols(db, &id.lookup(db), symbols),
+            rs::ItemKind::Struct(id) => {
+                let st = id.lookup(db);
+                for meth in &st.methods {
+                    if const_fn_value(db, meth).is_none() {
+                        symbols.push(c_func_name(db, &st, meth).to_string());
+                    }
+                }
+            }
+            rs::ItemKind::Reexport(..) | rs::ItemKind::Opaque(_) | rs::ItemKind::Alias(_) => (),
+        }
+    }
+}
+
+/// Returns the visibility of the shim declarations. In two-layer mode
+/// they're called from the parent of the `sys` module.
+fn extern_vis(db: &impl RsTargetIr) -> Snippet {
+    match db.options().two_layer {
+        true => "pub ".into(),
+        false => "".into(),
+    }
+}
+
+/// Returns the Rust literal of a constant returned as `ty`, or `None` if it
+/// doesn't fit.
+fn const_literal(value: cc::ConstValue, ty: &rs::Ty) -> Option<Snippet> {
+    use std::convert::TryFrom;
+    fn fits<T: TryFrom<i128> + ToString>(val: i128) -> Option<String> {
+        T::try_from(val).ok().map(|val| val.to_string())
+    }
+    let int = match value {
+        cc::ConstValue::Int(val) => val as i128,
+        cc::ConstValue::UInt(val) => val as i128,
+        cc::ConstValue::Float(bits) => {
+            let val = f64::from_bits(bits);
+            return match ty {
+                rs::Ty::F64 => Some(format!("{:?}", val).into()),
+                rs::Ty::F32 => Some(format!("{:?}", val as f32).into()),
+                _ => None,
+            };
+        }
+    };
+    let literal = match ty {
+        rs::Ty::Bool if int == 0 || int == 1 => Some((int == 1).to_string()),
+        rs::Ty::U8 => fits::<u8>(int),
+        rs::Ty::I8 => fits::<i8>(int),
+        rs::Ty::U16 => fits::<u16>(int),
+        rs::Ty::I16 => fits::<i16>(int),
+        rs::Ty::U32 => fits::<u32>(int),
+        rs::Ty::I32 => fits::<i32>(int),
+        rs::Ty::U64 => fits::<u64>(int),
+        rs::Ty::I64 => fits::<i64>(int),
+        rs::Ty::USize => fits::<usize>(int),
+        rs::Ty::ISize => fits::<isize>(int),
+        _ => None,
+    };
+    literal.map(Into::into)
+}
+
+/// Returns the parameters of a method's shim as they're declared in C++,
+/// and the arguments it calls the method with.
+///
+/// References are passed as pointers, except where the Rust side passes them
+/// by value, and the call site forms the reference again.
+fn shim_args(db: &impl RsTargetIr, meth: &rs::Method) -> (Snippet, Snippet) {
+    let func = meth.cc_func(db);
+    let (args_sig, args): (Vec<_>, Vec<_>) = arg_names(db, meth)
+        .iter()
+        .zip(func.param_tys(db))
+        .zip(meth.param_tys(db))
+        .map(|((name, ty), rs_ty)| match ty {
+            cc::Ty::Ref { pointee, .. } if param_ty(db, rs_ty).is_scalar() => {
+                let pointee = pointee.as_cc(db).skip_errs();
+                (
+                    snippet!(db, "$pointee $name").to_string(),
+                    name.as_str(db).to_string(),
+                )
+            }
+            cc::Ty::Ref { pointee, is_const } => {
+                let ptr = cc::Ty::Ptr { pointee, is_const };
+                (
+                    snippet!(db, "$ptr $name").to_string(),
+                    format!("*{}", name.as_str(db)),
+                )
+            }
+            ty => (
+                snippet!(db, "$ty $name").to_string(),
+                name.as_str(db).to_string(),
+            ),
+        })
+        .unzip();
+    (args_sig.join(", ").into(), args.join(", ").into())
+}
+
+/// Returns the type a shim returns a function's result as, and what to put
+/// before the call to convert it: returned references become pointers.
+fn shim_return_ty(db: &impl RsTargetIr, func: &cc::Function) -> (cc::Ty, Snippet) {
+    match func.return_ty(db) {
+        cc::Ty::Ref { pointee, is_const } => (cc::Ty::Ptr { pointee, is_const }, "&".into()),
+        ty => (ty, "".into()),
+    }
+}
+
+/// Writes wrappers of a method for `const char*` parameters and return
+/// values: one taking `&CStr` for each such parameter, and an unsafe one
+/// returning `Option<&CStr>`.
+#[rustfmt::skip::macros(write_gen)]
+fn gen_c_str_wrappers(
+    db: &impl RsTargetBindings,
+    st: &rs::Struct,
+    meth: &rs::Method,
+    rs: &mut CodeWriter<'_>,
+) -> io::Result<()> {
+    let func = meth.func();
+    let func_name = &meth.name;
+    let arg_names = arg_names(db, meth);
+    let rs_arg_names = rs_arg_names(db, meth);
+    let param_tys: Vec<_> = meth.param_tys(db).map(|ty| param_ty(db, ty)).collect();
+    let ret_ty = wrapper_return_ty(db, meth);
+    let gate = c_str_gate(db);
+    // Const methods can be called through a shared reference.
+    let self_param: Snippet = match func.is_const {
+        true => "&self".into(),
+        false => "&mut self".into(),
+    };
+
+    if param_tys.iter().any(|ty| ty.is_c_str(db)) {
+        let st_path = st.cc_id.lookup(db).path(db).display(db).to_string();
+        let nullable_prefix = format!(
+            "{}::{}::",
+            st_path.trim_start_matches("::"),
+            func.name.as_str(db)
+        );
+        let is_nullable = |name: &rs::Ident| {
+            let path = format!("{}{}", nullable_prefix, name.as_str(db));
+            db.options().nullable_params.contains(&path)
+        };
+        let (sig, args): (Vec<_>, Vec<_>) = arg_names
+            .iter()
+            .zip(&rs_arg_names)
+            .zip(&param_tys)
+            .map(|((cc_name, name), ty)| match ty.is_c_str(db) {
+                false => (snippet!(db, "$name: $ty").to_string(), name.to_string()),
+                true if is_nullable(cc_name) => (
+                    snippet!(db, "$name: Option<&::core::ffi::CStr>").to_string(),
+                    snippet!(
+                        db,
+                        "${name}.map_or(::core::ptr::null(), ::core::ffi::CStr::as_ptr)"
+                    )
+                    .to_string(),
+                ),
+                true => (
+                    snippet!(db, "$name: &::core::ffi::CStr").to_string(),
+                    snippet!(db, "${name}.as_ptr()").to_string(),
+                ),
+            })
+            .unzip();
+        let sig: Snippet = sig.join(", ").into();
+        let args: Snippet = args.join(", ").into();
+        if let Some(gate) = &gate {
+            write_gen!(db, rs, "
+                $gate
+            ")?;
+        }
+        // Call through the extension trait like the main wrapper does, since
+        // the main wrapper may take `&mut self` for a const method.
+        let call = snippet!(db, "::core::ptr::NonNull::from(self).$func_name($args)");
+        let body: Snippet = match ret_ty {
+            rs::Ty::Ref { mutable: true, .. } => snippet!(db, "unsafe { &mut *$call }"),
+            rs::Ty::Ref { mutable: false, .. } => snippet!(db, "unsafe { &*$call }"),
+            _ => call,
+        };
+        write_gen!(db, rs, "
+            pub fn ${func_name}_cstr($self_param, $sig) -> $ret_ty {
+                $body
+            }
+        ")?;
+    }
+
+    if ret_ty.is_c_str(db) {
+        let args_sig: Snippet = rs_arg_names
+            .iter()
+            .zip(&param_tys)
+            .map(|(name, ty)| snippet!(db, "$name: $ty").to_string())
+            .join(", ")
+            .into();
+        let arg_names: Snippet = rs_arg_names
+            .iter()
+            .map(Snippet::to_string)
+            .join(", ")
+            .into();
+        if let Some(gate) = &gate {
+            write_gen!(db, rs, "
+                $gate
+            ")?;
+        }
+        write_gen!(db, rs, "
+            /// Returns the string as a `CStr`, or `None` if it's null.
+            ///
+            /// # Safety
+            ///
+            /// The string must stay valid and unchanged while `self` is borrowed.
+            pub unsafe fn ${func_name}_str($self_param, $args_sig) -> Option<&::core::ffi::CStr> {
+                let ptr = ::core::ptr::NonNull::from(self).$func_name($arg_names);
+                match ptr.is_null() {
+                    true => None,
+                    false => Some(::core::ffi::CStr::from_ptr(ptr)),
+                }
+            }
+        ")?;
+    }
+    Ok(())
+}
+
+/// The feature of the bindings crate that enables the `CStr` conveniences
+/// when generating for `no_std`.
+const C_STR_FEATURE: &str = "c_str";
+
+/// Returns the attribute gating the `CStr` conveniences, if they're gated.
+fn c_str_gate(db: &impl RsTargetIr) -> Option<Snippet> {
+    match db.options().no_std {
+        true => Some(format!("#[cfg(feature = \"{}\")]", C_STR_FEATURE).into()),
+        false => None,
+    }
+}
+
+/// Returns the names of the `CStr` conveniences generated for a method.
+fn c_str_helpers(db: &impl RsTargetIr, meth: &rs::Method) -> Vec<String> {
+    let mut helpers = vec![];
+    if !meth.func().is_method {
+        return helpers;
+    }
+    if meth.param_tys(db).any(|ty| param_ty(db, ty).is_c_str(db)) {
+        helpers.push(format!("{}_cstr", meth.name.as_str(db)));
+    }
+    if wrapper_return_ty(db, meth).is_c_str(db) {
+        helpers.push(format!("{}_str", meth.name.as_str(db)));
+    }
+    helpers
+}
+
+/// Notes each `CStr` convenience that's left out of a `no_std` build unless
+/// the bindings crate enables its `c_str` feature.
+pub(crate) fn no_std_notes(db: &impl RsTargetIr, krate: &rs::BindingsCrate) -> Diagnostics {
+    let mut notes = Diagnostics::new();
+    if db.options().no_std {
+        add_no_std_notes(db, &krate.root.lookup(db), &mut notes);
+    }
+    notes
+}
+
+fn add_no_std_notes(db: &impl RsTargetIr, mdl: &rs::Module, notes: &mut Diagnostics) {
+    for item in &mdl.children {
+        match item {
+            rs::ItemKind::Module(id) => add_no_std_notes(db, &id.lookup(db), notes),
+            rs::ItemKind::Struct(id) => {
+                let st = id.lookup(db);
+                for meth in &st.methods {
+                    for helper in c_str_helpers(db, meth) {
+                        notes.add(Diagnostic::info(
+                            format!(
+                                "`{}::{}` is only generated with the `{}` feature",
+                                st.name.as_str(db),
+                                helper,
+                                C_STR_FEATURE
+                            ),
+                            meth.func()
+                                .span
+                                .label("this method takes or returns a C string"),
+                        ));
+                    }
+                }
+            }
+            rs::ItemKind::Reexport(..) | rs::ItemKind::Opaque(_) | rs::ItemKind::Alias(_) => (),
+        }
+    }
+}
+
+/// Returns the name of the C++ shim for a method.
+fn c_func_name(db: &impl DefIr, st: &rs::Struct, meth: &rs::Method) -> Snippet {
+    let mangled_path = st.name.as_str(db).replace("::", "__");
+    // Operators aren't identifiers, so their shims are named after the Rust
+    // method instead.
+    match meth.func().operator {
+        Some(_) => format!("_bind_{}__{}", mangled_path, meth.name.as_str(db)).into(),
+        None => format!("_bind_{}__{}", mangled_path, meth.func().name.as_str(db)).into(),
+    }
+}
+
+/// Returns the parameters of a method as they're declared in Rust, not
+/// including `self`.
+fn rs_args_sig(db: &impl RsTargetIr, meth: &rs::Method) -> Snippet {
+    rs_arg_names(db, meth)
+        .iter()
+        .zip(meth.param_tys(db))
+        .map(|(name, ty)| {
+            let ty = param_ty(db, ty);
+            snippet!(db, "$name: $ty").to_string()
+        })
+        .join(", ")
+        .into()
+}
+
+/// Returns a `#[doc(alias)]` attribute with the C++ name of an item, if it
+/// was renamed in Rust.
+fn doc_alias(db: &impl DefIr, name: rs::Ident, cc_name: cc::Ident) -> Option<Snippet> {
+    match name == cc_name {
+        true => None,
+        false => Some(format!("#[doc(alias = \"{}\")]", cc_name.as_str(db)).into()),
+    }
+}
+
+/// Returns a `#[deprecated]` attribute for an item deprecated in C++, with
+/// the same message.
+fn deprecated_attr(deprecated: Option<&rs::Deprecation>) -> Option<Snippet> {
+    let note = match deprecated?.note.as_ref() {
+        Some(note) => note,
+        None => return Some("#[deprecated]".into()),
+    };
+    // `Debug` writes the note as an escaped string literal, which Rust reads
+    // back the same.
+    Some(format!("#[deprecated(note = {:?})]", note).into())
+}
+
+/// Returns the names of a method's parameters, as they're used in both the
+/// Rust and C++ code.
+///
+/// Unnamed parameters are called `arg0`, `arg1`, and so on by position, and
+/// names that can't be parameters in Rust even as raw identifiers, like
+/// `self`, get a trailing underscore. Either way, an underscore is added until
+/// the name doesn't collide with another parameter. Other Rust keywords are
+/// kept, and escaped with [`rs_arg_names`].
+fn arg_names(db: &impl DefIr, meth: &rs::Method) -> Vec<rs::Ident> {
+    const RESERVED: &[&str] = &["crate", "self", "Self", "super"];
+    let names = &meth.func().param_names;
+    let mut taken: HashSet<String> = names
+        .iter()
+        .flatten()
+        .map(|name| name.as_str(db).to_string())
+        .collect();
+    let mut fresh = |mut name: String| {
+        while taken.contains(&name) {
+            name.push('_');
+        }
+        taken.insert(name.clone());
+        rs::Ident::new_mangled(db, &name)
+    };
+    names
         .iter()
         .enumerate()
-        .map(|(idx, name)| name.clone().unwrap_or_else(|| format!("_{}__", idx).into()))
+        .map(|(idx, name)| match name {
+            Some(name) if RESERVED.contains(&&*name.as_str(db)) => {
+                fresh(format!("{}_", name.as_str(db)))
+            }
+            Some(name) => *name,
+            None => fresh(format!("arg{}", idx)),
+        })
+        .collect()
+}
+
+/// Returns the names of a method's parameters as they're written in Rust.
+fn rs_arg_names(db: &impl DefIr, meth: &rs::Method) -> Vec<Snippet> {
+    arg_names(db, meth)
+        .iter()
+        .map(|name| name.to_rust_ident(db).into())
         .collect()
 }
 
@@ -283,10 +1474,30 @@ macro_rules! impl_gen_from_display {
     };
 }
 
-impl_gen_from_display!(cc::Path);
+macro_rules! impl_gen_from_db_display {
+    ($ty:path) => {
+        impl<DB: DefIr> Gen<DB> for $ty {
+            fn gen(&self, db: &DB, f: &mut CodeWriter<'_>) -> io::Result<()> {
+                write!(f, "{}", self.display(db))
+            }
+        }
+    };
+}
+
+impl_gen_from_db_display!(cc::Path);
 impl_gen_from_display!(rs::Align);
-impl_gen_from_display!(rs::Ident);
-impl_gen_from_display!(rs::Path);
+
+impl<DB: DefIr> Gen<DB> for rs::Ident {
+    fn gen(&self, db: &DB, f: &mut CodeWriter<'_>) -> io::Result<()> {
+        write!(f, "{}", self.to_rust_ident(db))
+    }
+}
+
+impl<DB: DefIr> Gen<DB> for rs::Path {
+    fn gen(&self, db: &DB, f: &mut CodeWriter<'_>) -> io::Result<()> {
+        write!(f, "{}", self.to_rust_path(db))
+    }
+}
 
 impl<DB: RsTargetIr> Gen<DB> for rs::Ty {
     fn gen(&self, db: &DB, f: &mut CodeWriter<'_>) -> io::Result<()> {
@@ -307,7 +1518,26 @@ impl<DB: RsTargetIr> Gen<DB> for rs::Ty {
             F32 => "f32",
             F64 => "f64",
             Bool => "bool",
+            CChar => "core::ffi::c_char",
             Struct(id) => return id.lookup(db).name.gen(db, f),
+            Opaque(id) => return id.lookup(db).name.gen(db, f),
+            Alias(id) => return id.lookup(db).name.gen(db, f),
+            Ptr { pointee, mutable } => {
+                write!(f, "*{} ", if *mutable { "mut" } else { "const" })?;
+                return match pointee.as_rs(db).skip_errs() {
+                    Unit => write!(f, "core::ffi::c_void"),
+                    pointee => pointee.gen(db, f),
+                };
+            }
+            Array { elem, len } => {
+                write!(f, "[")?;
+                elem.as_rs(db).skip_errs().gen(db, f)?;
+                return write!(f, "; {}]", len);
+            }
+            Ref { pointee, mutable } => {
+                write!(f, "&{}", if *mutable { "mut " } else { "" })?;
+                return pointee.as_rs(db).skip_errs().gen(db, f);
+            }
         };
         write!(f, "{}", name)
     }
@@ -338,6 +1568,23 @@ impl<DB: CcSourceIr> Gen<DB> for cc::Ty {
             Double => "double",
             Bool => "bool",
             Struct(id) => return id.lookup(db).name.gen(db, f),
+            Opaque(id) => return id.lookup(db).name.gen(db, f),
+            // The aliased type is always in scope, unlike the alias when it's
+            // declared in a namespace.
+            Alias(id) => return id.lookup(db).target.gen(db, f),
+            Ptr { pointee, is_const } => {
+                pointee.as_cc(db).skip_errs().gen(db, f)?;
+                return write!(f, "{}*", if *is_const { " const" } else { "" });
+            }
+            // Spelled as a type-id, like `int[8][8]`.
+            Array { elem, len } => {
+                elem.as_cc(db).skip_errs().gen(db, f)?;
+                return write!(f, "[{}]", len);
+            }
+            Ref { pointee, is_const } => {
+                pointee.as_cc(db).skip_errs().gen(db, f)?;
+                return write!(f, "{}&", if *is_const { " const" } else { "" });
+            }
         };
         write!(f, "{}", name)
     }
@@ -345,7 +1592,15 @@ impl<DB: CcSourceIr> Gen<DB> for cc::Ty {
 
 #[cfg(test)]
 mod tests {
-    use crate::Session;
+    use super::link_name;
+    use crate::{test_util, Session, SessionOptions};
+
+    #[test]
+    fn link_names() {
+        assert_eq!(link_name("libfoo"), "foo");
+        assert_eq!(link_name("foo"), "foo");
+        assert_eq!(link_name("lib"), "lib");
+    }
 
     #[test]
     fn pod() {
@@ -358,55 +1613,1304 @@ mod tests {
             };
 
             namespace rust_export {
-              using ::Pod;
+              using ::Pod;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Pod;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct Pod {
+                    pub a: i32,
+                    pub b: i32,
+                    pub e: core::ffi::c_char,
+                    pub c: f64,
+                    pub d: f64,
+                }
+                const _: () = assert!(::core::mem::size_of::<Pod>() == 32);
+                const _: () = assert!(::core::mem::align_of::<Pod>() == 8);
+            }
+        "#);
+    }
+
+    #[test]
+    fn char_signedness() {
+        // `char` is unsigned on ARM, but the bindings shouldn't depend on it.
+        let mut sess = Session::with_options(SessionOptions {
+            target: Some("armv7-unknown-linux-gnueabihf".to_string()),
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Chars {
+                char c;
+                signed char s;
+                unsigned char u;
+            };
+            namespace rust_export {
+                using ::Chars;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Chars;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(1))]
+                pub struct Chars {
+                    pub c: core::ffi::c_char,
+                    pub s: i8,
+                    pub u: u8,
+                }
+                const _: () = assert!(::core::mem::size_of::<Chars>() == 3);
+                const _: () = assert!(::core::mem::align_of::<Chars>() == 1);
+            }
+        "#);
+    }
+
+    #[test]
+    fn tuple_conversions() {
+        let mut sess = Session::with_options(SessionOptions {
+            tuple_conversions: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Point {
+                int x, y;
+            };
+            namespace rust_export {
+                using ::Point;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Point;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Point {
+                    pub x: i32,
+                    pub y: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Point>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Point>() == 4);
+                impl From<(i32, i32)> for Point {
+                    fn from((x, y): (i32, i32)) -> Self {
+                        Point { x, y }
+                    }
+                }
+                impl From<Point> for (i32, i32) {
+                    fn from(value: Point) -> Self {
+                        (value.x, value.y)
+                    }
+                }
+            }
+        "#);
+    }
+
+    #[test]
+    fn keyword_field_names() {
+        let mut sess = Session::with_options(SessionOptions {
+            tuple_conversions: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Token {
+                int type;
+                char match;
+            };
+            namespace rust_export {
+                using ::Token;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Token;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Token {
+                    pub r#type: i32,
+                    pub r#match: core::ffi::c_char,
+                }
+                const _: () = assert!(::core::mem::size_of::<Token>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Token>() == 4);
+                impl From<(i32, core::ffi::c_char)> for Token {
+                    fn from((r#type, r#match): (i32, core::ffi::c_char)) -> Self {
+                        Token { r#type, r#match }
+                    }
+                }
+                impl From<Token> for (i32, core::ffi::c_char) {
+                    fn from(value: Token) -> Self {
+                        (value.r#type, value.r#match)
+                    }
+                }
+            }
+        "#);
+    }
+
+    #[test]
+    fn tuple_conversions_skipped() {
+        let mut sess = Session::with_options(SessionOptions {
+            tuple_conversions: true,
+            ..Default::default()
+        });
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Wide {
+                    int a, b, c, d, e, f, g, h, i, j, k, l, m;
+                };
+                struct Inner {
+                    int a;
+                    double b;
+                };
+                // `inner` is only visible in the crate, since `Inner` isn't
+                // exported.
+                struct Outer {
+                    Inner inner;
+                    int c;
+                };
+                namespace rust_export {
+                    using ::Wide;
+                    using ::Outer;
+                }
+            }),
+        );
+        assert!(!rs.contains("for Wide"), "{}", rs);
+        assert!(!rs.contains("for Outer"), "{}", rs);
+        assert!(rs.contains("for Inner"), "{}", rs);
+    }
+
+    #[test]
+    fn thread_safe_types() {
+        let mut sess = Session::with_options(SessionOptions {
+            thread_safe: vec!["Logger".into(), "Sink".into()],
+            ..Default::default()
+        });
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Sink;
+                struct Logger {
+                    Sink* sink;
+                    int level;
+                };
+                struct Record {
+                    int level;
+                };
+                namespace rust_export {
+                    using ::Logger;
+                    using ::Record;
+                }
+            }),
+        );
+        for name in &["Logger", "Sink"] {
+            assert!(
+                rs.contains(&format!("unsafe impl Send for {} {{}}", name)),
+                "{}",
+                rs
+            );
+            assert!(
+                rs.contains(&format!("unsafe impl Sync for {} {{}}", name)),
+                "{}",
+                rs
+            );
+        }
+        assert!(!rs.contains("for Record"), "{}", rs);
+    }
+
+    #[test]
+    fn opaque_struct() {
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["BigConfig".into()],
+            ..Default::default()
+        });
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct BigConfig {
+                    int flags;
+                    double scale;
+                };
+                namespace rust_export {
+                    using ::BigConfig;
+                }
+            }),
+        );
+        assert!(rs.contains("#[repr(C, align(8))]"), "{}", rs);
+        assert!(rs.contains("_opaque: [u8; 16],"), "{}", rs);
+        assert!(!rs.contains("pub _opaque"), "{}", rs);
+        assert!(!rs.contains("pub flags:"), "{}", rs);
+    }
+
+    #[test]
+    fn dependency_order() {
+        // Structs found through an exported one come after it, until they're
+        // put before the structs that hold them.
+        let mut sess = Session::test();
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Inner {
+                    int x;
+                };
+                struct Middle {
+                    Inner inners[2];
+                };
+                struct Outer {
+                    Middle middle;
+                    int y;
+                };
+                namespace rust_export {
+                    using ::Outer;
+                }
+            }),
+        );
+        let inner = rs.find("struct Inner {").unwrap();
+        let middle = rs.find("struct Middle {").unwrap();
+        let outer = rs.find("struct Outer {").unwrap();
+        assert!(inner < middle && middle < outer, "{}", rs);
+
+        // Pointers don't order structs, so a cycle of them keeps the order
+        // the structs were exported in.
+        let mut sess = Session::test();
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Node;
+                struct Edge {
+                    Node* from;
+                    Node* to;
+                };
+                struct Node {
+                    Edge* first;
+                    int id;
+                };
+                namespace rust_export {
+                    using ::Node;
+                    using ::Edge;
+                }
+            }),
+        );
+        let node = rs.find("struct Node {").unwrap();
+        let edge = rs.find("struct Edge {").unwrap();
+        assert!(node < edge, "{}", rs);
+    }
+
+    #[test]
+    fn auto_return_types() {
+        let src = stringify!({
+            typedef unsigned long size_t;
+            struct Counter {
+                int n;
+                auto size() const -> size_t;
+                auto twice() const {
+                    return n * 2;
+                }
+            };
+            namespace rust_export {
+                using ::Counter;
+            }
+        });
+        let mut sess = Session::with_options(SessionOptions {
+            full_parse: true,
+            ..Default::default()
+        });
+        let (rs, cc) = test_util::gen_code(&mut sess, src);
+        assert!(rs.contains("pub fn size(&self, ) -> usize"), "{}", rs);
+        assert!(rs.contains("pub fn twice(&self, ) -> i32"), "{}", rs);
+        assert!(cc.contains("int _bind_Counter__twice("), "{}", cc);
+    }
+
+    #[test]
+    fn deprecated_items() {
+        let mut sess = Session::test();
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct [[deprecated("use `Vec2` instead")]] Point {
+                    [[deprecated("say \"x\" \\ 2")]] int x;
+                    int y;
+                    [[deprecated]] int norm() const;
+                };
+                namespace rust_export {
+                    using ::Point;
+                }
+            }),
+        );
+        assert!(
+            rs.contains(r#"#[deprecated(note = "use `Vec2` instead")]"#),
+            "{}",
+            rs
+        );
+        assert!(
+            rs.contains(r#"#[deprecated(note = "say \"x\" \\ 2")]"#),
+            "{}",
+            rs
+        );
+        assert!(rs.contains("#[deprecated]\n"), "{}", rs);
+        assert_eq!(rs.matches("#[deprecated").count(), 3, "{}", rs);
+    }
+
+    #[test]
+    fn opaque_over_aligned() {
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["Block".into()],
+            ..Default::default()
+        });
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct alignas(64) Block {
+                    int id;
+                };
+                struct Frame {
+                    char tag;
+                    Block block;
+                };
+                namespace rust_export {
+                    using ::Block;
+                    using ::Frame;
+                }
+            }),
+        );
+        assert!(rs.contains("#[repr(C, align(64))]"), "{}", rs);
+        assert!(rs.contains("_opaque: [u8; 64],"), "{}", rs);
+        assert!(
+            rs.contains("assert!(::core::mem::align_of::<Block>() == 64);"),
+            "{}",
+            rs
+        );
+        assert!(
+            rs.contains("assert!(::core::mem::size_of::<Frame>() == 128);"),
+            "{}",
+            rs
+        );
+        assert!(
+            rs.contains("assert!(::core::mem::align_of::<Frame>() == 64);"),
+            "{}",
+            rs
+        );
+    }
+
+    #[test]
+    fn opaque_field_accessors() {
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["Counter".into()],
+            ..Default::default()
+        });
+        let (rs, cc) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                class Counter {
+                public:
+                    int count;
+                };
+                namespace rust_export {
+                    using ::Counter;
+                }
+            }),
+        );
+        assert!(rs.contains("pub fn count(&self, ) -> i32"), "{}", rs);
+        assert!(
+            rs.contains("pub fn set_count(&mut self, value: i32) -> ()"),
+            "{}",
+            rs
+        );
+        assert!(!rs.contains("pub count:"), "{}", rs);
+        assert!(cc.contains("return self->count;"), "{}", cc);
+        assert!(cc.contains("self->count = value;"), "{}", cc);
+    }
+
+    #[test]
+    fn const_and_mutable_fields() {
+        let mut sess = Session::new();
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Entry {
+                    const int id;
+                    mutable int hits;
+                };
+                namespace rust_export {
+                    using ::Entry;
+                }
+            }),
+        );
+        let lines: Vec<_> = rs.lines().map(str::trim).collect();
+        let doc_of = |field| {
+            let idx = lines.iter().position(|line| *line == field).unwrap();
+            lines[idx - 1]
+        };
+        assert_eq!(
+            doc_of("pub id: i32,"),
+            "/// `const` in C++: it must not be changed after construction.",
+            "{}",
+            rs
+        );
+        assert_eq!(
+            doc_of("pub hits: i32,"),
+            "/// `mutable` in C++: const methods may change it.",
+            "{}",
+            rs
+        );
+
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["Entry".into()],
+            ..Default::default()
+        });
+        let (rs, cc) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Entry {
+                    const int id;
+                    mutable int hits;
+                };
+                namespace rust_export {
+                    using ::Entry;
+                }
+            }),
+        );
+        assert!(rs.contains("pub fn id(&self, ) -> i32"), "{}", rs);
+        assert!(!rs.contains("set_id"), "{}", rs);
+        assert!(cc.contains("return self->id;"), "{}", cc);
+        assert!(
+            rs.contains("pub fn set_hits(&self, value: i32) -> ()"),
+            "{}",
+            rs
+        );
+        assert!(cc.contains("self->hits = value;"), "{}", cc);
+    }
+
+    #[test]
+    fn restrict_pointers() {
+        let mut sess = Session::new();
+        let (rs, cc) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Mixer {
+                    int channels;
+                    void mix(float* __restrict dst, const float* __restrict src, int n);
+                };
+                namespace rust_export {
+                    using ::Mixer;
+                }
+            }),
+        );
+        assert!(
+            rs.contains(
+                "fn _bind_Mixer__mix(this: *mut Mixer, dst: *mut f32, src: *const f32, n: i32)"
+            ),
+            "{}",
+            rs
+        );
+        assert!(!cc.contains("__restrict"), "{}", cc);
+    }
+
+    #[test]
+    fn inherited_methods() {
+        let mut sess = Session::with_options(SessionOptions {
+            inherited_methods: true,
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            struct Base {
+                int x;
+                int get() const;
+                void reset();
+                static int zero();
+            };
+            struct Derived : Base {
+                void reset(int to);
+            };
+            namespace rust_export {
+                using ::Derived;
+            }
+        } => [
+            "`Base::reset` is hidden in `Derived`"
+        ]);
+        let (rs, cc) = test_util::codegen(&sess, &ir);
+        assert!(rs.contains("pub x: i32"), "{}", rs);
+        assert!(rs.contains("pub fn get(&self, ) -> i32"), "{}", rs);
+        assert!(cc.contains("return self->::Base::get();"), "{}", cc);
+        assert!(cc.contains("return ::Base::zero();"), "{}", cc);
+        // `Derived::reset` hides `Base::reset`.
+        assert!(cc.contains("return self->::Derived::reset(to);"), "{}", cc);
+        assert!(!cc.contains("::Base::reset"), "{}", cc);
+    }
+
+    #[test]
+    fn index_operators() {
+        let mut sess = Session::test();
+        let (rs, cc) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                typedef unsigned long size_t;
+                struct Triple {
+                    int values[3];
+                    int& operator[](size_t i);
+                    const int& operator[](size_t i) const;
+                };
+                struct Frozen {
+                    int values[2];
+                    const int& operator[](size_t i) const;
+                };
+                namespace rust_export {
+                    using ::Triple;
+                    using ::Frozen;
+                }
+            }),
+        );
+        assert!(
+            rs.contains("impl ::core::ops::Index<usize> for Triple"),
+            "{}",
+            rs
+        );
+        assert!(
+            rs.contains("impl ::core::ops::IndexMut<usize> for Triple"),
+            "{}",
+            rs
+        );
+        assert!(rs.contains("type Output = i32;"), "{}", rs);
+        assert!(rs.contains("#[doc(alias = \"operator[]\")]"), "{}", rs);
+        assert!(
+            rs.contains("impl ::core::ops::Index<usize> for Frozen"),
+            "{}",
+            rs
+        );
+        assert!(
+            !rs.contains("impl ::core::ops::IndexMut<usize> for Frozen"),
+            "{}",
+            rs
+        );
+        assert!(cc.contains("_bind_Triple__index_mut"), "{}", cc);
+        assert!(
+            cc.contains("return &static_cast<const ::Triple*>(self)->::Triple::operator[](i);"),
+            "{}",
+            cc
+        );
+        assert!(
+            cc.contains("return &self->::Triple::operator[](i);"),
+            "{}",
+            cc
+        );
+    }
+
+    #[test]
+    fn builtin_aliases() {
+        let src = stringify!({
+            using UserId = unsigned long long;
+            struct Account {
+                UserId id;
+                int balance;
+                UserId owner() const;
+            };
+            namespace rust_export {
+                using ::UserId;
+                using ::Account;
+                using Cents = int;
+            }
+        });
+        let mut sess = Session::new();
+        let (rs, _) = test_util::gen_code(&mut sess, src);
+        assert!(rs.contains("pub type UserId = u64;"), "{}", rs);
+        assert!(rs.contains("pub id: UserId,"), "{}", rs);
+        assert!(rs.contains("-> UserId"), "{}", rs);
+        assert!(rs.contains("pub use crate::bind::UserId;"), "{}", rs);
+        assert!(rs.contains("pub type Cents = i32;"), "{}", rs);
+        assert!(
+            rs.contains("pub use crate::bind::rust_export::Cents;"),
+            "{}",
+            rs
+        );
+
+        let mut sess = Session::with_options(SessionOptions {
+            newtypes: vec!["UserId".into()],
+            ..Default::default()
+        });
+        let (rs, cc) = test_util::gen_code(&mut sess, src);
+        assert!(!rs.contains("pub type UserId"), "{}", rs);
+        assert!(
+            rs.contains(
+                "#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]"
+            ),
+            "{}",
+            rs
+        );
+        assert!(rs.contains("pub struct UserId(pub u64);"), "{}", rs);
+        assert!(rs.contains("pub id: UserId,"), "{}", rs);
+        assert!(rs.contains("-> UserId"), "{}", rs);
+        // The C++ half only sees the aliased type.
+        assert!(cc.contains("unsigned long long"), "{}", cc);
+    }
+
+    #[test]
+    fn thread_safe_misspelled() {
+        let mut sess = Session::with_options(SessionOptions {
+            thread_safe: vec!["Loger".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct Logger {
+                int level;
+            };
+            namespace rust_export {
+                using ::Logger;
+            }
+        } => errors: [], warnings: [
+            "`Loger` is listed as thread-safe, but there is no such type in the bindings"
+        ]);
+    }
+
+    #[test]
+    fn nested_struct() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Foo {
+                int a, b;
+            };
+            struct Bar {
+                char c, d;
+                Foo foo;
+            };
+            namespace rust_export {
+                using ::Bar;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Bar;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub(crate) struct Foo {
+                    pub a: i32,
+                    pub b: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Foo>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Foo>() == 4);
+                #[repr(C, align(4))]
+                pub struct Bar {
+                    pub c: core::ffi::c_char,
+                    pub d: core::ffi::c_char,
+                    pub(crate) foo: Foo,
+                }
+                const _: () = assert!(::core::mem::size_of::<Bar>() == 12);
+                const _: () = assert!(::core::mem::align_of::<Bar>() == 4);
+            }
+        "#);
+    }
+
+    #[test]
+    fn self_referential_struct() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Node {
+                Node* next;
+                int value;
+            };
+            namespace rust_export {
+                using ::Node;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Node;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct Node {
+                    pub next: *mut Node,
+                    pub value: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Node>() == 16);
+                const _: () = assert!(::core::mem::align_of::<Node>() == 8);
+            }
+        "#);
+    }
+
+    #[test]
+    fn pointer_cycle() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct B;
+            struct A {
+                B* b;
+                int x;
+            };
+            struct B {
+                const A* a;
+                void* data;
+            };
+            namespace rust_export {
+                using ::A;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::A;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct A {
+                    pub(crate) b: *mut B,
+                    pub x: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<A>() == 16);
+                const _: () = assert!(::core::mem::align_of::<A>() == 8);
+                #[repr(C, align(8))]
+                pub(crate) struct B {
+                    pub a: *const A,
+                    pub data: *mut core::ffi::c_void,
+                }
+                const _: () = assert!(::core::mem::size_of::<B>() == 16);
+                const _: () = assert!(::core::mem::align_of::<B>() == 8);
+            }
+        "#);
+    }
+
+    #[test]
+    fn c_strings() {
+        let mut sess = Session::with_options(SessionOptions {
+            nullable_params: vec!["Person::set_name::nick".into()],
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Person {
+                const char* name;
+                char* buf;
+                void set_name(const char* name, const char* nick);
+                const char* greeting(int lang) const;
+                int find(const char* key) const;
+            };
+            namespace rust_export {
+                using ::Person;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Person;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct Person {
+                    pub name: *const core::ffi::c_char,
+                    pub buf: *mut core::ffi::c_char,
+                }
+                const _: () = assert!(::core::mem::size_of::<Person>() == 16);
+                const _: () = assert!(::core::mem::align_of::<Person>() == 8);
+                impl Person {
+                    pub fn set_name(&mut self, name: *const core::ffi::c_char, nick: *const core::ffi::c_char) -> () {
+                        ::core::ptr::NonNull::from(self).set_name(name, nick)
+                    }
+                    pub fn set_name_cstr(&mut self, name: &::core::ffi::CStr, nick: Option<&::core::ffi::CStr>) -> () {
+                        ::core::ptr::NonNull::from(self).set_name(name.as_ptr(), nick.map_or(::core::ptr::null(), ::core::ffi::CStr::as_ptr))
+                    }
+                    pub fn greeting(&mut self, lang: i32) -> *const core::ffi::c_char {
+                        ::core::ptr::NonNull::from(self).greeting(lang)
+                    }
+                    /// Returns the string as a `CStr`, or `None` if it's null.
+                    ///
+                    /// # Safety
+                    ///
+                    /// The string must stay valid and unchanged while `self` is borrowed.
+                    pub unsafe fn greeting_str(&self, lang: i32) -> Option<&::core::ffi::CStr> {
+                        let ptr = ::core::ptr::NonNull::from(self).greeting(lang);
+                        match ptr.is_null() {
+                            true => None,
+                            false => Some(::core::ffi::CStr::from_ptr(ptr)),
+                        }
+                    }
+                    pub fn find(&mut self, key: *const core::ffi::c_char) -> i32 {
+                        ::core::ptr::NonNull::from(self).find(key)
+                    }
+                    pub fn find_cstr(&self, key: &::core::ffi::CStr) -> i32 {
+                        ::core::ptr::NonNull::from(self).find(key.as_ptr())
+                    }
+                }
+                pub trait Person_set_name_Ext {
+                    fn set_name(self, name: *const core::ffi::c_char, nick: *const core::ffi::c_char) -> ();
+                }
+                impl Person_set_name_Ext for ::core::ptr::NonNull<Person> {
+                    fn set_name(self, name: *const core::ffi::c_char, nick: *const core::ffi::c_char) -> () {
+                        unsafe { _bind_Person__set_name(self.as_ptr(), name, nick) }
+                    }
+                }
+                pub trait Person_greeting_Ext {
+                    fn greeting(self, lang: i32) -> *const core::ffi::c_char;
+                }
+                impl Person_greeting_Ext for ::core::ptr::NonNull<Person> {
+                    fn greeting(self, lang: i32) -> *const core::ffi::c_char {
+                        unsafe { _bind_Person__greeting(self.as_ptr(), lang) }
+                    }
+                }
+                pub trait Person_find_Ext {
+                    fn find(self, key: *const core::ffi::c_char) -> i32;
+                }
+                impl Person_find_Ext for ::core::ptr::NonNull<Person> {
+                    fn find(self, key: *const core::ffi::c_char) -> i32 {
+                        unsafe { _bind_Person__find(self.as_ptr(), key) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Person__set_name(this: *mut Person, name: *const core::ffi::c_char, nick: *const core::ffi::c_char) -> ();
+                    fn _bind_Person__greeting(this: *mut Person, lang: i32) -> *const core::ffi::c_char;
+                    fn _bind_Person__find(this: *mut Person, key: *const core::ffi::c_char) -> i32;
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Person__set_name(::Person* self, char const* name, char const* nick) {
+                return self->::Person::set_name(name, nick);
+            }
+            extern "C" char const* _bind_Person__greeting(::Person* self, int lang) {
+                return self->::Person::greeting(lang);
+            }
+            extern "C" int _bind_Person__find(::Person* self, char const* key) {
+                return self->::Person::find(key);
+            }
+        "#);
+    }
+
+    #[test]
+    fn opaque_handle() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct FooHandle;
+            struct Device {
+                FooHandle* current;
+                int id;
+            };
+            struct Pool {
+                int refs;
+                void close(FooHandle* handle);
+            };
+            namespace rust_export {
+                using ::Device;
+                using ::Pool;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Device;
+                pub use crate::bind::Pool;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct Device {
+                    pub current: *mut FooHandle,
+                    pub id: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Device>() == 16);
+                const _: () = assert!(::core::mem::align_of::<Device>() == 8);
+                #[repr(C)]
+                pub struct FooHandle {
+                    _private: [u8; 0],
+                    _marker: ::core::marker::PhantomData<(*mut u8, ::core::marker::PhantomPinned)>,
+                }
+                #[repr(C, align(4))]
+                pub struct Pool {
+                    pub refs: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Pool>() == 4);
+                const _: () = assert!(::core::mem::align_of::<Pool>() == 4);
+                impl Pool {
+                    pub fn close(&mut self, handle: *mut FooHandle) -> () {
+                        ::core::ptr::NonNull::from(self).close(handle)
+                    }
+                }
+                pub trait Pool_close_Ext {
+                    fn close(self, handle: *mut FooHandle) -> ();
+                }
+                impl Pool_close_Ext for ::core::ptr::NonNull<Pool> {
+                    fn close(self, handle: *mut FooHandle) -> () {
+                        unsafe { _bind_Pool__close(self.as_ptr(), handle) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Pool__close(this: *mut Pool, handle: *mut FooHandle) -> ();
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Pool__close(::Pool* self, FooHandle* handle) {
+                return self->::Pool::close(handle);
+            }
+        "#);
+    }
+
+    #[test]
+    fn arrays() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Cell {
+                char piece;
+                int owner;
+            };
+            struct Board {
+                Cell cells[8][8];
+                char marks[8][8];
+                int turn;
+            };
+            namespace rust_export {
+                using ::Board;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Board;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub(crate) struct Cell {
+                    pub piece: core::ffi::c_char,
+                    pub owner: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Cell>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Cell>() == 4);
+                #[repr(C, align(4))]
+                pub struct Board {
+                    pub(crate) cells: [[Cell; 8]; 8],
+                    pub marks: [[core::ffi::c_char; 8]; 8],
+                    pub turn: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Board>() == 580);
+                const _: () = assert!(::core::mem::align_of::<Board>() == 4);
+            }
+        "#);
+    }
+
+    #[test]
+    fn reference_params() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Config {
+                int level, depth;
+            };
+            struct Engine {
+                int ticks, frame;
+                void update(const Config& cfg);
+                Config& current();
+            };
+            namespace rust_export {
+                using ::Config;
+                using ::Engine;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Config;
+                pub use crate::bind::Engine;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Config {
+                    pub level: i32,
+                    pub depth: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Config>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Config>() == 4);
+                #[repr(C, align(4))]
+                pub struct Engine {
+                    pub ticks: i32,
+                    pub frame: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Engine>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Engine>() == 4);
+                impl Engine {
+                    pub fn update(&mut self, cfg: &Config) -> () {
+                        ::core::ptr::NonNull::from(self).update(cfg)
+                    }
+                    pub fn current(&mut self, ) -> &mut Config {
+                        unsafe { &mut *::core::ptr::NonNull::from(self).current() }
+                    }
+                }
+                pub trait Engine_update_Ext {
+                    fn update(self, cfg: &Config) -> ();
+                }
+                impl Engine_update_Ext for ::core::ptr::NonNull<Engine> {
+                    fn update(self, cfg: &Config) -> () {
+                        unsafe { _bind_Engine__update(self.as_ptr(), cfg) }
+                    }
+                }
+                pub trait Engine_current_Ext {
+                    fn current(self, ) -> *mut Config;
+                }
+                impl Engine_current_Ext for ::core::ptr::NonNull<Engine> {
+                    fn current(self, ) -> *mut Config {
+                        unsafe { _bind_Engine__current(self.as_ptr(), ) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Engine__update(this: *mut Engine, cfg: &Config) -> ();
+                    fn _bind_Engine__current(this: *mut Engine, ) -> *mut Config;
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Engine__update(::Engine* self, Config const* cfg) {
+                return self->::Engine::update(*cfg);
+            }
+            extern "C" Config* _bind_Engine__current(::Engine* self, ) {
+                return &self->::Engine::current();
+            }
+        "#);
+    }
+
+    #[test]
+    fn reference_returns() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Item {
+                int id, weight;
+            };
+            struct List {
+                Item items[4];
+                const Item& get(int i) const;
+                Item& last() const;
+            };
+            namespace rust_export {
+                using ::Item;
+                using ::List;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Item;
+                pub use crate::bind::List;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Item {
+                    pub id: i32,
+                    pub weight: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Item>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Item>() == 4);
+                #[repr(C, align(4))]
+                pub struct List {
+                    pub items: [Item; 4],
+                }
+                const _: () = assert!(::core::mem::size_of::<List>() == 32);
+                const _: () = assert!(::core::mem::align_of::<List>() == 4);
+                impl List {
+                    pub fn get(&self, i: i32) -> &Item {
+                        unsafe { &*::core::ptr::NonNull::from(self).get(i) }
+                    }
+                    pub fn last(&self, ) -> &Item {
+                        unsafe { &*::core::ptr::NonNull::from(self).last() }
+                    }
+                }
+                pub trait List_get_Ext {
+                    fn get(self, i: i32) -> *const Item;
+                }
+                impl List_get_Ext for ::core::ptr::NonNull<List> {
+                    fn get(self, i: i32) -> *const Item {
+                        unsafe { _bind_List__get(self.as_ptr(), i) }
+                    }
+                }
+                pub trait List_last_Ext {
+                    fn last(self, ) -> *mut Item;
+                }
+                impl List_last_Ext for ::core::ptr::NonNull<List> {
+                    fn last(self, ) -> *mut Item {
+                        unsafe { _bind_List__last(self.as_ptr(), ) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_List__get(this: *mut List, i: i32) -> *const Item;
+                    fn _bind_List__last(this: *mut List, ) -> *mut Item;
+                }
+            }
+        "#, r#"
+            extern "C" Item const* _bind_List__get(::List* self, int i) {
+                return &self->::List::get(i);
+            }
+            extern "C" Item* _bind_List__last(::List* self, ) {
+                return &self->::List::last();
+            }
+        "#);
+    }
+
+    #[test]
+    fn reference_params_builtin() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Engine {
+                int ticks, frame;
+                void scale(const int& factor, int& out);
+            };
+            namespace rust_export {
+                using ::Engine;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Engine;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Engine {
+                    pub ticks: i32,
+                    pub frame: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Engine>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Engine>() == 4);
+                impl Engine {
+                    pub fn scale(&mut self, factor: i32, out: &mut i32) -> () {
+                        ::core::ptr::NonNull::from(self).scale(factor, out)
+                    }
+                }
+                pub trait Engine_scale_Ext {
+                    fn scale(self, factor: i32, out: &mut i32) -> ();
+                }
+                impl Engine_scale_Ext for ::core::ptr::NonNull<Engine> {
+                    fn scale(self, factor: i32, out: &mut i32) -> () {
+                        unsafe { _bind_Engine__scale(self.as_ptr(), factor, out) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Engine__scale(this: *mut Engine, factor: i32, out: &mut i32) -> ();
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Engine__scale(::Engine* self, int factor, int* out) {
+                return self->::Engine::scale(factor, *out);
+            }
+        "#);
+    }
+
+    #[test]
+    fn raw_pointer_refs() {
+        let mut sess = Session::with_options(SessionOptions {
+            raw_pointer_refs: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Engine {
+                int ticks, frame;
+                void merge(const Engine& other, const int& weight);
+            };
+            namespace rust_export {
+                using ::Engine;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Engine;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Engine {
+                    pub ticks: i32,
+                    pub frame: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Engine>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Engine>() == 4);
+                impl Engine {
+                    pub fn merge(&mut self, other: *const Engine, weight: *const i32) -> () {
+                        ::core::ptr::NonNull::from(self).merge(other, weight)
+                    }
+                }
+                pub trait Engine_merge_Ext {
+                    fn merge(self, other: *const Engine, weight: *const i32) -> ();
+                }
+                impl Engine_merge_Ext for ::core::ptr::NonNull<Engine> {
+                    fn merge(self, other: *const Engine, weight: *const i32) -> () {
+                        unsafe { _bind_Engine__merge(self.as_ptr(), other, weight) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Engine__merge(this: *mut Engine, other: *const Engine, weight: *const i32) -> ();
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Engine__merge(::Engine* self, Engine const* other, int const* weight) {
+                return self->::Engine::merge(*other, *weight);
+            }
+        "#);
+    }
+
+    #[test]
+    fn two_layer() {
+        let mut sess = Session::with_options(SessionOptions {
+            two_layer: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Foo {
+                int a, b;
+                int sum(int c);
+            };
+            namespace rust_export {
+                using ::Foo;
             }
         } => r#"
             pub mod export {
-                pub use crate::bind::Pod;
+                pub use crate::bind::Foo;
             }
             pub(crate) mod bind {
-                #[repr(C, align(8))]
-                pub struct Pod {
-                    pub a: i32,
-                    pub b: i32,
-                    pub e: i8,
-                    pub c: f64,
-                    pub d: f64,
+                pub mod sys {
+                    #[repr(C, align(4))]
+                    pub struct Foo {
+                        pub a: i32,
+                        pub b: i32,
+                    }
+                    const _: () = assert!(::core::mem::size_of::<Foo>() == 8);
+                    const _: () = assert!(::core::mem::align_of::<Foo>() == 4);
+                    extern "C" {
+                        pub fn _bind_Foo__sum(this: *mut Foo, c: i32) -> i32;
+                    }
+                }
+                pub use self::sys::Foo;
+                impl Foo {
+                    pub fn sum(&mut self, c: i32) -> i32 {
+                        ::core::ptr::NonNull::from(self).sum(c)
+                    }
+                }
+                pub trait Foo_sum_Ext {
+                    fn sum(self, c: i32) -> i32;
+                }
+                impl Foo_sum_Ext for ::core::ptr::NonNull<Foo> {
+                    fn sum(self, c: i32) -> i32 {
+                        unsafe { sys::_bind_Foo__sum(self.as_ptr(), c) }
+                    }
                 }
             }
+        "#, r#"
+            extern "C" int _bind_Foo__sum(::Foo* self, int c) {
+                return self->::Foo::sum(c);
+            }
         "#);
     }
 
     #[test]
-    fn nested_struct() {
+    fn param_names() {
         let mut sess = Session::new();
         cpp_to_rs!(sess, {
-            struct Foo {
-                int a, b;
-            };
-            struct Bar {
-                char c, d;
-                Foo foo;
+            struct Grid {
+                int w;
+                void resize(int, int arg2, int, int type);
             };
             namespace rust_export {
-                using ::Bar;
+                using ::Grid;
             }
         } => r#"
             pub mod export {
-                pub use crate::bind::Bar;
+                pub use crate::bind::Grid;
             }
             pub(crate) mod bind {
                 #[repr(C, align(4))]
-                pub struct Bar {
-                    pub c: i8,
-                    pub d: i8,
-                    foo: Foo,
+                pub struct Grid {
+                    pub w: i32,
                 }
-                #[repr(C, align(4))]
-                struct Foo {
-                    pub a: i32,
-                    pub b: i32,
+                const _: () = assert!(::core::mem::size_of::<Grid>() == 4);
+                const _: () = assert!(::core::mem::align_of::<Grid>() == 4);
+                impl Grid {
+                    pub fn resize(&mut self, arg0: i32, arg2: i32, arg2_: i32, r#type: i32) -> () {
+                        ::core::ptr::NonNull::from(self).resize(arg0, arg2, arg2_, r#type)
+                    }
+                }
+                pub trait Grid_resize_Ext {
+                    fn resize(self, arg0: i32, arg2: i32, arg2_: i32, r#type: i32) -> ();
+                }
+                impl Grid_resize_Ext for ::core::ptr::NonNull<Grid> {
+                    fn resize(self, arg0: i32, arg2: i32, arg2_: i32, r#type: i32) -> () {
+                        unsafe { _bind_Grid__resize(self.as_ptr(), arg0, arg2, arg2_, r#type) }
+                    }
                 }
+                extern "C" {
+                    fn _bind_Grid__resize(this: *mut Grid, arg0: i32, arg2: i32, arg2_: i32, r#type: i32) -> ();
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Grid__resize(::Grid* self, int arg0, int arg2, int arg2_, int type) {
+                return self->::Grid::resize(arg0, arg2, arg2_, type);
             }
         "#);
     }
@@ -441,44 +2945,52 @@ mod tests {
                     pub a: i32,
                     pub b: i32,
                 }
+                const _: () = assert!(::core::mem::size_of::<Foo>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Foo>() == 4);
+                impl Foo {
+                    pub fn sum(&mut self, c: i32, arg1: i32) -> i32 {
+                        ::core::ptr::NonNull::from(self).sum(c, arg1)
+                    }
+                }
                 pub trait Foo_sum_Ext {
-                    fn sum(self, c: i32, _1__: i32) -> i32;
+                    fn sum(self, c: i32, arg1: i32) -> i32;
                 }
                 impl Foo_sum_Ext for ::core::ptr::NonNull<Foo> {
-                    fn sum(self, c: i32, _1__: i32) -> i32 {
-                        extern "C" { fn _bind_Foo__sum(this: *mut Foo, c: i32, _1__: i32) -> i32; }
-                        unsafe { _bind_Foo__sum(self.as_ptr(), c, _1__) }
+                    fn sum(self, c: i32, arg1: i32) -> i32 {
+                        unsafe { _bind_Foo__sum(self.as_ptr(), c, arg1) }
                     }
                 }
-                impl Foo {
-                    pub fn sum(&mut self, c: i32, _1__: i32) -> i32 {
-                        ::core::ptr::NonNull::from(self).sum(c, _1__)
-                    }
+                extern "C" {
+                    fn _bind_Foo__sum(this: *mut Foo, c: i32, arg1: i32) -> i32;
                 }
                 pub(crate) mod ns {
                     #[repr(C, align(4))]
                     pub struct Bar {
                         pub x: i32,
                     }
+                    const _: () = assert!(::core::mem::size_of::<Bar>() == 4);
+                    const _: () = assert!(::core::mem::align_of::<Bar>() == 4);
+                    impl Bar {
+                        pub fn frob(&mut self, ) -> core::ffi::c_char {
+                            ::core::ptr::NonNull::from(self).frob()
+                        }
+                    }
                     pub trait Bar_frob_Ext {
-                        fn frob(self, ) -> i8;
+                        fn frob(self, ) -> core::ffi::c_char;
                     }
                     impl Bar_frob_Ext for ::core::ptr::NonNull<Bar> {
-                        fn frob(self, ) -> i8 {
-                            extern "C" { fn _bind_Bar__frob(this: *mut Bar, ) -> i8; }
+                        fn frob(self, ) -> core::ffi::c_char {
                             unsafe { _bind_Bar__frob(self.as_ptr(), ) }
                         }
                     }
-                    impl Bar {
-                        pub fn frob(&mut self, ) -> i8 {
-                            ::core::ptr::NonNull::from(self).frob()
-                        }
+                    extern "C" {
+                        fn _bind_Bar__frob(this: *mut Bar, ) -> core::ffi::c_char;
                     }
                 }
             }
         "#, r#"
-            extern "C" int _bind_Foo__sum(::Foo* self, int c, int _1__) {
-                return self->::Foo::sum(c, _1__);
+            extern "C" int _bind_Foo__sum(::Foo* self, int c, int arg1) {
+                return self->::Foo::sum(c, arg1);
             }
             extern "C" char _bind_Bar__frob(::ns::Bar* self, ) {
                 return self->::ns::Bar::frob();
@@ -486,6 +2998,310 @@ mod tests {
         "#);
     }
 
+    #[test]
+    fn static_constexpr_methods() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct Limits {
+                int id, count;
+                static constexpr int max_items() { return 16 * 4; }
+                static constexpr double scale() { return 0.5; }
+                static constexpr int flags_for(int kind) { return kind << 1; }
+            };
+            namespace rust_export {
+                using ::Limits;
+            }
+        } => [
+            "constexpr method `flags_for` is bound as a normal function"
+        ]);
+        let (rs_out, cc_out) = test_util::codegen(&sess, &ir);
+        test_util::check_output(
+            "Rust",
+            &rs_out,
+            r#"
+            pub mod export {
+                pub use crate::bind::Limits;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Limits {
+                    pub id: i32,
+                    pub count: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Limits>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Limits>() == 4);
+                impl Limits {
+                    pub const fn max_items() -> i32 {
+                        64
+                    }
+                    pub const fn scale() -> f64 {
+                        0.5
+                    }
+                    pub fn flags_for(kind: i32) -> i32 {
+                        unsafe { _bind_Limits__flags_for(kind) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Limits__flags_for(kind: i32) -> i32;
+                }
+            }
+        "#,
+        );
+        test_util::check_output(
+            "C++",
+            &cc_out,
+            r#"
+            extern "C" int _bind_Limits__flags_for(int kind) {
+                return ::Limits::flags_for(kind);
+            }
+        "#,
+        );
+    }
+
+    #[test]
+    fn item_order() {
+        let mut sess = Session::with_options(SessionOptions {
+            thread_safe: vec!["Counter".into()],
+            tuple_conversions: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Counter {
+                int count;
+                void add(int n);
+                static int limit();
+            };
+            struct Gauge {
+                int level;
+                int read() const;
+            };
+            namespace rust_export {
+                using ::Counter;
+                using ::Gauge;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Counter;
+                pub use crate::bind::Gauge;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Counter {
+                    pub count: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Counter>() == 4);
+                const _: () = assert!(::core::mem::align_of::<Counter>() == 4);
+                impl Counter {
+                    pub fn add(&mut self, n: i32) -> () {
+                        ::core::ptr::NonNull::from(self).add(n)
+                    }
+                    pub fn limit() -> i32 {
+                        unsafe { _bind_Counter__limit() }
+                    }
+                }
+                unsafe impl Send for Counter {}
+                unsafe impl Sync for Counter {}
+                impl From<(i32,)> for Counter {
+                    fn from((count,): (i32,)) -> Self {
+                        Counter { count }
+                    }
+                }
+                impl From<Counter> for (i32,) {
+                    fn from(value: Counter) -> Self {
+                        (value.count,)
+                    }
+                }
+                pub trait Counter_add_Ext {
+                    fn add(self, n: i32) -> ();
+                }
+                impl Counter_add_Ext for ::core::ptr::NonNull<Counter> {
+                    fn add(self, n: i32) -> () {
+                        unsafe { _bind_Counter__add(self.as_ptr(), n) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Counter__add(this: *mut Counter, n: i32) -> ();
+                    fn _bind_Counter__limit() -> i32;
+                }
+                #[repr(C, align(4))]
+                pub struct Gauge {
+                    pub level: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Gauge>() == 4);
+                const _: () = assert!(::core::mem::align_of::<Gauge>() == 4);
+                impl Gauge {
+                    pub fn read(&mut self, ) -> i32 {
+                        ::core::ptr::NonNull::from(self).read()
+                    }
+                }
+                impl From<(i32,)> for Gauge {
+                    fn from((level,): (i32,)) -> Self {
+                        Gauge { level }
+                    }
+                }
+                impl From<Gauge> for (i32,) {
+                    fn from(value: Gauge) -> Self {
+                        (value.level,)
+                    }
+                }
+                pub trait Gauge_read_Ext {
+                    fn read(self, ) -> i32;
+                }
+                impl Gauge_read_Ext for ::core::ptr::NonNull<Gauge> {
+                    fn read(self, ) -> i32 {
+                        unsafe { _bind_Gauge__read(self.as_ptr(), ) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Gauge__read(this: *mut Gauge, ) -> i32;
+                }
+            }
+        "#, r#"
+            extern "C" void _bind_Counter__add(::Counter* self, int n) {
+                return self->::Counter::add(n);
+            }
+            extern "C" int _bind_Counter__limit() {
+                return ::Counter::limit();
+            }
+            extern "C" int _bind_Gauge__read(::Gauge* self, ) {
+                return self->::Gauge::read();
+            }
+        "#);
+    }
+
+    #[test]
+    fn coalesced_externs() {
+        let mut sess = Session::with_options(SessionOptions {
+            coalesce_externs: true,
+            ..Default::default()
+        });
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                struct Counter {
+                    int count;
+                    void add(int n);
+                };
+                struct Gauge {
+                    int level;
+                    int read() const;
+                };
+                namespace rust_export {
+                    using ::Counter;
+                    using ::Gauge;
+                }
+            }),
+        );
+        assert_eq!(rs.matches("extern \"C\"").count(), 1, "{}", rs);
+        // The block comes after both types, at the end of their module.
+        let block = concat!(
+            "    extern \"C\" {\n",
+            "        fn _bind_Counter__add(this: *mut Counter, n: i32) -> ();\n",
+            "        fn _bind_Gauge__read(this: *mut Gauge, ) -> i32;\n",
+            "    }\n",
+            "}\n",
+        );
+        assert!(rs.ends_with(block), "{}", rs);
+    }
+
+    #[test]
+    fn typedef_anonymous_struct() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            typedef struct {
+                int x, y;
+            } Point;
+            namespace rust_export {
+                using ::Point;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Point;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Point {
+                    pub x: i32,
+                    pub y: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Point>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Point>() == 4);
+            }
+        "#);
+    }
+
+    #[test]
+    fn typedef_anonymous_struct_field() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            typedef struct {
+                int x, y;
+            } Point;
+            struct Line {
+                Point a, b;
+            };
+            namespace rust_export {
+                using ::Line;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Line;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub(crate) struct Point {
+                    pub x: i32,
+                    pub y: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Point>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Point>() == 4);
+                #[repr(C, align(4))]
+                pub struct Line {
+                    pub(crate) a: Point,
+                    pub(crate) b: Point,
+                }
+                const _: () = assert!(::core::mem::size_of::<Line>() == 16);
+                const _: () = assert!(::core::mem::align_of::<Line>() == 4);
+            }
+        "#);
+    }
+
+    #[test]
+    fn alias_anonymous_struct_field() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            using Point = struct {
+                int x, y;
+            };
+            struct Line {
+                Point a, b;
+            };
+            namespace rust_export {
+                using ::Line;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Line;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub(crate) struct Point {
+                    pub x: i32,
+                    pub y: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Point>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Point>() == 4);
+                #[repr(C, align(4))]
+                pub struct Line {
+                    pub(crate) a: Point,
+                    pub(crate) b: Point,
+                }
+                const _: () = assert!(::core::mem::size_of::<Line>() == 16);
+                const _: () = assert!(::core::mem::align_of::<Line>() == 4);
+            }
+        "#);
+    }
+
     // TODO handle these.
     #[test]
     #[should_panic(expected = "unsupported type")]
@@ -510,16 +3326,20 @@ mod tests {
             }
             pub(crate) mod bind {
                 #[repr(C, align(4))]
-                struct Foo {
+                pub(crate) struct Foo {
                     pub a: i32,
                     pub b: i32,
                 }
+                const _: () = assert!(::core::mem::size_of::<Foo>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Foo>() == 4);
                 #[repr(C, align(4))]
                 pub struct Bar {
-                    pub c: i8,
-                    pub d: i8,
-                    foo: Foo,
+                    pub c: core::ffi::c_char,
+                    pub d: core::ffi::c_char,
+                    pub(crate) foo: Foo,
                 }
+                const _: () = assert!(::core::mem::size_of::<Bar>() == 12);
+                const _: () = assert!(::core::mem::align_of::<Bar>() == 4);
             }
         "#);
     }
@@ -547,6 +3367,8 @@ mod tests {
                         pub a: i32,
                         pub b: i32,
                     }
+                    const _: () = assert!(::core::mem::size_of::<Foo>() == 8);
+                    const _: () = assert!(::core::mem::align_of::<Foo>() == 4);
                 }
             }
         "#);
@@ -569,4 +3391,199 @@ mod tests {
             "invalid rust_export item"
         ]);
     }
+
+    #[test]
+    fn transparent_wrapper() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Meters {
+                double value;
+            };
+            struct Pair {
+                double first, second;
+            };
+            namespace rust_export {
+                using ::Meters;
+                using ::Pair;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Meters;
+                pub use crate::bind::Pair;
+            }
+            pub(crate) mod bind {
+                #[repr(transparent)]
+                pub struct Meters {
+                    pub value: f64,
+                }
+                const _: () = assert!(::core::mem::size_of::<Meters>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Meters>() == 8);
+                impl From<f64> for Meters {
+                    fn from(value: f64) -> Self {
+                        Meters { value: value }
+                    }
+                }
+                impl From<Meters> for f64 {
+                    fn from(wrapper: Meters) -> Self {
+                        wrapper.value
+                    }
+                }
+                #[repr(C, align(8))]
+                pub struct Pair {
+                    pub first: f64,
+                    pub second: f64,
+                }
+                const _: () = assert!(::core::mem::size_of::<Pair>() == 16);
+                const _: () = assert!(::core::mem::align_of::<Pair>() == 8);
+            }
+        "#);
+    }
+
+    #[test]
+    fn transparent_wrapper_disabled() {
+        let mut sess = Session::with_options(SessionOptions {
+            repr_c_only: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Meters {
+                double value;
+            };
+            namespace rust_export {
+                using ::Meters;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Meters;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct Meters {
+                    pub value: f64,
+                }
+                const _: () = assert!(::core::mem::size_of::<Meters>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Meters>() == 8);
+            }
+        "#);
+    }
+
+    #[test]
+    fn transitive_visibility() {
+        use crate::TransitiveVisibility::*;
+        let src = stringify!({
+            struct Inner {
+                int a;
+            };
+            struct Outer {
+                Inner inner;
+            };
+            namespace rust_export {
+                using ::Outer;
+            }
+        });
+        for (mode, vis) in &[(Private, ""), (Crate, "pub(crate) "), (Public, "pub ")] {
+            let mut sess = Session::with_options(SessionOptions {
+                transitive_vis: *mode,
+                ..Default::default()
+            });
+            let (rs_out, _) = test_util::gen_code(&mut sess, src);
+            let has_line = |line: String| rs_out.lines().any(|l| l.trim() == line);
+            assert!(
+                has_line(format!("{}inner: Inner,", vis)),
+                "{:?}: {}",
+                mode,
+                rs_out
+            );
+            assert!(
+                has_line(format!("{}struct Inner {{", vis)),
+                "{:?}: {}",
+                mode,
+                rs_out
+            );
+        }
+    }
+
+    /// A header with 200 pairs of exported structs, for comparing lowering on
+    /// one thread and on several.
+    fn many_structs() -> String {
+        let mut decls = String::new();
+        let mut exports = String::new();
+        for i in 0..200 {
+            decls += &format!("struct Inner{0} {{ char c; double d; }};\n", i);
+            decls += &format!("struct Outer{0} {{ int a; Inner{0} inner; }};\n", i);
+            exports += &format!("using ::Outer{};\n", i);
+        }
+        format!("{{ {} namespace rust_export {{ {} }} }}", decls, exports)
+    }
+
+    // Run with `cargo bench lower_`. Both include parsing the header, which
+    // takes the same time either way.
+    #[bench]
+    fn lower_serial(b: &mut test::Bencher) {
+        let src = many_structs();
+        b.iter(|| test_util::gen_code(&mut Session::new(), &src));
+    }
+
+    #[bench]
+    fn lower_parallel(b: &mut test::Bencher) {
+        let src = many_structs();
+        b.iter(|| {
+            let opts = SessionOptions {
+                jobs: 4,
+                ..Default::default()
+            };
+            test_util::gen_code(&mut Session::with_options(opts), &src)
+        });
+    }
+
+    #[test]
+    fn parallel_lowering() {
+        let src = many_structs();
+        let serial = test_util::gen_code(&mut Session::new(), &src);
+        let parallel = test_util::gen_code(
+            &mut Session::with_options(SessionOptions {
+                jobs: 4,
+                ..Default::default()
+            }),
+            &src,
+        );
+        assert!(serial == parallel, "parallel lowering changed the output");
+    }
+
+    #[test]
+    fn anonymous_union() {
+        let mut sess = Session::new();
+        cpp_to_rs!(sess, {
+            struct Value {
+                char tag;
+                union {
+                    int i;
+                    float f;
+                };
+                int trailing;
+            };
+            namespace rust_export {
+                using ::Value;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Value;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Value {
+                    pub tag: core::ffi::c_char,
+                    pub union0: Value_union0,
+                    pub trailing: i32,
+                }
+                #[repr(C)]
+                pub union Value_union0 {
+                    pub i: i32,
+                    pub f: f32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Value>() == 12);
+                const _: () = assert!(::core::mem::align_of::<Value>() == 4);
+            }
+        "#);
+    }
 }
@@ -23,11 +23,22 @@ use std::{
     rc::Rc,
     sync::Arc,
 };
-use termcolor::{self, ColorChoice};
+use termcolor::{self, ColorChoice, ColorSpec, WriteColor};
 
-pub use codespan::{ColumnIndex, LineIndex, Location};
+pub use codespan::Location;
 pub use imp::Severity;
 
+/// A source file that only exists in memory, like a buffer passed to clang in
+/// place of the file on disk.
+///
+/// Spans in these files render from the stored contents, so they don't need
+/// the AST they came from to still be around.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct VirtualFile {
+    pub name: String,
+    pub contents: Arc<str>,
+}
+
 pub mod db {
     use super::*;
     use codespan_reporting::files::SimpleFile;
@@ -70,11 +81,18 @@ pub mod db {
 
         /// Cache for [`BasicFile`]. Should not be used outside of the `diagnostics` module.
         fn basic_file(&self, id: FileId) -> Arc<BasicFile>;
+
+        /// The start of every line in a file, for turning byte offsets into
+        /// lines and columns.
+        fn line_index(&self, id: FileId) -> Arc<LineIndex>;
     }
     fn basic_file(db: &impl SourceFileCache, id: FileId) -> Arc<BasicFile> {
         let (name, contents) = db.lookup_intern_source_file(id).get_name_and_contents(db);
         Arc::new(BasicFile(SimpleFile::new(name.into(), contents.into())))
     }
+    fn line_index(db: &impl SourceFileCache, id: FileId) -> Arc<LineIndex> {
+        Arc::new(LineIndex::new(db.basic_file(id).0.source().clone()))
+    }
 
     /// Since the Files trait (and libclang) copy the entire file contents every
     /// time we request them, we need a way of caching those contents. Once cached,
@@ -118,6 +136,46 @@ pub mod db {
 
 pub use db::FileId;
 
+/// Maps byte offsets in a file to lines and columns.
+///
+/// Lines and columns both start at 1. Columns count characters (Unicode
+/// scalar values), not bytes, so a multi-byte character is one column. Lines
+/// end at `\n`; a `\r` before it is the last character of its line.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LineIndex {
+    source: Arc<str>,
+    /// The byte offset of the start of each line.
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: Arc<str>) -> LineIndex {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        LineIndex {
+            source,
+            line_starts,
+        }
+    }
+
+    /// Returns the line and column of the byte at `offset`. Offsets past the
+    /// end of the file are treated as the end of the file.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.source.len());
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let start = self.line_starts[line];
+        let column = self.source[start..]
+            .char_indices()
+            .take_while(|&(idx, _)| start + idx < offset)
+            .count();
+        (line + 1, column + 1)
+    }
+}
+
 /// The source code associated with an object.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Span {
@@ -157,14 +215,34 @@ impl Span {
         }
     }
 
+    pub fn file_id(&self) -> FileId {
+        self.file_id
+    }
+
+    /// The byte range of the span within its file.
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.span.start().to_usize()..self.span.end().to_usize()
+    }
+
+    /// The line and column where the span starts. See [`LineIndex`] for how
+    /// they are counted.
+    pub fn start_position(&self, db: &impl db::SourceFileCache) -> (usize, usize) {
+        db.line_index(self.file_id)
+            .position(self.span.start().to_usize())
+    }
+
+    /// The line and column just past the end of the span.
+    pub fn end_position(&self, db: &impl db::SourceFileCache) -> (usize, usize) {
+        db.line_index(self.file_id)
+            .position(self.span.end().to_usize())
+    }
+
     pub fn label(&self, message: impl Into<String>) -> Label {
-        let range = self.span.start().to_usize()..self.span.end().to_usize();
-        Label(imp::Label::primary(self.file_id, range).with_message(message))
+        Label(imp::Label::primary(self.file_id, self.range()).with_message(message))
     }
 
     pub fn label_no_message(&self) -> Label {
-        let range = self.span.start().to_usize()..self.span.end().to_usize();
-        Label(imp::Label::primary(self.file_id, range))
+        Label(imp::Label::primary(self.file_id, self.range()))
     }
 }
 
@@ -188,6 +266,10 @@ enum Mode {
     Test {
         errs: Vec<String>,
     },
+    /// Renders diagnostics the same way as `Term`, but into a buffer.
+    Buffer {
+        writer: termcolor::NoColor<Vec<u8>>,
+    },
 }
 
 type Count = u32;
@@ -196,7 +278,6 @@ struct Counts {
     bugs: Count,
     errors: Count,
     warns: Count,
-    #[allow(dead_code)]
     infos: Count,
     helps: Count,
 }
@@ -232,6 +313,26 @@ impl DiagnosticsCtx {
         }
     }
 
+    pub(crate) fn buffered() -> Self {
+        let inner = CtxInner {
+            counts: Counts::default(),
+            mode: Mode::Buffer {
+                writer: termcolor::NoColor::new(vec![]),
+            },
+            seen: HashSet::new(),
+        };
+        DiagnosticsCtx(Rc::new(RefCell::new(inner)))
+    }
+
+    /// Returns everything rendered so far by a context created with
+    /// `buffered()`, or `None` for any other context.
+    pub(crate) fn captured(&self) -> Option<String> {
+        match &self.0.borrow().mode {
+            Mode::Buffer { writer } => Some(String::from_utf8_lossy(writer.get_ref()).into_owned()),
+            _ => None,
+        }
+    }
+
     pub fn has_bugs(&self) -> bool {
         // TODO: should we just panic instead?
         self.0.borrow().counts.errors > 0
@@ -241,6 +342,19 @@ impl DiagnosticsCtx {
         self.0.borrow().counts.errors > 0
     }
 
+    /// Returns the number of diagnostics of `severity` emitted so far.
+    pub(crate) fn count(&self, severity: Severity) -> Count {
+        let inner = self.0.borrow();
+        let counts = &inner.counts;
+        match severity {
+            Severity::Bug => counts.bugs,
+            Severity::Error => counts.errors,
+            Severity::Warning => counts.warns,
+            Severity::Note => counts.infos,
+            Severity::Help => counts.helps,
+        }
+    }
+
     #[allow(dead_code)]
     fn clone(&self) -> Self {
         DiagnosticsCtx(Rc::clone(&self.0))
@@ -311,16 +425,29 @@ impl Diagnostic {
             imp::Severity::Bug => counts.bugs += 1,
             imp::Severity::Error => counts.errors += 1,
             imp::Severity::Warning => counts.warns += 1,
-            imp::Severity::Note => (),
+            imp::Severity::Note => counts.infos += 1,
             imp::Severity::Help => counts.helps += 1,
         }
         match mode {
-            Mode::Term { writer } => {
-                term::emit(writer, &Default::default(), &db::FilesWrapper(db), &self.0)
-                    .expect("failed to emit diagnostic")
-            }
+            Mode::Term { writer } => self.render(db, writer),
             Mode::Test { errs } => errs.push(self.0.message.clone()),
+            Mode::Buffer { writer } => self.render(db, writer),
+        }
+    }
+
+    /// Renders the diagnostic for a person to read, starting the header with
+    /// `file:line:col: ` when it has a primary span.
+    fn render(&self, db: &'_ impl db::SourceFileCache, writer: &mut impl WriteColor) {
+        if let Some(span) = self.primary_span() {
+            let (line, col) = span.start_position(db);
+            writer
+                .set_color(ColorSpec::new().set_bold(true))
+                .and_then(|()| write!(writer, "{}:{}:{}: ", span.file_id().name(db), line, col))
+                .and_then(|()| writer.reset())
+                .expect("failed to emit diagnostic");
         }
+        term::emit(writer, &Default::default(), &db::FilesWrapper(db), &self.0)
+            .expect("failed to emit diagnostic")
     }
 
     pub fn with_note(mut self, note: impl Into<String>) -> Self {
@@ -348,6 +475,29 @@ impl Diagnostic {
     pub fn message(&self) -> &str {
         &self.0.message
     }
+
+    pub fn notes(&self) -> &[String] {
+        &self.0.notes
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.0.severity
+    }
+
+    /// The span of the first primary label, if there is one.
+    pub fn primary_span(&self) -> Option<Span> {
+        self.0
+            .labels
+            .iter()
+            .find(|label| label.style == imp::LabelStyle::Primary)
+            .map(|label| {
+                Span::new(
+                    label.file_id,
+                    label.range.start as u32,
+                    label.range.end as u32,
+                )
+            })
+    }
 }
 
 // This is not great. Hopefully this whole abstraction goes away when salsa
@@ -429,6 +579,45 @@ impl Diagnostics {
         Arc::make_mut(&mut self.val).append(&mut other.into());
     }
 
+    /// Returns true if `diag` is one of these diagnostics, and not just a copy
+    /// of one.
+    pub fn contains(&self, diag: &Diagnostic) -> bool {
+        self.val.iter().any(|d| std::ptr::eq(&*d.0, diag))
+    }
+
+    /// Returns the diagnostics `f` returns true for.
+    pub fn filter(&self, mut f: impl FnMut(&Diagnostic) -> bool) -> Diagnostics {
+        Diagnostics {
+            val: Arc::new(self.val.iter().filter(|d| f(&d.0)).cloned().collect()),
+        }
+    }
+
+    /// Adds the notes `notes` returns to each diagnostic.
+    ///
+    /// Diagnostics with new notes are copies, so only add notes to diagnostics
+    /// that reach the user no other way. A diagnostic that gets notes is only
+    /// kept the first time it's in `self`. Diagnostics that get no notes are
+    /// kept as they are.
+    pub fn with_notes_each(self, mut notes: impl FnMut(&Diagnostic) -> Vec<String>) -> Diagnostics {
+        let mut noted = HashSet::new();
+        let mut val = Vec::with_capacity(self.val.len());
+        for diag in self.val.iter() {
+            if noted.contains(diag) {
+                continue;
+            }
+            let notes = notes(&diag.0);
+            if notes.is_empty() {
+                val.push(diag.clone());
+                continue;
+            }
+            noted.insert(diag.clone());
+            val.push(UniqueDiagnostic::from(
+                Diagnostic::clone(&diag.0).with_notes(notes),
+            ));
+        }
+        Diagnostics { val: Arc::new(val) }
+    }
+
     pub fn emit(self, db: &impl db::SourceFileCache, ctx: &DiagnosticsCtx) {
         for diag in self.val.iter() {
             diag.emit(db, ctx);
@@ -439,6 +628,13 @@ impl Diagnostics {
         self.val.is_empty()
     }
 
+    /// Returns true if any diagnostic is an error (or a bug).
+    pub fn has_errors(&self) -> bool {
+        self.val
+            .iter()
+            .any(|diag| diag.0.severity() >= Severity::Error)
+    }
+
     // TODO this is brittle now
     #[cfg(test)]
     pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
@@ -525,7 +721,6 @@ impl<T> Outcome<T> {
         self.val
     }
 
-    #[allow(unused)]
     pub fn errs(self) -> Diagnostics {
         self.err
     }
@@ -548,6 +743,36 @@ impl<T> Outcome<T> {
             err: self.err,
         }
     }
+
+    /// Combines two independent outcomes, keeping the diagnostics of both:
+    /// those of `self` come first.
+    pub fn zip<U>(self, other: Outcome<U>) -> Outcome<(T, U)> {
+        let mut err = self.err;
+        err.append(other.err);
+        Outcome {
+            val: (self.val, other.val),
+            err,
+        }
+    }
+
+    /// Adds a note from `f` to each diagnostic, to say what was being done
+    /// when it was reported.
+    ///
+    /// The noted diagnostics are copies, so a diagnostic that also reaches
+    /// the user some other way is reported twice. Only add context to
+    /// diagnostics before they're shared, e.g. before caching them.
+    pub fn with_context(self, f: impl Fn() -> String) -> Outcome<T> {
+        let err = self
+            .err
+            .val
+            .iter()
+            .map(|diag| UniqueDiagnostic::from(Diagnostic::clone(&diag.0).with_note(f())))
+            .collect();
+        Outcome {
+            val: self.val,
+            err: Diagnostics { val: Arc::new(err) },
+        }
+    }
 }
 
 pub fn ok<T>(val: T) -> Outcome<T> {
@@ -636,3 +861,112 @@ impl From<Diagnostics> for Outcome<()> {
         Outcome { val: (), err }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use salsa::InternKey;
+
+    fn error(message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    fn messages<T>(outcome: &Outcome<T>) -> Vec<&str> {
+        outcome.err.iter().map(|diag| diag.message()).collect()
+    }
+
+    #[test]
+    fn zip_keeps_both_errors_in_order() {
+        let zipped = err(1, error("a")).zip(err("two", error("b")));
+        assert_eq!(messages(&zipped), ["a", "b"]);
+        assert_eq!(zipped.skip_errs(), (1, "two"));
+
+        let zipped = ok(()).zip(err((), error("b"))).zip(err((), error("c")));
+        assert_eq!(messages(&zipped), ["b", "c"]);
+    }
+
+    #[test]
+    fn collect_does_not_stop_at_errors() {
+        let outcomes = vec![err(1, error("a")), ok(2), err(3, error("c"))];
+        let collected: Outcome<Vec<i32>> = outcomes.into_iter().collect();
+        assert_eq!(messages(&collected), ["a", "c"]);
+        assert_eq!(collected.skip_errs(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn with_context_notes_every_error() {
+        let file = FileId::from_intern_id(0u32.into());
+        let span = Span::new(file, 0, 3);
+        let outcome = Outcome::from_parts(
+            (),
+            Diagnostics::build(|errs| {
+                errs.add(error("a"));
+                errs.add(error("b").with_label(span.label("existing")));
+            }),
+        )
+        .with_context(|| "while lowering struct `Foo`".to_string());
+        assert_eq!(messages(&outcome), ["a", "b"]);
+        let notes: Vec<Vec<&str>> = outcome
+            .err
+            .iter()
+            .map(|diag| diag.0.notes.iter().map(String::as_str).collect())
+            .collect();
+        assert_eq!(
+            notes,
+            [
+                vec!["while lowering struct `Foo`"],
+                vec!["while lowering struct `Foo`"],
+            ]
+        );
+        // The context doesn't take over the primary span.
+        let labels: Vec<usize> = outcome.err.iter().map(|diag| diag.0.labels.len()).collect();
+        assert_eq!(labels, [0, 1]);
+    }
+
+    #[test]
+    fn with_notes_each_keeps_one_copy() {
+        let a = Diagnostics::from(error("a"));
+        let b = Diagnostics::from(error("b"));
+        let mut all = Diagnostics::new();
+        for diags in &[&a, &b, &a, &b] {
+            all.append(Diagnostics::clone(diags));
+        }
+        let noted = all.with_notes_each(|diag| match a.contains(diag) {
+            true => vec!["about a".to_string()],
+            false => vec![],
+        });
+        let notes: Vec<_> = noted
+            .iter()
+            .map(|diag| (diag.message(), diag.notes().len()))
+            .collect();
+        assert_eq!(notes, [("a", 1), ("b", 0), ("b", 0)]);
+        assert!(!a.contains(noted.iter().next().unwrap()));
+    }
+
+    #[test]
+    fn line_index_counts_characters() {
+        let index = LineIndex::new("fn x() {}\n// héllo → wörld\nend".into());
+        assert_eq!(index.position(0), (1, 1));
+        assert_eq!(index.position(9), (1, 10));
+        assert_eq!(index.position(10), (2, 1));
+        // `é` is two bytes and `→` is three, but each is one column.
+        let world = "fn x() {}\n// héllo → ".len();
+        assert_eq!(index.position(world), (2, 12));
+        assert_eq!(index.position(world + "wörld".len()), (2, 17));
+        let end = "fn x() {}\n// héllo → wörld\n".len();
+        assert_eq!(index.position(end), (3, 1));
+        assert_eq!(index.position(end + 3), (3, 4));
+        assert_eq!(index.position(end + 100), (3, 4));
+    }
+
+    #[test]
+    fn line_index_crlf() {
+        let index = LineIndex::new("a\r\nbc\r\n\r\nd".into());
+        assert_eq!(index.position(1), (1, 2));
+        assert_eq!(index.position(2), (1, 3));
+        assert_eq!(index.position(3), (2, 1));
+        assert_eq!(index.position(5), (2, 3));
+        assert_eq!(index.position(7), (3, 1));
+        assert_eq!(index.position(9), (4, 1));
+    }
+}
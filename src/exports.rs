@@ -0,0 +1,152 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Describes the items in a generated bindings crate, for tools that drive
+//! mosaic as a library.
+//!
+//! Unlike the IR, these types own all their data and don't need the database
+//! to be inspected, so they stay valid after the session has moved on.
+
+use crate::{
+    codegen,
+    ir::{cc::RsTargetIr, rs},
+};
+
+/// The items exported by a bindings crate, as returned by
+/// [`Session::generate`](crate::Session::generate).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Bindings {
+    structs: Vec<StructInfo>,
+    symbols: Vec<String>,
+}
+
+impl Bindings {
+    pub(crate) fn new(structs: Vec<StructInfo>, symbols: Vec<String>) -> Bindings {
+        Bindings { structs, symbols }
+    }
+
+    /// Returns the exported structs, in the order they were exported.
+    pub fn structs(&self) -> impl Iterator<Item = &StructInfo> {
+        self.structs.iter()
+    }
+
+    /// Returns the exported struct with the given path, like `export::Pod`.
+    pub fn get_struct(&self, path: &str) -> Option<&StructInfo> {
+        self.structs.iter().find(|st| st.path == path)
+    }
+
+    /// Returns the names of the `extern "C"` shims defined by the generated
+    /// C++, for writing linker or version scripts that keep them visible.
+    pub fn generated_symbols(&self) -> Vec<String> {
+        self.symbols.clone()
+    }
+}
+
+/// A struct that is exported from the bindings crate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StructInfo {
+    /// The path the struct is exported under, relative to the crate root.
+    pub path: String,
+    pub size: u64,
+    pub align: u32,
+    pub fields: Vec<FieldInfo>,
+    pub methods: Vec<MethodInfo>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldInfo {
+    pub name: String,
+    /// The Rust type of the field, as spelled in the generated code.
+    pub ty: String,
+    pub offset: u64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MethodInfo {
+    pub name: String,
+    /// The Rust types of the parameters, not including `self`.
+    pub param_tys: Vec<String>,
+    pub return_ty: String,
+}
+
+/// Collects the structs reexported from public modules of `krate`.
+pub(crate) fn collect(db: &impl RsTargetIr, krate: &rs::BindingsCrate) -> Bindings {
+    let mut structs = vec![];
+    visit_module(db, krate.root, &mut vec![], &mut structs);
+    Bindings::new(structs, codegen::shim_symbols(db, krate))
+}
+
+fn visit_module(
+    db: &impl RsTargetIr,
+    id: rs::ModuleId,
+    path: &mut Vec<String>,
+    structs: &mut Vec<StructInfo>,
+) {
+    for item in id.lookup(db).children {
+        match item {
+            rs::ItemKind::Module(id) => {
+                let module = id.lookup(db);
+                if module.vis.is_public() {
+                    path.push(module.name.as_str(db).to_string());
+                    visit_module(db, id, path, structs);
+                    path.pop();
+                }
+            }
+            rs::ItemKind::Reexport(_, name, target) => {
+                if let rs::ItemKind::Struct(id) = *target {
+                    let st = id.lookup(db);
+                    if st.vis.is_public() {
+                        let name = name.as_str(db);
+                        let segments = path.iter().map(String::as_str).chain(Some(&*name));
+                        structs.push(struct_info(
+                            db,
+                            segments.collect::<Vec<_>>().join("::"),
+                            &st,
+                        ));
+                    }
+                }
+            }
+            // Structs are only exported through the reexports above, and
+            // opaque types are never exported. Aliases have no layout of
+            // their own to describe.
+            rs::ItemKind::Struct(_) | rs::ItemKind::Opaque(_) | rs::ItemKind::Alias(_) => {}
+        }
+    }
+}
+
+fn struct_info(db: &impl RsTargetIr, path: String, st: &rs::Struct) -> StructInfo {
+    let fields = st
+        .fields
+        .iter()
+        .zip(&st.layout.field_offsets)
+        .map(|(field, &offset)| FieldInfo {
+            name: field.name.as_str(db).to_string(),
+            ty: codegen::ty_to_string(db, field.ty(db)),
+            offset,
+        })
+        .collect();
+    let methods = st
+        .methods
+        .iter()
+        .map(|meth| MethodInfo {
+            name: meth.name.as_str(db).to_string(),
+            param_tys: meth
+                .param_tys(db)
+                .map(|ty| codegen::ty_to_string(db, codegen::param_ty(db, ty)))
+                .collect(),
+            return_ty: codegen::ty_to_string(db, codegen::return_ty(meth.return_ty(db))),
+        })
+        .collect();
+    StructInfo {
+        path,
+        size: st.layout.size.get(),
+        align: st.layout.align.get(),
+        fields,
+        methods,
+    }
+}
@@ -18,11 +18,13 @@
 
 use crate::diagnostics::{err, ok, Diagnostic, Diagnostics, Outcome, Span};
 use crate::libclang::CcSourceIr;
-use std::collections::{HashMap, VecDeque};
-use std::num::NonZeroU16;
+use crate::util::DisplayFn;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::num::NonZeroU32;
 use std::{
     fmt::{self, Debug, Display},
     iter::FromIterator,
+    ops::Range,
     sync::Arc,
 };
 
@@ -30,6 +32,9 @@ use std::{
 pub trait DefIr {
     #[salsa::interned]
     fn intern_def(&self, def: DefKind) -> Def;
+
+    #[salsa::interned]
+    fn intern_ident(&self, name: Arc<str>) -> common::Ident;
 }
 
 /// A top-level defintion of some kind.
@@ -54,6 +59,21 @@ impl From<cc::StructId> for DefKind {
         DefKind::CcDef(item.into())
     }
 }
+impl DefKind {
+    /// The unqualified name of the item.
+    pub fn name(&self, db: &impl CcSourceIr) -> cc::Ident {
+        match self {
+            DefKind::CcDef(item) => item.name(db),
+        }
+    }
+
+    /// Where the item was defined.
+    pub fn span(&self, db: &impl CcSourceIr) -> Span {
+        match self {
+            DefKind::CcDef(item) => item.span(db),
+        }
+    }
+}
 
 intern_key!(Def);
 impl Def {
@@ -68,26 +88,118 @@ impl Def {
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct CcSourceImport {
     pub import: bindings::Import,
+    /// The path of the item relative to the `export` module of the bindings.
+    ///
+    /// Items in rust_export are exported under their own name; items
+    /// imported with cc_use are exported under the path they were imported
+    /// with.
+    pub export_path: bindings::Path,
     pub def: DefKind,
 }
 
+/// Whether an item in a bindings lib was imported explicitly.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ExportStatus {
+    /// The item was imported, with the path it was imported as.
+    Explicit(bindings::Path),
+    /// The item is only included because an imported item refers to it.
+    Transitive(Provenance),
+}
+
+/// How an item was reached from an import, for explaining why the bindings
+/// need it.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Provenance {
+    /// The path the import was exported under.
+    pub export_path: bindings::Path,
+    /// The C++ name of the imported item.
+    pub root: cc::Ident,
+    /// The members followed from the imported item, like `inner` or `get()`.
+    /// Empty for the imported item itself.
+    pub members: Vec<String>,
+}
+impl Provenance {
+    fn with_member(&self, member: String) -> Provenance {
+        let mut provenance = self.clone();
+        provenance.members.push(member);
+        provenance
+    }
+
+    /// Displays the member path, like `Outer::middle.inner`.
+    pub fn display<'a>(&'a self, db: &'a impl DefIr) -> impl Display + 'a {
+        DisplayFn(move |f: &mut fmt::Formatter<'_>| {
+            write!(f, "{}", self.root.as_str(db))?;
+            for (idx, member) in self.members.iter().enumerate() {
+                let sep = if idx == 0 { "::" } else { "." };
+                write!(f, "{}{}", sep, member)?;
+            }
+            Ok(())
+        })
+    }
+}
+
 /// The set of defs that are being imported from one C++ translation unit.
 #[derive(Debug, Eq, PartialEq)]
 pub struct CcSourceBindingsLib {
     pub items: Vec<CcSourceImport>,
 }
 impl CcSourceBindingsLib {
+    /// The structs that were explicitly imported, in the order they were
+    /// imported.
+    pub fn exported_structs(&self) -> impl Iterator<Item = cc::StructId> + '_ {
+        self.items.iter().filter_map(|imp| match imp.def {
+            DefKind::CcDef(cc::ItemKind::Struct(st)) => Some(st),
+            DefKind::CcDef(_) => None,
+        })
+    }
+
+    /// Every item reachable from the imports, with its export status.
+    ///
+    /// Imported items come first, in the order they were imported, followed by
+    /// the items they refer to in breadth-first order.
+    pub fn all_items(&self, db: &impl CcSourceIr) -> Vec<(DefKind, ExportStatus)> {
+        self.reachable_items(db)
+            .map(|(def, provenance)| {
+                let status = match self.items.iter().find(|imp| imp.def == def) {
+                    Some(imp) => ExportStatus::Explicit(imp.export_path.clone()),
+                    None => ExportStatus::Transitive(provenance),
+                };
+                (def, status)
+            })
+            .collect()
+    }
+
+    /// Every item reachable from the imports, with the shortest way it can be
+    /// reached.
     pub fn reachable_items<'db>(
         &self,
         db: &'db (impl DefIr + CcSourceIr),
-    ) -> impl Iterator<Item = DefKind> + 'db {
-        let queue = self.items.iter().map(|imp| imp.def.clone()).collect();
-        ReachableIter { db, queue }
+    ) -> impl Iterator<Item = (DefKind, Provenance)> + 'db {
+        let mut iter = ReachableIter {
+            db,
+            queue: VecDeque::new(),
+            seen: HashSet::new(),
+        };
+        for imp in &self.items {
+            let provenance = Provenance {
+                export_path: imp.export_path.clone(),
+                root: imp.def.name(db),
+                members: vec![],
+            };
+            iter.enqueue(&imp.def, provenance);
+        }
+        iter
     }
 
+    /// Lowers `libs` into the `export` and `bind` modules of a crate.
+    ///
+    /// If `parent` is given, the caller puts those modules in a module of
+    /// that name at the crate root instead of the root itself, and paths
+    /// between them go through it.
     pub fn to_rs_bindings(
         db: &(impl DefIr + cc::RsTargetIr + cc::CcModule),
         libs: &[Arc<CcSourceBindingsLib>],
+        parent: Option<rs::Ident>,
     ) -> Outcome<rs::BindingsCrate> {
         // In addition to lowering every C++ item in this lib, this function is
         // responsible for creating the structure of the RsBindingsCrate. In
@@ -162,7 +274,10 @@ impl CcSourceBindingsLib {
         let mut add_to_ns = |mut ns: cc::NamespaceId, id: rs::ItemKind| {
             namespaces.entry(ns).or_default().items.push(id);
             while let Some(parent) = ns.lookup(db).parent {
-                namespaces.entry(parent).or_default().namespaces.push(ns);
+                let siblings = &mut namespaces.entry(parent).or_default().namespaces;
+                if !siblings.contains(&ns) {
+                    siblings.push(ns);
+                }
                 ns = parent;
             }
             if let Some(root) = root_ns {
@@ -172,61 +287,308 @@ impl CcSourceBindingsLib {
             }
         };
 
-        // Lower each item and register its parent namespace.
+        // Lower each item and register its parent namespace. Errors about
+        // items that weren't imported are explained once everything is
+        // lowered, since the error about an item can come from lowering
+        // anything that refers to it.
         let mut errs = Diagnostics::new();
-        let mut lower_def = |def| match def {
+        let mut required = vec![];
+        let mut lower_def = |lib: &CcSourceBindingsLib, def, status: &ExportStatus| match def {
             DefKind::CcDef(cc::ItemKind::Struct(st)) => {
                 let (rs_id, err) = db.rs_struct_from_cc(st).split();
+                required.extend(required_by(db, lib, st, status, &err));
                 errs.append(err);
-                (rs::ItemKind::Struct(rs_id), Some(st.lookup(db).parent))
+                Some((rs::ItemKind::Struct(rs_id), st.lookup(db).parent))
             }
+            // Free functions can't be lowered to Rust yet, and aliases are
+            // lowered below.
+            DefKind::CcDef(_) => None,
         };
+
+        // The same struct can be defined in more than one module, e.g. when two
+        // headers include a common header. Only the first definition is kept,
+        // and later definitions with the same name that don't match it are
+        // reported as violations of the one definition rule.
+        let mut definitions =
+            HashMap::<(cc::NamespaceId, cc::Ident), (cc::StructId, rs::ItemKind)>::new();
+        let mut duplicates = HashMap::<cc::StructId, cc::StructId>::new();
+        let mut odr_errs = Diagnostics::new();
+        let mut unsupported = Diagnostics::new();
+        let mut opaques = HashSet::new();
+        let mut aliases = HashSet::new();
+
+        // Distinct items can still end up with the same name in the generated
+        // Rust, e.g. a struct in one module and a namespace of the same name
+        // in another. Rust would reject the generated code, so report these
+        // here with the C++ source of each item instead.
+        #[derive(Copy, Clone, Eq, PartialEq)]
+        enum Generated {
+            Struct(cc::StructId),
+            Opaque(cc::OpaqueId),
+            Alias(cc::AliasId),
+            Namespace(cc::NamespaceId),
+        }
+        impl Generated {
+            fn describe(&self, db: &impl CcSourceIr) -> String {
+                match self {
+                    Generated::Struct(st) => {
+                        format!("struct `{}`", st.lookup(db).path(db).display(db))
+                    }
+                    Generated::Opaque(id) => {
+                        format!("opaque type `{}`", id.lookup(db).path(db).display(db))
+                    }
+                    Generated::Namespace(ns) => {
+                        format!("namespace `{}`", ns.lookup(db).path(db).display(db))
+                    }
+                    Generated::Alias(id) => {
+                        format!("type alias `{}`", id.lookup(db).path(db).display(db))
+                    }
+                }
+            }
+        }
+        let mut generated_names = HashMap::<(cc::NamespaceId, cc::Ident), (Generated, Span)>::new();
+        let mut collisions = HashSet::new();
+        let mut collision_errs = Diagnostics::new();
+        let mut check_collision = |key: (cc::NamespaceId, cc::Ident),
+                                   item: Generated,
+                                   span: &Span| {
+            let (prev, prev_span) = generated_names
+                .entry(key)
+                .or_insert_with(|| (item, span.clone()));
+            if *prev != item && collisions.insert(key) {
+                collision_errs.add(
+                    Diagnostic::error(
+                        format!(
+                            "multiple items named `{}` in the generated bindings",
+                            key.1.display(db)
+                        ),
+                        span.label(format!("{} generated here", item.describe(db))),
+                    )
+                    .with_label(prev_span.label(format!("conflicts with {}", prev.describe(db)))),
+                );
+            }
+        };
+
         for lib in libs {
-            for def in lib.reachable_items(db) {
-                let (item, parent_ns) = lower_def(def);
-                if let Some(parent) = parent_ns {
-                    add_to_ns(parent, item);
+            for (def, status) in lib.all_items(db) {
+                let st = match def {
+                    DefKind::CcDef(cc::ItemKind::Struct(st)) => st,
+                    // Functions are only reachable as imports, and are
+                    // reported where they're reexported below.
+                    DefKind::CcDef(cc::ItemKind::Function(_)) => continue,
+                    // An alias is the same in every module that exports it,
+                    // so it's only generated once.
+                    DefKind::CcDef(cc::ItemKind::Alias(id)) => {
+                        if aliases.insert(id) {
+                            let alias = id.lookup(db);
+                            let key = (alias.parent, alias.name);
+                            check_collision(key, Generated::Alias(id), &alias.span);
+                            add_to_ns(alias.parent, rs::ItemKind::Alias(id));
+                        }
+                        continue;
+                    }
+                };
+                let (item, parent) = match lower_def(lib, def, &status) {
+                    Some(lowered) => lowered,
+                    None => continue,
+                };
+                let key = (parent, st.lookup(db).name);
+                match definitions.get(&key) {
+                    None => {
+                        definitions.insert(key, (st, item.clone()));
+                    }
+                    Some((first, first_item)) if *first != st => {
+                        if !same_definition(db, first_item, &item) {
+                            odr_errs.add(
+                                Diagnostic::error(
+                                    format!(
+                                        "conflicting definitions of `{}`",
+                                        st.lookup(db).path(db).display(db)
+                                    ),
+                                    st.lookup(db).span.label("this definition"),
+                                )
+                                .with_label(first.lookup(db).span.label("differs from this one")),
+                            );
+                        }
+                        duplicates.insert(st, *first);
+                        continue;
+                    }
+                    Some(_) => (),
+                }
+                let st_info = st.lookup(db);
+                let rs_name = match &item {
+                    rs::ItemKind::Struct(id) => id.lookup(db).name,
+                    _ => st_info.name,
+                };
+                check_collision((parent, rs_name), Generated::Struct(st), &st_info.span);
+                let mut ns = parent;
+                while let Some(ns_parent) = ns.lookup(db).parent {
+                    let key = (ns_parent, ns.lookup(db).name);
+                    check_collision(key, Generated::Namespace(ns), &st_info.span);
+                    ns = ns_parent;
+                }
+                add_to_ns(parent, item);
+
+                // Generate a marker type for each opaque type the first time
+                // a struct refers to it.
+                for id in referenced_opaques(db, &st_info) {
+                    if !opaques.insert(id) {
+                        continue;
+                    }
+                    let opaque = id.lookup(db);
+                    check_collision(
+                        (opaque.parent, opaque.name),
+                        Generated::Opaque(id),
+                        &st_info.span,
+                    );
+                    let mut ns = opaque.parent;
+                    while let Some(ns_parent) = ns.lookup(db).parent {
+                        let key = (ns_parent, ns.lookup(db).name);
+                        check_collision(key, Generated::Namespace(ns), &st_info.span);
+                        ns = ns_parent;
+                    }
+                    add_to_ns(opaque.parent, rs::ItemKind::Opaque(id));
                 }
             }
         }
+        let mut errs = errs.with_notes_each(|diag| {
+            required
+                .iter()
+                .find(|(source, _)| source.contains(diag))
+                .map(|(_, note)| note.clone())
+                .into_iter()
+                .collect()
+        });
+        let canonical = |def: &DefKind| match def {
+            DefKind::CcDef(cc::ItemKind::Struct(st)) => {
+                DefKind::CcDef(cc::ItemKind::Struct(*duplicates.get(st).unwrap_or(st)))
+            }
+            DefKind::CcDef(_) => *def,
+        };
 
         // If there was nothing to lower, just exit now.
         let bind_ns = match root_ns {
             Some(ns) => ns,
             None => {
                 let empty = db.intern_module(rs::Module {
-                    name: common::Ident::from(""),
+                    name: common::Ident::new_mangled(db, ""),
                     vis: rs::Visibility::Public,
+                    feature: None,
                     children: Default::default(),
                 });
+                errs.append(unsupported);
                 return Outcome::from_parts(rs::BindingsCrate { root: empty }, errs);
             }
         };
 
-        // Compute the reexport path from the export module to each import and create the module.
-        let reexports = libs
-            .iter()
-            .flat_map(|lib| lib.items.iter())
-            .map(|import| {
-                let path: rs::Path = [
-                    rs::Ident::from("crate").into(), // TODO represent this properly
-                    rs::Ident::from("bind").into(),
-                ]
-                .iter()
-                .chain(import.import.path.iter())
-                .cloned()
-                .map(Into::into)
-                .collect();
-                let (item, _) = lower_def(import.def);
-                (path, item)
-            })
-            .map(|(path, def)| rs::ItemKind::Reexport(db.intern_path(path), Box::new(def)))
-            .collect();
-        let export_mod = db.intern_module(rs::Module {
-            name: rs::Ident::from("export"),
-            vis: rs::Visibility::Public,
-            children: reexports,
-        });
+        // Compute the reexport path from the bind module to each import, and lay out the export
+        // module so that it mirrors the path each item was exported under. Report imports from
+        // different modules that are exported under the same path.
+        #[derive(Default)]
+        struct ExportTree {
+            items: Vec<rs::ItemKind>,
+            modules: Vec<(rs::Ident, ExportTree)>,
+        }
+        impl ExportTree {
+            fn insert(&mut self, path: &[bindings::PathComponent], item: rs::ItemKind) {
+                match path {
+                    [] | [_] => self.items.push(item),
+                    [first, rest @ ..] => {
+                        let idx = match self.modules.iter().position(|(n, _)| *n == first.name) {
+                            Some(idx) => idx,
+                            None => {
+                                self.modules.push((first.name, Default::default()));
+                                self.modules.len() - 1
+                            }
+                        };
+                        self.modules[idx].1.insert(rest, item);
+                    }
+                }
+            }
+            fn lower(self, db: &impl cc::RsTargetIr, name: rs::Ident) -> rs::ModuleId {
+                let children = self
+                    .items
+                    .into_iter()
+                    .chain(
+                        self.modules
+                            .into_iter()
+                            .map(|(name, tree)| rs::ItemKind::Module(tree.lower(db, name))),
+                    )
+                    .collect();
+                db.intern_module(rs::Module {
+                    name,
+                    vis: rs::Visibility::Public,
+                    feature: None,
+                    children,
+                })
+            }
+        }
+        let mut export_paths = HashMap::new();
+        let mut conflicts = Diagnostics::new();
+        let mut exports = ExportTree::default();
+        for import in libs.iter().flat_map(|lib| lib.items.iter()) {
+            match export_paths.get(&import.export_path) {
+                None => {
+                    export_paths.insert(import.export_path.clone(), import);
+                }
+                Some(prev) if canonical(&prev.def) == canonical(&import.def) => continue,
+                Some(prev) => {
+                    conflicts.add(
+                        Diagnostic::error(
+                            format!(
+                                "conflicting exports named `{}`",
+                                import.export_path.display(db)
+                            ),
+                            import.import.span.label("this export conflicts"),
+                        )
+                        .with_label(prev.import.span.label("with this export")),
+                    );
+                    continue;
+                }
+            }
+            // Every import was lowered above, so its errors are already
+            // reported.
+            let item = match import.def {
+                DefKind::CcDef(cc::ItemKind::Struct(st)) => {
+                    rs::ItemKind::Struct(db.rs_struct_from_cc(st).skip_errs())
+                }
+                DefKind::CcDef(cc::ItemKind::Alias(id)) => rs::ItemKind::Alias(id),
+                DefKind::CcDef(cc::ItemKind::Function(_)) => {
+                    let span = &import.import.span;
+                    unsupported.add(
+                        Diagnostic::error(
+                            format!(
+                                "cannot export function `{}`",
+                                import.def.name(db).as_str(db)
+                            ),
+                            span.label("function reexports are not supported"),
+                        )
+                        .with_label(import.def.span(db).label("function declared here")),
+                    );
+                    continue;
+                }
+            };
+            // TODO represent `crate` properly
+            let mut components: Vec<rs::PathComponent> =
+                vec![rs::Ident::new_mangled(db, "crate").into()];
+            components.extend(parent.map(rs::PathComponent::from));
+            components.push(rs::Ident::new_mangled(db, "bind").into());
+            components.extend(import.import.path.iter().cloned().map(Into::into));
+            // The struct may have been renamed in Rust.
+            if let (rs::ItemKind::Struct(id), Some(last)) = (&item, components.last_mut()) {
+                last.name = id.lookup(db).name;
+            }
+            let path: rs::Path = components.into_iter().collect();
+            let name = *import.export_path.last();
+            let reexport = rs::ItemKind::Reexport(db.intern_path(path), name, Box::new(item));
+            let export_path = import.export_path.iter().cloned().collect::<Vec<_>>();
+            exports.insert(&export_path, reexport);
+        }
+        errs.append(unsupported);
+        errs.append(odr_errs);
+        errs.append(collision_errs);
+        errs.append(conflicts);
+        let export_mod = exports.lower(db, rs::Ident::new_mangled(db, "export"));
 
         // Recursively lower each namespace with its list of children.
         fn lower_ns(
@@ -242,11 +604,12 @@ impl CcSourceBindingsLib {
                 let info = &namespaces[&ns];
                 let id = db.intern_module(rs::Module {
                     name: if is_bind_root {
-                        rs::Ident::from("bind")
+                        rs::Ident::new_mangled(db, "bind")
                     } else {
                         ns.lookup(db).name
                     },
                     vis: rs::Visibility::Crate,
+                    feature: None,
                     children: info
                         .items
                         .iter()
@@ -264,8 +627,9 @@ impl CcSourceBindingsLib {
         let bind_mod = lower_ns(db, bind_ns, &namespaces, &mut lowered, true);
 
         let root = db.intern_module(rs::Module {
-            name: rs::Ident::from(""),
+            name: rs::Ident::new_mangled(db, ""),
             vis: rs::Visibility::Public,
+            feature: None,
             children: vec![
                 rs::ItemKind::Module(export_mod),
                 rs::ItemKind::Module(bind_mod),
@@ -275,23 +639,260 @@ impl CcSourceBindingsLib {
     }
 }
 
+/// Returns whether two lowered definitions of a struct with the same name
+/// from different modules are the same.
+///
+/// Struct types of fields are compared by name, since each module has its own
+/// copy of them.
+fn same_definition(db: &impl cc::RsTargetIr, a: &rs::ItemKind, b: &rs::ItemKind) -> bool {
+    let (a, b) = match (a, b) {
+        (rs::ItemKind::Struct(a), rs::ItemKind::Struct(b)) => (a.lookup(db), b.lookup(db)),
+        _ => return false,
+    };
+    fn same_ty(db: &impl cc::RsTargetIr, a: rs::Ty, b: rs::Ty) -> bool {
+        match (a, b) {
+            (rs::Ty::Struct(a), rs::Ty::Struct(b)) => {
+                let (a, b) = (a.lookup(db).cc_id.lookup(db), b.lookup(db).cc_id.lookup(db));
+                a.parent == b.parent && a.name == b.name
+            }
+            (
+                rs::Ty::Ptr {
+                    pointee: a,
+                    mutable: a_mut,
+                },
+                rs::Ty::Ptr {
+                    pointee: b,
+                    mutable: b_mut,
+                },
+            ) => a_mut == b_mut && same_ty(db, a.as_rs(db).skip_errs(), b.as_rs(db).skip_errs()),
+            (
+                rs::Ty::Array {
+                    elem: a,
+                    len: a_len,
+                },
+                rs::Ty::Array {
+                    elem: b,
+                    len: b_len,
+                },
+            ) => a_len == b_len && same_ty(db, a.as_rs(db).skip_errs(), b.as_rs(db).skip_errs()),
+            (
+                rs::Ty::Ref {
+                    pointee: a,
+                    mutable: a_mut,
+                },
+                rs::Ty::Ref {
+                    pointee: b,
+                    mutable: b_mut,
+                },
+            ) => a_mut == b_mut && same_ty(db, a.as_rs(db).skip_errs(), b.as_rs(db).skip_errs()),
+            (a, b) => a == b,
+        }
+    }
+    a.layout == b.layout
+        && a.fields.len() == b.fields.len()
+        && a.fields
+            .iter()
+            .zip(&b.fields)
+            .all(|(x, y)| x.name == y.name && same_ty(db, x.ty(db), y.ty(db)))
+}
+
+/// Returns the opaque types a struct refers to from its fields and methods,
+/// through any number of pointers and arrays.
+fn referenced_opaques(db: &impl CcSourceIr, st: &cc::Struct) -> Vec<cc::OpaqueId> {
+    let method_tys = st
+        .methods
+        .iter()
+        .flat_map(|meth| meth.param_tys.iter().chain(Some(&meth.return_ty)));
+    st.fields
+        .iter()
+        .map(|f| &f.ty)
+        .chain(method_tys)
+        .filter_map(|ty| match ty.as_cc(db).skip_errs().innermost(db) {
+            cc::Ty::Opaque(id) => Some(id),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the errors from lowering `st` that need explaining, each with a
+/// note on why the bindings need the type it's about.
+///
+/// An error from lowering the type of one of `st`'s fields is about that
+/// type, which is needed for the field unless it was imported itself. Errors
+/// that don't come from lowering a field's type are about `st`, which only
+/// needs explaining if it wasn't imported.
+fn required_by(
+    db: &(impl DefIr + cc::RsTargetIr),
+    lib: &CcSourceBindingsLib,
+    st: cc::StructId,
+    status: &ExportStatus,
+    errs: &Diagnostics,
+) -> Vec<(Diagnostics, String)> {
+    if errs.is_empty() {
+        return vec![];
+    }
+    let cc_st = st.lookup(db);
+    let provenance = match status {
+        ExportStatus::Explicit(path) => Provenance {
+            export_path: path.clone(),
+            root: cc_st.name,
+            members: vec![],
+        },
+        ExportStatus::Transitive(provenance) => provenance.clone(),
+    };
+    let note = |provenance: &Provenance| {
+        format!(
+            "required because `{}` (exported as `{}`) has this type",
+            provenance.display(db),
+            provenance.export_path.display(db)
+        )
+    };
+    let mut required = vec![];
+    let mut from_fields = vec![];
+    for field in &cc_st.fields {
+        let (cc_ty, cc_errs) = field.ty.as_cc(db).split();
+        let imported = match cc_ty.innermost(db) {
+            cc::Ty::Struct(id) => lib.exported_structs().any(|exp| exp == id),
+            _ => false,
+        };
+        if !cc_errs.is_empty() && !imported {
+            let field_provenance = provenance.with_member(field.name.as_str(db).to_string());
+            required.push((cc_errs, note(&field_provenance)));
+        }
+        from_fields.push(field.ty.as_rs(db).split().1);
+    }
+    if !provenance.members.is_empty() {
+        let own = errs.filter(|diag| !from_fields.iter().any(|errs| errs.contains(diag)));
+        if !own.is_empty() {
+            required.push((own, note(&provenance)));
+        }
+    }
+    required
+}
+
+/// Lowers every reachable struct to Rust on a pool of `jobs` threads.
+///
+/// This only fills in the database's memoized results. The bindings crate is
+/// still assembled serially by [`rs::RsTargetBindings::rs_bindings`], so its
+/// contents and diagnostics don't depend on the order work finishes in.
+///
+/// Only the Rust side of lowering runs on the pool. The libclang AST can only
+/// be accessed from the current thread, so every struct is lowered from C++
+/// here first, along with every type its fields and methods refer to, one at
+/// a time. The worker threads only read those memoized results, which is
+/// checked on every AST access.
+pub(crate) fn lower_in_parallel(
+    db: &crate::Database,
+    jobs: usize,
+) -> Result<(), rayon::ThreadPoolBuildError> {
+    use cc::{CcModule, RsTargetIr};
+    use rayon::prelude::*;
+    use salsa::ParallelDatabase;
+
+    let mut queue = vec![];
+    for mdl in db.cc_module_ids() {
+        // Errors are reported when the bindings are assembled.
+        let lib = db.cc_module(mdl).skip_errs();
+        queue.extend(lib.reachable_items(db).filter_map(|(def, _)| match def {
+            DefKind::CcDef(cc::ItemKind::Struct(st)) => Some(st),
+            DefKind::CcDef(_) => None,
+        }));
+    }
+    let mut structs = HashSet::new();
+    while let Some(st) = queue.pop() {
+        if !structs.insert(st) {
+            continue;
+        }
+        let cc_st = st.lookup(db);
+        let method_tys = cc_st
+            .methods
+            .iter()
+            .flat_map(|meth| meth.param_tys.iter().chain(Some(&meth.return_ty)));
+        for ty in cc_st.fields.iter().map(|f| &f.ty).chain(method_tys) {
+            if let cc::Ty::Struct(id) = ty.as_cc(db).skip_errs().innermost(db) {
+                queue.push(id);
+            }
+        }
+    }
+
+    // Snapshots can't be shared between threads, so give each chunk of work its own.
+    let structs = structs.into_iter().collect::<Vec<_>>();
+    let chunk_size = std::cmp::max(1, structs.len() / (jobs * 4));
+    let work = structs
+        .chunks(chunk_size)
+        .map(|chunk| (db.snapshot(), chunk))
+        .collect::<Vec<_>>();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .start_handler(|_| crate::libclang::forbid_ast_access())
+        .build()?;
+    pool.install(|| {
+        work.into_par_iter().for_each(|(db, chunk)| {
+            for st in chunk {
+                // Only the cached result matters; errors are reported when
+                // the bindings are assembled.
+                db.rs_struct_from_cc(*st).skip_errs();
+            }
+        })
+    });
+    Ok(())
+}
+
+/// Walks items breadth-first from a set of roots, yielding each item once
+/// along with how it was first reached.
 struct ReachableIter<'db, DB: CcSourceIr> {
     db: &'db DB,
-    queue: VecDeque<DefKind>,
+    queue: VecDeque<(DefKind, Provenance)>,
+    /// Every item that has been queued, so cycles terminate.
+    seen: HashSet<DefKind>,
+}
+impl<'db, DB: CcSourceIr> ReachableIter<'db, DB> {
+    fn enqueue(&mut self, item: &DefKind, provenance: Provenance) {
+        if self.seen.insert(*item) {
+            self.queue.push_back((*item, provenance));
+        }
+    }
 }
 impl<'db, DB: DefIr + CcSourceIr> Iterator for ReachableIter<'db, DB> {
-    type Item = DefKind;
+    type Item = (DefKind, Provenance);
     fn next<'a>(&'a mut self) -> Option<Self::Item> {
-        let item = self.queue.pop_front()?;
-        struct ReachableVisitor<'a>(&'a mut VecDeque<DefKind>);
-        impl<'a, DB: CcSourceIr + DefIr> Visitor<DB> for ReachableVisitor<'a> {
+        let (item, provenance) = self.queue.pop_front()?;
+        struct ReachableVisitor<'a, 'db, DB: CcSourceIr> {
+            iter: &'a mut ReachableIter<'db, DB>,
+            provenance: &'a Provenance,
+            /// The member of the current struct whose types are being visited.
+            member: Option<String>,
+        }
+        impl<'a, 'db, DB: CcSourceIr + DefIr> Visitor<DB> for ReachableVisitor<'a, 'db, DB> {
             fn visit_item(&mut self, _db: &DB, item: &DefKind) {
-                debug_assert!(!self.0.contains(item));
-                self.0.push_back(item.clone());
+                let provenance = match &self.member {
+                    Some(member) => self.provenance.with_member(member.clone()),
+                    None => self.provenance.clone(),
+                };
+                self.iter.enqueue(item, provenance);
+            }
+
+            fn visit_cc_struct(&mut self, db: &DB, id: cc::StructId) {
+                let st = id.lookup(db);
+                for field in &st.fields {
+                    self.member = Some(field.name.as_str(db).to_string());
+                    self.visit_cc_type_ref(db, field.ty.clone());
+                }
+                for method in &st.methods {
+                    self.member = Some(format!("{}()", method.name.as_str(db)));
+                    self.visit_cc_function(db, method);
+                }
+                self.member = None;
             }
         }
-        ReachableVisitor(&mut self.queue).super_visit_item(self.db, &item);
-        Some(item)
+        let db = self.db;
+        ReachableVisitor {
+            iter: self,
+            provenance: &provenance,
+            member: None,
+        }
+        .super_visit_item(db, &item);
+        Some((item, provenance))
     }
 }
 
@@ -321,6 +922,11 @@ trait Visitor<DB: DefIr + CcSourceIr> {
     fn super_visit_cc_item(&mut self, db: &DB, item: &cc::ItemKind) {
         match item {
             cc::ItemKind::Struct(id) => self.visit_cc_struct(db, *id),
+            cc::ItemKind::Function(id) => {
+                let func = id.lookup(db);
+                self.visit_cc_function(db, func.to_ref().skip_errs());
+            }
+            cc::ItemKind::Alias(_) => (),
         }
     }
 
@@ -335,13 +941,44 @@ trait Visitor<DB: DefIr + CcSourceIr> {
             parent,
             fields,
             methods,
-            align_attr,
+            attrs,
             layout,
+            opaque,
+            deprecated,
             span,
         } = st;
         for field in fields {
             self.visit_cc_type_ref(db, field.ty.clone())
         }
+        for method in methods {
+            self.visit_cc_function(db, method);
+        }
+    }
+
+    fn visit_cc_function(&mut self, db: &DB, func: &cc::Function) {
+        self.super_visit_cc_function(db, func);
+    }
+
+    fn super_visit_cc_function(&mut self, db: &DB, func: &cc::Function) {
+        #[allow(unused)]
+        let cc::Function {
+            name,
+            param_tys,
+            param_names,
+            return_ty,
+            is_method,
+            is_const,
+            accessor,
+            operator,
+            const_value,
+            inherited_from,
+            attrs,
+            span,
+        } = func;
+        for ty in param_tys {
+            self.visit_cc_type_ref(db, ty.clone());
+        }
+        self.visit_cc_type_ref(db, return_ty.clone());
     }
 
     fn visit_cc_type_ref(&mut self, db: &DB, ty_ref: cc::TypeRef) {
@@ -366,62 +1003,257 @@ trait Visitor<DB: DefIr + CcSourceIr> {
             | SChar | UChar | Size | SSize | PtrDiff => (),
             Bool => (),
             Struct(id) => self.visit_item(db, &DefKind::CcDef(cc::ItemKind::Struct(*id))),
+            Opaque(_) | Alias(_) => (),
+            Ptr { pointee, .. } | Ref { pointee, .. } => {
+                self.visit_cc_type_ref(db, pointee.clone())
+            }
+            Array { elem, .. } => self.visit_cc_type_ref(db, elem.clone()),
         }
     }
 }
 
+/// Implements the structural operations shared by every IR's `Path` type.
+///
+/// The path types are kept distinct so that a path can't accidentally cross
+/// from one IR to another, but they all support the same operations.
+macro_rules! impl_path {
+    ($path:ident, $component:ident, turbofish: $turbofish:expr) => {
+        #[allow(dead_code)]
+        impl $path {
+            pub fn iter(&self) -> impl Iterator<Item = &$component> {
+                self.0.iter()
+            }
+            pub fn len(&self) -> usize {
+                self.0.len()
+            }
+            pub fn is_empty(&self) -> bool {
+                self.0.is_empty()
+            }
+            /// Returns the path with the last component removed, or `None`
+            /// if the path is empty.
+            pub fn parent(&self) -> Option<Self> {
+                match self.0.split_last() {
+                    Some((_, parent)) => Some(Self(parent.to_vec())),
+                    None => None,
+                }
+            }
+            /// Returns the name of the last component. Panics if the path is
+            /// empty.
+            pub fn last(&self) -> &Ident {
+                &self.0.last().expect("last() called on an empty path").name
+            }
+            pub fn join(mut self, component: impl Into<$component>) -> Self {
+                self.0.push(component.into());
+                self
+            }
+            pub fn starts_with(&self, prefix: &Self) -> bool {
+                self.0.starts_with(&prefix.0)
+            }
+            /// Renders the path as Rust source, escaping any identifiers that
+            /// are Rust keywords.
+            pub fn to_rust_path(&self, db: &impl DefIr) -> String {
+                let mut out = String::new();
+                for (idx, comp) in self.0.iter().enumerate() {
+                    if idx > 0 {
+                        out += "::";
+                    }
+                    out += &comp.name.to_rust_ident(db);
+                    if !comp.args.is_empty() {
+                        let args: Vec<_> =
+                            comp.args.iter().map(|arg| arg.to_rust_path(db)).collect();
+                        out += &format!("::<{}>", args.join(", "));
+                    }
+                }
+                out
+            }
+        }
+        impl FromIterator<$component> for $path {
+            fn from_iter<I: IntoIterator<Item = $component>>(iter: I) -> Self {
+                Self(iter.into_iter().collect())
+            }
+        }
+        impl common::PrintPath for $path {
+            fn print_path<DB: DefIr>(&self, db: &DB, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                $component::print(db, &self.0, f, $turbofish)
+            }
+        }
+        #[allow(dead_code)]
+        impl $path {
+            /// Displays the path, looking up its identifiers in `db`.
+            pub fn display<'a>(&'a self, db: &'a impl DefIr) -> impl Display + 'a {
+                use common::PrintPath;
+                crate::util::DisplayFn(move |f: &mut fmt::Formatter<'_>| self.print_path(db, f))
+            }
+            /// Parses a `::`-separated path of plain identifiers.
+            ///
+            /// `""` is the empty path. Any other empty segment, as in `"::"` or
+            /// `"std::::vector"`, is an error.
+            pub fn parse(db: &impl DefIr, path: &str) -> Result<Self, common::InvalidIdent> {
+                if path.is_empty() {
+                    return Ok(Self(vec![]));
+                }
+                path.split("::")
+                    .map(|segment| Ident::new(db, segment).map(Into::into))
+                    .collect()
+            }
+        }
+    };
+}
+
 /// Types and utilities used from both the Rust and C++ IRs.
 mod common {
     use super::*;
     use crate::libclang;
+    use crate::util::DisplayFn;
 
     /// A C++ unqualified identifier.
     ///
     /// Examples: `std`, `vector`, or `MyClass`.
-    #[derive(Clone, Debug, Hash, Eq, PartialEq)]
-    pub struct Ident {
-        s: String,
+    ///
+    /// Identifiers are interned in the database, so they are cheap to copy,
+    /// compare, and hash, and they are freed along with it. Use
+    /// [`Ident::as_str`] to get the name back.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct Ident(salsa::InternId);
+    impl salsa::InternKey for Ident {
+        fn from_intern_id(v: salsa::InternId) -> Self {
+            Ident(v)
+        }
+        fn as_intern_id(&self) -> salsa::InternId {
+            self.0
+        }
     }
     impl Ident {
-        #[allow(dead_code)]
-        pub fn as_str(&self) -> &str {
-            &self.s
+        /// Returns the identifier as it was written.
+        pub fn as_str(&self, db: &impl DefIr) -> Arc<str> {
+            db.lookup_intern_ident(*self)
+        }
+
+        /// Displays the identifier, like [`Ident::as_str`].
+        pub fn display<'a>(&'a self, db: &'a impl DefIr) -> impl Display + 'a {
+            DisplayFn(move |f: &mut fmt::Formatter<'_>| write!(f, "{}", self.as_str(db)))
         }
     }
-    impl From<&str> for Ident {
-        /// Creates an identifier. Can panic if the identifier is invalid.
-        fn from(id: &str) -> Ident {
+    impl Ident {
+        /// Creates an identifier from a name read from the input, which must
+        /// be an identifier in both C++ and Rust: an ASCII letter or `_`
+        /// followed by letters, digits and `_`.
+        ///
+        /// Names that come from the AST aren't always identifiers. The
+        /// display name of a template specialization is like `vector<int>`,
+        /// and an operator is named like `operator==`.
+        pub fn new(db: &impl DefIr, id: &str) -> Result<Ident, InvalidIdent> {
+            let mut chars = id.chars();
+            let reason = match chars.next() {
+                None => Some("identifiers can't be empty".to_string()),
+                Some(c) if !c.is_ascii_alphabetic() && c != '_' => {
+                    Some(format!("identifiers can't start with `{}`", c))
+                }
+                _ if id == "_" => Some("`_` is not an identifier in Rust".to_string()),
+                _ => chars
+                    .find(|&c| !c.is_ascii_alphanumeric() && c != '_')
+                    .map(|c| format!("identifiers can't contain `{}`", c)),
+            };
+            match reason {
+                None => Ok(db.intern_ident(id.into())),
+                Some(reason) => Err(InvalidIdent {
+                    name: id.to_string(),
+                    reason,
+                }),
+            }
+        }
+
+        /// Creates an identifier for a name made up by the generator, like
+        /// the name of a setter or the root module, without checking it.
+        ///
+        /// Panics if the name contains `::` or angle brackets, which would
+        /// break every path it ends up in.
+        pub fn new_mangled(db: &impl DefIr, id: &str) -> Ident {
             assert!(
                 !id.contains("::") && !id.contains('<') && !id.contains('>'),
                 "invalid identifier `{}`",
                 id
             );
-            Ident { s: id.to_string() }
+            db.intern_ident(id.into())
         }
     }
-    impl From<String> for Ident {
-        fn from(id: String) -> Ident {
-            From::from(id.as_str())
-        }
+
+    /// A name that can't be used as an identifier. See [`Ident::new`].
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub struct InvalidIdent {
+        pub name: String,
+        /// Why the name isn't an identifier, written as a note.
+        pub reason: String,
     }
-    impl fmt::Display for Ident {
+    impl fmt::Display for InvalidIdent {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "{}", self.s)
+            write!(f, "`{}` is not a valid identifier", self.name)
+        }
+    }
+    impl InvalidIdent {
+        /// Reports the name as the name of `what` at `span`.
+        pub fn to_diagnostic(&self, span: &Span, what: &str) -> Diagnostic {
+            Diagnostic::error(
+                self.to_string(),
+                span.label(format!("the name of this {}", what)),
+            )
+            .with_note(self.reason.clone())
         }
     }
+    impl Ident {
+        /// Returns the identifier as it must be written in Rust source, using
+        /// a raw identifier if it collides with a keyword.
+        pub fn to_rust_ident(self, db: &impl DefIr) -> String {
+            // These keywords can't be used as raw identifiers; they only ever
+            // appear in paths with their keyword meaning.
+            const PATH_KEYWORDS: &[&str] = &["crate", "self", "Self", "super"];
+            const KEYWORDS: &[&str] = &[
+                "abstract", "as", "async", "await", "become", "box", "break", "const", "continue",
+                "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl",
+                "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv",
+                "pub", "ref", "return", "static", "struct", "trait", "true", "try", "type",
+                "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+            ];
+            let s = self.as_str(db);
+            if KEYWORDS.contains(&&*s) && !PATH_KEYWORDS.contains(&&*s) {
+                format!("r#{}", s)
+            } else {
+                s.to_string()
+            }
+        }
+    }
+
+    /// A `[[deprecated]]` attribute on a struct, field or function, which is
+    /// carried over to its binding as `#[deprecated]`.
+    #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct Deprecation {
+        /// The message given with the attribute, with escapes resolved.
+        pub note: Option<String>,
+    }
 
     /// A component in a path, with possible generic arguments.
     ///
     /// In the path `std::vector<int>`, the components are `std` and
     /// `vector<int>`.
-    #[derive(Clone, Hash, Eq, PartialEq)]
+    #[derive(Clone, Debug, Hash, Eq, PartialEq)]
     pub struct PathComponent<P> {
         pub name: Ident,
         pub args: Vec<P>,
     }
-    impl<P: Display> PathComponent<P> {
-        fn print_component(&self, f: &mut fmt::Formatter<'_>, turbofish: bool) -> fmt::Result {
-            write!(f, "{}", self.name)?;
+
+    /// Prints a path, which needs the database to look up its identifiers.
+    pub trait PrintPath {
+        fn print_path<DB: DefIr>(&self, db: &DB, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+    }
+
+    impl<P: PrintPath> PathComponent<P> {
+        fn print_component<DB: DefIr>(
+            &self,
+            db: &DB,
+            f: &mut fmt::Formatter<'_>,
+            turbofish: bool,
+        ) -> fmt::Result {
+            write!(f, "{}", self.name.as_str(db))?;
             if !self.args.is_empty() {
                 if turbofish {
                     write!(f, "::<")?;
@@ -430,7 +1262,7 @@ mod common {
                 }
                 let mut args = self.args.iter().peekable();
                 while let Some(path) = args.next() {
-                    write!(f, "{}", path)?;
+                    path.print_path(db, f)?;
                     if args.peek().is_some() {
                         write!(f, ", ")?;
                     }
@@ -440,14 +1272,15 @@ mod common {
             Ok(())
         }
 
-        pub(super) fn print(
+        pub(super) fn print<DB: DefIr>(
+            db: &DB,
             components: &[Self],
             f: &mut fmt::Formatter,
             turbofish: bool,
         ) -> fmt::Result {
             let mut components = components.iter().peekable();
             while let Some(comp) = components.next() {
-                comp.print_component(f, turbofish)?;
+                comp.print_component(db, f, turbofish)?;
                 if components.peek().is_some() {
                     write!(f, "::")?;
                 }
@@ -460,49 +1293,68 @@ mod common {
             Self { name, args: vec![] }
         }
     }
-    impl<P: Display> Debug for PathComponent<P> {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            self.print_component(f, true)
-        }
-    }
 
-    pub type Offset = u16;
+    pub type Offset = u64;
 
-    pub(super) fn align_to(off: Offset, align: Align) -> Offset {
-        let align = align.get();
-        ((off + (align - 1)) / align) * align
+    /// Rounds `off` up to the next multiple of `align`, or returns `None` if
+    /// the result is not representable.
+    pub(super) fn align_to(off: Offset, align: Align) -> Option<Offset> {
+        let mask = Offset::from(align.get()) - 1;
+        off.checked_add(mask).map(|off| off & !mask)
     }
 
+    /// An alignment in bytes. Always a power of two.
     #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
-    pub struct Align(NonZeroU16);
+    pub struct Align(NonZeroU32);
 
     impl Align {
-        pub fn new(align: u16) -> Align {
-            Align(NonZeroU16::new(align).expect("alignment must be nonzero"))
+        /// Creates an alignment. Panics if `align` is not a power of two.
+        pub fn new(align: u32) -> Align {
+            Self::try_new(align)
+                .unwrap_or_else(|| panic!("alignment must be a power of two, got {}", align))
+        }
+
+        /// Creates an alignment, or returns `None` if `align` is not a power
+        /// of two.
+        pub fn try_new(align: u32) -> Option<Align> {
+            if align.is_power_of_two() {
+                NonZeroU32::new(align).map(Align)
+            } else {
+                None
+            }
         }
 
-        fn get(&self) -> u16 {
+        pub fn get(&self) -> u32 {
             self.0.get()
         }
     }
 
+    impl Default for Align {
+        fn default() -> Self {
+            Align::new(1)
+        }
+    }
+
     impl fmt::Display for Align {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             write!(f, "{}", self.0)
         }
     }
 
-    // TODO u16 is probably not big enough for all cases
-    #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
-    pub struct Size(pub(super) u16);
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+    pub struct Size(pub(super) u64);
 
     impl Size {
-        pub fn new(size: u16) -> Size {
+        pub fn new(size: u64) -> Size {
             Size(size)
         }
+
+        pub fn get(&self) -> u64 {
+            self.0
+        }
     }
 
-    #[derive(Clone, Debug, Hash, Eq, PartialEq)]
+    #[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
     pub struct StructLayout {
         pub field_offsets: Vec<Offset>,
         pub size: Size,
@@ -510,13 +1362,43 @@ mod common {
     }
 
     #[derive(Clone, Debug, Hash, Eq, PartialEq)]
-    pub struct TypeRef(libclang::ModuleId, libclang::TypeId);
+    pub struct TypeRef(TypeRefKind);
+    #[derive(Clone, Debug, Hash, Eq, PartialEq)]
+    enum TypeRefKind {
+        Ast(libclang::ModuleId, libclang::TypeId),
+        /// A type that isn't in the AST, like padding standing in for a
+        /// skipped field.
+        Synthetic(Box<cc::Ty>),
+    }
     impl TypeRef {
         pub(crate) fn new(mdl: libclang::ModuleId, id: libclang::TypeId) -> Self {
-            TypeRef(mdl, id)
+            TypeRef(TypeRefKind::Ast(mdl, id))
+        }
+        /// Returns an array of unsigned integers with the given size and
+        /// alignment, or `None` if no integer type has the alignment.
+        pub(crate) fn padding(size: u64, align: u64) -> Option<Self> {
+            let elem = match align {
+                1 => cc::Ty::UChar,
+                2 => cc::Ty::UShort,
+                4 => cc::Ty::UInt,
+                8 => cc::Ty::ULongLong,
+                _ => return None,
+            };
+            let array = cc::Ty::Array {
+                elem: TypeRef(TypeRefKind::Synthetic(Box::new(elem))),
+                len: size / align,
+            };
+            Some(TypeRef(TypeRefKind::Synthetic(Box::new(array))))
+        }
+        /// Returns `void`, for the return type of generated functions.
+        pub(crate) fn void() -> Self {
+            TypeRef(TypeRefKind::Synthetic(Box::new(cc::Ty::Void)))
         }
         pub fn as_cc(&self, db: &impl CcSourceIr) -> Outcome<cc::Ty> {
-            db.type_of(self.0, self.1)
+            match &self.0 {
+                TypeRefKind::Ast(mdl, id) => db.type_of(*mdl, *id),
+                TypeRefKind::Synthetic(ty) => ok((**ty).clone()),
+            }
         }
         pub fn as_rs(&self, db: &impl cc::RsTargetIr) -> Outcome<rs::Ty> {
             db.rs_type_of(self.clone())
@@ -535,31 +1417,12 @@ pub mod bindings {
     /// A path in a cc_use macro.
     #[derive(Clone, Debug, Hash, Eq, PartialEq)]
     pub struct Path(pub(super) Vec<PathComponent>);
-    impl Path {
-        pub fn iter(&self) -> impl Iterator<Item = &PathComponent> {
-            self.0.iter()
-        }
-        #[allow(dead_code)]
-        pub fn join(mut self, component: PathComponent) -> Self {
-            self.0.push(component);
-            self
-        }
-    }
-    impl FromIterator<PathComponent> for Path {
-        fn from_iter<I: IntoIterator<Item = PathComponent>>(iter: I) -> Self {
-            Self(iter.into_iter().collect())
-        }
-    }
+    impl_path!(Path, PathComponent, turbofish: true);
     impl From<Vec<PathComponent>> for Path {
         fn from(inner: Vec<PathComponent>) -> Self {
             Self(inner)
         }
     }
-    impl fmt::Display for Path {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            PathComponent::print(&self.0, f, true)
-        }
-    }
 
     /// A C++ header file.
     #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -574,16 +1437,38 @@ pub mod bindings {
     pub struct Import {
         pub mdl: ModuleId,
         pub path: Path,
+        /// The name given with `as`, if the item was renamed.
+        pub alias: Option<Ident>,
         pub span: Span,
     }
+    impl Import {
+        /// The path the item is exported under, which is `path` with the last
+        /// component replaced by the alias, if any.
+        pub fn export_path(&self) -> Path {
+            match &self.alias {
+                Some(alias) => self
+                    .path
+                    .parent()
+                    .unwrap_or_else(|| Path(vec![]))
+                    .join(*alias),
+                None => self.path.clone(),
+            }
+        }
+    }
 }
 
 /// C++ intermediate representation.
 pub mod cc {
     use super::*;
-    use crate::libclang::CcSourceIr;
+    use crate::{
+        libclang::CcSourceIr,
+        naming::{self, NameKind},
+        timings::Phase,
+    };
 
-    pub use common::{Align, Ident, Offset, Size, StructLayout, TypeRef};
+    pub use common::{
+        Align, Deprecation, Ident, InvalidIdent, Offset, Size, StructLayout, TypeRef,
+    };
 
     mod bindings {
         use super::*;
@@ -630,7 +1515,6 @@ pub mod cc {
     pub trait RsTargetIr: CcSourceIr + CcModule {
         fn rs_struct_from_cc(&self, id: cc::StructId) -> Outcome<rs::StructId>;
 
-        #[salsa::dependencies]
         fn rs_type_of(&self, ty: TypeRef) -> Outcome<rs::Ty>;
 
         #[salsa::interned]
@@ -639,7 +1523,7 @@ pub mod cc {
         #[salsa::interned]
         fn intern_module(&self, st: rs::Module) -> rs::ModuleId;
         #[salsa::interned]
-        fn intern_struct(&self, st: rs::Struct) -> rs::StructId;
+        fn intern_struct(&self, st: Arc<rs::Struct>) -> rs::StructId;
     }
 
     fn rs_type_of(db: &(impl CcSourceIr + RsTargetIr), ty: TypeRef) -> Outcome<rs::Ty> {
@@ -652,19 +1536,18 @@ pub mod cc {
     ) -> Outcome<rs::StructId> {
         id.lookup(db)
             .to_rust(db, id)
-            .then(|rs_st| ok(db.intern_struct(rs_st)))
+            .then(|rs_st| ok(db.intern_struct(Arc::new(rs_st))))
     }
 
     intern_key!(StructId);
     impl StructId {
-        pub fn lookup(&self, db: &impl CcSourceIr) -> Struct {
+        pub fn lookup(&self, db: &impl CcSourceIr) -> Arc<Struct> {
             db.lookup_intern_cc_struct(*self)
         }
     }
 
     intern_key!(FunctionId);
     impl FunctionId {
-        #[allow(unused)]
         pub fn lookup(&self, db: &impl CcSourceIr) -> Arc<Outcome<Function>> {
             db.lookup_intern_cc_fn(*self)
         }
@@ -678,41 +1561,60 @@ pub mod cc {
         }
     }
 
+    intern_key!(OpaqueId);
+    impl OpaqueId {
+        pub fn lookup(&self, db: &impl CcSourceIr) -> Opaque {
+            db.lookup_intern_cc_opaque(*self)
+        }
+    }
+
+    intern_key!(AliasId);
+    impl AliasId {
+        pub fn lookup(&self, db: &impl CcSourceIr) -> Alias {
+            db.lookup_intern_cc_alias(*self)
+        }
+    }
+
     #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
     pub enum ItemKind {
         Struct(StructId),
+        Function(FunctionId),
+        Alias(AliasId),
     }
     impl From<StructId> for ItemKind {
         fn from(st: StructId) -> Self {
             ItemKind::Struct(st)
         }
     }
+    impl From<FunctionId> for ItemKind {
+        fn from(func: FunctionId) -> Self {
+            ItemKind::Function(func)
+        }
+    }
+    impl ItemKind {
+        pub fn name(&self, db: &impl CcSourceIr) -> Ident {
+            match self {
+                ItemKind::Struct(id) => id.lookup(db).name,
+                ItemKind::Function(id) => id.lookup(db).to_ref().skip_errs().name,
+                ItemKind::Alias(id) => id.lookup(db).name,
+            }
+        }
+
+        pub fn span(&self, db: &impl CcSourceIr) -> Span {
+            match self {
+                ItemKind::Struct(id) => id.lookup(db).span.clone(),
+                ItemKind::Function(id) => id.lookup(db).to_ref().skip_errs().span.clone(),
+                ItemKind::Alias(id) => id.lookup(db).span,
+            }
+        }
+    }
 
     pub type PathComponent = common::PathComponent<Path>;
 
     /// A C++ path, like `std::vector<int>::iterator`.
     #[derive(Clone, Debug, Hash, Eq, PartialEq)]
     pub struct Path(Vec<PathComponent>);
-    impl Path {
-        #[allow(dead_code)]
-        pub fn iter(&self) -> impl Iterator<Item = &PathComponent> {
-            self.0.iter()
-        }
-        pub fn join(mut self, component: PathComponent) -> Self {
-            self.0.push(component);
-            self
-        }
-    }
-    impl FromIterator<PathComponent> for Path {
-        fn from_iter<I: IntoIterator<Item = PathComponent>>(iter: I) -> Self {
-            Self(iter.into_iter().collect())
-        }
-    }
-    impl fmt::Display for Path {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            PathComponent::print(&self.0, f, false)
-        }
-    }
+    impl_path!(Path, PathComponent, turbofish: false);
 
     #[derive(Clone, Debug, Eq, PartialEq, Hash)]
     pub struct Namespace {
@@ -722,12 +1624,8 @@ pub mod cc {
     impl Namespace {
         pub fn path(&self, db: &impl CcSourceIr) -> Path {
             match self.parent {
-                Some(parent) => parent
-                    .lookup(db)
-                    .path(db)
-                    .join(self.name.clone().into())
-                    .into(),
-                None => std::iter::once(self.name.clone().into()).collect(),
+                Some(parent) => parent.lookup(db).path(db).join(self.name).into(),
+                None => std::iter::once(self.name.into()).collect(),
             }
         }
     }
@@ -764,6 +1662,35 @@ pub mod cc {
         Bool,
 
         Struct(StructId),
+
+        /// A record that is declared but never defined, like `FooHandle` in
+        /// `struct FooHandle;`. It can only be used behind a pointer.
+        Opaque(OpaqueId),
+
+        /// An exported alias of a builtin type, like `UserId` in
+        /// `using UserId = unsigned long;`. Aliases that aren't exported are
+        /// lowered to the type they alias.
+        Alias(AliasId),
+
+        /// A pointer. The pointee is referred to by `TypeRef` so that a struct
+        /// can point to itself.
+        Ptr {
+            pointee: TypeRef,
+            is_const: bool,
+        },
+
+        /// A fixed-size array. Multi-dimensional arrays are arrays of arrays.
+        Array {
+            elem: TypeRef,
+            len: u64,
+        },
+
+        /// An lvalue reference, like `const Foo&`. Only appears in function
+        /// signatures, since structs with reference fields aren't POD.
+        Ref {
+            pointee: TypeRef,
+            is_const: bool,
+        },
     }
 
     #[allow(dead_code)]
@@ -777,7 +1704,7 @@ pub mod cc {
                 | CharU | SChar | UChar | Size | SSize | PtrDiff => true,
                 Float | Double => false,
                 Bool => false,
-                Struct(_) => false,
+                Struct(_) | Opaque(_) | Alias(_) | Ptr { .. } | Array { .. } | Ref { .. } => false,
             }
         }
 
@@ -790,7 +1717,7 @@ pub mod cc {
                 Short | UShort | Int | UInt | Long | ULong | LongLong | ULongLong | CharS
                 | CharU | SChar | UChar | Size | SSize | PtrDiff => false,
                 Bool => false,
-                Struct(_) => false,
+                Struct(_) | Opaque(_) | Alias(_) | Ptr { .. } | Array { .. } | Ref { .. } => false,
             }
         }
 
@@ -803,7 +1730,7 @@ pub mod cc {
                 Short | UShort | Int | UInt | Long | ULong | LongLong | ULongLong | CharS
                 | CharU | SChar | UChar | Size | SSize | PtrDiff => true,
                 Bool => true,
-                Struct(_) => false,
+                Struct(_) | Opaque(_) | Alias(_) | Ptr { .. } | Array { .. } | Ref { .. } => false,
             }
         }
 
@@ -811,6 +1738,17 @@ pub mod cc {
             self == &Ty::Error
         }
 
+        /// Returns the type under any pointers, references and arrays, like
+        /// `Foo` for `Foo* [4]`.
+        pub fn innermost(&self, db: &impl CcSourceIr) -> Ty {
+            match self {
+                Ty::Ptr { pointee: inner, .. }
+                | Ty::Ref { pointee: inner, .. }
+                | Ty::Array { elem: inner, .. } => inner.as_cc(db).skip_errs().innermost(db),
+                ty => ty.clone(),
+            }
+        }
+
         pub(crate) fn is_visible(&self, db: &impl bindings::CcModule) -> bool {
             match self {
                 Ty::Struct(id) => db.cc_module_ids().into_iter().any(|mdl| {
@@ -819,10 +1757,18 @@ pub mod cc {
                     db.cc_module(mdl)
                         .to_ref()
                         .skip_errs()
-                        .items
-                        .iter()
-                        .any(|import| import.def == id.clone().into())
+                        .exported_structs()
+                        .any(|st| st == *id)
                 }),
+                // Opaque types are never exported, but the marker types they
+                // are bound as are public.
+                Ty::Opaque(_) => true,
+                // Only exported aliases are lowered to `Alias`.
+                Ty::Alias(_) => true,
+                Ty::Ptr { pointee, .. } | Ty::Ref { pointee, .. } => {
+                    pointee.as_cc(db).skip_errs().is_visible(db)
+                }
+                Ty::Array { elem, .. } => elem.as_cc(db).skip_errs().is_visible(db),
                 _ if self.is_builtin() => true,
                 Ty::Error => false,
                 _ => unreachable!(),
@@ -839,12 +1785,17 @@ pub mod cc {
                 UShort => rs::Ty::U16,
                 Int => rs::Ty::I32,
                 UInt => rs::Ty::U32,
-                Long => rs::Ty::I64, // TODO assumes LP64
+                Long if db.target_info().skip_errs().long_size == 4 => rs::Ty::I32,
+                ULong if db.target_info().skip_errs().long_size == 4 => rs::Ty::U32,
+                Long => rs::Ty::I64,
                 ULong => rs::Ty::U64,
                 LongLong => rs::Ty::I64,
                 ULongLong => rs::Ty::U64,
-                CharS | SChar => rs::Ty::I8,
-                CharU | UChar => rs::Ty::U8,
+                // The signedness of plain `char` depends on the target, so
+                // it's left to `c_char`.
+                CharS | CharU => rs::Ty::CChar,
+                SChar => rs::Ty::I8,
+                UChar => rs::Ty::U8,
                 Size => rs::Ty::USize,
                 SSize => rs::Ty::ISize,
                 PtrDiff => rs::Ty::ISize,
@@ -852,6 +1803,33 @@ pub mod cc {
                 Double => rs::Ty::F64,
                 Bool => rs::Ty::Bool,
                 Struct(id) => return db.rs_struct_from_cc(*id).map(rs::Ty::Struct),
+                Opaque(id) => rs::Ty::Opaque(*id),
+                Alias(id) => rs::Ty::Alias(*id),
+                // Don't lower the pointee to Rust here, since it may be the
+                // struct currently being lowered.
+                Ptr { pointee, is_const } => {
+                    return pointee.as_cc(db).map(|_| rs::Ty::Ptr {
+                        pointee: pointee.clone(),
+                        mutable: !*is_const,
+                    })
+                }
+                Ref { pointee, is_const } => {
+                    return pointee.as_cc(db).map(|_| rs::Ty::Ref {
+                        pointee: pointee.clone(),
+                        mutable: !*is_const,
+                    })
+                }
+                // Lowering the element type recurses through each dimension,
+                // but only the innermost element type can fail, so a bad
+                // element type is reported once.
+                Array { elem, len } => {
+                    return elem.as_cc(db).then(|elem_ty| elem_ty.to_rust(db)).map(|_| {
+                        rs::Ty::Array {
+                            elem: elem.clone(),
+                            len: *len,
+                        }
+                    })
+                }
             })
         }
     }
@@ -862,21 +1840,75 @@ pub mod cc {
         pub parent: NamespaceId,
         pub fields: Vec<Field>,
         pub methods: Vec<Function>,
-        pub align_attr: Option<(Align, Span)>,
+        pub attrs: LayoutAttrs,
         /// When we can get layout info from libclang we do, but only for
         /// internal verification.
         ///
         /// Layout info is not available for template instantiations.
         pub layout: Option<StructLayout>,
+        /// Whether the struct is imported without its fields, as listed in
+        /// `SessionOptions::opaque` or exported from `rust_export::opaque`.
+        /// `fields` is empty, but `layout` still has the real size and
+        /// alignment.
+        pub opaque: bool,
+        pub deprecated: Option<Deprecation>,
         pub span: Span,
     }
     impl Struct {
         pub fn path(&self, db: &impl CcSourceIr) -> Path {
-            self.parent
-                .lookup(db)
-                .path(db)
-                .join(self.name.clone().into())
-                .into()
+            self.parent.lookup(db).path(db).join(self.name).into()
+        }
+    }
+
+    /// Attributes that change the layout of a struct, however they were
+    /// spelled.
+    #[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+    pub struct LayoutAttrs {
+        /// The alignment set by `alignas`, `__attribute__((aligned))` or
+        /// `__declspec(align)`.
+        pub align_override: Option<(Align, Span)>,
+        /// The largest alignment any field gets, set by
+        /// `__attribute__((packed))` or `#pragma pack`.
+        pub packed: Option<(Align, Span)>,
+    }
+
+    /// A record that is declared but never defined.
+    ///
+    /// Every declaration of the record is interned to the same `OpaqueId`,
+    /// even across modules, since the USR identifies it.
+    #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct Opaque {
+        pub name: Ident,
+        pub parent: NamespaceId,
+        pub usr: String,
+    }
+    impl Opaque {
+        pub fn path(&self, db: &impl CcSourceIr) -> Path {
+            self.parent.lookup(db).path(db).join(self.name).into()
+        }
+    }
+
+    /// An exported alias of a builtin type.
+    #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+    pub struct Alias {
+        pub name: Ident,
+        pub parent: NamespaceId,
+        /// The builtin type it aliases.
+        pub target: Ty,
+        /// Whether it's bound as a `#[repr(transparent)]` newtype instead of
+        /// a type alias, as listed in `SessionOptions::newtypes`.
+        pub newtype: bool,
+        pub span: Span,
+    }
+    impl Alias {
+        pub fn path(&self, db: &impl CcSourceIr) -> Path {
+            self.parent.lookup(db).path(db).join(self.name).into()
+        }
+
+        /// The Rust type the alias is defined as.
+        pub fn rs_target(&self, db: &impl RsTargetIr) -> rs::Ty {
+            // Builtin types always lower.
+            self.target.to_rust(db).skip_errs()
         }
     }
 
@@ -885,6 +1917,36 @@ pub mod cc {
         pub name: Ident,
         pub ty: TypeRef,
         pub span: Span,
+        /// The anonymous union this field is a member of, if any, numbered in
+        /// declaration order within the struct.
+        pub union_group: Option<u32>,
+        /// Whether the field is listed in `SessionOptions::skip_members`. Its
+        /// type is padding of the same size and alignment, and it is private
+        /// in Rust.
+        pub hidden: bool,
+        /// Whether the field is declared `const`, so it can't be assigned
+        /// after the struct is constructed.
+        pub is_const_member: bool,
+        /// Whether the field is declared `mutable`, so it can be assigned
+        /// even through a const reference to the struct.
+        pub is_mutable: bool,
+        pub deprecated: Option<Deprecation>,
+    }
+
+    /// Splits the fields of a struct into the runs that are laid out
+    /// together, given the union group of each field: a single field, or
+    /// every member of an anonymous union.
+    fn layout_slots(groups: impl IntoIterator<Item = Option<u32>>) -> Vec<Range<usize>> {
+        let mut slots: Vec<Range<usize>> = vec![];
+        let mut prev = None;
+        for (idx, group) in groups.into_iter().enumerate() {
+            match slots.last_mut() {
+                Some(slot) if group.is_some() && group == prev => slot.end = idx + 1,
+                _ => slots.push(idx..idx + 1),
+            }
+            prev = group;
+        }
+        slots
     }
 
     #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -899,7 +1961,100 @@ pub mod cc {
         pub is_method: bool,
         /// For non-static methods, whether `this` is const.
         pub is_const: bool,
+        /// The field this method reads or writes, if it was generated for a
+        /// field of an opaque struct instead of declared in C++.
+        pub accessor: Option<Accessor>,
+        /// The operator this method overloads, if it's one that's bound.
+        pub operator: Option<Operator>,
+        /// What a `constexpr` static method with no parameters returns, if
+        /// clang could evaluate it. Such methods are bound as `const fn`s
+        /// returning the value, without a shim.
+        pub const_value: Option<ConstValue>,
+        /// The base class that declares this method, if it was inherited
+        /// instead of declared by the struct itself. Shims call it through
+        /// the base, so `this` is converted to the class that declares it.
+        pub inherited_from: Option<Path>,
+        pub attrs: FunctionAttrs,
+        pub span: Span,
+    }
+
+    /// Properties of a function read from its declaration. Most don't change
+    /// how it's bound, but are recorded for the IR dumps.
+    #[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+    pub struct FunctionAttrs {
+        /// Whether the function is declared `noexcept` or `throw()`. A
+        /// computed `noexcept(expr)` counts as false, since libclang can't
+        /// evaluate it.
+        pub noexcept: bool,
+        /// The calling convention, as named by libclang, like `cdecl`.
+        pub calling_conv: Option<String>,
+        /// Whether the function is `[[nodiscard]]` or
+        /// `__attribute__((warn_unused_result))`.
+        pub nodiscard: bool,
+        /// Whether the function is `= delete`d.
+        pub deleted: bool,
+        /// Whether the function is `= default`ed.
+        pub defaulted: bool,
+        pub constexpr: bool,
+        /// The name of the function's symbol, if it has one.
+        pub mangled_name: Option<String>,
+        /// Bound as `#[deprecated]` on the method.
+        pub deprecated: Option<Deprecation>,
     }
+
+    /// A constant evaluated by clang.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+    pub enum ConstValue {
+        Int(i64),
+        UInt(u64),
+        /// The bits of an `f64`, so the value can be hashed.
+        Float(u64),
+    }
+    impl fmt::Display for ConstValue {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ConstValue::Int(val) => write!(f, "{}", val),
+                ConstValue::UInt(val) => write!(f, "{}", val),
+                ConstValue::Float(bits) => write!(f, "{:?}", f64::from_bits(*bits)),
+            }
+        }
+    }
+
+    /// A getter or setter generated for a public field of an opaque struct,
+    /// since Rust can't reach the field directly.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+    pub enum Accessor {
+        Get(Ident),
+        Set(Ident),
+    }
+    /// An overloaded operator that's bound as a method.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+    pub enum Operator {
+        /// `operator[]`. A struct with a const one taking a `size_t` and
+        /// returning a reference implements `Index<usize>`, and also
+        /// `IndexMut<usize>` if it has a non-const one.
+        Index,
+    }
+    impl Operator {
+        /// Returns the operator overloaded by a method named `name`, if it's
+        /// one that's bound.
+        pub fn from_name(name: &str) -> Option<Operator> {
+            match name {
+                "operator[]" => Some(Operator::Index),
+                _ => None,
+            }
+        }
+
+        /// Returns the name of the Rust method the operator is bound as,
+        /// which is named after the method of the trait it implements.
+        pub fn method_name(self, db: &impl DefIr, is_const: bool) -> Ident {
+            match (self, is_const) {
+                (Operator::Index, true) => Ident::new_mangled(db, "index"),
+                (Operator::Index, false) => Ident::new_mangled(db, "index_mut"),
+            }
+        }
+    }
+
     impl Function {
         pub fn param_tys<'a>(&'a self, db: &'a impl CcSourceIr) -> impl Iterator<Item = Ty> + 'a {
             // skip_errs okay because errors get collected by Struct::to_rust()
@@ -912,128 +2067,474 @@ pub mod cc {
         }
     }
 
+    /// Chooses the Rust name of an item with the naming options of the
+    /// session. Keeps the C++ name if the chosen name isn't an identifier.
+    fn rust_ident(
+        db: &impl RsTargetIr,
+        kind: NameKind,
+        cc_name: Ident,
+        parent: Option<Ident>,
+        span: &Span,
+        errs: &mut Diagnostics,
+    ) -> Ident {
+        let parent = parent.map(|parent| parent.as_str(db));
+        let name = cc_name.as_str(db);
+        match naming::rust_name(db, &db.options(), kind, &name, parent.as_deref()) {
+            Ok(name) => name,
+            Err(invalid) => {
+                errs.add(Diagnostic::error(
+                    format!("`{}` is not a valid Rust identifier", invalid.name),
+                    span.label(format!("chosen as the Rust name of `{}`", name)),
+                ));
+                cc_name
+            }
+        }
+    }
+
+    /// Reports members of a struct that were renamed to the same Rust name.
+    fn check_renamed_members<'a>(
+        db: &impl DefIr,
+        what: &str,
+        members: impl Iterator<Item = (Ident, Ident, &'a Span)>,
+        errs: &mut Diagnostics,
+    ) {
+        let mut seen = HashMap::<Ident, (Ident, &Span)>::new();
+        for (name, cc_name, span) in members {
+            let (prev_cc_name, prev_span) = *seen.entry(name).or_insert((cc_name, span));
+            if prev_cc_name != cc_name {
+                errs.add(
+                    Diagnostic::error(
+                        format!(
+                            "multiple {} named `{}` in the generated bindings",
+                            what,
+                            name.as_str(db)
+                        ),
+                        span.label(format!("`{}` renamed here", cc_name.as_str(db))),
+                    )
+                    .with_label(
+                        prev_span.label(format!("conflicts with `{}`", prev_cc_name.as_str(db))),
+                    ),
+                );
+            }
+        }
+    }
+
     impl Struct {
         pub fn to_rust(
             &self,
             db: &(impl RsTargetIr + CcSourceIr),
             id: StructId,
         ) -> Outcome<rs::Struct> {
-            let fields = self
+            let mut name_errs = Diagnostics::new();
+            let name = rust_ident(
+                db,
+                NameKind::Struct,
+                self.name,
+                None,
+                &self.span,
+                &mut name_errs,
+            );
+            let field_names: Vec<_> = self
                 .fields
                 .iter()
                 .map(|f| {
+                    let parent = Some(self.name);
+                    rust_ident(db, NameKind::Field, f.name, parent, &f.span, &mut name_errs)
+                })
+                .collect();
+            let methods: Vec<_> = self
+                .methods
+                .iter()
+                .map(|meth| rs::Method {
+                    // Operators aren't identifiers, so they're not renamed.
+                    name: match meth.operator {
+                        Some(op) => op.method_name(db, meth.is_const),
+                        None => rust_ident(
+                            db,
+                            NameKind::Method,
+                            meth.name,
+                            Some(self.name),
+                            &meth.span,
+                            &mut name_errs,
+                        ),
+                    },
+                    func: meth.clone(),
+                })
+                .collect();
+            check_renamed_members(
+                db,
+                "fields",
+                self.fields
+                    .iter()
+                    .zip(&field_names)
+                    .map(|(f, &name)| (name, f.name, &f.span)),
+                &mut name_errs,
+            );
+            check_renamed_members(
+                db,
+                "methods",
+                methods.iter().map(|m| (m.name, m.func.name, &m.func.span)),
+                &mut name_errs,
+            );
+
+            let fields = self
+                .fields
+                .iter()
+                .zip(field_names)
+                .map(|(f, name)| {
                     f.ty.as_cc(db)
-                        // Collect errors from lowering each field's type to Rust here.
-                        // TODO find a more robust/explicit way.
-                        .then(|cc_ty| cc_ty.to_rust(db).map(|_| cc_ty))
-                        .map(|cc_ty| rs::Field {
-                            name: f.name.clone(),
-                            ty: f.ty.clone(),
-                            span: f.span.clone(),
-                            // Long term we probably don't want to condition
-                            // visibility on the visibility of the type (instead
-                            // controlling visibility with inner modules and `pub
-                            // use`), but this works well for now.
-                            vis: match cc_ty.is_visible(db) {
-                                true => rs::Visibility::Public,
-                                false => rs::Visibility::Private,
-                            },
+                        .then(|cc_ty| match cc_ty {
+                            // A `void` field would take up no space in the
+                            // computed layout, so leave it out entirely.
+                            Ty::Void => err(
+                                None,
+                                Diagnostic::error(
+                                    format!("field `{}` has type `void`", f.name.as_str(db)),
+                                    f.span.label("only return types can be `void`"),
+                                ),
+                            ),
+                            // Collect errors from lowering each field's type to Rust here.
+                            // TODO find a more robust/explicit way.
+                            cc_ty => cc_ty.to_rust(db).map(|_| Some(cc_ty)),
+                        })
+                        .map(|cc_ty| {
+                            cc_ty.map(|cc_ty| rs::Field {
+                                name,
+                                cc_name: f.name,
+                                ty: f.ty.clone(),
+                                span: f.span.clone(),
+                                union_group: f.union_group,
+                                is_const_member: f.is_const_member,
+                                is_mutable: f.is_mutable,
+                                deprecated: f.deprecated.clone(),
+                                // Long term we probably don't want to condition
+                                // visibility on the visibility of the type (instead
+                                // controlling visibility with inner modules and `pub
+                                // use`), but this works well for now.
+                                vis: match (f.hidden, cc_ty.is_visible(db)) {
+                                    (true, _) => rs::Visibility::Private,
+                                    (false, true) => rs::Visibility::Public,
+                                    (false, false) => db.options().transitive_vis.into(),
+                                },
+                            })
                         })
                 })
-                .collect::<Outcome<Vec<_>>>();
-            // TODO: Use the set of imports and exports, not the full Module, to determine
-            // visibility.
-            let vis = match db
-                .cc_module_ids()
-                .into_iter()
-                .map(|mdl_id| db.cc_module(mdl_id))
-                .any(|mdl| {
-                    mdl.to_ref()
-                        .skip_errs()
-                        .items
-                        .iter()
-                        .any(|imp| imp.def == id.clone().into())
-                }) {
+                .collect::<Outcome<Vec<_>>>()
+                .map(|fields| fields.into_iter().flatten().collect::<Vec<_>>());
+            let vis = match Ty::Struct(id).is_visible(db) {
                 true => rs::Visibility::Public,
-                false => rs::Visibility::Private,
+                false => db.options().transitive_vis.into(),
             };
-            ok(())
-                .then(|()| {
-                    // Check method types.
-                    self.methods
-                        .iter()
-                        .flat_map(|meth| meth.param_tys.iter().chain(Some(&meth.return_ty)))
-                        .map(|ty| ty.as_rs(db).map(|_| ()))
-                        .collect::<Outcome<Vec<()>>>()
-                        .map(|_| ())
+            // Check method types.
+            let method_tys = self
+                .methods
+                .iter()
+                .flat_map(|meth| {
+                    let tys = meth.param_tys.iter().chain(Some(&meth.return_ty));
+                    tys.map(move |ty| (meth, ty))
                 })
-                .then(|()| fields)
+                .map(|(meth, ty)| {
+                    ty.as_rs(db).then(|ty| match ty {
+                        rs::Ty::Opaque(id) => err(
+                            (),
+                            Diagnostic::error(
+                                format!(
+                                    "incomplete type `{}` passed by value",
+                                    id.lookup(db).path(db).display(db)
+                                ),
+                                meth.span
+                                    .label("incomplete types can only be used behind a pointer"),
+                            ),
+                        ),
+                        _ => ok(()),
+                    })
+                })
+                .collect::<Outcome<()>>();
+            // Only a method's returned reference can be borrowed from the
+            // object it's called on.
+            let static_refs = self
+                .methods
+                .iter()
+                .filter(|meth| !meth.is_method)
+                .map(|meth| match meth.return_ty.as_cc(db).skip_errs() {
+                    Ty::Ref { .. } => err(
+                        (),
+                        Diagnostic::warn(
+                            format!(
+                                "reference returned from static method `{}::{}` is bound as a raw pointer",
+                                self.name.as_str(db), meth.name.as_str(db)
+                            ),
+                            meth.span.label("this reference isn't tied to an object"),
+                        )
+                        .with_note("callers must make sure it's still valid before using it"),
+                    ),
+                    _ => ok(()),
+                })
+                .collect::<Outcome<()>>();
+            Outcome::from_parts((), name_errs)
+                .zip(method_tys)
+                .zip(static_refs)
+                .zip(fields)
+                .then(|(_, fields)| self.check_packed(db, fields))
+                .then(|fields| self.check_unions(db, fields))
                 .then(|fields| {
-                    let computed_layout = self.compute_layout(db, &fields);
-                    self.check_offsets(&computed_layout)
-                        .map(|_| (fields, computed_layout))
+                    // There are no fields to compute an opaque struct's
+                    // layout from, so it's taken from clang as is.
+                    if self.opaque {
+                        return ok((fields, self.layout.clone().unwrap_or_default()));
+                    }
+                    // A field whose type failed to lower would throw off the
+                    // computed layout, and the failure is already reported.
+                    if fields.iter().any(|f| f.ty(db).is_error(db)) {
+                        return ok((fields, StructLayout::default()));
+                    }
+                    db.metrics().time(Phase::Layout, || {
+                        let computed_layout = self.compute_layout(db, &fields);
+                        self.check_offsets(db, computed_layout.as_ref())
+                            .map(|_| (fields, computed_layout.unwrap_or_default()))
+                    })
                 })
-                .map(|(fields, layout)| rs::Struct {
-                    name: self.name.clone(),
-                    fields,
-                    methods: self.methods.iter().cloned().map(rs::Method).collect(),
-                    layout,
-                    vis,
-                    repr: rs::Repr::C,
-                    span: self.span.clone(),
-                    cc_id: id,
+                .map(|(fields, layout)| {
+                    let repr = match self.is_wrapper(db, &fields, &layout) {
+                        _ if self.opaque => rs::Repr::Opaque,
+                        true if !db.options().repr_c_only => rs::Repr::Transparent,
+                        _ => rs::Repr::C,
+                    };
+                    rs::Struct {
+                        name,
+                        fields,
+                        methods,
+                        layout,
+                        vis,
+                        repr,
+                        packed: self.attrs.packed.as_ref().map(|(align, _)| *align),
+                        deprecated: self.deprecated.clone(),
+                        span: self.span.clone(),
+                        cc_id: id,
+                    }
                 })
         }
 
-        fn compute_layout(&self, db: &impl RsTargetIr, fields: &Vec<rs::Field>) -> StructLayout {
+        /// Whether the struct only wraps a single value with the same layout,
+        /// so it can be `#[repr(transparent)]` in Rust.
+        ///
+        /// Structs with methods are left alone, since they are more than a
+        /// wrapper.
+        fn is_wrapper(
+            &self,
+            db: &impl RsTargetIr,
+            fields: &[rs::Field],
+            layout: &StructLayout,
+        ) -> bool {
+            let field = match fields {
+                [field] => field.ty(db),
+                _ => return false,
+            };
+            self.methods.is_empty()
+                && self.attrs == LayoutAttrs::default()
+                && self.fields.iter().all(|f| f.union_group.is_none())
+                && field.size(db).0 > 0
+                && field.size(db) == layout.size
+                && field.align(db) == layout.align
+        }
+
+        /// Computes the C layout of the struct from its Rust field types, or
+        /// returns `None` if the layout overflows.
+        fn compute_layout(
+            &self,
+            db: &impl RsTargetIr,
+            fields: &[rs::Field],
+        ) -> Option<StructLayout> {
             let mut field_offsets = Vec::with_capacity(self.fields.len());
             let mut offset = 0;
             let mut align = self
-                .align_attr
+                .attrs
+                .align_override
                 .clone()
                 .map_or(Align::new(1), |(align, _)| align);
-            for field in fields {
-                // TODO: This uses rs types for size/align. We should verify
-                // that they match cc types.
-                let field_ty = field.ty(db);
-                offset = common::align_to(offset, field_ty.align(db));
-                align = std::cmp::max(align, field_ty.align(db));
-                field_offsets.push(offset);
-                offset += field_ty.size(db).0;
-            }
-            let size = Size::new(common::align_to(offset, align));
-            StructLayout {
+            for slot in layout_slots(fields.iter().map(|f| f.union_group)) {
+                let mut slot_size = 0;
+                let mut slot_align = Align::new(1);
+                for field in &fields[slot.clone()] {
+                    // TODO: This uses rs types for size/align. We should verify
+                    // that they match cc types.
+                    let field_ty = field.ty(db);
+                    let field_align = match &self.attrs.packed {
+                        Some((packed, _)) => std::cmp::min(*packed, field_ty.align(db)),
+                        None => field_ty.align(db),
+                    };
+                    slot_align = std::cmp::max(slot_align, field_align);
+                    slot_size = std::cmp::max(slot_size, field_ty.size(db).0);
+                }
+                offset = common::align_to(offset, slot_align)?;
+                align = std::cmp::max(align, slot_align);
+                field_offsets.extend(slot.map(|_| offset));
+                offset = offset.checked_add(common::align_to(slot_size, slot_align)?)?;
+            }
+            let size = Size::new(common::align_to(offset, align)?);
+            Some(StructLayout {
                 field_offsets,
                 size,
                 align,
+            })
+        }
+
+        /// Checks that the members of anonymous unions can be Rust union
+        /// fields, which must be `Copy`.
+        fn check_unions(
+            &self,
+            db: &impl RsTargetIr,
+            fields: Vec<rs::Field>,
+        ) -> Outcome<Vec<rs::Field>> {
+            fn is_copy(db: &impl RsTargetIr, ty: &rs::Ty) -> bool {
+                match ty {
+                    rs::Ty::Ptr { .. } => true,
+                    rs::Ty::Array { elem, .. } => is_copy(db, &elem.as_rs(db).skip_errs()),
+                    ty => ty.is_scalar(),
+                }
+            }
+            let errs = Diagnostics::build(|errs| {
+                for field in fields.iter().filter(|f| f.union_group.is_some()) {
+                    if !is_copy(db, &field.ty(db)) {
+                        errs.add(Diagnostic::error(
+                            format!(
+                                "unsupported type for anonymous union member `{}`",
+                                field.cc_name.as_str(db)
+                            ),
+                            field.span.label(
+                                "only scalars, pointers and arrays of them are supported here",
+                            ),
+                        ));
+                    }
+                }
+            });
+            Outcome::from_parts(fields, errs)
+        }
+
+        /// Checks that a packed struct can be packed the same way in Rust.
+        fn check_packed(
+            &self,
+            db: &impl RsTargetIr,
+            fields: Vec<rs::Field>,
+        ) -> Outcome<Vec<rs::Field>> {
+            let packed_span = match &self.attrs.packed {
+                Some((_, span)) => span,
+                None => return ok(fields),
+            };
+            let mut errs = Diagnostics::new();
+            if let Some((_, align_span)) = &self.attrs.align_override {
+                errs.add(
+                    Diagnostic::error(
+                        format!("`{}` is both packed and aligned", self.name.as_str(db)),
+                        align_span.label("this alignment can't be combined with packing"),
+                    )
+                    .with_label(packed_span.label("packed here"))
+                    .with_note("Rust doesn't allow `packed` and `align` on the same struct"),
+                );
+            }
+            // Structs that aren't packed get an `align` repr, and Rust doesn't
+            // allow those inside packed structs, whatever their alignment.
+            // Packed structs don't, so they can be nested.
+            for field in &fields {
+                let mut ty = field.ty(db);
+                while let rs::Ty::Array { elem, .. } = ty {
+                    ty = elem.as_rs(db).skip_errs();
+                }
+                let aligned = match ty {
+                    rs::Ty::Struct(id) => id.lookup(db).packed.is_none(),
+                    _ => false,
+                };
+                if aligned {
+                    errs.add(
+                        Diagnostic::error(
+                            format!("packed struct `{}` contains a struct", self.name.as_str(db)),
+                            field
+                                .span
+                                .label("this field's type is a struct that isn't packed"),
+                        )
+                        .with_label(packed_span.label("packed here"))
+                        .with_note("Rust doesn't allow aligned structs inside packed structs"),
+                    );
+                }
             }
+            Outcome::from_parts(fields, errs)
         }
 
-        fn check_offsets(&self, computed: &StructLayout) -> Outcome<()> {
+        fn check_offsets(&self, db: &impl DefIr, computed: Option<&StructLayout>) -> Outcome<()> {
+            let computed = match computed {
+                Some(l) => l,
+                None => {
+                    return err(
+                        (),
+                        Diagnostic::error(
+                            "struct layout overflow",
+                            self.span.label("the size of this struct is too large"),
+                        ),
+                    )
+                }
+            };
             let actual = match &self.layout {
                 Some(l) => l,
                 // libclang couldn't provide us with a layout, so there's nothing to check.
                 None => return ok(()),
             };
 
+            // Both the Itanium and MSVC ABIs give empty C++ structs a size of
+            // 1 (and MSVC C gives them 4), which Rust can't match.
+            if self.fields.is_empty() && actual.size != computed.size {
+                return err(
+                    (),
+                    Diagnostic::error(
+                        format!("empty struct `{}` is not supported", self.name.as_str(db)),
+                        self.span.label("this struct has no fields"),
+                    )
+                    .with_note(format!(
+                        "it has a size of {} in C++, but an empty Rust struct has a size of 0",
+                        actual.size.0
+                    )),
+                );
+            }
+
             assert_eq!(self.fields.len(), actual.field_offsets.len());
-            for (idx, &offset) in computed.field_offsets.iter().enumerate() {
+            // Members of a union share an offset, so only the offset of each
+            // slot as a whole can be wrong.
+            for slot in layout_slots(self.fields.iter().map(|f| f.union_group)) {
+                let mismatch = slot.clone().find_map(|idx| {
+                    let offset = *computed.field_offsets.get(idx)?;
+                    (offset != actual.field_offsets[idx]).then_some((idx, offset))
+                });
                 // Here's where we could add padding, if we wanted to.
-                if offset != actual.field_offsets[idx] {
-                    return err(
-                        (),
+                let (idx, offset) = match mismatch {
+                    Some(mismatch) => mismatch,
+                    None => continue,
+                };
+                let field = &self.fields[idx];
+                let mut diag = match field.union_group {
+                    None => Diagnostic::error(
+                        "unexpected field offset",
+                        field
+                            .span
+                            .label("this field was not at the expected offset"),
+                    ),
+                    Some(_) => {
+                        let members: Vec<_> = self.fields[slot]
+                            .iter()
+                            .map(|f| format!("`{}`", f.name.as_str(db)))
+                            .collect();
                         Diagnostic::error(
-                            "unexpected field offset",
-                            self.fields[idx]
+                            "unexpected anonymous union offset",
+                            field
                                 .span
-                                .label("this field was not at the expected offset"),
+                                .label("this union was not at the expected offset"),
                         )
-                        .with_note(format!(
-                            "expected an offset of {}, but the offset is {}",
-                            offset, actual.field_offsets[idx]
-                        )),
-                    );
-                }
+                        .with_note(format!("the union holds {}", members.join(", ")))
+                    }
+                };
+                diag = diag.with_note(format!(
+                    "expected an offset of {}, but the offset is {}",
+                    offset, actual.field_offsets[idx]
+                ));
+                return err((), diag);
             }
 
             if computed.size != actual.size || computed.align != actual.align {
@@ -1067,12 +2568,12 @@ pub mod rs {
     use super::*;
     use cc::RsTargetIr;
 
-    pub use common::{Align, Ident, Offset, Size, StructLayout, TypeRef};
+    pub use common::{Align, Deprecation, Ident, Size, StructLayout, TypeRef};
 
     /// Code for bindings targeting Rust.
     mod bindings {
         use super::*;
-        use crate::diagnostics::Diagnostics;
+        use crate::diagnostics::{Diagnostics, Severity};
         use cc::CcModule;
 
         #[salsa::query_group(RsTargetBindingsStorage)]
@@ -1082,20 +2583,97 @@ pub mod rs {
 
         fn rs_bindings(db: &impl RsTargetBindings) -> Arc<Outcome<BindingsCrate>> {
             // For now we combine bindings from all cc modules into a single rs module.
-            // They should be separated at some point.
+            // They should be separated at some point. Modules parsed under a
+            // config set are the exception: each set gets its own module,
+            // since the same types can differ between them.
             let mut diags = Diagnostics::new();
-            let mut modules = Vec::new();
+            let mut configs: Vec<(Option<String>, Vec<_>)> = vec![(None, vec![])];
             for id in db.cc_module_ids() {
                 let (mdl, errs) = db.cc_module(id).split();
-                modules.push(mdl);
+                let config = db.cc_module_config(id);
+                match configs.iter_mut().find(|(name, _)| *name == config) {
+                    Some((_, modules)) => modules.push(mdl),
+                    None => configs.push((config, vec![mdl])),
+                }
                 diags.append(errs);
             }
-            let (rs_bindings, errs) =
-                crate::ir::CcSourceBindingsLib::to_rs_bindings(db, &modules).split();
-            diags.append(errs);
+            let mut children = vec![];
+            for (config, modules) in configs {
+                let parent = config.as_deref().map(|name| Ident::new_mangled(db, name));
+                if parent.is_some() || !modules.is_empty() {
+                    let (krate, errs) =
+                        crate::ir::CcSourceBindingsLib::to_rs_bindings(db, &modules, parent)
+                            .split();
+                    diags.append(errs);
+                    let root = krate.root.lookup(db);
+                    match config {
+                        Some(name) => children.push(ItemKind::Module(db.intern_module(Module {
+                            name: Ident::new_mangled(db, &name),
+                            vis: Visibility::Public,
+                            feature: Some(name),
+                            children: root.children,
+                        }))),
+                        None => children.extend(root.children),
+                    }
+                }
+            }
+            let rs_bindings = BindingsCrate {
+                root: db.intern_module(Module {
+                    name: Ident::new_mangled(db, ""),
+                    vis: Visibility::Public,
+                    feature: None,
+                    children,
+                }),
+            };
+            diags.append(check_thread_safe(db, &rs_bindings));
+            diags.append(db.check_option_exports().split().1);
+            diags.append(db.check_skip_members().split().1);
+            diags.append(db.check_opaque_types().split().1);
+            diags.append(db.check_newtypes().split().1);
             Arc::new(Outcome::from_parts(rs_bindings, diags))
         }
 
+        /// Warns about types listed in `thread_safe` that aren't in the
+        /// bindings, since they're probably misspelled.
+        fn check_thread_safe(db: &impl RsTargetBindings, krate: &BindingsCrate) -> Diagnostics {
+            let thread_safe = &db.options().thread_safe;
+            if thread_safe.is_empty() {
+                return Diagnostics::new();
+            }
+            let mut paths = HashSet::new();
+            let mut modules = vec![krate.root];
+            while let Some(id) = modules.pop() {
+                for item in id.lookup(db).children {
+                    let path = match item {
+                        ItemKind::Module(id) => {
+                            modules.push(id);
+                            continue;
+                        }
+                        ItemKind::Struct(id) => id.lookup(db).cc_id.lookup(db).path(db),
+                        ItemKind::Opaque(id) => id.lookup(db).path(db),
+                        ItemKind::Alias(_) | ItemKind::Reexport(..) => continue,
+                    };
+                    paths.insert(
+                        path.display(db)
+                            .to_string()
+                            .trim_start_matches("::")
+                            .to_string(),
+                    );
+                }
+            }
+            Diagnostics::build(|errs| {
+                for name in thread_safe.iter().filter(|name| !paths.contains(*name)) {
+                    errs.add(Diagnostic::new(
+                        Severity::Warning,
+                        format!(
+                            "`{}` is listed as thread-safe, but there is no such type in the bindings",
+                            name
+                        ),
+                    ));
+                }
+            })
+        }
+
         /// Represents everything that goes in a bindings crate.
         ///
         /// All information that's needed to generate bindings code in both Rust and
@@ -1132,12 +2710,13 @@ pub mod rs {
                 dbg!(item);
                 match item {
                     ItemKind::Module(id) => structs.append(&mut id.visible_structs(db)),
-                    ItemKind::Reexport(_, target) => visit_item(db, &target, structs),
+                    ItemKind::Reexport(_, _, target) => visit_item(db, target, structs),
                     ItemKind::Struct(id) => {
                         if id.lookup(db).vis.is_public() {
                             structs.push(*id)
                         }
                     }
+                    ItemKind::Opaque(_) | ItemKind::Alias(_) => (),
                 }
             }
             let mut structs = vec![];
@@ -1157,7 +2736,7 @@ pub mod rs {
 
     intern_key!(StructId);
     impl StructId {
-        pub fn lookup(&self, db: &impl cc::RsTargetIr) -> Struct {
+        pub fn lookup(&self, db: &impl cc::RsTargetIr) -> Arc<Struct> {
             db.lookup_intern_struct(*self)
         }
     }
@@ -1167,27 +2746,7 @@ pub mod rs {
     /// A Rust path, like `std::Vec::<i32>::clone`.
     #[derive(Clone, Debug, Hash, Eq, PartialEq)]
     pub struct Path(Vec<PathComponent>);
-    impl Path {
-        #[allow(dead_code)]
-        pub fn iter(&self) -> impl Iterator<Item = &PathComponent> {
-            self.0.iter()
-        }
-        #[allow(dead_code)]
-        pub fn join(mut self, component: PathComponent) -> Self {
-            self.0.push(component);
-            self
-        }
-    }
-    impl FromIterator<PathComponent> for Path {
-        fn from_iter<I: IntoIterator<Item = PathComponent>>(iter: I) -> Self {
-            Self(iter.into_iter().collect())
-        }
-    }
-    impl fmt::Display for Path {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            PathComponent::print(&self.0, f, true)
-        }
-    }
+    impl_path!(Path, PathComponent, turbofish: true);
 
     // By design, cc_use paths use exactly the same identifiers as the Rust
     // paths they result in, so this conversion works. They aren't aliased to
@@ -1227,50 +2786,139 @@ pub mod rs {
         F32,
         F64,
         Bool,
+        /// C++ `char`, spelled `core::ffi::c_char` so that it has the right
+        /// signedness for whichever target the bindings are compiled for.
+        CChar,
 
         Struct(StructId),
+
+        /// The marker type generated for an opaque C++ type. It's only ever
+        /// used behind a pointer.
+        Opaque(cc::OpaqueId),
+
+        /// The type alias or newtype generated for an exported C++ alias.
+        Alias(cc::AliasId),
+
+        /// A raw pointer. The pointee is lowered lazily, like in `cc::Ty`.
+        Ptr {
+            pointee: TypeRef,
+            mutable: bool,
+        },
+
+        /// A fixed-size array, like `[T; N]`.
+        Array {
+            elem: TypeRef,
+            len: u64,
+        },
+
+        /// A C++ reference. How it's passed across the FFI boundary is
+        /// decided when generating code; see `codegen::param_ty`.
+        Ref {
+            pointee: TypeRef,
+            mutable: bool,
+        },
     }
 
     impl Ty {
+        /// Returns whether this is, or contains, a type that failed to lower.
+        pub fn is_error(&self, db: &impl RsTargetIr) -> bool {
+            match self {
+                Ty::Error => true,
+                Ty::Array { elem, .. } => elem.as_rs(db).skip_errs().is_error(db),
+                _ => false,
+            }
+        }
+
         pub fn size(&self, db: &impl RsTargetIr) -> Size {
             use Ty::*;
             let sz = match self {
                 Error => 0,
-                Unit => 0, // TODO this depends on context!
-                U8 | I8 => 1,
+                // `void` is only allowed as a return type. A `void` field is
+                // reported when its struct is lowered and left out of the
+                // layout, so it never takes up space.
+                Unit => 0,
+                Opaque(_) => 0,
+                U8 | I8 | CChar => 1,
                 U16 | I16 => 2,
                 U32 | I32 => 4,
                 U64 | I64 => 8,
-                USize => 8, // TODO make target dependent
-                ISize => 8,
+                USize | ISize | Ptr { .. } | Ref { .. } => {
+                    db.target_info().skip_errs().pointer_size
+                }
                 F32 => 4,
                 F64 => 8,
                 Bool => 1,
                 Struct(id) => return id.lookup(db).layout.size,
+                Alias(id) => return id.lookup(db).rs_target(db).size(db),
+                // An overflowing size is reported when the struct layout is
+                // computed.
+                Array { elem, len } => elem.as_rs(db).skip_errs().size(db).0.saturating_mul(*len),
             };
             Size::new(sz)
         }
 
         pub fn align(&self, db: &impl RsTargetIr) -> Align {
+            use Ty::*;
+            match self {
+                Struct(id) => id.lookup(db).layout.align,
+                Alias(id) => id.lookup(db).rs_target(db).align(db),
+                Array { elem, .. } => elem.as_rs(db).skip_errs().align(db),
+                U64 | I64 => Align::new(db.target_info().skip_errs().i64_align),
+                F64 => Align::new(db.target_info().skip_errs().f64_align),
+                // Everything else is aligned to its size.
+                _ => Align::new(std::cmp::max(1, self.size(db).0) as u32),
+            }
+        }
+
+        /// Returns whether this is a pointer to plain C++ `char`.
+        pub fn points_to_c_char(&self, db: &impl RsTargetIr) -> bool {
+            match self {
+                Ty::Ptr { pointee, .. } => {
+                    matches!(pointee.as_cc(db).skip_errs(), cc::Ty::CharS | cc::Ty::CharU)
+                }
+                _ => false,
+            }
+        }
+
+        /// Returns whether this is a builtin scalar type, or an alias of one,
+        /// which can be passed by value instead of by reference.
+        pub fn is_scalar(&self) -> bool {
+            use Ty::*;
             match self {
-                Ty::Struct(id) => id.lookup(db).layout.align,
-                // TODO make target dependent. this assumes x86_64
-                _ => Align::new(std::cmp::max(1, self.size(db).0)),
+                U8 | I8 | U16 | I16 | U32 | I32 | U64 | I64 | USize | ISize | F32 | F64 | Bool
+                | CChar | Alias(_) => true,
+                Error | Unit | Struct(_) | Opaque(_) | Ptr { .. } | Array { .. } | Ref { .. } => {
+                    false
+                }
             }
         }
+
+        /// Returns whether this is `const char*`, which usually holds a C
+        /// string.
+        pub fn is_c_str(&self, db: &impl RsTargetIr) -> bool {
+            matches!(self, Ty::Ptr { mutable: false, .. }) && self.points_to_c_char(db)
+        }
     }
 
     #[derive(Clone, Debug, Eq, PartialEq, Hash)]
     pub enum ItemKind {
         Module(ModuleId),
         Struct(StructId),
-        Reexport(PathId, Box<ItemKind>),
+        /// A marker type for pointers to an opaque C++ type. Each opaque type
+        /// gets one, no matter how many structs refer to it.
+        Opaque(cc::OpaqueId),
+        /// A `pub type` alias or newtype for an exported C++ alias.
+        Alias(cc::AliasId),
+        /// A `pub use` of the path, exported under the given name.
+        Reexport(PathId, Ident, Box<ItemKind>),
     }
 
     #[derive(Clone, Debug, Eq, PartialEq, Hash)]
     pub struct Module {
         pub name: Ident,
         pub vis: Visibility,
+        /// The cargo feature the module is only compiled with, if any.
+        pub feature: Option<String>,
         pub children: Vec<ItemKind>,
     }
 
@@ -1282,17 +2930,36 @@ pub mod rs {
     }
     impl Visibility {
         #[allow(unused)]
-        fn is_public(&self) -> bool {
+        pub fn is_public(&self) -> bool {
             *self == Visibility::Public
         }
     }
+    impl From<crate::TransitiveVisibility> for Visibility {
+        fn from(vis: crate::TransitiveVisibility) -> Self {
+            match vis {
+                crate::TransitiveVisibility::Private => Visibility::Private,
+                crate::TransitiveVisibility::Crate => Visibility::Crate,
+                crate::TransitiveVisibility::Public => Visibility::Public,
+            }
+        }
+    }
 
     #[derive(Debug, Clone, Eq, PartialEq, Hash)]
     pub struct Field {
         pub name: Ident,
+        /// The name of the field in C++, which differs from `name` if the
+        /// field was renamed.
+        pub cc_name: Ident,
         pub ty: TypeRef,
         pub span: Span,
         pub vis: Visibility,
+        /// The anonymous union this field is a member of, if any.
+        pub union_group: Option<u32>,
+        /// Whether the C++ field is `const`, noted in its docs.
+        pub is_const_member: bool,
+        /// Whether the C++ field is `mutable`, noted in its docs.
+        pub is_mutable: bool,
+        pub deprecated: Option<Deprecation>,
     }
     impl Field {
         pub fn ty(&self, db: &impl RsTargetIr) -> Ty {
@@ -1303,9 +2970,12 @@ pub mod rs {
     }
 
     #[derive(Debug, Clone, Eq, PartialEq, Hash)]
-    #[allow(dead_code)]
     pub enum Repr {
         C,
+        /// A struct with a single field that has the same layout as the field.
+        Transparent,
+        /// A struct whose fields are hidden behind a byte array of the same
+        /// size and alignment.
         Opaque,
     }
 
@@ -1317,29 +2987,36 @@ pub mod rs {
         pub methods: Vec<Method>,
         pub layout: StructLayout,
         pub repr: Repr,
+        /// The largest alignment any field gets, if the struct is packed.
+        pub packed: Option<Align>,
+        pub deprecated: Option<Deprecation>,
         pub span: Span,
         // TODO: We might need a more general way of doing this. (Similar to TypeRef?)
         pub cc_id: cc::StructId,
     }
 
     #[derive(Debug, Clone, Eq, PartialEq, Hash)]
-    pub struct Method(pub(super) Function);
+    pub struct Method {
+        pub(super) func: Function,
+        /// The name of the method in Rust. The C++ name is `func().name`.
+        pub name: Ident,
+    }
     impl Method {
         pub fn func(&self) -> &Function {
-            &self.0
+            &self.func
         }
         pub fn param_tys<'a>(&'a self, db: &'a impl RsTargetIr) -> impl Iterator<Item = Ty> + 'a {
             // skip_errs is okay because we check method types in Struct::to_rust above.
-            self.0
+            self.func
                 .param_tys
                 .iter()
                 .map(move |ty_ref| ty_ref.as_rs(db).skip_errs())
         }
         pub fn return_ty(&self, db: &impl RsTargetIr) -> Ty {
-            self.0.return_ty.as_rs(db).skip_errs()
+            self.func.return_ty.as_rs(db).skip_errs()
         }
         pub fn cc_func(&self, _db: &impl RsTargetIr) -> cc::Function {
-            self.0.clone()
+            self.func.clone()
         }
     }
 
@@ -1349,86 +3026,244 @@ pub mod rs {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Session;
+    use crate::{cc_use::RsSource, libclang, Database, Session, SessionOptions};
     use std::iter;
 
     #[test]
     fn align() {
-        use common::{align_to, Align};
-        assert_eq!(align_to(5, Align::new(1)), 5);
-        assert_eq!(align_to(2, Align::new(4)), 4);
-        assert_eq!(align_to(4, Align::new(4)), 4);
-        assert_eq!(align_to(0, Align::new(4)), 0);
-        assert_eq!(align_to(0, Align::new(1)), 0);
+        use common::{align_to, Align, Offset};
+        assert_eq!(align_to(5, Align::new(1)), Some(5));
+        assert_eq!(align_to(2, Align::new(4)), Some(4));
+        assert_eq!(align_to(4, Align::new(4)), Some(4));
+        assert_eq!(align_to(0, Align::new(4)), Some(0));
+        assert_eq!(align_to(0, Align::new(1)), Some(0));
+
+        for shift in 0..32 {
+            let align = Align::new(1 << shift);
+            let a = 1u64 << shift;
+            assert_eq!(align_to(0, align), Some(0));
+            assert_eq!(align_to(1, align), Some(a));
+            assert_eq!(align_to(a, align), Some(a));
+            assert_eq!(align_to(a + 1, align), Some(2 * a));
+
+            // The largest aligned offset is representable, but anything past
+            // it overflows.
+            let last = Offset::MAX - a + 1;
+            assert_eq!(align_to(last, align), Some(last));
+            if shift > 0 {
+                assert_eq!(align_to(last + 1, align), None);
+                assert_eq!(align_to(Offset::MAX, align), None);
+            }
+        }
+    }
+
+    #[test]
+    fn align_power_of_two() {
+        use common::Align;
+        for align in 0..=1024u32 {
+            assert_eq!(Align::try_new(align).is_some(), align.is_power_of_two());
+        }
+        assert!(Align::try_new(1 << 31).is_some());
+        assert!(Align::try_new(u32::MAX).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "alignment must be a power of two")]
+    fn align_not_power_of_two() {
+        common::Align::new(3);
     }
 
     #[test]
     fn print_paths() {
         use common::Ident;
+        let db = &Database::new();
         assert_eq!(
             [
-                Ident::from("std").into(),
+                Ident::new_mangled(db, "std").into(),
                 cc::PathComponent {
-                    name: Ident::from("vector"),
+                    name: Ident::new_mangled(db, "vector"),
                     args: vec![cc::Path::from_iter(iter::once(
-                        Ident::from("string").into()
+                        Ident::new_mangled(db, "string").into()
                     ))]
                 },
-                Ident::from("iterator").into()
+                Ident::new_mangled(db, "iterator").into()
             ]
             .iter()
             .cloned()
             .collect::<cc::Path>()
+            .display(db)
             .to_string(),
             "std::vector<string>::iterator"
         );
         assert_eq!(
             [
-                Ident::from("std").into(),
+                Ident::new_mangled(db, "std").into(),
                 rs::PathComponent {
-                    name: Ident::from("vector"),
+                    name: Ident::new_mangled(db, "vector"),
                     args: vec![rs::Path::from_iter(iter::once(
-                        Ident::from("string").into()
+                        Ident::new_mangled(db, "string").into()
                     ))]
                 },
-                Ident::from("iterator").into()
+                Ident::new_mangled(db, "iterator").into()
             ]
             .iter()
             .cloned()
             .collect::<rs::Path>()
+            .display(db)
             .to_string(),
             "std::vector::<string>::iterator"
         );
     }
 
     #[test]
-    fn pod_layout() {
-        let mut sess = Session::test();
-        let ir = cpp_lower!(sess, {
-            struct Pod {
-                int a, b;
-                char c, d;
-                double e, f;
-            };
-            namespace rust_export {
-                using ::Pod;
-            }
-        });
-        let db = &sess.db;
-        let st = ir.visible_structs(db)[0].lookup(db);
+    fn path_operations() {
+        let db = &Database::new();
+        let path = rs::Path::parse(db, "std::vec::Vec").unwrap();
+        assert_eq!(path.len(), 3);
+        assert_eq!(&*path.last().as_str(db), "Vec");
         assert_eq!(
-            st.fields
-                .iter()
-                .map(|f| f.name.as_str())
-                .zip(st.layout.field_offsets.iter().copied())
-                .collect::<Vec<_>>(),
-            vec![("a", 0), ("b", 4), ("c", 8), ("d", 9), ("e", 16), ("f", 24)],
+            path.parent(),
+            Some(rs::Path::parse(db, "std::vec").unwrap())
+        );
+        assert_eq!(
+            rs::Path::parse(db, "std::vec")
+                .unwrap()
+                .join(rs::Ident::new_mangled(db, "Vec")),
+            path
         );
+        assert!(path.starts_with(&rs::Path::parse(db, "std::vec").unwrap()));
+        assert!(path.starts_with(&path));
+        assert!(path.starts_with(&rs::Path::parse(db, "").unwrap()));
+        assert!(!path.starts_with(&rs::Path::parse(db, "std::ve").unwrap()));
+        assert!(!rs::Path::parse(db, "std").unwrap().starts_with(&path));
+
+        let single = rs::Path::parse(db, "std").unwrap();
+        assert_eq!(single.parent(), Some(rs::Path::parse(db, "").unwrap()));
+        assert_eq!(single.parent().unwrap().parent(), None);
     }
 
-    // TODO
     #[test]
-    #[should_panic(expected = "lowering errors")]
+    fn path_from_degenerate_strings() {
+        let db = &Database::new();
+        let empty = cc::Path::parse(db, "").unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.display(db).to_string(), "");
+        for src in &["::", "::::", "::std::vector", "std::::vector", "std::"] {
+            let err = cc::Path::parse(db, src).unwrap_err();
+            assert_eq!(
+                err.reason, "identifiers can't be empty",
+                "parsing `{}`",
+                src
+            );
+        }
+        assert_eq!(
+            cc::Path::parse(db, "std::vector<int>")
+                .unwrap_err()
+                .to_string(),
+            "`vector<int>` is not a valid identifier"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "empty path")]
+    fn last_of_empty_path() {
+        bindings::Path::parse(&Database::new(), "").unwrap().last();
+    }
+
+    #[test]
+    fn rust_path_escaping() {
+        let db = &Database::new();
+        assert_eq!(
+            rs::Path::parse(db, "crate::bind::type::match")
+                .unwrap()
+                .to_rust_path(db),
+            "crate::bind::r#type::r#match"
+        );
+        assert_eq!(
+            rs::Path::parse(db, "self::super::Self")
+                .unwrap()
+                .to_rust_path(db),
+            "self::super::Self"
+        );
+        let generic = rs::Path::parse(db, "ns").unwrap().join(rs::PathComponent {
+            name: rs::Ident::new_mangled(db, "Box"),
+            args: vec![rs::Path::parse(db, "ns::fn").unwrap()],
+        });
+        assert_eq!(generic.to_rust_path(db), "ns::Box::<ns::r#fn>");
+        assert_eq!(generic.display(db).to_string(), "ns::Box::<ns::fn>");
+    }
+
+    #[test]
+    fn pod_layout() {
+        let mut sess = Session::test();
+        let ir = cpp_codegen!(sess, {
+            struct Pod {
+                int a, b;
+                char c, d;
+                double e, f;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Pod;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(8))]
+                pub struct Pod {
+                    pub a: i32,
+                    pub b: i32,
+                    pub c: core::ffi::c_char,
+                    pub d: core::ffi::c_char,
+                    pub e: f64,
+                    pub f: f64,
+                }
+                const _: () = assert!(::core::mem::size_of::<Pod>() == 32);
+                const _: () = assert!(::core::mem::align_of::<Pod>() == 8);
+            }
+        "#);
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        let names: Vec<_> = st.fields.iter().map(|f| f.name.as_str(db)).collect();
+        assert_eq!(
+            names
+                .iter()
+                .map(|name| &**name)
+                .zip(st.layout.field_offsets.iter().copied())
+                .collect::<Vec<_>>(),
+            vec![("a", 0), ("b", 4), ("c", 8), ("d", 9), ("e", 16), ("f", 24)],
+        );
+    }
+
+    #[test]
+    fn private_methods() {
+        let mut sess = Session::test();
+        let ir = cpp_lower!(sess, {
+            struct Foo {
+                int a;
+                int get() const;
+              private:
+                void helper();
+            };
+            namespace rust_export {
+                using ::Foo;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(
+            st.methods
+                .iter()
+                .map(|m| m.func().name.as_str(db).to_string())
+                .collect::<Vec<_>>(),
+            vec!["get"],
+        );
+    }
+
+    // TODO
+    #[test]
+    #[should_panic(expected = "lowering errors")]
     fn anonymous_field() {
         let mut sess = Session::test();
         let ir = cpp_lower!(sess, {
@@ -1448,7 +3283,7 @@ mod tests {
     #[test]
     fn nested_struct() {
         let mut sess = Session::new();
-        let ir = cpp_lower!(sess, {
+        let ir = cpp_codegen!(sess, {
             struct Foo {
                 int a, b;
             };
@@ -1459,7 +3294,28 @@ mod tests {
             namespace rust_export {
                 using ::Bar;
             }
-        });
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Bar;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub(crate) struct Foo {
+                    pub a: i32,
+                    pub b: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Foo>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Foo>() == 4);
+                #[repr(C, align(4))]
+                pub struct Bar {
+                    pub c: core::ffi::c_char,
+                    pub d: core::ffi::c_char,
+                    pub(crate) foo: Foo,
+                }
+                const _: () = assert!(::core::mem::size_of::<Bar>() == 12);
+                const _: () = assert!(::core::mem::align_of::<Bar>() == 4);
+            }
+        "#);
         let db = &sess.db;
         let st = ir.visible_structs(db)[0].lookup(db);
         assert_eq!(rs::Size::new(12), st.layout.size);
@@ -1486,4 +3342,1258 @@ mod tests {
         assert_eq!(rs::Size::new(16), st.layout.size);
         assert_eq!(rs::Align::new(8), st.layout.align);
     }
+
+    #[test]
+    fn nested_struct_gnu_aligned() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct __attribute__((aligned(8))) Foo {
+                int a, b;
+            };
+            struct Bar {
+                char c, d;
+                Foo foo;
+            };
+            namespace rust_export {
+                using ::Bar;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(rs::Size::new(16), st.layout.size);
+        assert_eq!(rs::Align::new(8), st.layout.align);
+    }
+
+    #[test]
+    fn declspec_align() {
+        let mut sess = Session::with_options(SessionOptions {
+            target: Some("x86_64-pc-windows-msvc".to_string()),
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            struct __declspec(align(16)) Foo {
+                int a;
+            };
+            namespace rust_export {
+                using ::Foo;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(rs::Size::new(16), st.layout.size);
+        assert_eq!(rs::Align::new(16), st.layout.align);
+    }
+
+    #[test]
+    fn packed_attribute() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct __attribute__((packed)) Pod {
+                char c;
+                int a;
+                double d;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 1, 5], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(13), st.layout.size);
+        assert_eq!(Some(rs::Align::new(1)), st.packed);
+    }
+
+    #[test]
+    fn pragma_pack() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            _Pragma("pack(push, 2)")
+            struct Pod {
+                char c;
+                int a;
+                double d;
+            };
+            _Pragma("pack(pop)")
+            namespace rust_export {
+                using ::Pod;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 2, 6], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(14), st.layout.size);
+        assert_eq!(Some(rs::Align::new(2)), st.packed);
+    }
+
+    #[test]
+    fn packed_struct_field() {
+        let mut sess = Session::new();
+        cpp_lower!(sess, {
+            struct Inner {
+                int a;
+            };
+            struct __attribute__((packed)) Outer {
+                char c;
+                Inner inner;
+            };
+            namespace rust_export {
+                using ::Outer;
+            }
+        } => [
+            "packed struct `Outer` contains a struct"
+        ]);
+    }
+
+    #[test]
+    fn packed_struct_in_packed_struct() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct __attribute__((packed)) Inner {
+                char c;
+                int a;
+            };
+            struct __attribute__((packed)) Outer {
+                char c;
+                Inner inner;
+            };
+            namespace rust_export {
+                using ::Outer;
+            }
+        });
+        let db = &sess.db;
+        let outer = ir
+            .visible_structs(db)
+            .iter()
+            .map(|id| id.lookup(db))
+            .find(|st| &*st.name.as_str(db) == "Outer")
+            .unwrap();
+        assert_eq!(vec![0, 1], outer.layout.field_offsets);
+        assert_eq!(rs::Size::new(6), outer.layout.size);
+    }
+
+    fn msvc_session(target: &str) -> Session {
+        Session::with_options(SessionOptions {
+            target: Some(target.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn msvc_pod() {
+        let mut sess = msvc_session("x86_64-pc-windows-msvc");
+        let ir = cpp_lower!(sess, {
+            struct Pod {
+                char c;
+                short s;
+                int *p;
+                double d;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 2, 8, 16], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(24), st.layout.size);
+        assert_eq!(rs::Align::new(8), st.layout.align);
+    }
+
+    #[test]
+    fn msvc_long() {
+        let mut sess = msvc_session("x86_64-pc-windows-msvc");
+        let ir = cpp_lower!(sess, {
+            struct Longs {
+                long a;
+                unsigned long b;
+                long long c;
+            };
+            namespace rust_export {
+                using ::Longs;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        let tys: Vec<_> = st.fields.iter().map(|f| f.ty(db)).collect();
+        assert_eq!(vec![rs::Ty::I32, rs::Ty::U32, rs::Ty::I64], tys);
+        assert_eq!(vec![0, 4, 8], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(16), st.layout.size);
+
+        // Unlike the i386 System V ABI, 32-bit MSVC aligns 8-byte scalars to
+        // 8 inside structs.
+        let mut sess = msvc_session("i686-pc-windows-msvc");
+        let ir = cpp_lower!(sess, {
+            struct Longs {
+                long a;
+                long long b;
+            };
+            namespace rust_export {
+                using ::Longs;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 8], st.layout.field_offsets);
+        assert_eq!(rs::Align::new(8), st.layout.align);
+    }
+
+    #[test]
+    fn msvc_over_aligned() {
+        let mut sess = msvc_session("x86_64-pc-windows-msvc");
+        let ir = cpp_lower!(sess, {
+            struct alignas(32) Vec4 {
+                float x, y, z, w;
+            };
+            struct Particle {
+                int id;
+                Vec4 pos;
+            };
+            namespace rust_export {
+                using ::Particle;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 32], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(64), st.layout.size);
+        assert_eq!(rs::Align::new(32), st.layout.align);
+    }
+
+    #[test]
+    fn empty_struct() {
+        let mut sess = msvc_session("x86_64-pc-windows-msvc");
+        cpp_lower!(sess, {
+            struct Empty {};
+            namespace rust_export {
+                using ::Empty;
+            }
+        } => [
+            "empty struct `Empty` is not supported"
+        ]);
+    }
+
+    #[test]
+    fn anonymous_union_layout() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct Value {
+                char tag;
+                union {
+                    int i;
+                    double d;
+                    char bytes[12];
+                };
+                int trailing;
+            };
+            namespace rust_export {
+                using ::Value;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        let groups: Vec<_> = st.fields.iter().map(|f| f.union_group).collect();
+        assert_eq!(vec![None, Some(0), Some(0), Some(0), None], groups);
+        // The union is padded to 16 bytes, a multiple of its alignment.
+        assert_eq!(vec![0, 8, 8, 8, 24], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(32), st.layout.size);
+        assert_eq!(rs::Align::new(8), st.layout.align);
+    }
+
+    #[test]
+    fn anonymous_union_struct_member() {
+        let mut sess = Session::new();
+        cpp_lower!(sess, {
+            struct Inner {
+                int a;
+            };
+            struct Outer {
+                union {
+                    int i;
+                    Inner inner;
+                };
+            };
+            namespace rust_export {
+                using ::Outer;
+            }
+        } => [
+            "unsupported type for anonymous union member `inner`"
+        ]);
+    }
+
+    #[test]
+    fn deeply_nested_structs() {
+        const DEPTH: usize = 64;
+        let names: Vec<_> = (0..DEPTH).map(|idx| format!("S{}", idx)).collect();
+        let mut src = crate::test_util::SourceBuilder::default();
+        src.add_struct(&names[0], None, vec![("int", "a")]);
+        for pair in names.windows(2) {
+            src.add_struct(
+                &pair[1],
+                None,
+                vec![(pair[0].as_str(), "inner"), ("char", "c")],
+            );
+        }
+        src.export(&names[DEPTH - 1]);
+
+        let mut sess = Session::new();
+        let ir = crate::test_util::parse_and_lower(&mut sess, &src.build(), Default::default());
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        // Each level adds a char, padded to the int's alignment.
+        assert_eq!(rs::Size::new(4 * DEPTH as u64), st.layout.size);
+        assert_eq!(rs::Align::new(4), st.layout.align);
+    }
+
+    #[test]
+    fn large_struct() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct alignas(65536) Big {
+                int a;
+            };
+            namespace rust_export {
+                using ::Big;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(rs::Size::new(65536), st.layout.size);
+        assert_eq!(rs::Align::new(65536), st.layout.align);
+    }
+
+    #[test]
+    fn large_alignas() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct alignas(256) Foo {
+                char c;
+            };
+            struct Bar {
+                char c;
+                Foo foo;
+            };
+            namespace rust_export {
+                using ::Bar;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 256], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(512), st.layout.size);
+        assert_eq!(rs::Align::new(256), st.layout.align);
+    }
+
+    #[test]
+    fn i686_layout() {
+        let mut sess = Session::with_options(SessionOptions {
+            target: Some("i686-unknown-linux-gnu".to_string()),
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            struct Foo {
+                char c;
+                double d;
+                long long ll;
+                long l;
+            };
+            namespace rust_export {
+                using ::Foo;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(vec![0, 4, 12, 20], st.layout.field_offsets);
+        assert_eq!(rs::Size::new(24), st.layout.size);
+        assert_eq!(rs::Align::new(4), st.layout.align);
+    }
+
+    #[test]
+    fn void_return() {
+        let mut sess = Session::new();
+        cpp_lower!(sess, {
+            struct Counter {
+                int count;
+                void reset();
+            };
+            namespace rust_export {
+                using ::Counter;
+            }
+        });
+    }
+
+    #[test]
+    fn void_field() {
+        // clang won't parse a `void` field, so give one to a lowered struct.
+        use cc::RsTargetIr;
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct Holder {
+                int value;
+                char c;
+            };
+            namespace rust_export {
+                using ::Holder;
+            }
+        });
+        let db = &sess.db;
+        let mut st = (*ir.visible_structs(db)[0].lookup(db).cc_id.lookup(db)).clone();
+        st.fields[0].ty = cc::TypeRef::void();
+        let (_, errs) = db
+            .rs_struct_from_cc(db.intern_cc_struct(Arc::new(st)))
+            .split();
+        let errs: Vec<_> = errs.iter().map(|diag| diag.message()).collect();
+        assert!(
+            errs.contains(&"field `value` has type `void`"),
+            "{:?}",
+            errs
+        );
+    }
+
+    #[test]
+    fn long_size_per_target() {
+        let lower = |target: &str| {
+            let mut sess = Session::with_options(SessionOptions {
+                target: Some(target.to_string()),
+                ..Default::default()
+            });
+            let ir = cpp_lower!(sess, {
+                struct Foo {
+                    long l;
+                    unsigned long ul;
+                };
+                namespace rust_export {
+                    using ::Foo;
+                }
+            });
+            let db = &sess.db;
+            let st = ir.visible_structs(db)[0].lookup(db);
+            let tys: Vec<_> = st.fields.iter().map(|field| field.ty(db)).collect();
+            (tys, st.layout.size)
+        };
+
+        // LP64
+        let (tys, size) = lower("x86_64-unknown-linux-gnu");
+        assert_eq!(tys, vec![rs::Ty::I64, rs::Ty::U64]);
+        assert_eq!(size, rs::Size::new(16));
+
+        // LLP64
+        let (tys, size) = lower("x86_64-pc-windows-msvc");
+        assert_eq!(tys, vec![rs::Ty::I32, rs::Ty::U32]);
+        assert_eq!(size, rs::Size::new(8));
+    }
+
+    #[test]
+    fn multiple_modules() {
+        let mut sess = Session::new();
+        let ir = cpp_lower_modules!(sess, [
+            {
+                struct Foo {
+                    int a;
+                };
+                namespace rust_export {
+                    using ::Foo;
+                }
+            },
+            {
+                struct Bar {
+                    char c;
+                };
+                namespace rust_export {
+                    using ::Bar;
+                }
+            }
+        ]);
+        let db = &sess.db;
+        let mut names = ir
+            .visible_structs(db)
+            .iter()
+            .map(|st| st.lookup(db).name.as_str(db).to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["Bar", "Foo"]);
+    }
+
+    #[test]
+    fn conflicting_modules() {
+        let mut sess = Session::test();
+        cpp_lower_modules!(sess, [
+            {
+                struct Foo {
+                    int a;
+                };
+                namespace rust_export {
+                    using ::Foo;
+                }
+            },
+            {
+                namespace ns {
+                    struct Foo {
+                        char c;
+                    };
+                }
+                namespace rust_export {
+                    using ::ns::Foo;
+                }
+            }
+        ] => [
+            "conflicting exports named `Foo`"
+        ]);
+    }
+
+    fn bound_struct_names(db: &crate::Database, krate: &rs::BindingsCrate) -> Vec<String> {
+        fn visit(db: &crate::Database, item: &rs::ItemKind, names: &mut Vec<String>) {
+            match item {
+                rs::ItemKind::Module(id) => {
+                    for child in id.lookup(db).children {
+                        visit(db, &child, names);
+                    }
+                }
+                rs::ItemKind::Struct(id) => names.push(id.lookup(db).name.as_str(db).to_string()),
+                rs::ItemKind::Opaque(_) | rs::ItemKind::Alias(_) | rs::ItemKind::Reexport(..) => (),
+            }
+        }
+        let mut names = vec![];
+        visit(db, &rs::ItemKind::Module(krate.root), &mut names);
+        names.sort();
+        names
+    }
+
+    fn bound_opaques(db: &crate::Database, krate: &rs::BindingsCrate) -> Vec<cc::OpaqueId> {
+        fn visit(db: &crate::Database, item: &rs::ItemKind, opaques: &mut Vec<cc::OpaqueId>) {
+            match item {
+                rs::ItemKind::Module(id) => {
+                    for child in id.lookup(db).children {
+                        visit(db, &child, opaques);
+                    }
+                }
+                rs::ItemKind::Opaque(id) => opaques.push(*id),
+                rs::ItemKind::Struct(_) | rs::ItemKind::Alias(_) | rs::ItemKind::Reexport(..) => (),
+            }
+        }
+        let mut opaques = vec![];
+        visit(db, &rs::ItemKind::Module(krate.root), &mut opaques);
+        opaques
+    }
+
+    #[test]
+    fn opaque_handle_shared() {
+        let mut sess = Session::test();
+        let ir = cpp_lower_modules!(sess, [
+            {
+                struct FooHandle;
+                struct Opener {
+                    int flags;
+                    FooHandle* open();
+                };
+                namespace rust_export {
+                    using ::Opener;
+                }
+            },
+            {
+                struct FooHandle;
+                struct Closer {
+                    int flags;
+                    void close(FooHandle* handle);
+                };
+                namespace rust_export {
+                    using ::Closer;
+                }
+            }
+        ]);
+        let db = &sess.db;
+        let opaques = bound_opaques(db, &ir);
+        assert_eq!(opaques.len(), 1);
+        assert_eq!(&*opaques[0].lookup(db).name.as_str(db), "FooHandle");
+
+        let pointee = |ty: rs::Ty| match ty {
+            rs::Ty::Ptr { pointee, .. } => pointee.as_rs(db).skip_errs(),
+            ty => panic!("expected a pointer, got {:?}", ty),
+        };
+        let structs = ir.visible_structs(db);
+        let method = |name: &str| {
+            let st = structs
+                .iter()
+                .map(|id| id.lookup(db))
+                .find(|st| &*st.name.as_str(db) == name)
+                .unwrap();
+            st.methods[0].clone()
+        };
+        let opened = pointee(method("Opener").return_ty(db));
+        let closed = pointee(method("Closer").param_tys(db).next().unwrap());
+        assert_eq!(opened, rs::Ty::Opaque(opaques[0]));
+        assert_eq!(closed, opened);
+    }
+
+    #[test]
+    fn opaque_by_value() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct FooHandle;
+            struct Pool {
+                int refs;
+                void take(FooHandle handle);
+            };
+            namespace rust_export {
+                using ::Pool;
+            }
+        } => [
+            "incomplete type `::FooHandle` passed by value"
+        ]);
+    }
+
+    #[test]
+    fn static_method_returns_reference() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct Item {
+                int id;
+            };
+            struct Registry {
+                int count;
+                static const Item& fallback();
+            };
+            namespace rust_export {
+                using ::Registry;
+            }
+        } => errors: [], warnings: [
+            "reference returned from static method `Registry::fallback` is bound as a raw pointer"
+        ]);
+    }
+
+    #[test]
+    fn array_layouts() {
+        let mut sess = Session::test();
+        let ir = cpp_lower!(sess, {
+            struct Cell {
+                char piece;
+                int owner;
+            };
+            struct Board {
+                Cell cells[8][8];
+                char marks[8][8];
+                int turn;
+            };
+            struct Tagged {
+                char tag;
+                Cell cells[3];
+                short counts[2][3];
+            };
+            namespace rust_export {
+                using ::Board;
+                using ::Tagged;
+            }
+        });
+        // Lowering checks the computed layouts against clang's, so these are
+        // also the values of sizeof and offsetof.
+        let db = &sess.db;
+        let layouts: Vec<_> = ir
+            .visible_structs(db)
+            .iter()
+            .map(|id| {
+                let st = id.lookup(db);
+                let layout = &st.layout;
+                (
+                    st.name.as_str(db).to_string(),
+                    layout.size.get(),
+                    layout.field_offsets.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            layouts,
+            vec![
+                ("Board".to_string(), 580, vec![0, 512, 576]),
+                ("Tagged".to_string(), 40, vec![0, 4, 28]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_array_element_reported_once() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct __attribute__((__packed__)) Cell {
+                char piece;
+                int owner;
+            };
+            struct Board {
+                Cell cells[8][8];
+                int turn;
+            };
+            namespace rust_export {
+                using ::Board;
+            }
+        } => [
+            "packed structs not supported"
+        ]);
+    }
+
+    #[test]
+    fn reachable_through_method() {
+        let mut sess = Session::test();
+        let ir = cpp_lower!(sess, {
+            struct Returned {
+                int a;
+            };
+            struct Param {
+                char c;
+            };
+            struct Exported {
+                Returned get() const;
+                void set(Param p);
+                int b;
+            };
+            namespace rust_export {
+                using ::Exported;
+            }
+        });
+        assert_eq!(
+            bound_struct_names(&sess.db, &ir),
+            vec!["Exported", "Param", "Returned"]
+        );
+    }
+
+    fn reachable_names(sess: &mut Session, src: &str) -> Vec<String> {
+        use cc::CcModule;
+        let mut names = vec![];
+        let index = libclang::create_index();
+        let (ast, _) = libclang::parse_with(
+            &sess.db,
+            &index,
+            libclang::ModuleId::new(0),
+            std::path::Path::new(crate::test_util::TEST_FILENAME),
+            Some(src.to_string()),
+        )
+        .unwrap();
+        sess.db.set_rs_source_root(None);
+        libclang::set_ast(&mut sess.db, vec![ast], |db| {
+            let lib = db.cc_module(libclang::ModuleId::new(0)).skip_errs();
+            for (def, _) in lib.reachable_items(&*db) {
+                names.push(def.name(&*db).as_str(&*db).to_string());
+            }
+        });
+        names
+    }
+
+    #[test]
+    fn reachable_through_function() {
+        let mut sess = Session::test();
+        let names = reachable_names(
+            &mut sess,
+            "
+            struct Param {
+                int a;
+            };
+            struct Returned {
+                Param p;
+            };
+            Returned make(Param p);
+            namespace rust_export {
+                using ::make;
+            }
+            ",
+        );
+        assert_eq!(names, vec!["make", "Param", "Returned"]);
+    }
+
+    #[test]
+    fn transitive_error_notes() {
+        let mut sess = Session::test();
+        let (_, errs) = crate::test_util::lower_files(
+            &mut sess,
+            &[(
+                "test.cc",
+                stringify!({
+                    struct Inner {
+                        int a : 3, b : 2;
+                    };
+                    struct Middle {
+                        Inner inner;
+                    };
+                    struct Outer {
+                        Middle middle;
+                    };
+                    namespace rust_export {
+                        using ::Outer;
+                    }
+                }),
+            )],
+        );
+        let errs: Vec<_> = errs
+            .iter()
+            .map(|diag| (diag.message(), diag.notes().to_vec()))
+            .collect();
+        assert_eq!(
+            errs,
+            [(
+                "bitfields are not supported",
+                vec![
+                    "required because `Outer::middle.inner` (exported as `Outer`) has this type"
+                        .to_string()
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn export_function() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct Pod {
+                int a;
+            };
+            Pod make_pod(int a);
+            namespace rust_export {
+                using ::Pod;
+                using ::make_pod;
+            }
+        } => [
+            "cannot export function `make_pod`"
+        ]);
+    }
+
+    #[test]
+    fn export_function_points_at_import() {
+        let mut sess = Session::test();
+        let src = stringify!({
+            struct Pod {
+                int a;
+            };
+            Pod make_pod(int a);
+            namespace rust_export {
+                using ::Pod;
+                using ::make_pod;
+            }
+        });
+        let (_, errs) = crate::test_util::lower_files(&mut sess, &[("test.cc", src)]);
+        let errs: Vec<_> = errs.iter().collect();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].message(), "cannot export function `make_pod`");
+        // The file holds `src` without its outer braces.
+        let import = src.rfind("make_pod").unwrap() - 1;
+        assert!(errs[0].primary_span().unwrap().range().contains(&import));
+    }
+
+    #[test]
+    fn reachable_cycles() {
+        let mut sess = Session::test();
+        // There are no pointer types yet, so the cycles go through methods.
+        let names = reachable_names(
+            &mut sess,
+            "
+            struct B;
+            struct A {
+                A clone() const;
+                B get_b() const;
+            };
+            struct B {
+                A get_a() const;
+                A a1, a2;
+            };
+            namespace rust_export {
+                using ::A;
+                using ::B;
+            }
+            ",
+        );
+        assert_eq!(names, vec!["A", "B"]);
+    }
+
+    #[test]
+    fn export_status() {
+        use cc::CcModule;
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct C {
+                int c;
+            };
+            struct D {
+                int d;
+            };
+            struct A {
+                C c;
+            };
+            struct B {
+                D d;
+                A a;
+            };
+            namespace rust_export {
+                using ::B;
+                using ::A;
+            }
+        });
+        let db = &sess.db;
+        let lib = db.cc_module(libclang::ModuleId::new(0)).skip_errs();
+        let items = lib
+            .all_items(db)
+            .into_iter()
+            .map(|(def, status)| {
+                let status = match status {
+                    ExportStatus::Explicit(path) => format!("explicit {}", path.display(db)),
+                    ExportStatus::Transitive(provenance) => {
+                        format!("transitive via {}", provenance.display(db))
+                    }
+                };
+                (def.name(db).as_str(db).to_string(), status)
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            ("B", "explicit B"),
+            ("A", "explicit A"),
+            ("D", "transitive via B::d"),
+            ("C", "transitive via A::c"),
+        ];
+        assert_eq!(
+            items,
+            expected
+                .iter()
+                .map(|(name, status)| (name.to_string(), status.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            lib.exported_structs()
+                .map(|st| st.lookup(db).name.as_str(db).to_string())
+                .collect::<Vec<_>>(),
+            vec!["B", "A"]
+        );
+    }
+
+    #[test]
+    fn identical_definitions() {
+        let mut sess = Session::test();
+        let ir = cpp_lower_modules!(sess, [
+            {
+                struct Point {
+                    int x, y;
+                };
+                struct A {
+                    Point p;
+                };
+                namespace rust_export {
+                    using ::A;
+                    using ::Point;
+                }
+            },
+            {
+                struct Point {
+                    int x, y;
+                };
+                struct B {
+                    Point p;
+                };
+                namespace rust_export {
+                    using ::B;
+                    using ::Point;
+                }
+            }
+        ]);
+        assert_eq!(bound_struct_names(&sess.db, &ir), vec!["A", "B", "Point"]);
+    }
+
+    #[test]
+    fn odr_violation() {
+        let mut sess = Session::test();
+        cpp_lower_modules!(sess, [
+            {
+                struct Point {
+                    int x, y;
+                };
+                namespace rust_export {
+                    using ::Point;
+                }
+            },
+            {
+                struct Point {
+                    long x, y;
+                };
+                namespace rust_export {
+                    using ::Point;
+                }
+            }
+        ] => [
+            "conflicting definitions of `Point`"
+        ]);
+    }
+
+    #[test]
+    fn struct_and_namespace_collide() {
+        let mut sess = Session::test();
+        cpp_lower_modules!(sess, [
+            {
+                namespace geom {
+                    struct Point {
+                        int x, y;
+                    };
+                }
+                namespace rust_export {
+                    using ::geom::Point;
+                }
+            },
+            {
+                struct geom {
+                    int dims;
+                };
+                namespace rust_export {
+                    using ::geom;
+                }
+            }
+        ] => [
+            "multiple items named `geom` in the generated bindings"
+        ]);
+    }
+
+    #[test]
+    fn nested_struct_and_namespace_collide() {
+        let mut sess = Session::test();
+        cpp_lower_modules!(sess, [
+            {
+                namespace outer {
+                    namespace inner {
+                        struct A {
+                            int a;
+                        };
+                        struct B {
+                            int b;
+                        };
+                    }
+                }
+                namespace rust_export {
+                    using ::outer::inner::A;
+                    using ::outer::inner::B;
+                }
+            },
+            {
+                namespace outer {
+                    struct inner {
+                        int i;
+                    };
+                }
+                namespace rust_export {
+                    using ::outer::inner;
+                }
+            }
+        ] => [
+            "multiple items named `inner` in the generated bindings"
+        ]);
+    }
+
+    #[test]
+    fn idents_are_interned() {
+        use common::Ident;
+        let db = &Database::new();
+        let foo = Ident::new(db, "foo").unwrap();
+        assert_eq!(foo, Ident::new_mangled(db, "foo"));
+        assert_eq!(&*foo.as_str(db), "foo");
+        assert_ne!(foo, Ident::new(db, "bar").unwrap());
+        assert_eq!(Ident::new(db, &foo.as_str(db)), Ok(foo));
+        let set: HashSet<_> = vec![
+            foo,
+            Ident::new_mangled(db, "foo"),
+            Ident::new_mangled(db, "bar"),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn invalid_idents() {
+        use common::Ident;
+        let db = &Database::new();
+        let reason = |id: &str| Ident::new(db, id).unwrap_err().reason;
+        assert_eq!(reason(""), "identifiers can't be empty");
+        assert_eq!(reason("vector<int>"), "identifiers can't contain `<`");
+        assert_eq!(reason("unsigned int"), "identifiers can't contain ` `");
+        assert_eq!(reason("operator=="), "identifiers can't contain `=`");
+        assert_eq!(reason("2d"), "identifiers can't start with `2`");
+        assert_eq!(reason("_"), "`_` is not an identifier in Rust");
+        assert!(Ident::new(db, "_private2").is_ok());
+        assert_eq!(
+            Ident::new(db, "vector<int>").unwrap_err().to_string(),
+            "`vector<int>` is not a valid identifier"
+        );
+    }
+
+    #[test]
+    fn same_name_in_different_namespaces() {
+        let mut sess = Session::test();
+        let krate = cpp_lower_modules!(sess, [
+            {
+                namespace a {
+                    namespace b {
+                        struct X {
+                            int x;
+                        };
+                    }
+                }
+                namespace rust_export {
+                    using ::a::b::X;
+                }
+            },
+            {
+                struct b {
+                    int b;
+                };
+                namespace rust_export {
+                    using ::b;
+                }
+            }
+        ]);
+        assert_eq!(bound_struct_names(&sess.db, &krate), vec!["X", "b"]);
+    }
+
+    /// Field types for generated structs, besides the generated structs
+    /// themselves.
+    const PRIMITIVES: &[&str] = &[
+        "char",
+        "bool",
+        "short",
+        "int",
+        "unsigned",
+        "long",
+        "long long",
+        "float",
+        "double",
+        "int*",
+    ];
+
+    /// A struct generated by `generated_layouts_match_clang`. Field types index
+    /// into `PRIMITIVES`, followed by the structs generated before this one.
+    #[derive(Clone, Debug)]
+    struct GenStruct {
+        align: Option<u32>,
+        fields: Vec<usize>,
+    }
+
+    /// A small deterministic PRNG (xorshift64), so failures reproduce from the
+    /// seed alone.
+    struct Rng(u64);
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn gen_structs(rng: &mut Rng) -> Vec<GenStruct> {
+        let mut structs = vec![];
+        for idx in 0..1 + rng.below(3) {
+            let align = match rng.below(4) {
+                0 => Some([8, 16][rng.below(2)]),
+                _ => None,
+            };
+            let fields = (0..1 + rng.below(6))
+                .map(|_| match rng.below(4) {
+                    0 if idx > 0 => PRIMITIVES.len() + rng.below(idx),
+                    _ => rng.below(PRIMITIVES.len()),
+                })
+                .collect();
+            structs.push(GenStruct { align, fields });
+        }
+        structs
+    }
+
+    fn gen_source(structs: &[GenStruct]) -> String {
+        let names: Vec<_> = (0..structs.len()).map(|idx| format!("S{}", idx)).collect();
+        let field_names: Vec<_> = (0..6).map(|idx| format!("f{}", idx)).collect();
+        let mut src = crate::test_util::SourceBuilder::default();
+        for (st, name) in structs.iter().zip(&names) {
+            let fields = st.fields.iter().zip(&field_names).map(|(&ty, field)| {
+                let ty = match PRIMITIVES.get(ty) {
+                    Some(prim) => prim,
+                    None => names[ty - PRIMITIVES.len()].as_str(),
+                };
+                (ty, field.as_str())
+            });
+            src.add_struct(name, st.align, fields).export(name);
+        }
+        src.build()
+    }
+
+    fn lowering_errors(structs: &[GenStruct]) -> Vec<String> {
+        let mut sess = Session::test();
+        crate::test_util::lower_modules(&mut sess, &[&gen_source(structs)]).1
+    }
+
+    /// Returns every way of making `structs` one step simpler.
+    fn shrink_candidates(structs: &[GenStruct]) -> Vec<Vec<GenStruct>> {
+        let mut candidates = vec![];
+        for idx in 0..structs.len() {
+            let ty = PRIMITIVES.len() + idx;
+            let is_used = structs.iter().any(|st| st.fields.contains(&ty));
+            if structs.len() > 1 && !is_used {
+                let mut smaller = structs.to_vec();
+                smaller.remove(idx);
+                for field in smaller.iter_mut().flat_map(|st| st.fields.iter_mut()) {
+                    if *field > ty {
+                        *field -= 1;
+                    }
+                }
+                candidates.push(smaller);
+            }
+            if structs[idx].align.is_some() {
+                let mut smaller = structs.to_vec();
+                smaller[idx].align = None;
+                candidates.push(smaller);
+            }
+            for field in 0..structs[idx].fields.len() {
+                if structs[idx].fields.len() > 1 {
+                    let mut smaller = structs.to_vec();
+                    smaller[idx].fields.remove(field);
+                    candidates.push(smaller);
+                }
+                if structs[idx].fields[field] != 0 {
+                    let mut smaller = structs.to_vec();
+                    smaller[idx].fields[field] = 0;
+                    candidates.push(smaller);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Simplifies a failing input until no single step keeps it failing.
+    fn shrink(mut structs: Vec<GenStruct>) -> Vec<GenStruct> {
+        while let Some(smaller) = shrink_candidates(&structs)
+            .into_iter()
+            .find(|candidate| !lowering_errors(candidate).is_empty())
+        {
+            structs = smaller;
+        }
+        structs
+    }
+
+    fn env_or(var: &str, default: u64) -> u64 {
+        std::env::var(var)
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// Checks that the layouts we compute for random POD structs always agree
+    /// with clang's. Set `MOSAIC_LAYOUT_SEED` to try different structs, and
+    /// `MOSAIC_LAYOUT_CASES` to try more of them.
+    #[test]
+    fn generated_layouts_match_clang() {
+        let seed = env_or("MOSAIC_LAYOUT_SEED", 0x5eed).max(1);
+        let cases = env_or("MOSAIC_LAYOUT_CASES", 64);
+        let mut rng = Rng(seed);
+        for case in 0..cases {
+            let structs = gen_structs(&mut rng);
+            if lowering_errors(&structs).is_empty() {
+                continue;
+            }
+            let minimal = shrink(structs);
+            panic!(
+                "lowering failed for case {} of seed {}: {:?}\n{}",
+                case,
+                seed,
+                lowering_errors(&minimal),
+                gen_source(&minimal)
+            );
+        }
+    }
 }
@@ -0,0 +1,618 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Dumps the Rust IR as JSON, for `--emit ir-json`.
+//!
+//! The IR refers to most things by interned ids, which mean nothing outside
+//! the database. The types here mirror the IR with every id resolved to a
+//! name or path, so the document can be read on its own.
+//!
+//! Bump [`VERSION`] whenever the schema changes in a way readers could notice.
+
+use crate::{
+    codegen, diagnostics,
+    ir::{
+        cc::{self, RsTargetIr},
+        rs,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io::Write};
+
+/// The version of the schema.
+pub(crate) const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Document {
+    pub version: u32,
+    pub root: Module,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Module {
+    pub name: String,
+    pub vis: Visibility,
+    /// The cargo feature the module is gated on, for config sets.
+    pub feature: Option<String>,
+    pub modules: Vec<Module>,
+    pub structs: Vec<Struct>,
+    pub opaques: Vec<Opaque>,
+    pub aliases: Vec<Alias>,
+    pub reexports: Vec<Reexport>,
+}
+
+/// A `pub use` of an item in another module.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Reexport {
+    /// The name the item is exported under.
+    pub name: String,
+    /// The path of the item, relative to the crate root.
+    pub path: String,
+    pub kind: ItemKind,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ItemKind {
+    Module,
+    Struct,
+    Opaque,
+    Alias,
+    Reexport,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Struct {
+    pub name: String,
+    /// The path of the struct, relative to the crate root.
+    pub path: String,
+    /// The fully qualified path of the C++ class the struct binds.
+    pub cc_path: String,
+    pub vis: Visibility,
+    pub repr: Repr,
+    pub size: u64,
+    pub align: u32,
+    /// The largest alignment any field gets, if the struct is packed.
+    pub packed: Option<u32>,
+    pub fields: Vec<Field>,
+    pub methods: Vec<Method>,
+    pub span: Span,
+}
+
+/// A marker type for pointers to a C++ type that is never defined.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Opaque {
+    pub name: String,
+    /// The path of the marker type, relative to the crate root.
+    pub path: String,
+    /// The fully qualified path of the C++ type.
+    pub cc_path: String,
+}
+
+/// A type alias or newtype for a C++ alias of a builtin type.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Alias {
+    pub name: String,
+    /// The path of the alias, relative to the crate root.
+    pub path: String,
+    /// The fully qualified path of the C++ alias.
+    pub cc_path: String,
+    /// The builtin type it aliases.
+    pub target: Ty,
+    /// Whether it's a `#[repr(transparent)]` newtype instead of a type alias.
+    pub newtype: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Field {
+    pub name: String,
+    /// The name of the field in C++.
+    pub cc_name: String,
+    pub vis: Visibility,
+    pub ty: Ty,
+    pub offset: u64,
+    /// The anonymous union the field is a member of, if any. Members of the
+    /// same union share an offset.
+    pub union_group: Option<u32>,
+    pub span: Span,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Method {
+    pub name: String,
+    /// The name of the method in C++.
+    pub cc_name: String,
+    /// Whether the method takes `self`, as opposed to being static.
+    pub has_self: bool,
+    /// Whether `self` is taken by shared reference.
+    pub is_const: bool,
+    /// The parameters, not including `self`.
+    pub params: Vec<Param>,
+    pub return_ty: Ty,
+    /// Whether the method is declared `noexcept` or `throw()`.
+    pub noexcept: bool,
+    /// The calling convention, as named by libclang, like `cdecl`.
+    pub calling_conv: Option<String>,
+    pub nodiscard: bool,
+    pub deleted: bool,
+    pub defaulted: bool,
+    pub constexpr: bool,
+    /// What a `constexpr` static method evaluated to, if it's bound as a
+    /// `const fn`.
+    pub const_value: Option<String>,
+    /// The symbol of the C++ method. Accessors generated for the fields of
+    /// opaque structs have none.
+    pub mangled_name: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Param {
+    pub name: Option<String>,
+    pub ty: Ty,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Ty {
+    /// A type that could not be lowered.
+    Error,
+    /// A builtin type, like `i32` or `()`.
+    Primitive {
+        name: String,
+    },
+    Struct {
+        path: String,
+    },
+    Opaque {
+        path: String,
+    },
+    Alias {
+        path: String,
+    },
+    Pointer {
+        mutable: bool,
+        pointee: Box<Ty>,
+    },
+    Array {
+        len: u64,
+        elem: Box<Ty>,
+    },
+    /// A C++ reference, before deciding how it's passed.
+    Reference {
+        mutable: bool,
+        pointee: Box<Ty>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Visibility {
+    Public,
+    Crate,
+    Private,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Repr {
+    C,
+    Transparent,
+    Opaque,
+}
+
+/// A range of bytes in a source file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Span {
+    pub file: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Writes the IR of `krate` to `out` as a JSON document.
+///
+/// Must be called from inside `set_ast`, since spans are resolved to file
+/// names through the AST.
+pub(crate) fn write(
+    db: &impl RsTargetIr,
+    krate: &rs::BindingsCrate,
+    out: impl Write,
+) -> serde_json::Result<()> {
+    let mut dumper = Dumper {
+        db,
+        struct_paths: HashMap::new(),
+        opaque_paths: HashMap::new(),
+        alias_paths: HashMap::new(),
+    };
+    dumper.collect_paths(krate.root, &mut vec![]);
+    let doc = Document {
+        version: VERSION,
+        root: dumper.module(krate.root),
+    };
+    serde_json::to_writer_pretty(out, &doc)
+}
+
+struct Dumper<'a, DB> {
+    db: &'a DB,
+    /// The path each struct is defined at.
+    struct_paths: HashMap<rs::StructId, String>,
+    /// The path the marker type of each opaque type is defined at.
+    opaque_paths: HashMap<cc::OpaqueId, String>,
+    /// The path each alias is defined at.
+    alias_paths: HashMap<cc::AliasId, String>,
+}
+
+impl<DB: RsTargetIr> Dumper<'_, DB> {
+    fn collect_paths(&mut self, id: rs::ModuleId, path: &mut Vec<String>) {
+        for item in id.lookup(self.db).children {
+            match item {
+                rs::ItemKind::Module(id) => {
+                    path.push(id.lookup(self.db).name.as_str(self.db).to_string());
+                    self.collect_paths(id, path);
+                    path.pop();
+                }
+                rs::ItemKind::Struct(id) => {
+                    let name = id.lookup(self.db).name.as_str(self.db).to_string();
+                    self.struct_paths.insert(id, join_path(path, &name));
+                }
+                rs::ItemKind::Opaque(id) => {
+                    let name = id.lookup(self.db).name.as_str(self.db).to_string();
+                    self.opaque_paths.insert(id, join_path(path, &name));
+                }
+                rs::ItemKind::Alias(id) => {
+                    let name = id.lookup(self.db).name.as_str(self.db).to_string();
+                    self.alias_paths.insert(id, join_path(path, &name));
+                }
+                rs::ItemKind::Reexport(..) => {}
+            }
+        }
+    }
+
+    fn module(&self, id: rs::ModuleId) -> Module {
+        let module = id.lookup(self.db);
+        let mut out = Module {
+            name: module.name.as_str(self.db).to_string(),
+            vis: module.vis.into(),
+            feature: module.feature.clone(),
+            modules: vec![],
+            structs: vec![],
+            opaques: vec![],
+            aliases: vec![],
+            reexports: vec![],
+        };
+        for item in module.children {
+            match item {
+                rs::ItemKind::Module(id) => out.modules.push(self.module(id)),
+                rs::ItemKind::Struct(id) => out.structs.push(self.strukt(id)),
+                rs::ItemKind::Opaque(id) => out.opaques.push(self.opaque(id)),
+                rs::ItemKind::Alias(id) => out.aliases.push(self.alias(id)),
+                rs::ItemKind::Reexport(target, name, kind) => out.reexports.push(Reexport {
+                    name: name.as_str(self.db).to_string(),
+                    path: relative_path(target.lookup(self.db).display(self.db).to_string()),
+                    kind: match *kind {
+                        rs::ItemKind::Module(_) => ItemKind::Module,
+                        rs::ItemKind::Struct(_) => ItemKind::Struct,
+                        rs::ItemKind::Opaque(_) => ItemKind::Opaque,
+                        rs::ItemKind::Alias(_) => ItemKind::Alias,
+                        rs::ItemKind::Reexport(..) => ItemKind::Reexport,
+                    },
+                }),
+            }
+        }
+        out
+    }
+
+    fn strukt(&self, id: rs::StructId) -> Struct {
+        let db = self.db;
+        let st = id.lookup(db);
+        let fields = st
+            .fields
+            .iter()
+            .zip(&st.layout.field_offsets)
+            .map(|(field, &offset)| Field {
+                name: field.name.as_str(self.db).to_string(),
+                cc_name: field.cc_name.as_str(self.db).to_string(),
+                vis: field.vis.into(),
+                ty: self.ty(field.ty(db)),
+                offset,
+                union_group: field.union_group,
+                span: self.span(&field.span),
+            })
+            .collect();
+        let methods = st
+            .methods
+            .iter()
+            .map(|meth| {
+                let func = meth.func();
+                Method {
+                    name: meth.name.as_str(self.db).to_string(),
+                    cc_name: func.name.as_str(self.db).to_string(),
+                    has_self: func.is_method,
+                    is_const: func.is_const,
+                    params: func
+                        .param_names
+                        .iter()
+                        .zip(meth.param_tys(db))
+                        .map(|(name, ty)| Param {
+                            name: name.map(|name| name.as_str(db).to_string()),
+                            ty: self.ty(ty),
+                        })
+                        .collect(),
+                    return_ty: self.ty(meth.return_ty(db)),
+                    noexcept: func.attrs.noexcept,
+                    calling_conv: func.attrs.calling_conv.clone(),
+                    nodiscard: func.attrs.nodiscard,
+                    deleted: func.attrs.deleted,
+                    defaulted: func.attrs.defaulted,
+                    constexpr: func.attrs.constexpr,
+                    const_value: func.const_value.map(|val| val.to_string()),
+                    mangled_name: func.attrs.mangled_name.clone(),
+                    span: self.span(&func.span),
+                }
+            })
+            .collect();
+        Struct {
+            name: st.name.as_str(self.db).to_string(),
+            path: self.struct_paths[&id].clone(),
+            cc_path: st.cc_id.lookup(db).path(db).display(db).to_string(),
+            vis: st.vis.into(),
+            repr: match st.repr {
+                rs::Repr::C => Repr::C,
+                rs::Repr::Transparent => Repr::Transparent,
+                rs::Repr::Opaque => Repr::Opaque,
+            },
+            size: st.layout.size.get(),
+            align: st.layout.align.get(),
+            packed: st.packed.map(|align| align.get()),
+            fields,
+            methods,
+            span: self.span(&st.span),
+        }
+    }
+
+    fn opaque(&self, id: cc::OpaqueId) -> Opaque {
+        let opaque = id.lookup(self.db);
+        Opaque {
+            name: opaque.name.as_str(self.db).to_string(),
+            path: self.opaque_paths[&id].clone(),
+            cc_path: opaque.path(self.db).display(self.db).to_string(),
+        }
+    }
+
+    fn alias(&self, id: cc::AliasId) -> Alias {
+        let alias = id.lookup(self.db);
+        Alias {
+            name: alias.name.as_str(self.db).to_string(),
+            path: self.alias_paths[&id].clone(),
+            cc_path: alias.path(self.db).display(self.db).to_string(),
+            target: self.ty(alias.rs_target(self.db)),
+            newtype: alias.newtype,
+        }
+    }
+
+    fn ty(&self, ty: rs::Ty) -> Ty {
+        match ty {
+            rs::Ty::Error => Ty::Error,
+            rs::Ty::Struct(id) => Ty::Struct {
+                path: self.struct_paths[&id].clone(),
+            },
+            rs::Ty::Opaque(id) => Ty::Opaque {
+                path: self.opaque_paths[&id].clone(),
+            },
+            rs::Ty::Alias(id) => Ty::Alias {
+                path: self.alias_paths[&id].clone(),
+            },
+            rs::Ty::Ptr { pointee, mutable } => Ty::Pointer {
+                mutable,
+                pointee: Box::new(self.ty(pointee.as_rs(self.db).skip_errs())),
+            },
+            rs::Ty::Array { elem, len } => Ty::Array {
+                len,
+                elem: Box::new(self.ty(elem.as_rs(self.db).skip_errs())),
+            },
+            rs::Ty::Ref { pointee, mutable } => Ty::Reference {
+                mutable,
+                pointee: Box::new(self.ty(pointee.as_rs(self.db).skip_errs())),
+            },
+            rs::Ty::CChar => Ty::Primitive {
+                name: "c_char".into(),
+            },
+            ty => Ty::Primitive {
+                name: codegen::ty_to_string(self.db, ty),
+            },
+        }
+    }
+
+    fn span(&self, span: &diagnostics::Span) -> Span {
+        let range = span.range();
+        Span {
+            file: span.file_id().name(self.db),
+            start: range.start,
+            end: range.end,
+        }
+    }
+}
+
+fn join_path(path: &[String], name: &str) -> String {
+    path.iter()
+        .map(String::as_str)
+        .filter(|seg| !seg.is_empty())
+        .chain(Some(name))
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// Strips the leading `crate::` from a path, if any.
+fn relative_path(path: String) -> String {
+    match path.strip_prefix("crate::") {
+        Some(rest) => rest.to_string(),
+        None => path,
+    }
+}
+
+impl From<rs::Visibility> for Visibility {
+    fn from(vis: rs::Visibility) -> Self {
+        match vis {
+            rs::Visibility::Public => Visibility::Public,
+            rs::Visibility::Crate => Visibility::Crate,
+            rs::Visibility::Private => Visibility::Private,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    fn find_struct<'a>(module: &'a Module, path: &str) -> Option<&'a Struct> {
+        module
+            .structs
+            .iter()
+            .find(|st| st.path == path)
+            .or_else(|| module.modules.iter().find_map(|m| find_struct(m, path)))
+    }
+
+    #[test]
+    fn dump_roundtrips() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = dir.path().join("test.h");
+        std::fs::write(
+            &header,
+            "
+            struct Inner {
+                char c;
+            };
+            struct Outer {
+                Inner inner;
+                int *p;
+                double d;
+                int get(int idx) const;
+            };
+            namespace rust_export {
+                using ::Outer;
+            }
+            ",
+        )
+        .unwrap();
+
+        let mut sess = Session::test();
+        sess.parse_header(&header).unwrap();
+        let mut json = vec![];
+        let generated = sess.generate_with_ir_json(std::io::sink(), std::io::sink(), &mut json);
+        assert!(generated.is_some());
+
+        let doc: Document = serde_json::from_slice(&json).unwrap();
+        assert_eq!(doc.version, VERSION);
+        let export = doc
+            .root
+            .modules
+            .iter()
+            .find(|m| m.name == "export")
+            .unwrap();
+        assert_eq!(export.reexports.len(), 1);
+        assert_eq!(export.reexports[0].name, "Outer");
+        assert_eq!(export.reexports[0].kind, ItemKind::Struct);
+
+        let outer = find_struct(&doc.root, &export.reexports[0].path).unwrap();
+        assert_eq!(outer.cc_path, "::Outer");
+        assert_eq!(outer.repr, Repr::C);
+        assert_eq!((outer.size, outer.align), (24, 8));
+        assert!(outer.span.file.ends_with("test.h"), "{}", outer.span.file);
+        let fields: Vec<_> = outer
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), &f.ty, f.offset))
+            .collect();
+        let inner_path = find_struct(&doc.root, "bind::Inner").unwrap().path.clone();
+        assert_eq!(
+            fields,
+            [
+                ("inner", &Ty::Struct { path: inner_path }, 0),
+                (
+                    "p",
+                    &Ty::Pointer {
+                        mutable: true,
+                        pointee: Box::new(Ty::Primitive { name: "i32".into() })
+                    },
+                    8
+                ),
+                ("d", &Ty::Primitive { name: "f64".into() }, 16),
+            ]
+        );
+
+        assert_eq!(outer.methods.len(), 1);
+        let get = &outer.methods[0];
+        assert_eq!(get.name, "get");
+        assert!(get.has_self && get.is_const);
+        assert_eq!(get.params.len(), 1);
+        assert_eq!(get.params[0].name.as_deref(), Some("idx"));
+        assert_eq!(get.return_ty, Ty::Primitive { name: "i32".into() });
+    }
+
+    #[test]
+    fn method_attrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = dir.path().join("test.h");
+        std::fs::write(
+            &header,
+            "
+            struct Mixed {
+                int value;
+                int get() const noexcept;
+                [[nodiscard]] int checked();
+                constexpr int answer() const { return 42; }
+                void legacy() throw();
+                void gone() = delete;
+            };
+            namespace rust_export {
+                using ::Mixed;
+            }
+            ",
+        )
+        .unwrap();
+
+        let mut sess = Session::test();
+        sess.parse_header(&header).unwrap();
+        let mut json = vec![];
+        let generated = sess.generate_with_ir_json(std::io::sink(), std::io::sink(), &mut json);
+        assert!(generated.is_some());
+
+        let doc: Document = serde_json::from_slice(&json).unwrap();
+        let mixed = find_struct(&doc.root, "bind::Mixed").unwrap();
+        let attrs: Vec<_> = mixed
+            .methods
+            .iter()
+            .map(|meth| {
+                (
+                    meth.name.as_str(),
+                    meth.noexcept,
+                    meth.nodiscard,
+                    meth.constexpr,
+                    meth.deleted,
+                    meth.defaulted,
+                )
+            })
+            .collect();
+        assert_eq!(
+            attrs,
+            [
+                ("get", true, false, false, false, false),
+                ("checked", false, true, false, false, false),
+                ("answer", false, false, true, false, false),
+                ("legacy", true, false, false, false, false),
+                ("gone", false, false, false, true, false),
+            ]
+        );
+        for meth in &mixed.methods {
+            assert_eq!(meth.calling_conv.as_deref(), Some("cdecl"), "{}", meth.name);
+            let mangled = meth.mangled_name.as_deref().unwrap_or_default();
+            assert!(mangled.contains("Mixed"), "{}: {}", meth.name, mangled);
+        }
+    }
+}
@@ -0,0 +1,627 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Prints the C++ and Rust IR as text, for `--emit ir` and for tests.
+//!
+//! The `Debug` output of the IR is mostly interned ids. These printers
+//! resolve every id through the database and print one item per block, with
+//! the layout of each struct and the signature of each method. The format is
+//! stable, so IR dumps can be compared in tests and diffed in review.
+
+use crate::{
+    ir::{
+        cc::{self, RsTargetIr},
+        rs, CcSourceBindingsLib, DefIr, DefKind, ExportStatus,
+    },
+    libclang::CcSourceIr,
+    util::DisplayFn,
+};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, Write},
+};
+
+/// Writes the C++ IR of every module followed by the Rust IR of `krate`.
+pub(crate) fn write(
+    db: &(impl RsTargetIr + DefIr),
+    krate: &rs::BindingsCrate,
+    mut out: impl Write,
+) -> io::Result<()> {
+    for mdl in db.cc_module_ids() {
+        writeln!(out, "// C++ module {}", mdl.as_usize())?;
+        write!(
+            out,
+            "{}",
+            db.cc_module(mdl).to_ref().skip_errs().display(db)
+        )?;
+    }
+    writeln!(out, "// Rust bindings")?;
+    write!(out, "{}", krate.display(db))
+}
+
+/// Prints indented lines.
+struct Printer<'a, 'f> {
+    f: &'a mut Formatter<'f>,
+    indent: usize,
+}
+impl Printer<'_, '_> {
+    fn line(&mut self, args: fmt::Arguments<'_>) -> fmt::Result {
+        writeln!(self.f, "{:width$}{}", "", args, width = self.indent * 4)
+    }
+
+    /// Prints `header {`, then the lines printed by `body` indented one
+    /// level, then `}`.
+    fn block(
+        &mut self,
+        header: fmt::Arguments<'_>,
+        body: impl FnOnce(&mut Self) -> fmt::Result,
+    ) -> fmt::Result {
+        self.line(format_args!("{} {{", header))?;
+        self.indent += 1;
+        body(self)?;
+        self.indent -= 1;
+        self.line(format_args!("}}"))
+    }
+}
+
+/// Prints a block item, like a module or a struct.
+fn display_block<'a>(
+    print: impl Fn(&mut Printer<'_, '_>) -> fmt::Result + 'a,
+) -> impl Display + 'a {
+    DisplayFn(move |f: &mut Formatter<'_>| print(&mut Printer { f, indent: 0 }))
+}
+
+/// Formats the properties of an item that go in parentheses after its name,
+/// or nothing if there are none.
+fn properties(props: &[String]) -> String {
+    match props.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", props.join(", ")),
+    }
+}
+
+fn union_suffix(union_group: Option<u32>) -> String {
+    match union_group {
+        Some(group) => format!(" in union{}", group),
+        None => String::new(),
+    }
+}
+
+impl CcSourceBindingsLib {
+    /// Returns a printer for every item in the module, each preceded by how
+    /// it came to be included.
+    pub fn display<'a>(&'a self, db: &'a impl CcSourceIr) -> impl Display + 'a {
+        display_block(move |p| {
+            for (def, status) in self.all_items(db) {
+                match status {
+                    ExportStatus::Explicit(path) => {
+                        p.line(format_args!("// exported as {}", path.display(db)))?
+                    }
+                    ExportStatus::Transitive(provenance) => {
+                        p.line(format_args!("// transitive via {}", provenance.display(db)))?
+                    }
+                }
+                match def {
+                    DefKind::CcDef(cc::ItemKind::Struct(id)) => {
+                        print_cc_struct(db, &id.lookup(db), p)?
+                    }
+                    DefKind::CcDef(cc::ItemKind::Function(id)) => {
+                        let func = id.lookup(db);
+                        p.line(format_args!("{}", func.to_ref().skip_errs().display(db)))?;
+                    }
+                    DefKind::CcDef(cc::ItemKind::Alias(id)) => {
+                        let alias = id.lookup(db);
+                        p.line(format_args!(
+                            "using {} = {}",
+                            alias.path(db).display(db),
+                            alias.target.display(db)
+                        ))?;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl cc::Struct {
+    /// Returns a printer for the struct, its layout, fields and methods.
+    #[allow(unused)]
+    pub fn display<'a>(&'a self, db: &'a impl CcSourceIr) -> impl Display + 'a {
+        display_block(move |p| print_cc_struct(db, self, p))
+    }
+}
+
+fn print_cc_struct(db: &impl CcSourceIr, st: &cc::Struct, p: &mut Printer<'_, '_>) -> fmt::Result {
+    let mut props = vec![];
+    if let Some(layout) = &st.layout {
+        props.push(format!("size {}", layout.size.get()));
+        props.push(format!("align {}", layout.align));
+    }
+    if let Some((align, _)) = &st.attrs.packed {
+        props.push(format!("packed({})", align));
+    }
+    if let Some((align, _)) = &st.attrs.align_override {
+        props.push(format!("aligned({})", align));
+    }
+    if st.opaque {
+        props.push("opaque".to_string());
+    }
+    let header = format!("struct {}{}", st.path(db).display(db), properties(&props));
+    p.block(format_args!("{}", header), |p| {
+        for (idx, field) in st.fields.iter().enumerate() {
+            let offset = match &st.layout {
+                Some(layout) => format!(" @ {}", layout.field_offsets[idx]),
+                None => String::new(),
+            };
+            p.line(format_args!(
+                "{}: {}{}{}",
+                field.name.as_str(db),
+                field.ty.as_cc(db).skip_errs().display(db),
+                offset,
+                union_suffix(field.union_group)
+            ))?;
+        }
+        for meth in &st.methods {
+            let prefix = if meth.is_method { "" } else { "static " };
+            p.line(format_args!("{}{}", prefix, meth.display(db)))?;
+        }
+        Ok(())
+    })
+}
+
+impl cc::Function {
+    /// Returns a printer for the signature of the function, like
+    /// `fn get(idx: int) const -> int (noexcept)`.
+    ///
+    /// The mangled name and calling convention are left out, since they
+    /// depend on the target. The JSON dump has them.
+    pub fn display<'a>(&'a self, db: &'a impl CcSourceIr) -> impl Display + 'a {
+        DisplayFn(move |f: &mut Formatter<'_>| {
+            write!(f, "fn {}(", self.name.as_str(db))?;
+            for (idx, ty) in self.param_tys(db).enumerate() {
+                if idx > 0 {
+                    write!(f, ", ")?;
+                }
+                match self.param_names[idx] {
+                    Some(name) => write!(f, "{}: ", name.as_str(db))?,
+                    None => write!(f, "_: ")?,
+                }
+                write!(f, "{}", ty.display(db))?;
+            }
+            write!(f, ")")?;
+            if self.is_const {
+                write!(f, " const")?;
+            }
+            write!(f, " -> {}", self.return_ty(db).display(db))?;
+            let attrs = &self.attrs;
+            let props: Vec<_> = [
+                (attrs.noexcept, "noexcept"),
+                (attrs.nodiscard, "nodiscard"),
+                (attrs.constexpr, "constexpr"),
+                (attrs.deleted, "deleted"),
+                (attrs.defaulted, "defaulted"),
+                (attrs.deprecated.is_some(), "deprecated"),
+            ]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| name.to_string())
+            .chain(self.const_value.map(|val| format!("= {}", val)))
+            .chain(
+                self.inherited_from
+                    .as_ref()
+                    .map(|base| format!("inherited from {}", base.display(db))),
+            )
+            .collect();
+            write!(f, "{}", properties(&props))
+        })
+    }
+}
+
+impl cc::Ty {
+    /// Returns a printer for the type as it's spelled in C++, with paths in
+    /// full and `const` after what it applies to, like `::Foo const*`.
+    pub fn display<'a>(&'a self, db: &'a impl CcSourceIr) -> impl Display + 'a {
+        DisplayFn(move |f: &mut Formatter<'_>| write_cc_ty(db, self, f))
+    }
+}
+
+fn write_cc_ty(db: &impl CcSourceIr, ty: &cc::Ty, f: &mut Formatter<'_>) -> fmt::Result {
+    use cc::Ty::*;
+    let name = match ty {
+        Error => "<error>",
+        Void => "void",
+        Short => "short",
+        UShort => "unsigned short",
+        Int => "int",
+        UInt => "unsigned int",
+        Long => "long",
+        ULong => "unsigned long",
+        LongLong => "long long",
+        ULongLong => "unsigned long long",
+        CharS | CharU => "char",
+        SChar => "signed char",
+        UChar => "unsigned char",
+        Size => "size_t",
+        SSize => "ssize_t",
+        PtrDiff => "ptrdiff_t",
+        Float => "float",
+        Double => "double",
+        Bool => "bool",
+        Struct(id) => return write!(f, "{}", id.lookup(db).path(db).display(db)),
+        Opaque(id) => return write!(f, "{}", id.lookup(db).path(db).display(db)),
+        Alias(id) => return write!(f, "{}", id.lookup(db).path(db).display(db)),
+        Ptr { pointee, is_const } | Ref { pointee, is_const } => {
+            write_cc_ty(db, &pointee.as_cc(db).skip_errs(), f)?;
+            if *is_const {
+                write!(f, " const")?;
+            }
+            return match ty {
+                Ptr { .. } => write!(f, "*"),
+                _ => write!(f, "&"),
+            };
+        }
+        // The dimensions of a multi-dimensional array are written outermost
+        // first, after the element type.
+        Array { .. } => {
+            let mut dims = vec![];
+            let mut elem = ty.clone();
+            while let Array { elem: inner, len } = elem {
+                dims.push(len);
+                elem = inner.as_cc(db).skip_errs();
+            }
+            write_cc_ty(db, &elem, f)?;
+            for len in dims {
+                write!(f, "[{}]", len)?;
+            }
+            return Ok(());
+        }
+    };
+    write!(f, "{}", name)
+}
+
+impl rs::BindingsCrate {
+    /// Returns a printer for every module and item in the crate.
+    pub fn display<'a>(&'a self, db: &'a impl RsTargetIr) -> impl Display + 'a {
+        DisplayFn(move |f: &mut Formatter<'_>| write!(f, "{}", self.root.lookup(db).display(db)))
+    }
+}
+
+impl rs::Module {
+    /// Returns a printer for the module and everything in it.
+    pub fn display<'a>(&'a self, db: &'a impl RsTargetIr) -> impl Display + 'a {
+        display_block(move |p| print_rs_module(db, self, p))
+    }
+}
+
+fn vis_prefix(vis: rs::Visibility) -> &'static str {
+    match vis {
+        rs::Visibility::Public => "pub ",
+        rs::Visibility::Crate => "pub(crate) ",
+        rs::Visibility::Private => "",
+    }
+}
+
+fn print_rs_module(db: &impl RsTargetIr, mdl: &rs::Module, p: &mut Printer<'_, '_>) -> fmt::Result {
+    let print_children = |p: &mut Printer<'_, '_>| {
+        for item in &mdl.children {
+            match item {
+                rs::ItemKind::Module(id) => print_rs_module(db, &id.lookup(db), p)?,
+                rs::ItemKind::Struct(id) => print_rs_struct(db, &id.lookup(db), p)?,
+                rs::ItemKind::Opaque(id) => p.line(format_args!(
+                    "opaque {}",
+                    id.lookup(db).path(db).display(db)
+                ))?,
+                rs::ItemKind::Alias(id) => {
+                    let alias = id.lookup(db);
+                    let kind = if alias.newtype { "newtype" } else { "type" };
+                    p.line(format_args!(
+                        "{} {} = {}",
+                        kind,
+                        alias.name.as_str(db),
+                        alias.rs_target(db).display(db)
+                    ))?
+                }
+                rs::ItemKind::Reexport(path, name, _) => {
+                    let path = path.lookup(db);
+                    match path.last() == name {
+                        true => p.line(format_args!("use {}", path.display(db)))?,
+                        false => p.line(format_args!(
+                            "use {} as {}",
+                            path.display(db),
+                            name.as_str(db)
+                        ))?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+    // The crate root has no name, and its contents aren't indented.
+    if mdl.name.as_str(db).is_empty() {
+        return print_children(p);
+    }
+    if let Some(feature) = &mdl.feature {
+        p.line(format_args!("#[cfg(feature = {:?})]", feature))?;
+    }
+    p.block(
+        format_args!("{}mod {}", vis_prefix(mdl.vis), mdl.name.as_str(db)),
+        print_children,
+    )
+}
+
+impl rs::Struct {
+    /// Returns a printer for the struct, its layout, fields and methods.
+    #[allow(unused)]
+    pub fn display<'a>(&'a self, db: &'a impl RsTargetIr) -> impl Display + 'a {
+        display_block(move |p| print_rs_struct(db, self, p))
+    }
+}
+
+fn print_rs_struct(db: &impl RsTargetIr, st: &rs::Struct, p: &mut Printer<'_, '_>) -> fmt::Result {
+    let mut props = vec![
+        format!("size {}", st.layout.size.get()),
+        format!("align {}", st.layout.align),
+        match st.repr {
+            rs::Repr::C => "repr(C)".to_string(),
+            rs::Repr::Transparent => "repr(transparent)".to_string(),
+            rs::Repr::Opaque => "opaque".to_string(),
+        },
+    ];
+    if let Some(align) = st.packed {
+        props.push(format!("packed({})", align));
+    }
+    let header = format!(
+        "{}struct {}{}",
+        vis_prefix(st.vis),
+        st.name.as_str(db),
+        properties(&props)
+    );
+    p.block(format_args!("{}", header), |p| {
+        for (field, offset) in st.fields.iter().zip(&st.layout.field_offsets) {
+            let renamed = match field.name == field.cc_name {
+                true => String::new(),
+                false => format!(" (C++ name {})", field.cc_name.as_str(db)),
+            };
+            p.line(format_args!(
+                "{}{}: {} @ {}{}{}",
+                vis_prefix(field.vis),
+                field.name.as_str(db),
+                field.ty(db).display(db),
+                offset,
+                union_suffix(field.union_group),
+                renamed
+            ))?;
+        }
+        for meth in &st.methods {
+            p.line(format_args!("{}", meth.display(db)))?;
+        }
+        Ok(())
+    })
+}
+
+impl rs::Method {
+    /// Returns a printer for the signature of the method, like
+    /// `fn get(&self, idx: i32) -> i32`.
+    pub fn display<'a>(&'a self, db: &'a impl RsTargetIr) -> impl Display + 'a {
+        DisplayFn(move |f: &mut Formatter<'_>| {
+            let func = self.func();
+            let mut params = vec![];
+            if func.is_method {
+                params.push(match func.is_const {
+                    true => "&self".to_string(),
+                    false => "&mut self".to_string(),
+                });
+            }
+            for (name, ty) in func.param_names.iter().zip(self.param_tys(db)) {
+                let name = name.map_or("_".to_string(), |name| name.to_rust_ident(db));
+                params.push(format!("{}: {}", name, ty.display(db)));
+            }
+            write!(
+                f,
+                "fn {}({}) -> {}",
+                self.name.as_str(db),
+                params.join(", "),
+                self.return_ty(db).display(db)
+            )?;
+            if self.name != func.name {
+                write!(f, " (C++ name {})", func.name.as_str(db))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl rs::Ty {
+    /// Returns a printer for the type as it's spelled in Rust, with structs
+    /// and opaque types referred to by name.
+    pub fn display<'a>(&'a self, db: &'a impl RsTargetIr) -> impl Display + 'a {
+        DisplayFn(move |f: &mut Formatter<'_>| write_rs_ty(db, self, f))
+    }
+}
+
+fn write_rs_ty(db: &impl RsTargetIr, ty: &rs::Ty, f: &mut Formatter<'_>) -> fmt::Result {
+    use rs::Ty::*;
+    let name = match ty {
+        Error => "<error>",
+        Unit => "()",
+        U8 => "u8",
+        I8 => "i8",
+        U16 => "u16",
+        I16 => "i16",
+        U32 => "u32",
+        I32 => "i32",
+        U64 => "u64",
+        I64 => "i64",
+        USize => "usize",
+        ISize => "isize",
+        F32 => "f32",
+        F64 => "f64",
+        Bool => "bool",
+        CChar => "c_char",
+        Struct(id) => return write!(f, "{}", id.lookup(db).name.as_str(db)),
+        Opaque(id) => return write!(f, "{}", id.lookup(db).name.as_str(db)),
+        Alias(id) => return write!(f, "{}", id.lookup(db).name.as_str(db)),
+        Ptr { pointee, mutable } => {
+            write!(f, "{}", if *mutable { "*mut " } else { "*const " })?;
+            return write_rs_ty(db, &pointee.as_rs(db).skip_errs(), f);
+        }
+        Ref { pointee, mutable } => {
+            write!(f, "{}", if *mutable { "&mut " } else { "&" })?;
+            return write_rs_ty(db, &pointee.as_rs(db).skip_errs(), f);
+        }
+        Array { elem, len } => {
+            write!(f, "[")?;
+            write_rs_ty(db, &elem.as_rs(db).skip_errs(), f)?;
+            return write!(f, "; {}]", len);
+        }
+    };
+    write!(f, "{}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::check_output, Session};
+
+    #[test]
+    fn cc_and_rs_ir() {
+        let mut sess = Session::test();
+        let ir = cpp_lower!(sess, {
+            struct Inner {
+                double d;
+            };
+            struct Pod {
+                int a;
+                Inner inner;
+                const char* name;
+                int grid[2][3];
+                int sum(int x, int) const;
+                void reset();
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        });
+        let mut out = vec![];
+        write(&sess.db, &ir, &mut out).unwrap();
+        check_output(
+            "IR",
+            &String::from_utf8(out).unwrap(),
+            r#"
+            // C++ module 0
+            // exported as Pod
+            struct ::Pod (size 48, align 8) {
+                a: int @ 0
+                inner: ::Inner @ 8
+                name: char const* @ 16
+                grid: int[2][3] @ 24
+                fn sum(x: int, _: int) const -> int
+                fn reset() -> void
+            }
+            // transitive via Pod::inner
+            struct ::Inner (size 8, align 8) {
+                d: double @ 0
+            }
+            // Rust bindings
+            pub mod export {
+                use crate::bind::Pod
+            }
+            pub(crate) mod bind {
+                pub struct Pod (size 48, align 8, repr(C)) {
+                    pub a: i32 @ 0
+                    pub(crate) inner: Inner @ 8
+                    pub name: *const c_char @ 16
+                    pub grid: [[i32; 3]; 2] @ 24
+                    fn sum(&self, x: i32, _: i32) -> i32
+                    fn reset(&mut self) -> ()
+                }
+                pub(crate) struct Inner (size 8, align 8, repr(transparent)) {
+                    pub d: f64 @ 0
+                }
+            }
+        "#,
+        );
+    }
+
+    #[test]
+    fn constexpr_methods() {
+        let mut sess = Session::test();
+        let ir = cpp_lower!(sess, {
+            struct Limits {
+                int id, count;
+                static constexpr int max_items() { return 16 * 4; }
+                static constexpr int flags_for(int kind) { return kind << 1; }
+            };
+            namespace rust_export {
+                using ::Limits;
+            }
+        } => [
+            "constexpr method `flags_for` is bound as a normal function"
+        ]);
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        check_output(
+            "IR",
+            &st.cc_id.lookup(db).display(db).to_string(),
+            r#"
+            struct ::Limits (size 8, align 4) {
+                id: int @ 0
+                count: int @ 4
+                static fn max_items() -> int (constexpr, = 64)
+                static fn flags_for(kind: int) -> int (constexpr)
+            }
+        "#,
+        );
+    }
+
+    #[test]
+    fn struct_ir() {
+        let mut sess = Session::test();
+        let ir = cpp_lower!(sess, {
+            struct Handle;
+            struct Value {
+                int kind;
+                union {
+                    int i;
+                    float f;
+                };
+                Handle* handle;
+            };
+            namespace rust_export {
+                using ::Value;
+            }
+        });
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        check_output(
+            "IR",
+            &st.cc_id.lookup(db).display(db).to_string(),
+            r#"
+            struct ::Value (size 16, align 8) {
+                kind: int @ 0
+                i: int @ 4 in union0
+                f: float @ 4 in union0
+                handle: ::Handle* @ 8
+            }
+        "#,
+        );
+        check_output(
+            "IR",
+            &st.display(db).to_string(),
+            r#"
+            pub struct Value (size 16, align 8, repr(C)) {
+                pub kind: i32 @ 0
+                pub i: i32 @ 4 in union0
+                pub f: f32 @ 4 in union0
+                pub handle: *mut Handle @ 8
+            }
+        "#,
+        );
+    }
+}
@@ -1,4 +1,5 @@
 #![feature(proc_macro_hygiene)]
+#![cfg_attr(test, feature(test))]
 // Copyright (c) 2021 Google LLC
 //
 // Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
@@ -7,6 +8,8 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+#[cfg(test)]
+extern crate test;
 
 #[cfg(test)]
 #[macro_use]
@@ -18,24 +21,40 @@ mod util;
 #[macro_use]
 extern crate rental;
 
+mod abi;
 mod cc_use;
 mod codegen;
 mod diagnostics;
+mod exports;
 mod ir;
+mod ir_json;
+mod ir_text;
 mod libclang;
+mod naming;
+mod target;
+mod timings;
+#[cfg(test)]
+mod ui_test;
+mod verify;
 
 use cc_use::{RsImportIr, RsSource};
-use diagnostics::DiagnosticsCtx;
+use diagnostics::{Diagnostic, Diagnostics, DiagnosticsCtx, Outcome, Severity};
+pub use exports::{Bindings, FieldInfo, MethodInfo, StructInfo};
+pub use libclang::{SearchOptions, SymbolHit, SymbolKind};
+pub use naming::{NameContext, NameKind, NamingPolicy, RenameHook};
+pub use timings::TimingReport;
 
 use salsa;
 use std::{
     ffi::OsString,
     io::{self, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 use structopt::StructOpt;
 
 #[salsa::database(
+    OptionsStorage,
     diagnostics::db::SourceFileCacheStorage,
     cc_use::RsSourceStorage,
     cc_use::RsImportIrStorage,
@@ -48,24 +67,33 @@ use structopt::StructOpt;
 )]
 pub(crate) struct Database {
     runtime: salsa::Runtime<Database>,
+    metrics: Arc<timings::Metrics>,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub enum SourceFileKind {
     Cc(libclang::SourceFile),
     Rs(cc_use::SourceFile),
+    Virtual(diagnostics::VirtualFile),
 }
 impl SourceFileKind {
     fn get_name_and_contents(&self, db: &impl SourceFileLookup) -> (String, String) {
         match self {
             SourceFileKind::Cc(src) => src.get_name_and_contents(db),
             SourceFileKind::Rs(src) => src.get_name_and_contents(),
+            SourceFileKind::Virtual(src) => (src.name.clone(), src.contents.to_string()),
         }
     }
 }
 pub trait SourceFileLookup: libclang::CcSource {}
 impl SourceFileLookup for Database {}
 
+impl timings::Instrumented for Database {
+    fn metrics(&self) -> &timings::Metrics {
+        &self.metrics
+    }
+}
+
 impl salsa::Database for Database {
     fn salsa_runtime(&self) -> &salsa::Runtime<Database> {
         &self.runtime
@@ -73,35 +101,611 @@ impl salsa::Database for Database {
     fn salsa_runtime_mut(&mut self) -> &mut salsa::Runtime<Database> {
         &mut self.runtime
     }
+    fn salsa_event(&self, event_fn: impl Fn() -> salsa::Event<Self>) {
+        if let salsa::EventKind::WillExecute { .. } = event_fn().kind {
+            self.metrics.count(timings::Counter::QueriesExecuted);
+        }
+    }
+}
+
+impl salsa::ParallelDatabase for Database {
+    fn snapshot(&self) -> salsa::Snapshot<Self> {
+        salsa::Snapshot::new(Database {
+            runtime: self.runtime.snapshot(self),
+            metrics: Arc::clone(&self.metrics),
+        })
+    }
 }
 
 impl Database {
+    #[cfg(test)]
     pub fn new() -> Database {
-        Database {
+        Database::with_options(SessionOptions::default())
+    }
+
+    pub fn with_options(opts: SessionOptions) -> Database {
+        let mut db = Database {
             runtime: salsa::Runtime::default(),
+            metrics: Default::default(),
+        };
+        db.set_options(Arc::new(opts));
+        libclang::init(&mut db);
+        db
+    }
+
+    /// Overrides the contents of a C++ source file, or goes back to reading
+    /// it from disk if `contents` is `None`.
+    ///
+    /// Modules that include the file are reparsed right away, or when they're
+    /// next set up for lowering if none are right now.
+    pub fn set_file_contents(&mut self, path: impl Into<PathBuf>, contents: Option<String>) {
+        use libclang::CcSource;
+        let path = path.into();
+        let mut files = (*self.overridden_files()).clone();
+        if files.insert(path.clone()) {
+            self.set_overridden_files(Arc::new(files));
+        }
+        self.set_file_override(path, contents.map(Arc::new));
+        libclang::reparse_overridden(self);
+    }
+}
+
+/// Options that control how bindings are generated.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SessionOptions {
+    /// Emit notes for items that are skipped during lowering.
+    pub verbose: bool,
+    /// Treat protected members as accessible.
+    ///
+    /// This is reserved for inheritance support, where derived types can
+    /// access protected members of their bases. Off by default.
+    pub protected_members: bool,
+    /// Bind the methods a struct inherits from its base classes as methods
+    /// of the struct, unless the struct hides them with its own method of
+    /// the same name. Only public, non-virtual bases are walked.
+    pub inherited_methods: bool,
+    /// The target triple to generate bindings for, or `None` for the host.
+    pub target: Option<String>,
+    /// The number of threads to parse modules and lower structs on. With 0 or
+    /// 1, everything happens on the current thread.
+    pub jobs: usize,
+    /// How to expose types that are only included because an exported type
+    /// refers to them.
+    pub transitive_vis: TransitiveVisibility,
+    /// Use `#[repr(C)]` for every struct, instead of `#[repr(transparent)]`
+    /// for structs that wrap a single value.
+    pub repr_c_only: bool,
+    /// Additional directories to search for included headers.
+    pub include_dirs: Vec<PathBuf>,
+    /// Leave out crate-level attributes from the generated Rust, so it can be
+    /// `include!`d at the root of another crate.
+    pub inline_crate: bool,
+    /// Mark a whole generated file `#![no_std]`, for embedded crates. The
+    /// bindings only use paths from `core`, so they work in either kind of
+    /// crate. The `CStr` wrappers are only generated with the `c_str`
+    /// feature of the bindings crate, with a note for each one.
+    pub no_std: bool,
+    /// Parse the bodies of functions, which are skipped by default. Clang
+    /// can then deduce more `auto` return types, at the cost of a slower
+    /// parse.
+    pub full_parse: bool,
+    /// A directory to keep precompiled headers in, so later runs don't have
+    /// to parse the same headers again.
+    pub pch_dir: Option<PathBuf>,
+    /// How to convert C++ names to Rust names.
+    pub naming: NamingPolicy,
+    /// Overrides the Rust name of any item, after `naming` is applied.
+    pub rename_hook: Option<RenameHook>,
+    /// Pointer parameters that may be null, written as `Struct::method::param`
+    /// with the struct's full C++ path. Wrappers taking `&CStr` for these take
+    /// `Option<&CStr>` instead.
+    pub nullable_params: Vec<String>,
+    /// Pass C++ references as raw pointers instead of Rust references.
+    ///
+    /// Rust references promise that nothing else mutates the value while
+    /// they're held, which C++ APIs can't always keep, like when an argument
+    /// aliases `self`.
+    pub raw_pointer_refs: bool,
+    /// Put the raw types and extern declarations in a `sys` module, apart
+    /// from the safe wrappers that call them, like a `-sys` crate.
+    pub two_layer: bool,
+    /// Declare the shims of each module in one `extern` block at the end of
+    /// the module, instead of one block after each type. In two-layer mode
+    /// they're always in the `sys` module's block.
+    pub coalesce_externs: bool,
+    /// Generate `From` conversions between each `#[repr(C)]` struct and a
+    /// tuple of its fields, for structs whose fields are all public.
+    pub tuple_conversions: bool,
+    /// Types that can be sent and shared between threads, written as their
+    /// full C++ path like `mylib::Logger`. Their bindings implement `Send`
+    /// and `Sync`.
+    pub thread_safe: Vec<String>,
+    /// The language headers are written in.
+    pub language: Language,
+    /// Items to export by their full C++ path, in addition to the ones in
+    /// `rust_export` namespaces. This is how items are exported from C
+    /// headers, which can't have namespaces.
+    pub exports: Vec<String>,
+    /// Fields and methods to leave out of the bindings, written as
+    /// `Struct::member` with the struct's full C++ path. Skipped fields are
+    /// replaced with private padding, so the struct keeps its layout.
+    pub skip_members: Vec<String>,
+    /// Structs to import without their fields, written as their full C++
+    /// path. They keep their size and alignment, but Rust code can only use
+    /// them through their methods. Structs can also be made opaque by
+    /// exporting them from a `namespace opaque` inside `rust_export`.
+    pub opaque: Vec<String>,
+    /// Exported aliases of builtin types to bind as `#[repr(transparent)]`
+    /// newtypes instead of type aliases, written as their full C++ path.
+    /// Fields and parameters spelled with the alias get the newtype, so it
+    /// can't be mixed up with other values of the same builtin type.
+    pub newtypes: Vec<String>,
+}
+
+/// The language of the parsed headers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Language {
+    /// C++17. This is the default.
+    #[default]
+    Cxx,
+    /// C11.
+    C,
+}
+impl std::str::FromStr for Language {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c++" => Ok(Language::Cxx),
+            "c" => Ok(Language::C),
+            _ => Err(format!("invalid language `{}`", s)),
+        }
+    }
+}
+
+/// The visibility of types that are included in the bindings without being
+/// exported, like the types of fields of exported structs.
+///
+/// Fields of exported structs whose type isn't exported get the same
+/// visibility.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum TransitiveVisibility {
+    Private,
+    /// Visible within the bindings crate. This is the default.
+    #[default]
+    Crate,
+    Public,
+}
+impl std::str::FromStr for TransitiveVisibility {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "private" => Ok(TransitiveVisibility::Private),
+            "crate" => Ok(TransitiveVisibility::Crate),
+            "public" => Ok(TransitiveVisibility::Public),
+            _ => Err(format!("invalid visibility `{}`", s)),
         }
     }
 }
 
+/// A named set of preprocessor definitions to parse a header with.
+///
+/// A header parsed under a config set is bound in its own module named after
+/// the set, which is only compiled with the cargo feature of the same name.
+/// Written as `name=DEFINE,OTHER=1` on the command line.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ConfigSet {
+    pub name: String,
+    /// Each definition, as passed to `-D`.
+    pub defines: Vec<String>,
+}
+impl std::str::FromStr for ConfigSet {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, defines) = s.split_once('=').unwrap_or((s, ""));
+        let is_ident = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_ident {
+            return Err(format!("invalid config set name `{}`", name));
+        }
+        // The config modules sit next to these in the crate root.
+        if name == "bind" || name == "export" {
+            return Err(format!("config sets cannot be named `{}`", name));
+        }
+        Ok(ConfigSet {
+            name: name.to_string(),
+            defines: defines
+                .split(',')
+                .filter(|define| !define.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Provides the [`SessionOptions`] for the current session.
+#[salsa::query_group(OptionsStorage)]
+pub trait Options {
+    #[salsa::input]
+    fn options(&self) -> Arc<SessionOptions>;
+
+    /// Sizes and alignments of builtin types on the target. An unknown
+    /// target is reported when the session is created.
+    #[salsa::invoke(target::target_info)]
+    fn target_info(&self) -> Outcome<Arc<target::TargetInfo>>;
+}
+
+/// A bindings generation session.
+///
+/// Parse a C++ header with [`Session::parse_header`], or a Rust file using
+/// `cc_use!` with [`Session::parse_rust`], and then call
+/// [`Session::generate`] to write the bindings.
 pub struct Session {
-    // TODO: opts
     diags: DiagnosticsCtx,
     db: Database,
+    /// Modules parsed since the last call to `generate`.
+    modules: Vec<(libclang::ModuleContext, libclang::ParseErrors)>,
+    /// The headers the generated C++ must include.
+    headers: Vec<ir::bindings::Header>,
+    /// Whether the modules came from the `cc_use!`s in a Rust file.
+    rs_input: bool,
 }
 
+/// Identifies a module parsed by a [`Session`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ModuleHandle(u32);
+
 impl Session {
     pub fn new() -> Self {
+        Session::with_options(SessionOptions::default())
+    }
+
+    pub fn with_options(opts: SessionOptions) -> Self {
+        Session::from_parts(DiagnosticsCtx::new(), Database::with_options(opts))
+    }
+
+    fn from_parts(diags: DiagnosticsCtx, db: Database) -> Self {
+        // Layouts can't be computed for an unknown target, so report it up
+        // front. Generating fails on the error.
+        db.target_info().errs().emit(&db, &diags);
         Session {
-            diags: DiagnosticsCtx::new(),
-            db: Database::new(),
+            diags,
+            db,
+            modules: vec![],
+            headers: vec![],
+            rs_input: false,
+        }
+    }
+
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    /// See [`Database::set_file_contents`].
+    pub fn set_file_contents(&mut self, path: impl Into<PathBuf>, contents: Option<String>) {
+        self.db.set_file_contents(path, contents)
+    }
+
+    /// Parses a C++ header with a `rust_export` namespace.
+    ///
+    /// More than one header can be parsed, and bindings are generated for all
+    /// of them together.
+    pub fn parse_header(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<ModuleHandle, Box<dyn std::error::Error>> {
+        self.parse_header_impl(path.as_ref(), None)
+    }
+
+    /// Parses a C++ header with the definitions in `config`.
+    ///
+    /// The same header can be parsed under several config sets. Each one's
+    /// bindings go in a module named after it, gated on the feature of the
+    /// same name, so their types don't conflict.
+    pub fn parse_header_with_config(
+        &mut self,
+        path: impl AsRef<Path>,
+        config: &ConfigSet,
+    ) -> Result<ModuleHandle, Box<dyn std::error::Error>> {
+        self.parse_header_impl(path.as_ref(), Some(config.clone()))
+    }
+
+    fn parse_header_impl(
+        &mut self,
+        path: &Path,
+        config: Option<ConfigSet>,
+    ) -> Result<ModuleHandle, Box<dyn std::error::Error>> {
+        if self.rs_input {
+            return Err("cannot parse headers in a session that parsed a Rust file".into());
+        }
+        let include_path = path
+            .file_name()
+            .ok_or("header path must name a file")?
+            .to_str()
+            .ok_or("Input filename must be valid UTF-8")?;
+        libclang::load_clang()?;
+        self.db.set_rs_source_root(None);
+        let handle = ModuleHandle(self.modules.len() as u32);
+        let index = libclang::create_index();
+        let id = libclang::ModuleId::new(handle.0);
+        let module = self.db.metrics.time(timings::Phase::Parse, || {
+            libclang::parse_configured(&self.db, &index, id, path, config)
+        });
+        match module {
+            Ok(module) => self.modules.push(module),
+            Err(diag) => {
+                Diagnostics::from(diag).emit(&self.db, &self.diags);
+                return Err("could not parse the header".into());
+            }
+        }
+        // A header parsed under several config sets is still included once.
+        if !self.headers.iter().any(|hdr| hdr.path == include_path) {
+            self.headers.push(ir::bindings::Header {
+                path: include_path.to_string(),
+                is_system: false,
+                span: None,
+            });
+        }
+        Ok(handle)
+    }
+
+    /// Parses a Rust file for `cc_use!` macros, and then parses the headers
+    /// they point to.
+    ///
+    /// This can't be combined with other calls to `parse_header` or
+    /// `parse_rust` before the next call to [`Session::generate`].
+    pub fn parse_rust(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<ModuleHandle>, Box<dyn std::error::Error>> {
+        if !self.modules.is_empty() {
+            return Err("cannot parse a Rust file in a session that parsed other input".into());
+        }
+        libclang::load_clang()?;
+        self.db
+            .set_rs_source_root(Some(cc_use::SourceFile::intern_from_path(
+                &self.db,
+                path.as_ref(),
+            )?));
+        let (module_ids, errs) = self.db.module_ids().split();
+        errs.emit(&self.db, &self.diags);
+        let (modules, errs) = self
+            .db
+            .metrics
+            .time(timings::Phase::Parse, || {
+                cc_use::cc_modules_from_rs(&self.db, &module_ids)
+            })?
+            .split();
+        errs.emit(&self.db, &self.diags);
+        self.modules = modules.ok_or("could not parse the imported headers")?;
+        self.headers = self.db.headers().skip_errs().to_vec();
+        self.rs_input = true;
+        Ok((0..self.modules.len() as u32).map(ModuleHandle).collect())
+    }
+
+    /// Generates bindings for everything parsed so far, and writes the Rust
+    /// and C++ halves to `out_rs` and `out_cc`.
+    ///
+    /// Returns a description of what was exported, or `None` if errors were
+    /// reported. Either way, the parsed modules are consumed and the session
+    /// can be used to parse new input.
+    pub fn generate(&mut self, out_rs: impl Write, out_cc: impl Write) -> Option<Bindings> {
+        self.generate_impl(out_rs, out_cc, IrOutputs::default(), None)
+    }
+
+    /// Like [`Session::generate`], but also writes a JSON dump of the Rust IR
+    /// to `out_ir`.
+    pub fn generate_with_ir_json(
+        &mut self,
+        out_rs: impl Write,
+        out_cc: impl Write,
+        mut out_ir: impl Write,
+    ) -> Option<Bindings> {
+        let out_ir = IrOutputs {
+            json: Some(&mut out_ir),
+            text: None,
+        };
+        self.generate_impl(out_rs, out_cc, out_ir, None)
+    }
+
+    fn generate_impl(
+        &mut self,
+        out_rs: impl Write,
+        out_cc: impl Write,
+        out_ir: IrOutputs<'_>,
+        verify: Option<verify::Verify>,
+    ) -> Option<Bindings> {
+        let modules = std::mem::take(&mut self.modules);
+        let headers = std::mem::take(&mut self.headers);
+        self.rs_input = false;
+        run_generator(self, modules, &headers, out_rs, out_cc, out_ir, verify)
+    }
+
+    /// Returns the files read by the input parsed so far: each parsed header
+    /// and everything it includes.
+    pub fn input_files(&mut self) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for (module, _) in &mut self.modules {
+            for file in module.input_files(true) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
         }
+        files
+    }
+
+    /// Returns the files the bindings for the input parsed so far depend on,
+    /// sorted and without duplicates, for build systems that rerun the
+    /// generator when one of them changes.
+    ///
+    /// System headers are left out unless `include_system` is set.
+    pub fn dependencies(&mut self, include_system: bool) -> Vec<PathBuf> {
+        let mut files: Vec<_> = self
+            .modules
+            .iter_mut()
+            .flat_map(|(module, _)| module.input_files(include_system))
+            .collect();
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    /// Returns the items in the parsed headers whose name contains `query`,
+    /// ignoring case, for tools that complete the names in `rust_export`
+    /// blocks or `cc_use!`.
+    ///
+    /// Hits are sorted by path, and items declared in several headers are
+    /// only returned once.
+    pub fn search_symbols(
+        &mut self,
+        query: &str,
+        kind_filter: Option<SymbolKind>,
+        opts: &SearchOptions,
+    ) -> Vec<SymbolHit> {
+        let mut hits: Vec<SymbolHit> = vec![];
+        for (module, _) in &mut self.modules {
+            for hit in module.search_symbols(&self.db, query, kind_filter, opts) {
+                if !hits
+                    .iter()
+                    .any(|h| h.path == hit.path && h.kind == hit.kind)
+                {
+                    hits.push(hit);
+                }
+            }
+        }
+        hits.sort_by(|a, b| a.path.cmp(&b.path));
+        hits.truncate(opts.max_results);
+        hits
+    }
+
+    /// Returns true if any errors have been reported.
+    pub fn has_errors(&self) -> bool {
+        self.diags.has_errors()
+    }
+
+    /// Returns the diagnostics reported so far, rendered as they would be on
+    /// the terminal.
+    ///
+    /// Diagnostics are only kept for sessions built with
+    /// [`SessionBuilder::capture_diagnostics`]. Otherwise they go straight to
+    /// stderr, and this returns `None`.
+    pub fn diagnostics(&self) -> Option<String> {
+        self.diags.captured()
+    }
+
+    /// Returns how long each phase of generating bindings has taken so far,
+    /// with counts of what was lowered and reported.
+    pub fn timings(&self) -> TimingReport {
+        self.db.metrics.report(
+            self.diags.count(Severity::Error) + self.diags.count(Severity::Bug),
+            self.diags.count(Severity::Warning),
+            self.diags.count(Severity::Note),
+        )
     }
 
     #[cfg(test)]
     pub(crate) fn test() -> Self {
-        Session {
-            diags: DiagnosticsCtx::test(),
-            db: Database::new(),
+        Session::from_parts(DiagnosticsCtx::test(), Database::new())
+    }
+}
+
+/// Configures a [`Session`].
+#[derive(Clone, Debug, Default)]
+pub struct SessionBuilder {
+    opts: SessionOptions,
+    capture_diagnostics: bool,
+}
+
+impl SessionBuilder {
+    /// Replaces all the options set so far.
+    pub fn options(&mut self, opts: SessionOptions) -> &mut Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Adds a directory to search for included headers.
+    pub fn include_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.opts.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Sets a function to choose the Rust names of bound items. See
+    /// [`RenameHook`].
+    pub fn rename(&mut self, hook: fn(&NameContext<'_>) -> Option<String>) -> &mut Self {
+        self.opts.rename_hook = Some(RenameHook(hook));
+        self
+    }
+
+    /// Keeps diagnostics for [`Session::diagnostics`] instead of printing them
+    /// to stderr.
+    pub fn capture_diagnostics(&mut self) -> &mut Self {
+        self.capture_diagnostics = true;
+        self
+    }
+
+    pub fn build(&self) -> Session {
+        let diags = if self.capture_diagnostics {
+            DiagnosticsCtx::buffered()
+        } else {
+            DiagnosticsCtx::new()
+        };
+        Session::from_parts(diags, Database::with_options(self.opts.clone()))
+    }
+}
+
+/// Dumps of the IR to write along with the bindings.
+#[derive(Default)]
+struct IrOutputs<'a> {
+    json: Option<&'a mut dyn Write>,
+    /// The C++ and Rust IR as text, for reading.
+    text: Option<&'a mut dyn Write>,
+}
+
+/// An extra output of the command line tool.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Emit {
+    /// A JSON dump of the Rust IR, written to `<crate name>.ir.json`.
+    IrJson,
+    /// The C++ and Rust IR as text, written to `<crate name>.ir.txt`.
+    Ir,
+    /// The files the bindings depend on, written to `<crate name>.d`, or
+    /// `<crate name>.deps` with `--deps-format list`.
+    Deps,
+    /// The names of the shims defined by the C++ output, one per line,
+    /// written to `<crate name>.symbols`.
+    Symbols,
+}
+impl std::str::FromStr for Emit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ir-json" => Ok(Emit::IrJson),
+            "ir" => Ok(Emit::Ir),
+            "deps" => Ok(Emit::Deps),
+            "symbols" => Ok(Emit::Symbols),
+            _ => Err(format!("invalid output `{}`", s)),
+        }
+    }
+}
+
+/// How `--emit deps` writes the dependency list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DepsFormat {
+    /// A rule for make, with the generated Rust as the target.
+    Make,
+    /// One path per line.
+    List,
+}
+impl std::str::FromStr for DepsFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "make" => Ok(DepsFormat::Make),
+            "list" => Ok(DepsFormat::List),
+            _ => Err(format!("invalid dependency format `{}`", s)),
         }
     }
 }
@@ -117,6 +721,156 @@ struct Opts {
     #[structopt(long)]
     crate_name: Option<OsString>,
 
+    /// print notes about items that were skipped
+    #[structopt(short, long)]
+    verbose: bool,
+
+    /// the target triple to generate bindings for (defaults to the host)
+    #[structopt(long)]
+    target: Option<String>,
+
+    /// the number of threads to use when parsing headers and lowering them
+    #[structopt(short, long, default_value = "1")]
+    jobs: usize,
+
+    /// the visibility of types that are only included because exported types use them
+    #[structopt(long, default_value = "crate", possible_values = &["private", "crate", "public"])]
+    transitive_vis: TransitiveVisibility,
+
+    /// use repr(C) even for structs that only wrap a single value
+    #[structopt(long)]
+    repr_c_only: bool,
+
+    /// add a directory to search for included headers
+    #[structopt(short = "I", long = "include-dir", number_of_values = 1)]
+    include_dirs: Vec<PathBuf>,
+
+    /// generate Rust that can be include!d at the root of another crate
+    #[structopt(long)]
+    inline_crate: bool,
+
+    /// mark the generated Rust #![no_std], gating CStr wrappers on the c_str feature
+    #[structopt(long)]
+    no_std: bool,
+
+    /// parse function bodies too, so more auto return types can be deduced
+    #[structopt(long)]
+    full_parse: bool,
+
+    /// precompile the parsed headers next to the output, and reuse them on later runs
+    #[structopt(long)]
+    pch: bool,
+
+    /// strip a prefix from C++ type names, like C in CFoo
+    #[structopt(long = "strip-type-prefix", number_of_values = 1)]
+    strip_type_prefixes: Vec<String>,
+
+    /// convert type names to CamelCase
+    #[structopt(long)]
+    camel_case_types: bool,
+
+    /// convert field and method names to snake_case
+    #[structopt(long)]
+    snake_case_members: bool,
+
+    /// mark a parameter as nullable, as Struct::method::param
+    #[structopt(long = "nullable-param", number_of_values = 1)]
+    nullable_params: Vec<String>,
+
+    /// pass C++ references as raw pointers instead of Rust references
+    #[structopt(long)]
+    raw_pointer_refs: bool,
+
+    /// put raw types and extern declarations in a separate sys module
+    #[structopt(long)]
+    two_layer: bool,
+
+    /// declare the shims of a module in one extern block at its end
+    #[structopt(long)]
+    coalesce_externs: bool,
+
+    /// bind the methods a struct inherits from its bases on the struct too
+    #[structopt(long)]
+    inherited_methods: bool,
+
+    /// convert structs with public fields from and to tuples of the fields
+    #[structopt(long)]
+    tuple_conversions: bool,
+
+    /// implement Send and Sync for a type, given by its full C++ path
+    #[structopt(long = "thread-safe", number_of_values = 1)]
+    thread_safe: Vec<String>,
+
+    /// the language of the input header
+    #[structopt(long, default_value = "c++", possible_values = &["c++", "c"])]
+    language: Language,
+
+    /// export an item, given by its full path; needed for C headers
+    #[structopt(long = "export", number_of_values = 1)]
+    exports: Vec<String>,
+
+    /// leave a field or method out of the bindings, given as Struct::member
+    #[structopt(long = "skip-member", number_of_values = 1)]
+    skip_members: Vec<String>,
+
+    /// import a struct without its fields, given by its full C++ path
+    #[structopt(long = "opaque", number_of_values = 1)]
+    opaque: Vec<String>,
+
+    /// bind an exported alias of a builtin type as a newtype struct, given by
+    /// its full C++ path
+    #[structopt(long = "newtype", number_of_values = 1)]
+    newtypes: Vec<String>,
+
+    /// keep running, and regenerate the bindings when the input changes
+    #[structopt(long)]
+    watch: bool,
+
+    /// check that an existing output file is up to date, instead of writing
+    /// the outputs; .cc files are compared with the C++ output
+    #[structopt(long, number_of_values = 1, conflicts_with = "watch")]
+    check: Vec<PathBuf>,
+
+    /// extra outputs to write next to the bindings
+    #[structopt(long, number_of_values = 1, possible_values = &["ir-json", "ir", "deps", "symbols"])]
+    emit: Vec<Emit>,
+
+    /// the format of the dependency list written by --emit deps
+    #[structopt(long, default_value = "make", possible_values = &["make", "list"])]
+    deps_format: DepsFormat,
+
+    /// list system headers in the dependency list
+    #[structopt(long)]
+    deps_include_system: bool,
+
+    /// write static_asserts of the struct layouts next to the bindings, and
+    /// check them with a C++ compiler if one is found
+    #[structopt(long)]
+    verify: bool,
+
+    /// the C++ compiler to check layouts with, like clang++ or g++
+    #[structopt(long, requires = "verify")]
+    cxx: Option<PathBuf>,
+
+    /// parse the header once per config set, given as name=DEFINE,OTHER=1,
+    /// and bind each one in a module gated on the feature `name`
+    #[structopt(long = "config-set", number_of_values = 1)]
+    config_sets: Vec<ConfigSet>,
+
+    /// warn about changes that break the ABI recorded in a manifest from an
+    /// earlier run, like a changed struct size or a removed method
+    #[structopt(long)]
+    abi_baseline: Option<PathBuf>,
+
+    /// report ABI changes as errors, and fail without writing any outputs
+    #[structopt(long, requires = "abi-baseline")]
+    abi_check: bool,
+
+    /// print how long each phase took and what was lowered, as a table or
+    /// with --timings=json as JSON
+    #[structopt(long, require_equals = true, possible_values = &["table", "json"])]
+    timings: Option<Option<timings::TimingsFormat>>,
+
     /// path to the C++ header file to generate bindings for
     input: String,
 }
@@ -131,67 +885,396 @@ pub fn main() -> Result<i32, Box<dyn std::error::Error>> {
         );
         return Ok(1);
     }
+    if let Some(target) = &opts.target {
+        if target::TargetInfo::from_triple(target).is_none() {
+            eprintln!("Error: unsupported target: {}", target);
+            return Ok(1);
+        }
+    }
     let out_dir = opts
         .out_dir
         .as_ref()
         .map(Path::new)
         .unwrap_or_else(|| input_path.parent().unwrap());
-    let out_basename = opts.crate_name.unwrap_or_else(|| {
+    let out_basename = opts.crate_name.clone().unwrap_or_else(|| {
         let mut name = input_path.file_stem().unwrap().to_os_string();
         name.push("_bind");
         name
     });
     let out_base = out_dir.join(out_basename);
 
-    // If the input is a Rust file, parse it for cc_use! macros and then parse the header files it
-    // points to. Otherwise, parse the input file directly as C++.
-    let mut sess = Session::new();
-    let index = libclang::create_index();
-    let (rs_headers, cli_headers);
-    let (cc_modules, headers) = if let Some("rs") = input_path.extension().and_then(|p| p.to_str())
-    {
-        sess.db
-            .set_rs_source_root(Some(cc_use::SourceFile::intern_from_path(
-                &sess.db,
-                &input_path,
-            )?));
-        let (module_ids, errs) = sess.db.module_ids().split();
-        errs.emit(&sess.db, &sess.diags);
-        let modules = module_ids
+    if opts.watch {
+        return watch(&opts, &input_path, out_dir, &out_base);
+    }
+    let (code, _) = run_once(&opts, &input_path, out_dir, &out_base)?;
+    Ok(code)
+}
+
+/// Generates bindings for the input and writes every output file.
+///
+/// Returns the exit code, and the files the bindings were generated from.
+fn run_once(
+    opts: &Opts,
+    input_path: &Path,
+    out_dir: &Path,
+    out_base: &Path,
+) -> Result<(i32, Vec<PathBuf>), Box<dyn std::error::Error>> {
+    let mut sess = Session::builder()
+        .options(SessionOptions {
+            verbose: opts.verbose,
+            target: opts.target.clone(),
+            jobs: opts.jobs,
+            transitive_vis: opts.transitive_vis,
+            repr_c_only: opts.repr_c_only,
+            include_dirs: opts.include_dirs.clone(),
+            inline_crate: opts.inline_crate,
+            no_std: opts.no_std,
+            full_parse: opts.full_parse,
+            pch_dir: if opts.pch {
+                Some(out_dir.to_owned())
+            } else {
+                None
+            },
+            naming: NamingPolicy {
+                strip_type_prefixes: opts.strip_type_prefixes.clone(),
+                camel_case_types: opts.camel_case_types,
+                snake_case_members: opts.snake_case_members,
+            },
+            nullable_params: opts.nullable_params.clone(),
+            raw_pointer_refs: opts.raw_pointer_refs,
+            two_layer: opts.two_layer,
+            coalesce_externs: opts.coalesce_externs,
+            inherited_methods: opts.inherited_methods,
+            tuple_conversions: opts.tuple_conversions,
+            thread_safe: opts.thread_safe.clone(),
+            language: opts.language,
+            exports: opts.exports.clone(),
+            skip_members: opts.skip_members.clone(),
+            opaque: opts.opaque.clone(),
+            newtypes: opts.newtypes.clone(),
+            ..Default::default()
+        })
+        .build();
+    let result = generate_outputs(&mut sess, opts, input_path, out_base);
+    if let Some(format) = opts.timings {
+        let report = sess.timings();
+        match format.unwrap_or(timings::TimingsFormat::Table) {
+            timings::TimingsFormat::Table => eprint!("{}", report),
+            timings::TimingsFormat::Json => eprintln!("{}", report.to_json()),
+        }
+    }
+    result
+}
+
+/// Parses the input with `sess`, and writes every output file. See
+/// [`run_once`].
+fn generate_outputs(
+    sess: &mut Session,
+    opts: &Opts,
+    input_path: &Path,
+    out_base: &Path,
+) -> Result<(i32, Vec<PathBuf>), Box<dyn std::error::Error>> {
+    if let Some("rs") = input_path.extension().and_then(|p| p.to_str()) {
+        if !opts.config_sets.is_empty() {
+            return Err("--config-set can only be used with a C++ header".into());
+        }
+        sess.parse_rust(input_path)?;
+    } else if opts.config_sets.is_empty() {
+        sess.parse_header(input_path)?;
+    } else {
+        for config in &opts.config_sets {
+            sess.parse_header_with_config(input_path, config)?;
+        }
+    }
+    let mut input_files = sess.input_files();
+    if !input_files.iter().any(|file| file == input_path) {
+        input_files.insert(0, input_path.to_owned());
+    }
+    let deps = sess.dependencies(opts.deps_include_system);
+    let input_dir = input_path.parent().unwrap();
+    // The baseline is read first, since it may be the manifest about to be
+    // overwritten.
+    let abi_baseline = match &opts.abi_baseline {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+            Some(abi::parse_manifest(&text).map_err(|err| format!("{}: {}", path.display(), err))?)
+        }
+        None => None,
+    };
+    let inputs = sess.dependencies(false);
+    let mut manifest = manifest(&sess.db, input_dir, &sess.headers, &inputs);
+
+    let (mut out_rs, mut out_cc) = (vec![], vec![]);
+    let emit = |kind| match opts.emit.contains(&kind) {
+        true => Some(vec![]),
+        false => None,
+    };
+    let (mut out_ir, mut out_ir_text) = (emit(Emit::IrJson), emit(Emit::Ir));
+    let ir_outputs = IrOutputs {
+        json: out_ir.as_mut().map(|out| out as &mut dyn Write),
+        text: out_ir_text.as_mut().map(|out| out as &mut dyn Write),
+    };
+    // `--check` doesn't write any outputs, so it doesn't verify layouts
+    // either.
+    let verify = match opts.verify && opts.check.is_empty() {
+        true => Some(verify::Verify {
+            path: out_base.with_extension("layout.cc"),
+            compiler: layout_compiler(opts),
+            include_dirs: Some(input_dir.to_owned())
+                .into_iter()
+                .chain(opts.include_dirs.iter().cloned())
+                .collect(),
+        }),
+        false => None,
+    };
+    let bindings = match sess.generate_impl(&mut out_rs, &mut out_cc, ir_outputs, verify) {
+        Some(bindings) => bindings,
+        None => return Ok((101, input_files)),
+    };
+    if let Some(baseline) = &abi_baseline {
+        let diags = abi::compare(baseline, &bindings, opts.abi_check);
+        let failed = diags.has_errors();
+        diags.emit(&sess.db, &sess.diags);
+        if failed {
+            return Ok((1, input_files));
+        }
+    }
+    manifest += &abi::manifest_lines(&bindings);
+    if !opts.check.is_empty() {
+        return Ok((check_outputs(&opts.check, &out_rs, &out_cc), input_files));
+    }
+    write_if_changed(&out_base.with_extension("rs"), &out_rs)?;
+    write_if_changed(&out_base.with_extension("cc"), &out_cc)?;
+    if let Some(out_ir) = out_ir {
+        write_if_changed(&out_base.with_extension("ir.json"), &out_ir)?;
+    }
+    if let Some(out_ir_text) = out_ir_text {
+        write_if_changed(&out_base.with_extension("ir.txt"), &out_ir_text)?;
+    }
+    if opts.emit.contains(&Emit::Deps) {
+        let (ext, deps) = match opts.deps_format {
+            DepsFormat::Make => ("d", make_deps(&out_base.with_extension("rs"), &deps)),
+            DepsFormat::List => ("deps", list_deps(&deps)),
+        };
+        write_if_changed(&out_base.with_extension(ext), deps.as_bytes())?;
+    }
+    if opts.emit.contains(&Emit::Symbols) {
+        let symbols: String = bindings
+            .generated_symbols()
             .iter()
-            .map(|module_id| cc_use::cc_module_from_rs(&sess.db, &index, *module_id))
+            .map(|symbol| format!("{}\n", symbol))
             .collect();
-        rs_headers = sess.db.headers().skip_errs();
-        (modules, &*rs_headers)
-    } else {
-        sess.db.set_rs_source_root(None);
-        let include_path = input_path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .ok_or("Input filename must be valid UTF-8")?;
-        let module_id = libclang::ModuleId::new(0);
-        cli_headers = vec![ir::bindings::Header {
-            path: include_path.to_string(),
-            is_system: false,
-            span: None,
-        }];
-        (
-            vec![libclang::parse(&sess.db, &index, module_id, &input_path)],
-            &*cli_headers,
-        )
+        write_if_changed(&out_base.with_extension("symbols"), symbols.as_bytes())?;
+    }
+    write_if_changed(&out_base.with_extension("manifest"), manifest.as_bytes())?;
+
+    Ok((0, input_files))
+}
+
+/// Returns the compiler to check layouts with for `--verify`: the one given
+/// with `--cxx`, or the one the `cc` crate would use for the target.
+fn layout_compiler(opts: &Opts) -> Option<std::process::Command> {
+    if let Some(cxx) = &opts.cxx {
+        return Some(std::process::Command::new(cxx));
+    }
+    let mut build = cc_crate::Build::new();
+    build.cpp(true).cargo_metadata(false).opt_level(0);
+    // Outside of a build script there's no `TARGET` or `HOST` to read.
+    if let Some(target) = &opts.target {
+        build.target(target).host(target);
+    }
+    build.try_get_compiler().ok().map(|tool| tool.to_command())
+}
+
+/// Regenerates the bindings whenever the input or a file it includes
+/// changes. Only returns if watching fails.
+fn watch(
+    opts: &Opts,
+    input_path: &Path,
+    out_dir: &Path,
+    out_base: &Path,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(tx, std::time::Duration::from_millis(200))?;
+    let mut watched: Vec<PathBuf> = vec![];
+    loop {
+        let files = match run_once(opts, input_path, out_dir, out_base) {
+            Ok((_, files)) => files,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                vec![input_path.to_owned()]
+            }
+        };
+        // Editors often replace files instead of writing to them, which ends
+        // the watch on the old file, so start over each time.
+        for file in watched.drain(..) {
+            let _ = watcher.unwatch(&file);
+        }
+        for file in files {
+            match watcher.watch(&file, RecursiveMode::NonRecursive) {
+                Ok(()) => watched.push(file),
+                Err(err) => eprintln!("Warning: cannot watch {}: {}", file.display(), err),
+            }
+        }
+        eprintln!("Watching {} files for changes...", watched.len());
+        loop {
+            match rx.recv()? {
+                DebouncedEvent::Write(_)
+                | DebouncedEvent::Create(_)
+                | DebouncedEvent::Remove(_)
+                | DebouncedEvent::Rename(..) => break,
+                DebouncedEvent::Error(err, _) => return Err(err.into()),
+                _ => {}
+            }
+        }
+        // Changes that arrived during the rerun are picked up by it.
+        while rx.try_recv().is_ok() {}
+    }
+}
+
+/// Compares each file given to `--check` with the output it should match,
+/// printing a diff for each one that differs. Returns the exit code.
+fn check_outputs(paths: &[PathBuf], out_rs: &[u8], out_cc: &[u8]) -> i32 {
+    let mut code = 0;
+    for path in paths {
+        let generated = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cc") => out_cc,
+            _ => out_rs,
+        };
+        let existing = match std::fs::read(path) {
+            Ok(existing) => existing,
+            Err(err) => {
+                eprintln!("Error: cannot read {}: {}", path.display(), err);
+                code = 1;
+                continue;
+            }
+        };
+        // Files checked out on Windows may have gained CRLF line endings.
+        let normalize = |text: &[u8]| String::from_utf8_lossy(text).replace("\r\n", "\n");
+        let (existing, generated) = (normalize(&existing), normalize(generated));
+        if existing != generated {
+            let name = path.display().to_string();
+            println!(
+                "{}",
+                similar::TextDiff::from_lines(&existing, &generated)
+                    .unified_diff()
+                    .context_radius(3)
+                    .header(&name, "generated")
+            );
+            eprintln!("Error: {} is out of date", name);
+            code = 1;
+        }
+    }
+    code
+}
+
+/// Writes `contents` to `path`, unless the file already has those contents.
+///
+/// Leaving unchanged outputs alone keeps build tools that watch them from
+/// rebuilding for nothing. Returns whether the file was written.
+fn write_if_changed(path: &Path, contents: &[u8]) -> io::Result<bool> {
+    match std::fs::read(path) {
+        Ok(old) if old == contents => return Ok(false),
+        _ => {}
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = tempfile::Builder::new().tempfile_in(dir)?;
+    file.write_all(contents)?;
+    file.persist(path)?;
+    Ok(true)
+}
+
+/// Describes what the generated bindings depend on, for build scripts.
+///
+/// Each line is a directive followed by a space and its argument:
+///
+/// * `header <path>` names a header the bindings were generated from.
+///   Relative paths are relative to the directory of the input file.
+/// * `link-lib <name>` names a library the bindings must be linked with, as
+///   it would be passed to `-l`.
+/// * `depends <path>` names a file the bindings depend on, like a header
+///   included by one of the headers. System headers are left out.
+///
+/// The ABI of the exported structs is appended after generating, see
+/// `abi::manifest_lines`.
+fn manifest(
+    db: &Database,
+    input_dir: &Path,
+    headers: &[ir::bindings::Header],
+    deps: &[PathBuf],
+) -> String {
+    let mut out = String::new();
+    for hdr in headers.iter().filter(|hdr| !hdr.is_system) {
+        out += &format!("header {}\n", input_dir.join(&hdr.path).display());
+    }
+    for lib in db.link_libs().skip_errs().iter() {
+        out += &format!("link-lib {}\n", codegen::link_name(lib));
+    }
+    for dep in deps {
+        out += &format!("depends {}\n", dep.display());
+    }
+    out
+}
+
+/// Writes `deps` as a make rule for `target`, like the `.d` files written by
+/// compilers.
+fn make_deps(target: &Path, deps: &[PathBuf]) -> String {
+    // Make splits on spaces and expands `$`, even in file names.
+    let escape = |path: &Path| {
+        path.display()
+            .to_string()
+            .replace('$', "$$")
+            .replace(' ', "\\ ")
     };
+    let mut out = format!("{}:", escape(target));
+    for dep in deps {
+        out += " ";
+        out += &escape(dep);
+    }
+    out + "\n"
+}
+
+/// Writes `deps` one per line.
+fn list_deps(deps: &[PathBuf]) -> String {
+    deps.iter()
+        .map(|dep| format!("{}\n", dep.display()))
+        .collect()
+}
 
-    let out_rs = tempfile::Builder::new().tempfile_in(out_dir)?;
-    let out_cc = tempfile::Builder::new().tempfile_in(out_dir)?;
-    let success = run_generator(&mut sess, cc_modules, headers, &out_rs, &out_cc);
-    if !success {
-        return Ok(101);
+/// Lowers the modules passed to `libclang::set_ast` into a bindings crate.
+///
+/// This is the pipeline used by both the command line tool and tests, and must
+/// be called from inside `set_ast`.
+pub(crate) fn lower_bindings(
+    db: &Database,
+    parse_errs: Vec<libclang::ParseErrors>,
+) -> Outcome<ir::rs::BindingsCrate> {
+    let mut errs = Diagnostics::new();
+    for parse_errs in parse_errs {
+        errs.append(parse_errs.to_diagnostics(db));
     }
-    out_rs.persist(out_base.with_extension("rs"))?;
-    out_cc.persist(out_base.with_extension("cc"))?;
 
-    Ok(0)
+    let jobs = db.options().jobs;
+    if jobs > 1 {
+        if let Err(err) = ir::lower_in_parallel(db, jobs) {
+            // Everything is still lowered serially below, just more slowly.
+            errs.add(Diagnostic::new(
+                Severity::Warning,
+                format!("could not start {} lowering threads: {}", jobs, err),
+            ));
+        }
+    }
+
+    use ir::rs::RsTargetBindings;
+    Outcome::from_parts((), errs)
+        .then(|_| Outcome::clone(&db.rs_bindings()))
+        .then(|krate| {
+            let notes = codegen::no_std_notes(db, &krate);
+            Outcome::from_parts(krate, notes)
+        })
 }
 
 fn run_generator(
@@ -200,7 +1283,9 @@ fn run_generator(
     headers: &[ir::bindings::Header],
     out_rs: impl Write,
     out_cc: impl Write,
-) -> bool {
+    out_ir: IrOutputs<'_>,
+    verify: Option<verify::Verify>,
+) -> Option<Bindings> {
     let mut rs_writer = io::BufWriter::new(out_rs);
     let mut cc_writer = io::BufWriter::new(out_cc);
     let mut rs_writer = codegen::CodeWriter::new(&mut rs_writer);
@@ -215,19 +1300,325 @@ fn run_generator(
 
     let diags = &sess.diags;
     libclang::set_ast(&mut sess.db, cc_modules, |db| {
-        for errs in parse_errs {
-            errs.to_diagnostics(db).emit(db, diags);
-        }
-
-        use ir::rs::RsTargetBindings;
-        let rs_module = db.rs_bindings();
-        let (rs_module, errs) = rs_module.to_ref().split();
-        errs.clone().emit(db, diags);
+        let db = &*db;
+        let (rs_module, errs) = lower_bindings(db, parse_errs).split();
+        errs.emit(db, diags);
 
         if diags.has_errors() {
-            return false;
+            return None;
         }
-        codegen::perform_codegen(db, &rs_module, headers, false, outputs).expect("Codegen failed");
-        true
+        db.metrics
+            .time(timings::Phase::Codegen, || {
+                codegen::perform_codegen(db, &rs_module, headers, false, outputs)
+            })
+            .expect("Codegen failed");
+        if let Some(out) = out_ir.json {
+            ir_json::write(db, &rs_module, out).expect("failed to write the IR");
+        }
+        if let Some(out) = out_ir.text {
+            ir_text::write(db, &rs_module, out).expect("failed to write the IR");
+        }
+        if let Some(verify) = verify {
+            verify::run(db, &rs_module, headers, verify).emit(db, diags);
+            if diags.has_errors() {
+                return None;
+            }
+        }
+        Some(exports::collect(db, &rs_module))
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn main_and_test_pipelines_agree() {
+        let src = "
+            struct Pod {
+                int a;
+                double b;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        ";
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("test.h");
+        std::fs::write(&input_path, src).unwrap();
+
+        let mut sess = Session::test();
+        sess.parse_header(&input_path).unwrap();
+        let (mut rs_out, mut cc_out) = (vec![], vec![]);
+        assert!(sess.generate(&mut rs_out, &mut cc_out).is_some());
+        let rs_out = String::from_utf8(rs_out).unwrap();
+        let cc_out = String::from_utf8(cc_out).unwrap();
+
+        let (rs_expected, cc_expected) =
+            test_util::gen_code(&mut Session::test(), &format!("{{{}}}", src));
+        assert!(!rs_expected.is_empty());
+        // The command line tool also writes a preamble, which tests skip.
+        assert!(rs_out.ends_with(&rs_expected), "{}", rs_out);
+        assert!(cc_out.ends_with(&cc_expected), "{}", cc_out);
+    }
+
+    #[test]
+    fn skip_identical_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.rs");
+        assert!(write_if_changed(&path, b"struct A;").unwrap());
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+
+        assert!(!write_if_changed(&path, b"struct A;").unwrap());
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+
+        assert!(write_if_changed(&path, b"struct B;").unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), b"struct B;");
+    }
+
+    #[test]
+    fn missing_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.h");
+        let mut sess = Session::builder().capture_diagnostics().build();
+        assert!(sess.parse_header(&path).is_err());
+        let diags = sess.diagnostics().unwrap();
+        let expected = format!("header not found: `{}`", path.display());
+        assert!(diags.contains(&expected), "{}", diags);
+
+        let mut sess = Session::builder().capture_diagnostics().build();
+        assert!(sess.parse_header("missing/missing.h").is_err());
+        let diags = sess.diagnostics().unwrap();
+        assert!(diags.contains("searched"), "{}", diags);
+    }
+
+    #[test]
+    fn inline_crate_has_no_inner_attributes() {
+        let src = "
+            struct Pod {
+                int a;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        ";
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("test.h");
+        std::fs::write(&input_path, src).unwrap();
+
+        let mut sess = Session::with_options(SessionOptions {
+            inline_crate: true,
+            ..Default::default()
+        });
+        sess.parse_header(&input_path).unwrap();
+        let (mut rs_out, mut cc_out) = (vec![], vec![]);
+        assert!(sess.generate(&mut rs_out, &mut cc_out).is_some());
+        let rs_out = String::from_utf8(rs_out).unwrap();
+        assert!(!rs_out.contains("#!["), "{}", rs_out);
+        syn::parse_str::<syn::File>(&rs_out).unwrap();
+    }
+
+    #[test]
+    fn no_std() {
+        let src = "
+            using Id = unsigned int;
+            struct Handle;
+            struct Device {
+                Id id;
+                Handle* handle;
+                const char* name() const;
+                void rename(const char* name);
+                static int count();
+            };
+            namespace rust_export {
+                using ::Id;
+                using ::Device;
+            }
+        ";
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("test.h");
+        std::fs::write(&input_path, src).unwrap();
+
+        let mut sess = Session::builder()
+            .options(SessionOptions {
+                no_std: true,
+                newtypes: vec!["Id".into()],
+                ..Default::default()
+            })
+            .capture_diagnostics()
+            .build();
+        sess.parse_header(&input_path).unwrap();
+        let (mut rs_out, mut cc_out) = (vec![], vec![]);
+        assert!(sess.generate(&mut rs_out, &mut cc_out).is_some());
+        let rs_out = String::from_utf8(rs_out).unwrap();
+        assert!(rs_out.contains("#![no_std]\n"), "{}", rs_out);
+        assert!(!rs_out.contains("std::"), "{}", rs_out);
+        let gate = "#[cfg(feature = \"c_str\")]";
+        assert_eq!(rs_out.matches(gate).count(), 2, "{}", rs_out);
+        let diags = sess.diagnostics().unwrap();
+        for helper in &["rename_cstr", "name_str"] {
+            let note = format!(
+                "`Device::{}` is only generated with the `c_str` feature",
+                helper
+            );
+            assert!(diags.contains(&note), "{}", diags);
+        }
+        syn::parse_str::<syn::File>(&rs_out).unwrap();
+    }
+
+    #[test]
+    fn config_sets() {
+        let src = "
+            struct Point {
+            #ifdef USE_DOUBLE
+                double x, y;
+            #else
+                float x, y;
+            #endif
+            };
+            namespace rust_export {
+                using ::Point;
+            }
+        ";
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("test.h");
+        std::fs::write(&input_path, src).unwrap();
+
+        let mut sess = Session::test();
+        for config in &["use_float", "use_double=USE_DOUBLE"] {
+            let config: ConfigSet = config.parse().unwrap();
+            sess.parse_header_with_config(&input_path, &config).unwrap();
+        }
+        let (mut rs_out, mut cc_out) = (vec![], vec![]);
+        let bindings = sess.generate(&mut rs_out, &mut cc_out).unwrap();
+        let rs_out = String::from_utf8(rs_out).unwrap();
+        let cc_out = String::from_utf8(cc_out).unwrap();
+        syn::parse_str::<syn::File>(&rs_out).unwrap();
+
+        let float = bindings.get_struct("use_float::export::Point").unwrap();
+        let double = bindings.get_struct("use_double::export::Point").unwrap();
+        assert_eq!((float.size, double.size), (8, 16));
+        assert_eq!(
+            (&*float.fields[0].ty, &*double.fields[0].ty),
+            ("f32", "f64")
+        );
+        for name in &["use_float", "use_double"] {
+            let gate = format!("#[cfg(feature = \"{0}\")]\npub mod {0} {{", name);
+            assert!(rs_out.contains(&gate), "{}", rs_out);
+            let reexport = format!("pub use crate::{}::bind::Point;", name);
+            assert!(rs_out.contains(&reexport), "{}", rs_out);
+            let guard = format!("#ifdef MOSAIC_CONFIG_{}", name);
+            assert!(cc_out.contains(&guard), "{}", cc_out);
+        }
+        assert_eq!(cc_out.matches("#include \"test.h\"").count(), 1);
+    }
+
+    #[test]
+    fn config_set_names() {
+        let config: ConfigSet = "fast=NDEBUG,LEVEL=2".parse().unwrap();
+        assert_eq!(config.name, "fast");
+        assert_eq!(config.defines, ["NDEBUG", "LEVEL=2"]);
+        assert!("2fast=X".parse::<ConfigSet>().is_err());
+        assert!("bind=X".parse::<ConfigSet>().is_err());
+    }
+
+    #[test]
+    fn manifest_lists_headers_and_libs() {
+        let dir = tempfile::tempdir().unwrap();
+        let rs_path = dir.path().join("lib.rs");
+        std::fs::write(
+            &rs_path,
+            r#"
+            cc_use!("a.h" in "libfoo", A);
+            cc_use!(<vector> in bar, B);
+            cc_use!("sub/c.h", C);
+            "#,
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for hdr in &["a.h", "sub/c.h"] {
+            std::fs::write(dir.path().join(hdr), "").unwrap();
+        }
+
+        let mut sess = Session::test();
+        let file_id = cc_use::SourceFile::intern_from_path(&sess.db, &rs_path).unwrap();
+        sess.db.set_rs_source_root(Some(file_id));
+        let headers = sess.db.headers().skip_errs();
+        let expected = format!(
+            "header {}\nheader {}\nlink-lib bar\nlink-lib foo\n",
+            dir.path().join("a.h").canonicalize().unwrap().display(),
+            dir.path().join("sub/c.h").canonicalize().unwrap().display(),
+        );
+        assert_eq!(expected, manifest(&sess.db, dir.path(), &headers, &[]));
+    }
+
+    #[test]
+    fn deps_formats() {
+        let deps = vec![
+            PathBuf::from("include/a.h"),
+            PathBuf::from("my dir/b.h"),
+            PathBuf::from("$c.h"),
+        ];
+        assert_eq!(
+            make_deps(Path::new("out/foo_bind.rs"), &deps),
+            "out/foo_bind.rs: include/a.h my\\ dir/b.h $$c.h\n"
+        );
+        assert_eq!(list_deps(&deps), "include/a.h\nmy dir/b.h\n$c.h\n");
+    }
+
+    #[test]
+    fn timings() {
+        let src = "
+            struct Pod {
+                int a;
+                int get() const;
+                void set(int value);
+              private:
+                void hidden();
+            };
+            struct Outer {
+                Pod pod;
+                static int count();
+            };
+            namespace rust_export {
+                using ::Pod;
+                using ::Outer;
+            }
+        ";
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("test.h");
+        std::fs::write(&input_path, src).unwrap();
+
+        let mut sess = Session::builder()
+            .options(SessionOptions {
+                verbose: true,
+                ..Default::default()
+            })
+            .capture_diagnostics()
+            .build();
+        sess.parse_header(&input_path).unwrap();
+        assert!(sess.generate(vec![], vec![]).is_some());
+        let report = sess.timings();
+        assert_eq!(report.structs_lowered, 2);
+        assert_eq!(report.functions_lowered, 3);
+        assert_eq!((report.errors, report.warnings, report.notes), (0, 0, 1));
+        assert!(report.queries_executed > 0);
+        assert!(report.parse > std::time::Duration::default());
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        let phases = json["phases"].as_object().unwrap();
+        let mut names: Vec<_> = phases.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["codegen", "exports", "layout", "lower", "parse"]);
+        assert!(phases.values().all(|time| time.as_f64().unwrap() >= 0.0));
+        assert_eq!(json["counts"]["structs_lowered"], 2);
+        assert_eq!(json["counts"]["functions_lowered"], 3);
+        assert!(json["counts"]["queries_executed"].as_u64().unwrap() > 0);
+        assert_eq!(
+            json["diagnostics"],
+            serde_json::json!({ "errors": 0, "warnings": 0, "notes": 1 })
+        );
+    }
+}
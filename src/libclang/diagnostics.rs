@@ -10,9 +10,12 @@
 //! [`crate::diagnostics`] equivalents.
 
 use super::{with_ast_module, CcSource, LocalFileId, ModuleContextInner, ModuleId};
-use crate::diagnostics::{db::SourceFileCache, Diagnostic, Diagnostics, Span};
+use crate::diagnostics::{db::SourceFileCache, Diagnostic, Diagnostics, FileId, Span};
 use crate::SourceFileKind;
-use clang::{source::SourceRange, Entity};
+use clang::{
+    source::{File, SourceLocation, SourceRange},
+    Entity,
+};
 use codespan_reporting::diagnostic::Severity;
 
 /// A C++ source file.
@@ -37,14 +40,39 @@ impl SourceFile {
 ///
 /// These can be resolved with `to_diagnostics()`.
 // We don't actually store the diagnostics here, just the ModuleId so they can be retrieved later.
-pub struct ParseErrors(pub(super) ModuleId);
+pub struct ParseErrors(pub(super) ModuleId, pub(super) Vec<Span>);
 impl ParseErrors {
+    /// Attributes errors on line `n` of the main file to `spans[n - 1]`.
+    ///
+    /// Used when the main file is generated, like the file that includes the
+    /// headers named in a cc_use, so errors such as a missing header point to
+    /// the code that named it.
+    pub(crate) fn attribute_to(self, spans: Vec<Span>) -> Self {
+        ParseErrors(self.0, spans)
+    }
+
     pub fn to_diagnostics(self, db: &(impl CcSource + SourceFileCache)) -> Diagnostics {
         with_ast_module(db, self.0, |tu, ast| {
             Diagnostics::build(|errs| {
                 for err in tu.get_diagnostics() {
-                    if let Some(err) = convert_error(db, self.0, ast, err) {
-                        errs.add(err);
+                    let location = err.get_location();
+                    let main_file_span = match location.is_in_main_file() {
+                        true => {
+                            let line = location.get_file_location().line as usize;
+                            self.1.get(line.saturating_sub(1)).or(self.1.last())
+                        }
+                        false => None,
+                    };
+                    let err = match convert_error(db, self.0, ast, err) {
+                        Some(err) => err,
+                        None => continue,
+                    };
+                    match main_file_span {
+                        Some(span) => errs.add(
+                            Diagnostic::new(err.severity(), err.message())
+                                .with_label(span.label_no_message()),
+                        ),
+                        None => errs.add(err),
                     }
                 }
             })
@@ -74,6 +102,13 @@ fn convert_error<'tu>(
             maybe_span_from_range(db, mdl, ast, Some(*range)).map(|span| span.label_no_message())
         })
         .collect();
+    // Many errors, like redefinitions, only have a location.
+    if labels.is_empty() {
+        labels.extend(
+            maybe_span_from_location(db, mdl, ast, err.get_location())
+                .map(|span| span.label_no_message()),
+        );
+    }
 
     // TODO: Fix invariant lifetime in libclang API :'((
     let err = &err;
@@ -82,8 +117,14 @@ fn convert_error<'tu>(
     for child_err in err.get_children() {
         // TODO: Should be marked secondary
         assert!(child_err.get_children().is_empty());
-        for range in child_err.get_ranges() {
-            if let Some(span) = maybe_span_from_range(db, mdl, ast, Some(range)) {
+        let ranges = child_err.get_ranges();
+        for range in &ranges {
+            if let Some(span) = maybe_span_from_range(db, mdl, ast, Some(*range)) {
+                labels.push(span.label(child_err.get_text()));
+            }
+        }
+        if ranges.is_empty() {
+            if let Some(span) = maybe_span_from_location(db, mdl, ast, child_err.get_location()) {
                 labels.push(span.label(child_err.get_text()));
             }
         }
@@ -120,15 +161,59 @@ pub(super) fn maybe_span_from_range<'tu>(
         (Some(f), Some(g)) if f == g => f,
         _ => return None,
     };
-    let file_id = ast.files.intern(file);
-    let source = SourceFile {
-        module,
-        file: file_id,
-    };
     Some(Span::new(
-        db.intern_source_file(SourceFileKind::Cc(source)),
+        intern_file(db, module, ast, file),
         // TODO this is wrong! char offset instead of byte offsets...
         start.offset,
         end.offset,
     ))
 }
+
+/// Returns a span for the token at `loc`, for diagnostics that only have a
+/// location.
+///
+/// The token is assumed to be an identifier; if there isn't one at `loc`, the
+/// span covers a single character.
+fn maybe_span_from_location<'tu>(
+    db: &impl SourceFileCache,
+    module: ModuleId,
+    ast: &ModuleContextInner<'tu>,
+    loc: SourceLocation<'tu>,
+) -> Option<Span> {
+    let loc = loc.get_file_location();
+    let file = loc.file?;
+    let contents = match ast.virtual_files.get(&file.get_path()) {
+        Some(file) => file.contents.to_string(),
+        None => file.get_contents()?,
+    };
+    let start = loc.offset as usize;
+    let rest = contents.as_bytes().get(start..)?;
+    let len = rest
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+        .count();
+    let len = len.max(1).min(rest.len());
+    Some(Span::new(
+        intern_file(db, module, ast, file),
+        loc.offset,
+        loc.offset + len as u32,
+    ))
+}
+
+/// Interns `file` for use in spans, as a
+/// [`VirtualFile`](crate::diagnostics::VirtualFile) if clang read it from memory.
+fn intern_file<'tu>(
+    db: &impl SourceFileCache,
+    module: ModuleId,
+    ast: &ModuleContextInner<'tu>,
+    file: File<'tu>,
+) -> FileId {
+    let kind = match ast.virtual_files.get(&file.get_path()) {
+        Some(file) => SourceFileKind::Virtual(file.clone()),
+        None => SourceFileKind::Cc(SourceFile {
+            module,
+            file: ast.files.intern(file),
+        }),
+    };
+    db.intern_source_file(kind)
+}
@@ -8,9 +8,12 @@
 
 //! A lazily populated index for looking up entities by name.
 
-use crate::ir::bindings::{Ident, Path, PathComponent};
+use crate::ir::{
+    bindings::{Ident, Path, PathComponent},
+    DefIr,
+};
 use clang::{self, Entity, EntityKind};
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, path::PathBuf};
 
 /// Represents errors which can occur while looking up entities in the index.
 #[derive(Debug)]
@@ -21,6 +24,45 @@ pub enum LookupError {
 /// The result of a lookup operation.
 type Result<T> = std::result::Result<T, LookupError>;
 
+/// The kinds of items returned by [`PathIndex::search_symbols`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SymbolKind {
+    Namespace,
+    Struct,
+    Function,
+    TypeAlias,
+}
+
+/// Options for [`PathIndex::search_symbols`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SearchOptions {
+    /// Also return items declared in system headers.
+    pub include_system_headers: bool,
+    /// The maximum number of hits to return.
+    pub max_results: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            include_system_headers: false,
+            max_results: 50,
+        }
+    }
+}
+
+/// An item found by a symbol search.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SymbolHit {
+    /// The full C++ path of the item, like `ns::Widget`.
+    pub path: String,
+    pub kind: SymbolKind,
+    /// The file the item is declared in.
+    pub file: PathBuf,
+    /// The byte range of the declaration in `file`.
+    pub span: Range<usize>,
+}
+
 /// An ID assigned to a `Path` in the index.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct NodeId(u32);
@@ -89,17 +131,17 @@ impl<'tu> PathIndex<'tu> {
     /// Returns the `Node` corresponding to the given `Path`.
     ///
     /// If the path does not exist, returns `LookupError::NotFound`.
-    pub fn lookup(&mut self, path: &Path) -> Result<&Node<'tu>> {
-        self.lookup_id(&path).map(move |id| self.node(id))
+    pub fn lookup(&mut self, db: &impl DefIr, path: &Path) -> Result<&Node<'tu>> {
+        self.lookup_id(db, &path).map(move |id| self.node(id))
     }
 
     /// Returns the `NodeId` corresponding to the given `Path`.
     ///
     /// If the path does not exist, returns `LookupError::NotFound`.
-    pub fn lookup_id(&mut self, path: &Path) -> Result<NodeId> {
+    pub fn lookup_id(&mut self, db: &impl DefIr, path: &Path) -> Result<NodeId> {
         let mut cur = NodeId(0);
         for (idx, name) in path.iter().enumerate() {
-            cur = match self.child_id_of(cur, name)? {
+            cur = match self.child_id_of(db, cur, name)? {
                 Some(child) => child,
                 None => {
                     let err_path = path.iter().take(idx + 1).cloned().collect::<Path>();
@@ -110,23 +152,134 @@ impl<'tu> PathIndex<'tu> {
         Ok(cur)
     }
 
+    /// Suggests an existing path that is similar to `path`, for use in error
+    /// messages when `path` could not be found.
+    ///
+    /// The first component of `path` that does not exist is replaced with the
+    /// most similar name in its parent. Returns `None` if `path` exists or no
+    /// name is close enough.
+    pub fn suggest(&mut self, db: &impl DefIr, path: &Path) -> Option<Path> {
+        let mut cur = NodeId(0);
+        let mut prefix = vec![];
+        let mut components = path.iter();
+        let missing = loop {
+            let name = components.next()?;
+            match self.child_id_of(db, cur, name).ok()? {
+                Some(child) => {
+                    cur = child;
+                    prefix.push(name.clone());
+                }
+                None => break name,
+            }
+        };
+
+        let missing_name = missing.name.as_str(db);
+        let max_distance = missing_name.len() / 3;
+        let (_, _, best) = self
+            .node(cur)
+            .items
+            .as_ref()
+            .unwrap()
+            .keys()
+            .filter(|candidate| candidate.args == missing.args)
+            .map(|candidate| {
+                let name = candidate.name.as_str(db);
+                (edit_distance(&name, &missing_name), name, candidate)
+            })
+            .filter(|(distance, ..)| *distance <= max_distance)
+            .min_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)))?;
+        prefix.push(best.clone());
+        Some(prefix.into_iter().chain(components.cloned()).collect())
+    }
+
+    /// Returns the items whose name contains `query`, ignoring case, sorted by
+    /// path.
+    ///
+    /// Only items that can be exported are returned, along with namespaces.
+    /// This expands the whole index, so it is much slower than a lookup.
+    pub fn search_symbols(
+        &mut self,
+        db: &impl DefIr,
+        query: &str,
+        kind_filter: Option<SymbolKind>,
+        opts: &SearchOptions,
+    ) -> Vec<SymbolHit> {
+        let query = query.to_lowercase();
+        let mut hits = vec![];
+        let mut stack = vec![(NodeId(0), String::new())];
+        while let Some((node, prefix)) = stack.pop() {
+            if self.node(node).items.is_none() && self.expand(db, node).is_err() {
+                continue;
+            }
+            let children: Vec<(String, NodeId)> = self
+                .node(node)
+                .items
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|(name, &id)| (name.name.as_str(db).to_string(), id))
+                .collect();
+            for (name, child) in children {
+                let entities = &self.node(child).entities;
+                let ent = match entities.iter().find(|ent| ent.is_definition()) {
+                    Some(&ent) => ent,
+                    None => entities[0],
+                };
+                // The contents of inline namespaces are already part of the
+                // enclosing namespace.
+                if ent.is_inline_namespace() {
+                    continue;
+                }
+                let kind = match symbol_kind(ent) {
+                    Some(kind) => kind,
+                    None => continue,
+                };
+                if !opts.include_system_headers && ent.is_in_system_header() {
+                    continue;
+                }
+                let path = format!("{}{}", prefix, name);
+                if kind_filter.is_none_or(|filter| filter == kind)
+                    && name.to_lowercase().contains(&query)
+                {
+                    hits.push(symbol_hit(path.clone(), kind, ent));
+                }
+                if should_expand(ent) {
+                    stack.push((child, path + "::"));
+                }
+            }
+        }
+        hits.sort_by(|a, b| a.path.cmp(&b.path));
+        hits.truncate(opts.max_results);
+        hits
+    }
+
     /// Returns the child named `child` of the given `node`.
     #[allow(dead_code)]
-    pub fn child_of(&mut self, node: NodeId, child: &PathComponent) -> Result<Option<&Node>> {
-        self.child_id_of(node, child)
+    pub fn child_of(
+        &mut self,
+        db: &impl DefIr,
+        node: NodeId,
+        child: &PathComponent,
+    ) -> Result<Option<&Node>> {
+        self.child_id_of(db, node, child)
             .map(|opt| opt.map(move |id| self.node(id)))
     }
 
     /// Returns the `NodeId` of the child named `child` of the given `node`.
-    pub fn child_id_of(&mut self, node: NodeId, child: &PathComponent) -> Result<Option<NodeId>> {
+    pub fn child_id_of(
+        &mut self,
+        db: &impl DefIr,
+        node: NodeId,
+        child: &PathComponent,
+    ) -> Result<Option<NodeId>> {
         if self.node(node).items.is_none() {
-            self.expand(node)?;
+            self.expand(db, node)?;
         }
         let children = self.node(node).items.as_ref().unwrap();
         Ok(children.get(child).copied())
     }
 
-    fn expand(&mut self, node: NodeId) -> Result<()> {
+    fn expand(&mut self, db: &impl DefIr, node: NodeId) -> Result<()> {
         self.node_mut(node).items = Some(HashMap::new());
         let num_entities = self.node(node).entities.len();
         for ent_idx in 0..num_entities {
@@ -135,17 +288,30 @@ impl<'tu> PathIndex<'tu> {
             // TODO: Looking up children of unsupported entity types will result
             // in confusing NotFound errors.
             if should_expand(ent) {
-                self.populate_children(node, ent)?;
+                self.populate_children(db, node, ent)?;
             }
         }
         Ok(())
     }
 
-    fn populate_children(&mut self, parent: NodeId, ent: Entity<'tu>) -> Result<()> {
+    fn populate_children(
+        &mut self,
+        db: &impl DefIr,
+        parent: NodeId,
+        ent: Entity<'tu>,
+    ) -> Result<()> {
         for child in ent.get_children() {
-            let name = match child.get_name() {
-                Some(name) => PathComponent::from(Ident::from(name)),
-                None => continue,
+            // `extern "C"` blocks have no name, and their contents belong to
+            // the enclosing scope.
+            if child.get_kind() == EntityKind::LinkageSpec {
+                self.populate_children(db, parent, child)?;
+                continue;
+            }
+            // Operators and other items whose names aren't identifiers
+            // can't be exported by name.
+            let name = match child.get_name().map(|name| Ident::new(db, &name)) {
+                Some(Ok(name)) => PathComponent::from(name),
+                _ => continue,
             };
             let child_id = self.get_or_insert_child(parent, name);
             self.node_mut(child_id).entities.push(child);
@@ -153,7 +319,7 @@ impl<'tu> PathIndex<'tu> {
             if should_inline(child) {
                 //self.node_mut(parent).inline_items.push(child_id);
                 // Populate the parent with all of this node's children.
-                self.populate_children(parent, child)?;
+                self.populate_children(db, parent, child)?;
             }
         }
         Ok(())
@@ -178,6 +344,51 @@ impl<'tu> PathIndex<'tu> {
     }
 }
 
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = substitute.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+fn symbol_kind(ent: Entity<'_>) -> Option<SymbolKind> {
+    use EntityKind::*;
+    match ent.get_kind() {
+        Namespace => Some(SymbolKind::Namespace),
+        StructDecl | ClassDecl => Some(SymbolKind::Struct),
+        FunctionDecl => Some(SymbolKind::Function),
+        TypedefDecl | TypeAliasDecl => Some(SymbolKind::TypeAlias),
+        _ => None,
+    }
+}
+
+fn symbol_hit(path: String, kind: SymbolKind, ent: Entity<'_>) -> SymbolHit {
+    let (file, span) = match ent.get_range() {
+        Some(range) => {
+            let (start, end) = (range.get_start(), range.get_end());
+            let (start, end) = (start.get_file_location(), end.get_file_location());
+            let file = start.file.map(|file| file.get_path()).unwrap_or_default();
+            (file, start.offset as usize..end.offset as usize)
+        }
+        None => (PathBuf::new(), 0..0),
+    };
+    SymbolHit {
+        path,
+        kind,
+        file,
+        span,
+    }
+}
+
 fn should_expand(ent: Entity<'_>) -> bool {
     use EntityKind::*;
     match ent.get_kind() {
@@ -200,9 +411,9 @@ fn should_inline(ent: Entity<'_>) -> bool {
 mod tests {
     use super::*;
 
-    fn path(p: &str) -> Path {
+    fn path(db: &impl DefIr, p: &str) -> Path {
         p.split("::")
-            .map(Ident::from)
+            .map(|name| Ident::new_mangled(db, name))
             .map(PathComponent::from)
             .collect()
     }
@@ -221,10 +432,147 @@ mod tests {
                 }
             }
         });
+        let db = &crate::Database::new();
+        let mut index = PathIndex::new(&file);
+        assert!(index.lookup(db, &path(db, "std::__1::vector")).is_ok());
+        assert!(index.lookup(db, &path(db, "std::vector")).is_ok());
+        assert!(index.lookup(db, &path(db, "std::notinline::foo")).is_ok());
+        assert!(index.lookup(db, &path(db, "std::foo")).is_err());
+    }
+
+    #[test]
+    fn nested_namespaces() {
+        let clang = crate::libclang::clang();
+        let index = clang::Index::new(&clang, true, true);
+        let file = cpp_parse!(&index, {
+            namespace a {
+                namespace b {
+                    namespace c {
+                        struct Deep {};
+                    }
+                }
+            }
+        });
+        let db = &crate::Database::new();
+        let mut index = PathIndex::new(&file);
+        assert!(index.lookup(db, &path(db, "a::b::c::Deep")).is_ok());
+        assert!(index.lookup(db, &path(db, "a::b::c")).is_ok());
+        assert!(index.lookup(db, &path(db, "a::c::Deep")).is_err());
+        assert!(index.lookup(db, &path(db, "Deep")).is_err());
+    }
+
+    #[test]
+    fn shadowed_names() {
+        let clang = crate::libclang::clang();
+        let index = clang::Index::new(&clang, true, true);
+        let file = cpp_parse!(&index, {
+            struct Foo {};
+            namespace a {
+                struct Foo {};
+                namespace b {
+                    struct Foo {};
+                }
+            }
+        });
+        let db = &crate::Database::new();
+        let mut index = PathIndex::new(&file);
+        let mut parent_of = |p| {
+            let node = index.lookup(db, &path(db, p)).unwrap();
+            assert_eq!(node.entities.len(), 1);
+            let parent = node.entities[0].get_semantic_parent().unwrap();
+            parent.get_name()
+        };
+        assert_eq!(parent_of("Foo"), None);
+        assert_eq!(parent_of("a::Foo").as_deref(), Some("a"));
+        assert_eq!(parent_of("a::b::Foo").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn suggestions() {
+        let clang = crate::libclang::clang();
+        let index = clang::Index::new(&clang, true, true);
+        let file = cpp_parse!(&index, {
+            namespace outer {
+                namespace inner {
+                    struct Widget {};
+                    struct Gadget {};
+                }
+            }
+        });
+        let db = &crate::Database::new();
+        let mut index = PathIndex::new(&file);
+        let mut suggest = |p| {
+            index
+                .suggest(db, &path(db, p))
+                .map(|p| p.display(db).to_string())
+        };
+        assert_eq!(
+            suggest("outer::inner::Widgte").as_deref(),
+            Some("outer::inner::Widget")
+        );
+        assert_eq!(
+            suggest("outer::iner::Gadget").as_deref(),
+            Some("outer::inner::Gadget")
+        );
+        assert_eq!(suggest("outer::inner::Sprocket"), None);
+        assert_eq!(suggest("outer::inner::Widget"), None);
+    }
+
+    #[test]
+    fn search_symbols() {
+        let clang = crate::libclang::clang();
+        let index = clang::Index::new(&clang, true, true);
+        let file = cpp_parse!(&index, {
+            struct Widget {
+                int widgetCount;
+                struct Part {};
+            };
+            int widget_count();
+            typedef Widget WidgetAlias;
+            namespace widgets {
+                struct Gadget {};
+                void make_widget();
+                inline namespace v1 {
+                    struct OldWidget {};
+                }
+            }
+            namespace other {
+                struct Sprocket {};
+            }
+        });
+        let db = &crate::Database::new();
         let mut index = PathIndex::new(&file);
-        assert!(index.lookup(&path("std::__1::vector")).is_ok());
-        assert!(index.lookup(&path("std::vector")).is_ok());
-        assert!(index.lookup(&path("std::notinline::foo")).is_ok());
-        assert!(index.lookup(&path("std::foo")).is_err());
+        let mut search = |query, kind, max_results| {
+            let opts = SearchOptions {
+                max_results,
+                ..Default::default()
+            };
+            index
+                .search_symbols(db, query, kind, &opts)
+                .into_iter()
+                .map(|hit| (hit.path, hit.kind))
+                .collect::<Vec<_>>()
+        };
+        use SymbolKind::*;
+        assert_eq!(
+            search("WID", None, 50),
+            vec![
+                ("Widget".to_string(), Struct),
+                ("WidgetAlias".to_string(), TypeAlias),
+                ("widget_count".to_string(), Function),
+                ("widgets".to_string(), Namespace),
+                ("widgets::OldWidget".to_string(), Struct),
+                ("widgets::make_widget".to_string(), Function),
+            ]
+        );
+        assert_eq!(
+            search("part", Some(Struct), 50),
+            vec![("Widget::Part".to_string(), Struct)]
+        );
+        assert_eq!(
+            search("widget", Some(Function), 1),
+            vec![("widget_count".to_string(), Function)]
+        );
+        assert_eq!(search("gizmo", None, 50), vec![]);
     }
 }
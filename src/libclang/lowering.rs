@@ -11,46 +11,274 @@
 //! The entry point for all code in this module is lowering queries (declared in libclang::db).
 
 use super::{
-    diagnostics::span_for_entity, index, with_ast_module, CcSourceIr, HashType, ModuleContextInner,
-    ModuleId, TypeId,
+    diagnostics::span_for_entity, index, with_ast_module, CcSourceIr, EntityId, ExportId,
+    ExportKey, HashType, ModuleContextInner, ModuleId, TypeId,
 };
 use crate::{
-    diagnostics::{err, ok, Diagnostic, Diagnostics, Outcome, Span},
-    ir::{bindings, DefKind},
+    diagnostics::{err, ok, Diagnostic, Diagnostics, Outcome, Severity, Span},
+    ir::{bindings, DefIr, DefKind},
     ir::{
         cc::{self, *},
         CcSourceImport,
     },
+    timings::{Counter, Phase},
+    util::DisplayName,
 };
 use clang::{
-    self, Accessibility, Entity, EntityKind, EntityVisitResult, TranslationUnit, Type, TypeKind,
+    self, Accessibility, Availability, Entity, EntityKind, EntityVisitResult, EvaluationResult,
+    TranslationUnit, Type, TypeKind,
 };
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::{hash::Hash, sync::Arc};
+use std::{hash::Hash, iter, sync::Arc};
+
+#[cfg(test)]
+thread_local! {
+    /// Counts calls to `lower_struct`, so tests can check what gets recomputed.
+    static LOWER_STRUCT_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    /// Counts executions of `cc_item_for_export`.
+    static LOWER_EXPORT_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    /// Counts executions of `type_of`.
+    static LOWER_TY_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
 
 pub(super) fn lower_ty(db: &impl CcSourceIr, mdl: ModuleId, ty: TypeId) -> Outcome<cc::Ty> {
+    #[cfg(test)]
+    LOWER_TY_CALLS.with(|calls| calls.set(calls.get() + 1));
+
     with_ast_module(db, mdl, |_tu, ast| -> Outcome<cc::Ty> {
         ast.types.lookup(ty).0.lower(&LowerCtx { db, mdl, ast })
     })
 }
 
+pub(super) fn lower_struct(
+    db: &impl CcSourceIr,
+    mdl: ModuleId,
+    ent: EntityId,
+    fallback_name: Ident,
+) -> Outcome<Option<cc::StructId>> {
+    with_ast_module(db, mdl, |_tu, ast| {
+        let ctx = LowerCtx { db, mdl, ast };
+        let ent = ast.entities.lookup(ent);
+        db.metrics().time(Phase::Lower, || {
+            ctx.lower_struct_uncached(&fallback_name, ent)
+        })
+    })
+}
+
 pub(super) fn cc_exported_items(
     db: &impl CcSourceIr,
     mdl: ModuleId,
 ) -> Outcome<Arc<[CcSourceImport]>> {
+    db.cc_exports(mdl).then(|exports| {
+        let mut items = vec![];
+        let mut export_set = HashSet::new();
+        let mut diags = Diagnostics::new();
+        for export in exports.iter() {
+            let (item, errs) = db.cc_item_for_export(*export).split();
+            diags.append(errs);
+            let item = match item {
+                Some(item) => item,
+                None => continue,
+            };
+            if !export_set.insert(item.def) {
+                // TODO we should represent this as unique aliases to
+                // the same item, so we can't have "duplicate" exports.
+                diags.add(Diagnostic::error(
+                    "multiple exports of the same item are not supported",
+                    item.import
+                        .span
+                        .label("this item has already been exported"),
+                ));
+                continue;
+            }
+            items.push(item);
+        }
+        Outcome::from_parts(items.into(), diags)
+    })
+}
+
+pub(super) fn cc_exports(db: &impl CcSourceIr, mdl: ModuleId) -> Outcome<Arc<[ExportId]>> {
+    with_ast_module(db, mdl, |tu, ast| {
+        let ctx = LowerCtx { db, mdl, ast };
+        let exports = db.metrics().time(Phase::Exports, || ctx.get_exports(tu));
+        exports.map(|exports| {
+            let ids = exports
+                .iter()
+                .enumerate()
+                .map(|(index, export)| {
+                    db.intern_cc_export(ExportKey {
+                        mdl,
+                        index,
+                        name: export.name,
+                        path: export.path.clone(),
+                        span: export.span.clone(),
+                    })
+                })
+                .collect();
+            *ast.exports.borrow_mut() = Some(exports);
+            ids
+        })
+    })
+}
+
+pub(super) fn cc_item_for_export(
+    db: &impl CcSourceIr,
+    id: ExportId,
+) -> Outcome<Option<CcSourceImport>> {
+    #[cfg(test)]
+    LOWER_EXPORT_CALLS.with(|calls| calls.set(calls.get() + 1));
+
+    let ExportKey {
+        mdl,
+        index,
+        name,
+        path,
+        span,
+    } = db.lookup_intern_cc_export(id);
     with_ast_module(db, mdl, |tu, ast| {
         let ctx = LowerCtx { db, mdl, ast };
-        ctx.get_exports(tu)
-            .then(|exports| ctx.lower_cc_exports(&exports))
+        let kind = {
+            let mut exports = ast.exports.borrow_mut();
+            // Errors in the list of exports are reported by `cc_exports`.
+            let exports = exports.get_or_insert_with(|| {
+                db.metrics()
+                    .time(Phase::Exports, || ctx.get_exports(tu))
+                    .skip_errs()
+            });
+            match exports.get(index).filter(|export| export.name == name) {
+                Some(export) => export.kind.clone(),
+                None => {
+                    return err(
+                        None,
+                        Diagnostic::bug(
+                            format!("export `{}` not found", name.display(db)),
+                            span.label("exported here"),
+                        ),
+                    )
+                }
+            }
+        };
+        ctx.lower_export(&name, &kind).map(|def| {
+            def.map(|def| CcSourceImport {
+                import: bindings::Import {
+                    mdl,
+                    // If we got Some, the export was valid and the path must exist.
+                    path: path.unwrap(),
+                    alias: None,
+                    span,
+                },
+                export_path: iter::once(name.into()).collect(),
+                def,
+            })
+        })
     })
 }
 
+/// Reports the names in `SessionOptions::exports` that aren't in any module.
+pub(super) fn check_option_exports(db: &impl CcSourceIr) -> Outcome<()> {
+    let errs = Diagnostics::build(|errs| {
+        for (name, suggestion) in missing_option_paths(db, &db.options().exports) {
+            let mut diag = Diagnostic::new(
+                Severity::Error,
+                format!("exported item not found: `{}`", name),
+            );
+            if let Some(suggestion) = suggestion {
+                diag = diag.with_note(format!("did you mean `{}`?", suggestion.display(db)));
+            }
+            errs.add(diag);
+        }
+    });
+    Outcome::from_parts((), errs)
+}
+
+/// Warns about the names in `SessionOptions::skip_members` that aren't in any
+/// module, since they're probably misspelled.
+pub(super) fn check_skip_members(db: &impl CcSourceIr) -> Outcome<()> {
+    warn_missing_paths(db, &db.options().skip_members, "skipped member")
+}
+
+/// Warns about the names in `SessionOptions::opaque` that aren't in any
+/// module.
+pub(super) fn check_opaque_types(db: &impl CcSourceIr) -> Outcome<()> {
+    warn_missing_paths(db, &db.options().opaque, "opaque type")
+}
+
+/// Warns about the names in `SessionOptions::newtypes` that aren't in any
+/// module.
+pub(super) fn check_newtypes(db: &impl CcSourceIr) -> Outcome<()> {
+    warn_missing_paths(db, &db.options().newtypes, "newtype")
+}
+
+fn warn_missing_paths(db: &impl CcSourceIr, names: &[String], what: &str) -> Outcome<()> {
+    let errs = Diagnostics::build(|errs| {
+        for (name, suggestion) in missing_option_paths(db, names) {
+            let mut diag =
+                Diagnostic::new(Severity::Warning, format!("{} not found: `{}`", what, name));
+            if let Some(suggestion) = suggestion {
+                diag = diag.with_note(format!("did you mean `{}`?", suggestion.display(db)));
+            }
+            errs.add(diag);
+        }
+    });
+    Outcome::from_parts((), errs)
+}
+
+/// Returns the paths in `names` that aren't in any module, each with a
+/// similar path that is, if there is one.
+fn missing_option_paths<'a>(
+    db: &impl CcSourceIr,
+    names: &'a [String],
+) -> Vec<(&'a String, Option<bindings::Path>)> {
+    if names.is_empty() {
+        return vec![];
+    }
+    let mut missing: Vec<_> = names.iter().map(|name| (name, None)).collect();
+    for mdl in db.cc_module_ids() {
+        with_ast_module(db, mdl, |_, ast| {
+            let mut index = ast.path_index.borrow_mut();
+            missing.retain(|(name, _)| {
+                option_export_path(db, name).map_or(true, |path| index.lookup(db, &path).is_err())
+            });
+            for (name, suggestion) in &mut missing {
+                if suggestion.is_none() {
+                    *suggestion = option_export_path(db, name)
+                        .ok()
+                        .and_then(|path| index.suggest(db, &path));
+                }
+            }
+        });
+    }
+    missing
+}
+
+/// Parses a full C++ path like `::ns::Foo`, as given in
+/// `SessionOptions::exports` and `SessionOptions::skip_members`.
+///
+/// Returns an error for names that aren't paths of identifiers, like
+/// `std::vector<int>`, which can't name anything in the index.
+fn option_export_path(db: &impl DefIr, name: &str) -> Result<bindings::Path, InvalidIdent> {
+    name.trim_start_matches("::")
+        .split("::")
+        .map(|segment| Ident::new(db, segment).map(bindings::PathComponent::from))
+        .collect()
+}
+
 pub(super) fn cc_item(
     db: &impl CcSourceIr,
     import: bindings::Import,
 ) -> Outcome<Option<CcSourceImport>> {
-    with_ast_module(db, import.mdl, |_tu, ast| {
+    with_ast_module(db, import.mdl, |tu, ast| {
+        // If the header couldn't be parsed, the parse errors already explain
+        // why the item can't be found.
+        let fatal = clang::diagnostic::Severity::Fatal;
+        if tu
+            .get_diagnostics()
+            .iter()
+            .any(|diag| diag.get_severity() == fatal)
+        {
+            return ok(None);
+        }
         let ctx = LowerCtx {
             db,
             mdl: import.mdl,
@@ -76,13 +304,19 @@ pub(super) fn cc_item(
                 .next()
                 .map(|item| ctx.lower(item))
                 .unwrap_or(ok(None))
-                .map(|opt| opt.map(|def| CcSourceImport { import, def }))
+                .map(|opt| {
+                    opt.map(|def| CcSourceImport {
+                        export_path: import.export_path(),
+                        import,
+                        def,
+                    })
+                })
         })
     })
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
-struct Export<'tu> {
+pub(super) struct Export<'tu> {
     /// Name of the export item itself.
     ///
     /// Often the same as the name of the thing being exported.
@@ -118,27 +352,41 @@ impl<'tu> ExportKind<'tu> {
         }
     }
 
+    /// Returns whether two exports refer to the same C++ entity. Each using
+    /// declaration refers to it through a cursor of its own, so entities
+    /// are compared by USR where they have one.
+    fn same_entity(&self, other: &ExportKind<'tu>) -> bool {
+        let usr = |kind: &ExportKind<'tu>| kind.declaration()?.get_usr().map(|usr| usr.0);
+        match (usr(self), usr(other)) {
+            (Some(usr), Some(other_usr)) => usr == other_usr,
+            _ => self == other,
+        }
+    }
+
     /// Creates a synthetic bindings path for the C++ entity.
-    fn synthetic_path(&self) -> Option<bindings::Path> {
+    fn synthetic_path(&self, db: &impl DefIr) -> Option<bindings::Path> {
         let target_decl = self.declaration()?;
         let mut components = vec![target_decl.get_name().unwrap()];
-        let mut parent = target_decl.get_semantic_parent();
+        let mut parent = path_parent(target_decl);
         while let Some(ent) = parent {
             if ent.get_kind() == EntityKind::TranslationUnit {
                 break;
             }
             // TODO: If there are template params on this entity, collect them here.
-            components.push(ent.get_name().unwrap());
-            parent = ent.get_semantic_parent();
-        }
-        Some(
-            components
-                .into_iter()
-                .rev()
-                .map(Ident::from)
-                .map(bindings::PathComponent::from)
-                .collect::<bindings::Path>(),
-        )
+            // Items in anonymous namespaces have no path; lowering reports them.
+            components.push(ent.get_name()?);
+            parent = path_parent(ent);
+        }
+        // Lowering reports names that aren't identifiers, like operators.
+        components
+            .into_iter()
+            .rev()
+            .map(|name| {
+                Ident::new(db, &name)
+                    .ok()
+                    .map(bindings::PathComponent::from)
+            })
+            .collect()
     }
 }
 
@@ -154,15 +402,18 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         let mut indices = HashMap::new();
 
         let errs = Diagnostics::build(|mut diags| {
-            for ent in tu.get_entity().get_children() {
+            for ent in top_level_decls(tu.get_entity()) {
                 if let EntityKind::Namespace = ent.get_kind() {
                     if let Some("rust_export") = ent.get_name().as_deref() {
-                        for decl in ent.get_children() {
+                        for decl in rust_export_decls(ent) {
                             self.handle_rust_export(decl, &mut exports, &mut indices, &mut diags);
                         }
                     }
                 }
             }
+            for name in &self.db.options().exports {
+                self.handle_option_export(name, &mut exports, &mut indices, &mut diags);
+            }
         });
 
         Outcome::from_parts(exports, errs)
@@ -177,10 +428,16 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
     ) {
         match self.make_export(decl) {
             Some(kind) => {
-                let name = decl.get_name().unwrap().into();
+                let name = match self.ident(&decl.get_name().unwrap(), decl, "export") {
+                    Ok(name) => name,
+                    Err(diag) => {
+                        diags.add(diag);
+                        return;
+                    }
+                };
                 self.maybe_add_export(
                     name,
-                    kind.synthetic_path(),
+                    kind.synthetic_path(self.db),
                     kind,
                     self.span(decl),
                     exports,
@@ -196,9 +453,50 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         }
     }
 
+    /// Exports an item named with `SessionOptions::exports`, if it's in this
+    /// module. Names that aren't in any module are reported by
+    /// [`check_option_exports`].
+    fn handle_option_export(
+        &self,
+        name: &str,
+        exports: &mut Vec<Export<'tu>>,
+        indices: &mut HashMap<Ident, usize>,
+        diags: &mut Diagnostics,
+    ) {
+        // Names that aren't paths are reported as not found, like any other
+        // name that isn't in the index.
+        let path = match option_export_path(self.db, name) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let ent = match self.ast.path_index.borrow_mut().lookup(self.db, &path) {
+            // In C, `typedef struct Foo { ... } Foo;` gives both the struct
+            // and the typedef the same name, so prefer the definition.
+            Ok(node) => node
+                .entities
+                .iter()
+                .copied()
+                .find(|ent| ent.is_definition())
+                .unwrap_or(node.entities[0]),
+            Err(_) => return,
+        };
+        self.maybe_add_export(
+            path.iter().last().unwrap().name,
+            Some(path),
+            ExportKind::Decl(ent),
+            self.span(ent),
+            exports,
+            indices,
+            diags,
+        );
+    }
+
     fn make_export(&self, decl: Entity<'tu>) -> Option<ExportKind<'tu>> {
         Some(match decl.get_kind() {
             EntityKind::UsingDeclaration => ExportKind::Decl(decl.get_reference().unwrap()),
+            // An alias of a builtin type declared in `rust_export` is bound
+            // where it's declared, like any other alias.
+            EntityKind::TypeAliasDecl if is_builtin_alias(decl) => ExportKind::Decl(decl),
             EntityKind::TypeAliasDecl => {
                 ExportKind::Type(HashType(decl.get_typedef_underlying_type().unwrap()))
             }
@@ -217,19 +515,25 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         indices: &mut HashMap<Ident, usize>,
         diags: &mut Diagnostics,
     ) {
+        // `rust_export` blocks in different headers may export the same item
+        // again, which is fine as long as it's the same entity.
         if let Some(idx) = indices.get(&name) {
             let existing_export = &exports[*idx];
-            if existing_export.kind != kind {
+            if !existing_export.kind.same_entity(&kind) {
                 diags.add(
                     Diagnostic::error(
                         "conflicting name in exported items",
-                        span.label(format!("the name `{}` has already been exported", name)),
+                        span.label(format!(
+                            "the name `{}` has already been exported",
+                            name.as_str(self.db)
+                        )),
                     )
                     .with_label(existing_export.span.label("previous export defined here")),
                 )
             }
             return;
         }
+        indices.insert(name, exports.len());
         exports.push(Export {
             name,
             path,
@@ -247,7 +551,7 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         indices: &mut HashMap<Ident, usize>,
         diags: &mut Diagnostics,
     ) {
-        let ent = match index.lookup(&path) {
+        let ent = match index.lookup(self.db, &path) {
             Ok(node) => match node.entities.as_slice() {
                 [ent] => *ent,
                 [] => unreachable!(),
@@ -257,16 +561,21 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
                 _ => todo!("report error"),
             },
             Err(index::LookupError::NotFound(_)) => {
-                return diags.add(Diagnostic::error(
-                    format!("item not found: `{}`", path),
+                let mut diag = Diagnostic::error(
+                    format!("item not found: `{}`", path.display(self.db)),
                     span.label("this item could not be found"),
-                ));
+                );
+                if let Some(suggestion) = index.suggest(self.db, &path) {
+                    diag =
+                        diag.with_note(format!("did you mean `{}`?", suggestion.display(self.db)));
+                }
+                return diags.add(diag);
             }
         };
         // Assume this would be an ordinary using decl. TODO: Don't.
         let span = self.span(ent); // TODO this should be a span to the rust cc_use
         self.maybe_add_export(
-            path.iter().last().unwrap().name.clone(),
+            path.iter().last().unwrap().name,
             Some(path),
             ExportKind::Decl(ent),
             span,
@@ -278,66 +587,21 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
 }
 
 impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
-    /// Lowers the set of items in the `rust_export` namespace in C++.
-    fn lower_cc_exports(&self, exports: &[Export<'tu>]) -> Outcome<Arc<[CcSourceImport]>> {
-        let mut items = vec![];
-        let mut export_set = HashSet::new();
-        let mut diags = Diagnostics::new();
-        for export in exports {
-            let (def, errs) = self
-                .lower_export(&export.name, &export.kind, Some(&mut export_set))
-                .split();
-            items.extend(
-                def.map(|def| CcSourceImport {
-                    import: bindings::Import {
-                        mdl: self.mdl,
-                        // If we got Some, the export was valid and the path must exist.
-                        path: export.path.clone().unwrap(),
-                        span: export.span.clone(),
-                    },
-                    def,
-                })
-                .into_iter(),
-            );
-            diags.append(errs);
-        }
-        Outcome::from_parts(items.into(), diags)
-    }
-
     fn lower(&self, export: &Export<'tu>) -> Outcome<Option<DefKind>> {
-        self.lower_export(&export.name, &export.kind, None)
+        self.lower_export(&export.name, &export.kind)
     }
 
     fn lower_export(
         &self,
         fallback_name: &Ident,
         export: &ExportKind<'tu>,
-        export_set: Option<&mut HashSet<DefKind>>,
     ) -> Outcome<Option<DefKind>> {
         match export {
-            ExportKind::Decl(decl_ref) => self.lower_decl(fallback_name, *decl_ref).then(|item| {
-                if let Some(kind) = item {
-                    let def = DefKind::CcDef(kind);
-                    if let Some(set) = export_set {
-                        if !set.insert(def.clone()) {
-                            // TODO we should represent this as unique aliases to
-                            // the same item, so we can't have "duplicate" exports.
-                            return err(
-                                None,
-                                Diagnostic::error(
-                                    "multiple exports of the same item are not supported",
-                                    self.span(*decl_ref)
-                                        .label("this item has already been exported"),
-                                ),
-                            );
-                        }
-                    }
-                    return ok(Some(def));
-                }
-                ok(None)
-            }),
+            ExportKind::Decl(decl_ref) => self
+                .lower_decl(fallback_name, *decl_ref)
+                .map(|item| item.map(DefKind::CcDef)),
             ExportKind::Type(ty) => {
-                println!("{} = {:?}", fallback_name, ty);
+                println!("{} = {:?}", fallback_name.as_str(self.db), ty);
                 println!(
                     "  {:?}",
                     ty.0.get_elaborated_type()
@@ -347,7 +611,7 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
                 ok(None)
             }
             ExportKind::TemplateType(t) => {
-                println!("{} = {:?}", fallback_name, t);
+                println!("{} = {:?}", fallback_name.as_str(self.db), t);
                 for child in t.get_children() {
                     match child.get_kind() {
                         EntityKind::TemplateTypeParameter => {
@@ -391,6 +655,53 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
             EntityKind::StructDecl => self
                 .lower_struct(fallback_name, ent)
                 .map(|st| st.map(cc::ItemKind::Struct)),
+            EntityKind::FunctionDecl if self.in_anonymous_namespace(ent) => {
+                match self.ident(&ent.get_name().unwrap(), ent, "function") {
+                    Ok(name) => err(None, self.internal_linkage_error(&name, ent)),
+                    Err(diag) => err(None, diag),
+                }
+            }
+            EntityKind::FunctionDecl => {
+                let mut errs = Diagnostics::new();
+                let func = self.lower_function(ent, &mut errs).map(|func| {
+                    let id = self.db.intern_cc_fn(Arc::new(ok(func)));
+                    cc::ItemKind::Function(id)
+                });
+                Outcome::from_parts(func, errs)
+            }
+            EntityKind::TypedefDecl | EntityKind::TypeAliasDecl => {
+                // Support C-style `typedef struct { ... } Foo;` by lowering
+                // the anonymous record under the typedef's name.
+                let underlying = ent
+                    .get_typedef_underlying_type()
+                    .unwrap()
+                    .get_canonical_type();
+                match underlying.get_declaration() {
+                    Some(record)
+                        if record.get_kind() == EntityKind::StructDecl
+                            && record.get_name().is_none() =>
+                    {
+                        let name = match self.ident(&ent.get_name().unwrap(), ent, "typedef") {
+                            Ok(name) => name,
+                            Err(diag) => return err(None, diag),
+                        };
+                        self.lower_struct(&name, record)
+                            .map(|st| st.map(cc::ItemKind::Struct))
+                    }
+                    _ if is_builtin_alias(ent) => self
+                        .lower_alias(ent)
+                        .map(|alias| alias.map(cc::ItemKind::Alias)),
+                    _ => err(
+                        None,
+                        Diagnostic::error(
+                            "unsupported typedef",
+                            self.span(ent).label(
+                                "only typedefs of anonymous structs and builtin types are supported",
+                            ),
+                        ),
+                    ),
+                }
+            }
             //other => eprintln!("{}: Unsupported type {:?}", name, other),
             other => err(
                 None,
@@ -402,52 +713,99 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         }
     }
 
+    /// Returns whether `ent` is declared in an anonymous namespace, which
+    /// gives it internal linkage.
+    fn in_anonymous_namespace(&self, ent: Entity<'tu>) -> bool {
+        let mut parent = path_parent(ent);
+        while let Some(ent) = parent {
+            if ent.get_kind() == EntityKind::Namespace && ent.is_anonymous() {
+                return true;
+            }
+            parent = path_parent(ent);
+        }
+        false
+    }
+
+    fn internal_linkage_error(&self, name: &Ident, ent: Entity<'tu>) -> Diagnostic {
+        Diagnostic::error(
+            format!("`{}` has internal linkage", name.as_str(self.db)),
+            self.span(ent).label(
+                "this is declared in an anonymous namespace, so the bindings can't refer to it",
+            ),
+        )
+        .with_note(format!(
+            "move `{}` to a named namespace to export it",
+            name.as_str(self.db)
+        ))
+    }
+
     fn lower_parent(&self, ent: Entity<'tu>) -> Outcome<cc::NamespaceId> {
-        let parent = ent
-            .get_semantic_parent()
-            .expect("`lower_parent` called on the root namespace");
-        if parent.get_semantic_parent().is_none() {
+        let parent = path_parent(ent).expect("`lower_parent` called on the root namespace");
+        if path_parent(parent).is_none() {
             ok(self.db.intern_cc_namespace(cc::Namespace {
-                name: Ident::from(""),
+                name: Ident::new_mangled(self.db, ""),
                 parent: None,
             }))
         } else {
             let name = parent.get_name().expect("Entity with anonymous parent");
-            self.lower_parent(parent).map(|parent| {
-                self.db.intern_cc_namespace(cc::Namespace {
-                    name: Ident::from(name),
-                    parent: Some(parent),
-                })
+            let name = self.ident(&name, parent, "namespace");
+            self.lower_parent(parent).then(|grandparent| match name {
+                Ok(name) => ok(self.db.intern_cc_namespace(cc::Namespace {
+                    name,
+                    parent: Some(grandparent),
+                })),
+                // The item is still given a parent, so lowering can go on
+                // and report the rest of its errors.
+                Err(diag) => err(grandparent, diag),
             })
         }
     }
 
+    /// Lowers a struct, or returns the result of lowering it earlier.
     fn lower_struct(
         &self,
         fallback_name: &Ident,
         ent: Entity<'tu>,
+    ) -> Outcome<Option<cc::StructId>> {
+        let ent = self.ast.entities.intern(ent);
+        self.db.cc_struct(self.mdl, ent, *fallback_name)
+    }
+
+    fn lower_struct_uncached(
+        &self,
+        fallback_name: &Ident,
+        ent: Entity<'tu>,
     ) -> Outcome<Option<cc::StructId>> {
         assert_eq!(ent.get_kind(), EntityKind::StructDecl);
+        #[cfg(test)]
+        LOWER_STRUCT_CALLS.with(|calls| calls.set(calls.get() + 1));
+        self.db.metrics().count(Counter::StructsLowered);
 
-        let name = ent
-            .get_name()
-            .map(Ident::from)
-            .unwrap_or_else(|| fallback_name.clone());
+        let name = record_name(self.db, ent).unwrap_or(*fallback_name);
+        if self.in_anonymous_namespace(ent) {
+            return err(None, self.internal_linkage_error(&name, ent));
+        }
+        // Opaque structs keep their layout and methods, but their fields are
+        // only lowered to accessors, and layout attributes are ignored.
+        let opaque = self.is_opaque(ent, &name);
         let ty = ent.get_type().unwrap();
         if !ty.is_pod() {
-            return err(
+            return Outcome::from_parts(
                 None,
-                Diagnostic::error(
-                    "unsupported type",
-                    self.span(ent).label("only POD structs are supported"),
-                ),
+                Diagnostics::build(|errs| {
+                    errs.add(Diagnostic::error(
+                        "unsupported type",
+                        self.span(ent).label("only POD structs are supported"),
+                    ));
+                    self.explain_non_pod(&name, ent, errs);
+                }),
             );
         }
 
         // Check for incomplete types in one place.
         // After that, alignof and every field offset should succeed.
         let size = match ty.get_sizeof() {
-            Ok(size) => size.try_into().expect("size too big"),
+            Ok(size) => size as u64,
             Err(e) => {
                 return err(
                     None,
@@ -459,65 +817,171 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
                 );
             }
         };
-        let align = ty.get_alignof().unwrap().try_into().expect("align too big");
+        let raw_align = ty.get_alignof().unwrap();
+        let align = match raw_align.try_into().ok().and_then(cc::Align::try_new) {
+            Some(align) => align,
+            None => {
+                return err(
+                    None,
+                    Diagnostic::error(
+                        "unsupported alignment",
+                        self.span(ent)
+                            .label("this type's alignment is not supported"),
+                    )
+                    .with_note(format!("the alignment is {}", raw_align)),
+                );
+            }
+        };
 
         let mut fields = vec![];
         let mut offsets = vec![];
-        let mut methods = vec![];
-        let mut align_attr = None;
+        let mut methods: Vec<cc::Function> = vec![];
+        let mut attrs = cc::LayoutAttrs::default();
+        // The largest alignment of any field's type, before packing.
+        let mut natural_align = 1;
+        let mut unions = 0;
         let mut errs = Diagnostics::new();
-        ent.visit_children(|child, _| {
-            match child.get_kind() {
-                EntityKind::FieldDecl => {
-                    self.lower_field(child, &mut fields, &mut offsets, &mut errs)
-                }
-                EntityKind::Method => self.lower_method(child, &mut methods, &mut errs),
-                EntityKind::AlignedAttr => {
-                    // HACK: Instead of parse the attribute, we just use the
-                    // final value given by libclang here.
-                    align_attr = Some((cc::Align::new(align), self.span(child)));
-                }
-                EntityKind::PackedAttr => {
-                    errs.add(Diagnostic::error(
-                        "packed structs not supported",
-                        self.span(child).label("this attribute is not allowed"),
-                    ));
-                }
-                EntityKind::UnexposedAttr => {
-                    errs.add(Diagnostic::warn(
-                        "unknown attribute",
-                        self.span(child).label("this attribute is not recognized"),
-                    ));
-                }
-                _ => {
-                    errs.add(Diagnostic::bug(
-                        "unhandled child of struct",
-                        self.span(child)
-                            .label("this kind of item is not handled yet"),
-                    ));
-                    #[cfg(test)]
-                    eprintln!("unhandled child: {:?}", child);
+        // The struct's own members are lowered first, then those of each of
+        // its bases. A standard-layout struct only has fields in one class of
+        // its hierarchy, so the fields stay in order either way.
+        let mut class: Option<(Entity<'tu>, Option<cc::Path>)> = Some((ent, None));
+        while let Some((current, inherited_from)) = class.take() {
+            let class_ty = current.get_type().unwrap();
+            let inherited = inherited_from.is_some();
+            // A method hides every method of its bases with the same name,
+            // whatever their signatures.
+            let hidden: HashMap<Ident, Span> = methods
+                .iter()
+                .map(|meth| (meth.name, meth.span.clone()))
+                .collect();
+            let own_methods = methods.len();
+            let mut bases = vec![];
+            current.visit_children(|child, _| {
+                match child.get_kind() {
+                    EntityKind::BaseSpecifier => bases.push(child),
+                    EntityKind::Method if inherited && !self.db.options().inherited_methods => (),
+                    EntityKind::Method => self.lower_method(child, &mut methods, &mut errs),
+                    EntityKind::FieldDecl if opaque => {
+                        self.lower_accessors(child, &mut methods, &mut errs)
+                    }
+                    _ if opaque => (),
+                    EntityKind::FieldDecl => {
+                        if let Ok(field_align) = child.get_type().unwrap().get_alignof() {
+                            natural_align = std::cmp::max(natural_align, field_align);
+                        }
+                        self.lower_field(child, &mut fields, &mut offsets, &mut errs)
+                    }
+                    EntityKind::UnionDecl if is_anonymous_member(class_ty, child) => {
+                        if let Ok(union_align) = child.get_type().unwrap().get_alignof() {
+                            natural_align = std::cmp::max(natural_align, union_align);
+                        }
+                        self.lower_anonymous_union(
+                            class_ty,
+                            child,
+                            unions,
+                            &mut fields,
+                            &mut offsets,
+                            &mut errs,
+                        );
+                        unions += 1;
+                    }
+                    EntityKind::AccessSpecifier => (),
+                    // The layout of a base already shows in the struct's.
+                    _ if inherited => (),
+                    EntityKind::AlignedAttr
+                    | EntityKind::PackedAttr
+                    | EntityKind::UnexposedAttr => match layout_attr(child) {
+                        // HACK: Instead of parse the attribute, we just use the
+                        // final value given by libclang here.
+                        LayoutAttr::Aligned => {
+                            attrs.align_override = Some((align, self.span(child)))
+                        }
+                        LayoutAttr::Packed => {
+                            attrs.packed = Some((cc::Align::new(1), self.span(child)))
+                        }
+                        LayoutAttr::Implicit => (),
+                        LayoutAttr::Unknown(name) => errs.add(Diagnostic::warn(
+                            format!("unknown attribute `{}`", name),
+                            self.span(child).label("this attribute is not recognized"),
+                        )),
+                    },
+                    _ => {
+                        errs.add(Diagnostic::bug(
+                            "unhandled child of struct",
+                            self.span(child)
+                                .label("this kind of item is not handled yet"),
+                        ));
+                        #[cfg(test)]
+                        eprintln!("unhandled child: {:?}", child);
+                    }
+                };
+                EntityVisitResult::Continue
+            });
+            if let Some(base_path) = &inherited_from {
+                let mut own = methods.split_off(own_methods);
+                own.retain(|meth| {
+                    let hiding = match hidden.get(&meth.name) {
+                        Some(hiding) => hiding,
+                        None => return true,
+                    };
+                    errs.add(
+                        Diagnostic::info(
+                            format!(
+                                "`{}::{}` is hidden in `{}`",
+                                base_path.display(self.db),
+                                meth.name.as_str(self.db),
+                                name.as_str(self.db)
+                            ),
+                            meth.span.label("this method is not bound"),
+                        )
+                        .with_label(hiding.label("hidden by this method"))
+                        .with_note("a method hides every base method with the same name"),
+                    );
+                    false
+                });
+                for meth in &mut own {
+                    meth.inherited_from = inherited_from.clone();
                 }
-            };
-            EntityVisitResult::Continue
-        });
+                methods.extend(own);
+            }
+            let (base, base_errs) = self.lower_base(&bases).split();
+            errs.append(base_errs);
+            class = base.map(|(base, path)| (base, Some(path)));
+        }
+
+        // `#pragma pack` leaves no attribute we can see, but it shows in the
+        // layout as a struct less aligned than its fields. This guess misses
+        // packing that doesn't lower any field's alignment, which leaves the
+        // layout unchanged anyway. It also misses packing in a struct with an
+        // alignment attribute, and `packed` attributes on single fields,
+        // which don't always lower the struct's alignment; those structs are
+        // reported when their offsets don't match.
+        if attrs.packed.is_none() && attrs.align_override.is_none() && natural_align > raw_align {
+            attrs.packed = Some((align, self.span(ent)));
+        }
 
-        let (parent, err) = self.lower_parent(ent).split();
-        errs.append(err);
-        let st = if errs.is_empty() {
-            let st = self.db.intern_cc_struct(cc::Struct {
-                name: name.clone(),
+        // Messages about members don't name the struct, so point it out.
+        // These errors are cached with the struct, so they're only noted
+        // once.
+        let members = Outcome::from_parts((), errs)
+            .with_context(|| format!("in struct `{}`", name.as_str(self.db)));
+        let (((), parent), errs) = members.zip(self.lower_parent(ent)).split();
+        let st = if !errs.has_errors() {
+            let st = self.db.intern_cc_struct(Arc::new(cc::Struct {
+                name,
                 parent,
                 fields,
-                align_attr,
+                attrs,
                 layout: Some(cc::StructLayout {
                     field_offsets: offsets,
                     size: cc::Size::new(size),
-                    align: cc::Align::new(align),
+                    align,
                 }),
                 methods,
+                opaque,
+                deprecated: deprecation(ent),
                 span: self.span(ent),
-            });
+            }));
             Some(st)
         } else {
             None
@@ -525,36 +989,239 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         Outcome::from_parts(st, errs)
     }
 
+    /// Finds the definition of the base class a struct inherits its members
+    /// from, given the struct's base specifiers, and its path.
+    ///
+    /// Only single, public inheritance is supported. Virtual bases never get
+    /// here, since they keep a struct from being POD.
+    fn lower_base(&self, bases: &[Entity<'tu>]) -> Outcome<Option<(Entity<'tu>, cc::Path)>> {
+        let base = match bases {
+            [] => return ok(None),
+            [base] => *base,
+            [_, extra, ..] => {
+                return err(
+                    None,
+                    Diagnostic::error(
+                        "multiple inheritance is not supported",
+                        self.span(*extra).label("only one base class is supported"),
+                    ),
+                );
+            }
+        };
+        if base.get_accessibility() != Some(Accessibility::Public) {
+            return err(
+                None,
+                Diagnostic::error(
+                    "unsupported base class",
+                    self.span(base)
+                        .label("only public base classes are supported"),
+                ),
+            );
+        }
+        let decl = match base
+            .get_type()
+            .and_then(|ty| ty.get_declaration())
+            .and_then(|decl| decl.get_definition())
+        {
+            Some(decl) => decl,
+            None => {
+                return err(
+                    None,
+                    Diagnostic::error(
+                        "incomplete or dependent base class",
+                        self.span(base)
+                            .label("only complete base classes are supported"),
+                    ),
+                );
+            }
+        };
+        let name = match record_name(self.db, decl) {
+            Some(name) => name,
+            None => {
+                return err(
+                    None,
+                    Diagnostic::error(
+                        "unsupported base class",
+                        self.span(base).label("base classes must be named"),
+                    ),
+                );
+            }
+        };
+        self.lower_parent(decl).map(|parent| {
+            let path = parent.lookup(self.db).path(self.db).join(name).into();
+            Some((decl, path))
+        })
+    }
+
+    /// Lowers a record that is declared but never defined.
+    fn lower_opaque(&self, fallback_name: &Ident, decl: Entity<'tu>) -> Outcome<cc::OpaqueId> {
+        let name = record_name(self.db, decl).unwrap_or(*fallback_name);
+        let usr = decl
+            .get_usr()
+            .map_or_else(|| name.as_str(self.db).to_string(), |usr| usr.0);
+        self.lower_parent(decl)
+            .map(|parent| self.db.intern_cc_opaque(cc::Opaque { name, parent, usr }))
+    }
+
+    /// Lowers a typedef or alias of a builtin type.
+    fn lower_alias(&self, decl: Entity<'tu>) -> Outcome<Option<cc::AliasId>> {
+        let name = match self.ident(&decl.get_name().unwrap(), decl, "alias") {
+            Ok(name) => name,
+            Err(diag) => return err(None, diag),
+        };
+        let target = decl
+            .get_typedef_underlying_type()
+            .unwrap()
+            .get_canonical_type();
+        let newtype = self.is_newtype(decl, &name);
+        let span = self.span(decl);
+        self.lower_parent(decl).then(|parent| {
+            target.lower(self).map(|target| {
+                Some(self.db.intern_cc_alias(cc::Alias {
+                    name,
+                    parent,
+                    target,
+                    newtype,
+                    span,
+                }))
+            })
+        })
+    }
+
+    /// Adds a note for each member of a non-POD struct that keeps it from
+    /// being POD.
+    fn explain_non_pod(&self, name: &Ident, ent: Entity<'tu>, errs: &mut Diagnostics) {
+        ent.visit_children(|child, _| {
+            let span = self.span(child);
+            match child.get_kind() {
+                EntityKind::FieldDecl => {
+                    let field_ty = child.get_type().unwrap();
+                    if field_ty.is_pod() {
+                        return EntityVisitResult::Continue;
+                    }
+                    let mut diag = Diagnostic::info(
+                        format!(
+                            "field `{}` has non-trivial type `{}`",
+                            child.get_name().unwrap_or_default(),
+                            field_ty.get_display_name()
+                        ),
+                        span.label(format!(
+                            "this field keeps `{}` from being POD",
+                            name.as_str(self.db)
+                        )),
+                    );
+                    if is_std_atomic(field_ty) {
+                        diag = diag.with_note(
+                            "atomic fields may be supported in the future through \
+                             volatile-style accessors",
+                        );
+                    }
+                    errs.add(diag);
+                }
+                EntityKind::BaseSpecifier if child.is_virtual_base() => {
+                    errs.add(Diagnostic::info(
+                        format!("`{}` has a virtual base", name.as_str(self.db)),
+                        span.label("virtual bases are not supported"),
+                    ));
+                }
+                EntityKind::BaseSpecifier if !child.get_type().unwrap().is_pod() => {
+                    errs.add(Diagnostic::info(
+                        format!(
+                            "`{}` has non-trivial base `{}`",
+                            name.as_str(self.db),
+                            child.get_type().unwrap().get_display_name()
+                        ),
+                        span.label("bases must be POD too"),
+                    ));
+                }
+                kind @ EntityKind::Constructor | kind @ EntityKind::Destructor
+                    if !child.is_defaulted() =>
+                {
+                    let what = match kind {
+                        EntityKind::Constructor => "constructor",
+                        _ => "destructor",
+                    };
+                    errs.add(Diagnostic::info(
+                        format!("`{}` has a user-provided {}", name.as_str(self.db), what),
+                        span.label("special members must be trivial"),
+                    ));
+                }
+                EntityKind::Method if child.is_virtual_method() => {
+                    errs.add(Diagnostic::info(
+                        format!(
+                            "`{}` has virtual method `{}`",
+                            name.as_str(self.db),
+                            child.get_name().unwrap_or_default()
+                        ),
+                        span.label("virtual methods are not supported"),
+                    ));
+                }
+                _ => (),
+            }
+            EntityVisitResult::Continue
+        });
+    }
+
     fn lower_field(
         &self,
         field: Entity<'tu>,
         fields: &mut Vec<Field>,
-        offsets: &mut Vec<u16>,
+        offsets: &mut Vec<cc::Offset>,
         errs: &mut Diagnostics,
     ) {
-        if let Some(acc) = field.get_accessibility() {
-            if Accessibility::Public != acc {
+        if !self.is_accessible(field) {
+            self.note_skipped(field, "field", errs);
+            return;
+        }
+        let field_name = match field
+            .get_name()
+            .map(|name| self.ident(&name, field, "field"))
+        {
+            Some(Ok(name)) => name,
+            Some(Err(diag)) => {
+                errs.add(diag);
                 return;
             }
-        }
-        let field_name = match field.get_name() {
-            Some(name) => name,
             // Don't "peer through" anonymous struct/union fields, for now.
             // This will report an error when checking layouts.
             // TODO report an error here
             None => return,
         };
-        let ty = self.mk_type_ref(field.get_type().unwrap());
-        fields.push(Field {
-            name: Ident::from(field_name),
-            ty,
-            span: self.span(field),
+        let hidden = self.is_skipped(field);
+        let ty = match hidden {
+            true => match self.padding_for(field) {
+                Some(padding) => {
+                    errs.add(
+                        Diagnostic::info(
+                            format!("skipping field `{}`", field_name.as_str(self.db)),
+                            self.span(field).label("this field is in `skip_members`"),
+                        )
+                        .with_note("it is kept as private padding, so the layout doesn't change"),
+                    );
+                    padding
+                }
+                None => {
+                    errs.add(Diagnostic::error(
+                        format!("cannot skip field `{}`", field_name.as_str(self.db)),
+                        self.span(field)
+                            .label("no padding has the size and alignment of this field"),
+                    ));
+                    return;
+                }
+            },
+            false => self.mk_type_ref(field.get_type().unwrap()),
+        };
+        fields.push(Field {
+            name: field_name,
+            ty,
+            span: self.span(field),
+            union_group: None,
+            hidden,
+            is_const_member: field.get_type().unwrap().is_const_qualified(),
+            is_mutable: field.is_mutable(),
+            deprecated: deprecation(field),
         });
-        let offset: u16 = field
-            .get_offset_of_field()
-            .unwrap()
-            .try_into()
-            .expect("offset too big");
+        let offset = field.get_offset_of_field().unwrap() as cc::Offset;
         // TODO put this in a helper
         if offset % 8 != 0 {
             errs.add(Diagnostic::error(
@@ -567,25 +1234,115 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
         offsets.push(offset / 8);
     }
 
+    /// Lowers the members of an anonymous union as fields of the struct
+    /// `outer`, all in the same union group.
+    fn lower_anonymous_union(
+        &self,
+        outer: Type<'tu>,
+        union: Entity<'tu>,
+        group: u32,
+        fields: &mut Vec<Field>,
+        offsets: &mut Vec<cc::Offset>,
+        errs: &mut Diagnostics,
+    ) {
+        for member in union.get_children() {
+            let name = match (member.get_kind(), member.get_name()) {
+                (EntityKind::FieldDecl, Some(name)) => name,
+                _ => {
+                    errs.add(Diagnostic::error(
+                        "unsupported member of anonymous union",
+                        self.span(member)
+                            .label("only named fields are supported here"),
+                    ));
+                    continue;
+                }
+            };
+            // Offsets of the union's members are relative to the union, but
+            // the enclosing struct can look them up by name.
+            let offset = outer.get_offsetof(&name).unwrap() as cc::Offset;
+            let name = match self.ident(&name, member, "field") {
+                Ok(name) => name,
+                Err(diag) => {
+                    errs.add(diag);
+                    continue;
+                }
+            };
+            fields.push(Field {
+                name,
+                ty: self.mk_type_ref(member.get_type().unwrap()),
+                span: self.span(member),
+                union_group: Some(group),
+                hidden: false,
+                is_const_member: member.get_type().unwrap().is_const_qualified(),
+                is_mutable: member.is_mutable(),
+                deprecated: deprecation(member),
+            });
+            offsets.push(offset / 8);
+        }
+    }
+
     fn lower_method(
         &self,
         method: Entity<'tu>,
         methods: &mut Vec<cc::Function>,
         errs: &mut Diagnostics,
     ) {
-        let ty = method.get_type().unwrap();
-        // eprintln!("calling convention: {:?}", ty.get_calling_convention());
+        if !self.is_accessible(method) {
+            self.note_skipped(method, "method", errs);
+            return;
+        }
+        if self.is_skipped(method) {
+            errs.add(Diagnostic::info(
+                format!(
+                    "skipping method `{}`",
+                    method.get_name().unwrap_or_default()
+                ),
+                self.span(method).label("this method is in `skip_members`"),
+            ));
+            return;
+        }
+        methods.extend(self.lower_function(method, errs));
+    }
+
+    /// Lowers the signature of a free function or method, or reports why it
+    /// can't be bound.
+    fn lower_function(&self, func: Entity<'tu>, errs: &mut Diagnostics) -> Option<cc::Function> {
+        let is_method = func.get_kind() == EntityKind::Method;
+        let what = if is_method { "method" } else { "function" };
+        let cc_name = func.get_name().unwrap();
+        let operator = cc::Operator::from_name(&cc_name).filter(|_| is_method);
+        let name = match operator {
+            Some(_) => Ident::new_mangled(self.db, &cc_name),
+            None => match self.ident(&cc_name, func, what) {
+                Ok(name) => name,
+                Err(diag) => {
+                    errs.add(diag);
+                    return None;
+                }
+            },
+        };
+        self.db.metrics().count(Counter::FunctionsLowered);
         let mut param_tys = vec![];
         let mut param_names = vec![];
-        method.visit_children(|child, _| {
+        func.visit_children(|child, _| {
             match child.get_kind() {
                 EntityKind::ParmDecl => {
-                    param_names.push(child.get_name().map(Ident::from));
+                    // Parameters named like `_` are bound as if they were
+                    // unnamed, since their names don't matter to callers.
+                    param_names.push(
+                        child
+                            .get_name()
+                            .and_then(|name| Ident::new(self.db, &name).ok()),
+                    );
                     param_tys.push(self.mk_type_ref(child.get_type().unwrap()));
                 }
+                // The body of an inline function, and attributes, which are
+                // read by `function_attrs`.
+                EntityKind::CompoundStmt => (),
+                _ if child.is_attribute() => (),
                 _ => {
                     errs.add(Diagnostic::bug(
-                        "unhandled child of method",
+                        "unhandled child of function",
                         self.span(child)
                             .label("this kind of item is not yet handled"),
                     ));
@@ -593,14 +1350,270 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
             }
             EntityVisitResult::Continue
         });
-        methods.push(cc::Function {
-            name: method.get_name().unwrap().into(),
+        let return_ty = match self.return_type(func) {
+            Some(return_ty) => return_ty,
+            None => {
+                errs.add(self.undeduced_return_error(&name, func));
+                return None;
+            }
+        };
+        let is_static = is_method && func.is_static_method();
+        let attrs = function_attrs(func);
+        let const_value = match is_static && attrs.constexpr && param_tys.is_empty() {
+            true => evaluate_return(func),
+            false => None,
+        };
+        if is_static && attrs.constexpr && const_value.is_none() {
+            errs.add(Diagnostic::info(
+                format!(
+                    "constexpr method `{}` is bound as a normal function",
+                    name.as_str(self.db)
+                ),
+                self.span(func)
+                    .label("its result couldn't be evaluated when generating the bindings"),
+            ));
+        }
+        Some(cc::Function {
+            name,
             param_tys,
             param_names,
-            return_ty: self.mk_type_ref(ty.get_result_type().unwrap()),
-            is_method: !method.is_static_method(),
-            is_const: method.is_const_method(),
+            return_ty: self.mk_type_ref(return_ty),
+            is_method: is_method && !is_static,
+            is_const: is_method && func.is_const_method(),
+            accessor: None,
+            operator,
+            const_value,
+            inherited_from: None,
+            attrs,
+            span: self.span(func),
+        })
+    }
+
+    /// Returns the type a function returns, or `None` if it's declared to
+    /// return `auto` and clang couldn't deduce the type.
+    ///
+    /// A trailing return type is already the function's return type. A
+    /// deduced one is only known from the definition, which may be a later
+    /// declaration than `func`.
+    fn return_type(&self, func: Entity<'tu>) -> Option<Type<'tu>> {
+        let result_ty = func.get_type().unwrap().get_result_type().unwrap();
+        if result_ty.get_kind() != TypeKind::Auto {
+            return Some(result_ty);
+        }
+        let def = func.get_definition().unwrap_or(func);
+        let deduced = def.get_type()?.get_result_type()?.get_canonical_type();
+        match deduced.get_kind() {
+            TypeKind::Auto => None,
+            _ => Some(deduced),
+        }
+    }
+
+    /// Reports a function whose `auto` return type couldn't be deduced.
+    fn undeduced_return_error(&self, name: &Ident, func: Entity<'tu>) -> Diagnostic {
+        let diag = Diagnostic::error(
+            format!(
+                "cannot deduce the return type of `{}`",
+                name.as_str(self.db)
+            ),
+            self.span(func)
+                .label("this function is declared to return `auto`"),
+        );
+        match (self.db.options().full_parse, func.get_definition()) {
+            (_, None) => diag.with_note(
+                "its return type is deduced from its body, which isn't in the parsed headers",
+            ),
+            (false, Some(_)) => diag
+                .with_note("function bodies are skipped when parsing, so it couldn't be deduced")
+                .with_note("parse every body with `--full-parse` or `SessionOptions::full_parse`"),
+            (true, Some(_)) => diag,
+        }
+    }
+
+    /// Adds a getter and setter for a public field of an opaque struct, so
+    /// Rust code can still reach it.
+    fn lower_accessors(
+        &self,
+        field: Entity<'tu>,
+        methods: &mut Vec<cc::Function>,
+        errs: &mut Diagnostics,
+    ) {
+        if !self.is_accessible(field) {
+            self.note_skipped(field, "field", errs);
+            return;
+        }
+        let name = match field
+            .get_name()
+            .map(|name| self.ident(&name, field, "field"))
+        {
+            Some(Ok(name)) => name,
+            Some(Err(diag)) => {
+                errs.add(diag);
+                return;
+            }
+            None => return,
+        };
+        if self.is_skipped(field) {
+            errs.add(Diagnostic::info(
+                format!("skipping field `{}`", name.as_str(self.db)),
+                self.span(field).label("this field is in `skip_members`"),
+            ));
+            return;
+        }
+        let ty = field.get_type().unwrap();
+        let unsupported = match ty.lower(self).split() {
+            (_, ty_errs) if ty_errs.has_errors() => Some("this field's type isn't supported"),
+            (Ty::Array { .. }, _) => Some("arrays can't be returned from functions"),
+            _ => None,
+        };
+        if let Some(reason) = unsupported {
+            errs.add(Diagnostic::info(
+                format!(
+                    "no accessors generated for field `{}`",
+                    name.as_str(self.db)
+                ),
+                self.span(field).label(reason),
+            ));
+            return;
+        }
+        let ty_ref = self.mk_type_ref(ty);
+        // The accessors stand in for the field, so they're deprecated with it.
+        let attrs = cc::FunctionAttrs {
+            deprecated: deprecation(field),
+            ..Default::default()
+        };
+        methods.push(cc::Function {
+            name,
+            param_tys: vec![],
+            param_names: vec![],
+            return_ty: ty_ref.clone(),
+            is_method: true,
+            is_const: true,
+            accessor: Some(cc::Accessor::Get(name)),
+            operator: None,
+            const_value: None,
+            inherited_from: None,
+            attrs: attrs.clone(),
+            span: self.span(field),
         });
+        // A const member can only be initialized, so it gets no setter. A
+        // mutable one can be assigned through a const `this`, so its setter
+        // is const too.
+        if !ty.is_const_qualified() {
+            methods.push(cc::Function {
+                name: Ident::new_mangled(self.db, &format!("set_{}", name.as_str(self.db))),
+                param_tys: vec![ty_ref],
+                param_names: vec![Some(Ident::new_mangled(self.db, &"value"))],
+                return_ty: TypeRef::void(),
+                is_method: true,
+                is_const: field.is_mutable(),
+                accessor: Some(cc::Accessor::Set(name)),
+                operator: None,
+                const_value: None,
+                inherited_from: None,
+                attrs,
+                span: self.span(field),
+            });
+        }
+    }
+
+    /// Whether a struct member can be accessed from generated code.
+    ///
+    /// Protected members are only included when the `protected_members`
+    /// option is set.
+    fn is_accessible(&self, member: Entity<'tu>) -> bool {
+        match member.get_accessibility() {
+            None | Some(Accessibility::Public) => true,
+            Some(Accessibility::Protected) => self.db.options().protected_members,
+            Some(Accessibility::Private) => false,
+        }
+    }
+
+    /// Returns whether `member` is listed in `SessionOptions::skip_members`.
+    fn is_skipped(&self, member: Entity<'tu>) -> bool {
+        let skip_members = &self.db.options().skip_members;
+        if skip_members.is_empty() {
+            return false;
+        }
+        let path = option_path(member, member.get_name().unwrap_or_default());
+        skip_members
+            .iter()
+            .any(|name| name.trim_start_matches("::") == path)
+    }
+
+    /// Returns whether the alias `decl` is exported, so types spelled with it
+    /// should refer to its bindings: it's either exported from `rust_export`
+    /// or listed in `SessionOptions::exports`.
+    fn is_exported_alias(&self, decl: Entity<'tu>) -> bool {
+        if self
+            .ast
+            .alias_exports
+            .contains(&decl.get_canonical_entity())
+        {
+            return true;
+        }
+        let exports = &self.db.options().exports;
+        if exports.is_empty() || !is_builtin_alias(decl) {
+            return false;
+        }
+        let path = option_path(decl, decl.get_name().unwrap_or_default());
+        exports
+            .iter()
+            .any(|name| name.trim_start_matches("::") == path)
+    }
+
+    /// Returns whether the alias `decl`, named `name`, is listed in
+    /// `SessionOptions::newtypes`.
+    fn is_newtype(&self, decl: Entity<'tu>, name: &Ident) -> bool {
+        let newtypes = &self.db.options().newtypes;
+        if newtypes.is_empty() {
+            return false;
+        }
+        let path = option_path(decl, name.as_str(self.db).to_string());
+        newtypes
+            .iter()
+            .any(|name| name.trim_start_matches("::") == path)
+    }
+
+    /// Returns whether the struct `ent`, named `name`, is listed in
+    /// `SessionOptions::opaque` or exported from `rust_export::opaque`.
+    fn is_opaque(&self, ent: Entity<'tu>, name: &Ident) -> bool {
+        if self
+            .ast
+            .opaque_exports
+            .contains(&ent.get_canonical_entity())
+        {
+            return true;
+        }
+        let opaque = &self.db.options().opaque;
+        if opaque.is_empty() {
+            return false;
+        }
+        let path = option_path(ent, name.as_str(self.db).to_string());
+        opaque
+            .iter()
+            .any(|name| name.trim_start_matches("::") == path)
+    }
+
+    /// Returns padding to stand in for a skipped `field`.
+    fn padding_for(&self, field: Entity<'tu>) -> Option<TypeRef> {
+        let ty = field.get_type().unwrap();
+        let size = ty.get_sizeof().ok()?;
+        let align = ty.get_alignof().ok()?;
+        TypeRef::padding(size as u64, align as u64)
+    }
+
+    fn note_skipped(&self, member: Entity<'tu>, what: &str, errs: &mut Diagnostics) {
+        if self.db.options().verbose {
+            let access = match member.get_accessibility() {
+                Some(Accessibility::Protected) => "protected",
+                _ => "private",
+            };
+            errs.add(Diagnostic::info(
+                format!("skipping inaccessible {} `{}`", what, member.display_name()),
+                self.span(member)
+                    .label(format!("this {} is {}", what, access)),
+            ));
+        }
     }
 
     fn mk_type_ref(&self, ty: clang::Type<'tu>) -> TypeRef {
@@ -610,6 +1623,411 @@ impl<'ctx, 'tu, DB: CcSourceIr> LowerCtx<'ctx, 'tu, DB> {
     fn span(&self, ent: Entity<'tu>) -> Span {
         span_for_entity(self.db, self.mdl, self.ast, ent)
     }
+
+    /// Makes an identifier of `name`, which was read from `ent`, or reports
+    /// it as the name of `what` if it isn't one.
+    fn ident(&self, name: &str, ent: Entity<'tu>, what: &str) -> Result<Ident, Diagnostic> {
+        Ident::new(self.db, name).map_err(|invalid| invalid.to_diagnostic(&self.span(ent), what))
+    }
+}
+
+/// Returns whether `ty`, or the element type of an array `ty`, is
+/// `std::atomic`.
+fn is_std_atomic(ty: Type<'_>) -> bool {
+    let mut ty = ty.get_canonical_type();
+    while let Some(elem) = ty.get_element_type() {
+        ty = elem;
+    }
+    let decl = match ty.get_declaration() {
+        Some(decl) => decl,
+        None => return false,
+    };
+    if decl.get_name().as_deref() != Some("atomic") {
+        return false;
+    }
+    // Standard libraries may declare it in an inline namespace inside `std`.
+    let mut parent = path_parent(decl);
+    while let Some(ent) = parent {
+        if ent.get_kind() == EntityKind::Namespace && ent.get_name().as_deref() == Some("std") {
+            return true;
+        }
+        parent = path_parent(ent);
+    }
+    false
+}
+
+/// Returns the semantic parent of `ent` that appears in its path.
+///
+/// This looks through `extern "C"` blocks, which don't introduce a scope, and
+/// inline namespaces, whose members can be named as if they were in the
+/// enclosing namespace. Libraries use the latter for versioning, and users
+/// write `lib::Foo`, not `lib::v2::Foo`.
+fn path_parent(ent: Entity<'_>) -> Option<Entity<'_>> {
+    let mut parent = ent.get_semantic_parent()?;
+    while parent.get_kind() == EntityKind::LinkageSpec || parent.is_inline_namespace() {
+        parent = parent.get_semantic_parent()?;
+    }
+    Some(parent)
+}
+
+/// Returns the full C++ path of `ent` as it's written in `SessionOptions`,
+/// without a leading `::`. The last component is `name`, since anonymous
+/// structs are named by their typedef.
+fn option_path(ent: Entity<'_>, name: String) -> String {
+    let mut names = vec![name];
+    let mut parent = path_parent(ent);
+    while let Some(cur) = parent.filter(|ent| ent.get_kind() != EntityKind::TranslationUnit) {
+        names.push(cur.get_name().unwrap_or_default());
+        parent = path_parent(cur);
+    }
+    names.reverse();
+    names.join("::")
+}
+
+/// Returns the items in a `rust_export` namespace, with the contents of a
+/// nested `opaque` namespace in place of the namespace itself.
+fn rust_export_decls(ns: Entity<'_>) -> Vec<Entity<'_>> {
+    let mut decls = vec![];
+    for child in ns.get_children() {
+        match child.get_kind() {
+            EntityKind::Namespace if child.get_name().as_deref() == Some("opaque") => {
+                decls.extend(child.get_children())
+            }
+            _ => decls.push(child),
+        }
+    }
+    decls
+}
+
+/// Returns the structs exported from a `rust_export::opaque` namespace in
+/// `tu`, as canonical entities.
+pub(super) fn opaque_exports<'tu>(tu: &'tu TranslationUnit<'tu>) -> HashSet<Entity<'tu>> {
+    let mut structs = HashSet::new();
+    for ent in top_level_decls(tu.get_entity()) {
+        if ent.get_kind() != EntityKind::Namespace
+            || ent.get_name().as_deref() != Some("rust_export")
+        {
+            continue;
+        }
+        let opaque_nss = ent.get_children().into_iter().filter(|child| {
+            child.get_kind() == EntityKind::Namespace
+                && child.get_name().as_deref() == Some("opaque")
+        });
+        for decl in opaque_nss.flat_map(|ns| ns.get_children()) {
+            let target = match decl.get_kind() {
+                EntityKind::UsingDeclaration => decl.get_reference(),
+                _ => None,
+            };
+            let target = target.map(|target| match target.get_overloaded_declarations() {
+                Some(overloads) => overloads[0],
+                None => target,
+            });
+            // `typedef struct { ... } Foo;` names an anonymous struct.
+            let target = target.and_then(|target| match target.get_kind() {
+                EntityKind::TypedefDecl | EntityKind::TypeAliasDecl => target
+                    .get_typedef_underlying_type()?
+                    .get_canonical_type()
+                    .get_declaration(),
+                _ => Some(target),
+            });
+            if let Some(target) = target {
+                structs.insert(target.get_canonical_entity());
+            }
+        }
+    }
+    structs
+}
+
+/// Returns the aliases of builtin types exported from a `rust_export`
+/// namespace in `tu`, as canonical entities. These are either named by a
+/// using declaration or declared in the namespace itself.
+pub(super) fn alias_exports<'tu>(tu: &'tu TranslationUnit<'tu>) -> HashSet<Entity<'tu>> {
+    let mut aliases = HashSet::new();
+    for ent in top_level_decls(tu.get_entity()) {
+        if ent.get_kind() != EntityKind::Namespace
+            || ent.get_name().as_deref() != Some("rust_export")
+        {
+            continue;
+        }
+        for decl in ent.get_children() {
+            let target = match decl.get_kind() {
+                EntityKind::UsingDeclaration => {
+                    decl.get_reference()
+                        .map(|target| match target.get_overloaded_declarations() {
+                            Some(overloads) => overloads[0],
+                            None => target,
+                        })
+                }
+                EntityKind::TypeAliasDecl => Some(decl),
+                _ => None,
+            };
+            if let Some(target) = target.filter(|target| is_builtin_alias(*target)) {
+                aliases.insert(target.get_canonical_entity());
+            }
+        }
+    }
+    aliases
+}
+
+/// Returns whether `decl` is a typedef or alias of a builtin type, like
+/// `using UserId = unsigned long;`.
+fn is_builtin_alias(decl: Entity<'_>) -> bool {
+    use TypeKind::*;
+    if !matches!(
+        decl.get_kind(),
+        EntityKind::TypedefDecl | EntityKind::TypeAliasDecl
+    ) {
+        return false;
+    }
+    let target = match decl.get_typedef_underlying_type() {
+        Some(ty) => ty.get_canonical_type(),
+        None => return false,
+    };
+    matches!(
+        target.get_kind(),
+        Bool | Short
+            | UShort
+            | Int
+            | UInt
+            | Long
+            | ULong
+            | LongLong
+            | ULongLong
+            | CharS
+            | SChar
+            | CharU
+            | UChar
+            | Float
+            | Double
+    )
+}
+
+/// Returns the children of `ent`, with the contents of any `extern "C"`
+/// blocks in place of the blocks themselves.
+fn top_level_decls(ent: Entity<'_>) -> Vec<Entity<'_>> {
+    let mut decls = vec![];
+    for child in ent.get_children() {
+        match child.get_kind() {
+            EntityKind::LinkageSpec => decls.extend(top_level_decls(child)),
+            _ => decls.push(child),
+        }
+    }
+    decls
+}
+
+/// Returns the name of a record, or the name of the typedef that names it if
+/// the record is anonymous (as in `typedef struct { ... } Foo;` or
+/// `using Foo = struct { ... };`).
+///
+/// Names that aren't identifiers are left for the caller to report, with
+/// the name it falls back to.
+fn record_name(db: &impl DefIr, record: Entity<'_>) -> Option<Ident> {
+    if let Some(name) = record.get_name() {
+        return Ident::new(db, &name).ok();
+    }
+    let parent = record.get_lexical_parent()?;
+    let record = record.get_canonical_entity();
+    parent
+        .get_children()
+        .into_iter()
+        .filter(|child| {
+            matches!(
+                child.get_kind(),
+                EntityKind::TypedefDecl | EntityKind::TypeAliasDecl
+            )
+        })
+        .find(|typedef| {
+            typedef
+                .get_typedef_underlying_type()
+                .and_then(|ty| ty.get_canonical_type().get_declaration())
+                .map(|decl| decl.get_canonical_entity())
+                == Some(record)
+        })
+        .and_then(|typedef| typedef.get_name())
+        .and_then(|name| Ident::new(db, &name).ok())
+}
+
+/// Returns whether `union` is an anonymous union member of the struct type
+/// `outer`, as in `struct S { union { int a; float b; }; };`, whose members
+/// are named as if they were members of `S`.
+fn is_anonymous_member(outer: Type<'_>, union: Entity<'_>) -> bool {
+    let first_member = union
+        .get_children()
+        .into_iter()
+        .find(|child| child.get_kind() == EntityKind::FieldDecl)
+        .and_then(|child| child.get_name());
+    union.is_anonymous() && first_member.is_some_and(|name| outer.get_offsetof(&name).is_ok())
+}
+
+/// An attribute on a struct, as far as its layout is concerned.
+enum LayoutAttr {
+    /// `alignas(N)`, `__attribute__((aligned(N)))` or `__declspec(align(N))`.
+    Aligned,
+    /// `__attribute__((packed))`.
+    Packed,
+    /// An attribute with no spelling in the source, like the ones added by
+    /// pragmas.
+    Implicit,
+    Unknown(String),
+}
+
+/// Collects the properties of a function that are only recorded in the IR.
+fn function_attrs(func: Entity<'_>) -> cc::FunctionAttrs {
+    use clang::ExceptionSpecification::*;
+    let noexcept = matches!(
+        func.get_exception_specification(),
+        Some(BasicNoexcept) | Some(DynamicNone) | Some(NoThrow)
+    );
+    let nodiscard = func.get_children().into_iter().any(|child| {
+        match child.get_kind() {
+            EntityKind::WarnUnusedResultAttr => true,
+            // Older spellings may not be exposed, so look at the first token.
+            EntityKind::UnexposedAttr => child
+                .get_range()
+                .and_then(|range| range.tokenize().into_iter().next())
+                .is_some_and(|token| {
+                    matches!(
+                        token.get_spelling().as_str(),
+                        "nodiscard" | "warn_unused_result" | "__warn_unused_result__"
+                    )
+                }),
+            _ => false,
+        }
+    });
+    // libclang has no query for `constexpr` before LLVM 17, so look for the
+    // keyword among the tokens before the name.
+    let name = func.get_name();
+    let constexpr = func.get_range().is_some_and(|range| {
+        range
+            .tokenize()
+            .into_iter()
+            .map(|token| token.get_spelling())
+            .take_while(|token| Some(token) != name.as_ref())
+            .any(|token| token == "constexpr")
+    });
+    cc::FunctionAttrs {
+        noexcept,
+        calling_conv: func
+            .get_type()
+            .and_then(|ty| ty.get_calling_convention())
+            .map(|conv| format!("{:?}", conv).to_lowercase()),
+        nodiscard,
+        // libclang reports deleted functions as unavailable.
+        deleted: func.get_availability() == Availability::Unavailable,
+        defaulted: func.is_defaulted(),
+        constexpr,
+        mangled_name: func.get_mangled_name(),
+        deprecated: deprecation(func),
+    }
+}
+
+/// Returns whether a declaration is deprecated, and the message it gives.
+fn deprecation(decl: Entity<'_>) -> Option<cc::Deprecation> {
+    if decl.get_availability() != Availability::Deprecated {
+        return None;
+    }
+    // libclang doesn't expose the message, so it's read from the string
+    // literals in the attribute, after a name like `deprecated` or
+    // `gnu::deprecated`.
+    let note = decl
+        .get_children()
+        .into_iter()
+        .filter(|child| child.get_kind() == EntityKind::UnexposedAttr)
+        .filter_map(|attr| attr.get_range())
+        .map(|range| range.tokenize())
+        .find(|tokens| {
+            tokens
+                .iter()
+                .map(|token| token.get_spelling())
+                .take_while(|token| token != "(")
+                .any(|token| token == "deprecated" || token == "__deprecated__")
+        })
+        .map(|tokens| {
+            tokens
+                .iter()
+                .filter(|token| token.get_kind() == clang::token::TokenKind::Literal)
+                .map(|token| unescape_string(&token.get_spelling()))
+                .collect::<String>()
+        })
+        .filter(|note| !note.is_empty());
+    Some(cc::Deprecation { note })
+}
+
+/// Returns the contents of a C++ string literal, like `"say \"hi\""`, with
+/// its escapes resolved. Adjacent literals are concatenated by the caller.
+fn unescape_string(literal: &str) -> String {
+    // Skip any prefix, like `u8`.
+    let body = match (literal.find('"'), literal.rfind('"')) {
+        (Some(start), Some(end)) if start < end => &literal[start + 1..end],
+        _ => return String::new(),
+    };
+    let mut out = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            // `\\`, `\"`, `\'` and `\?` stand for the character itself.
+            Some(c) => out.push(c),
+            None => (),
+        }
+    }
+    out
+}
+
+/// Evaluates a function whose body only returns a constant expression, like
+/// `{ return 1 << 4; }`.
+///
+/// Bodies are normally skipped when parsing, but clang keeps those of
+/// `constexpr` functions, since it may need to evaluate them.
+fn evaluate_return(func: Entity<'_>) -> Option<cc::ConstValue> {
+    let body = func
+        .get_children()
+        .into_iter()
+        .find(|child| child.get_kind() == EntityKind::CompoundStmt)?;
+    let ret = match body.get_children().as_slice() {
+        [ret] if ret.get_kind() == EntityKind::ReturnStmt => *ret,
+        _ => return None,
+    };
+    match ret.get_children().first()?.evaluate()? {
+        EvaluationResult::SignedInteger(val) => Some(cc::ConstValue::Int(val)),
+        EvaluationResult::UnsignedInteger(val) => Some(cc::ConstValue::UInt(val)),
+        EvaluationResult::Float(val) if val.is_finite() => {
+            Some(cc::ConstValue::Float(val.to_bits()))
+        }
+        _ => None,
+    }
+}
+
+/// Classifies an attribute on a struct.
+///
+/// libclang only exposes `AlignedAttr` from 9.0 on, and never exposes some
+/// spellings, so unexposed attributes are recognized by their first token.
+fn layout_attr(attr: Entity<'_>) -> LayoutAttr {
+    match attr.get_kind() {
+        EntityKind::AlignedAttr => return LayoutAttr::Aligned,
+        EntityKind::PackedAttr => return LayoutAttr::Packed,
+        _ => (),
+    }
+    let first_token = attr
+        .get_range()
+        .and_then(|range| range.tokenize().into_iter().next());
+    let name = match first_token {
+        Some(token) => token.get_spelling(),
+        None => return LayoutAttr::Implicit,
+    };
+    match name.as_str() {
+        "alignas" | "_Alignas" | "aligned" | "__aligned__" | "align" => LayoutAttr::Aligned,
+        "packed" | "__packed__" => LayoutAttr::Packed,
+        // Bound as `#[deprecated]`, and doesn't affect the layout.
+        "deprecated" | "__deprecated__" => LayoutAttr::Implicit,
+        _ => LayoutAttr::Unknown(name),
+    }
 }
 
 trait Lower<'ctx, 'tu> {
@@ -620,34 +2038,175 @@ trait Lower<'ctx, 'tu> {
 impl<'ctx, 'tu> Lower<'ctx, 'tu> for Type<'tu> {
     type Output = Ty;
     fn lower<DB: CcSourceIr>(&self, ctx: &LowerCtx<'ctx, 'tu, DB>) -> Outcome<Ty> {
-        use TypeKind::*;
-        ok(match self.get_kind() {
-            Void => Ty::Void,
-            Int => Ty::Int,
-            UInt => Ty::UInt,
-            CharS => Ty::CharS,
-            SChar => Ty::SChar,
-            CharU => Ty::CharU,
-            UChar => Ty::UChar,
-            Float => Ty::Float,
-            Double => Ty::Double,
-            Record => {
-                let decl = self.get_declaration().unwrap();
-                return ctx
-                    .lower_struct(&Ident::from(self.get_display_name()), decl)
-                    .map(|st| st.map_or(Ty::Error, |st| Ty::Struct(st)));
-            }
-            _ => panic!("unsupported type {:?}", self),
-        })
+        let (ty, stripped) = strip_ignored(*self);
+        let lowered = lower_kind(ty, ctx);
+        if stripped.is_empty() || !ctx.db.options().verbose {
+            return lowered;
+        }
+        let note = Diagnostic::new(
+            Severity::Note,
+            format!(
+                "ignoring {} of type `{}`",
+                stripped.join(", "),
+                self.get_display_name()
+            ),
+        );
+        lowered.then(|ty| err(ty, note))
+    }
+}
+
+/// Strips what doesn't change how a type is bound from the top of `ty`, and
+/// returns what's left along with a description of each thing stripped.
+///
+/// `__restrict` only promises the compiler there's no aliasing, which Rust
+/// pointers don't express. libclang already looks through parentheses, and
+/// through attributes unless it's asked to keep them, but attributed types
+/// can still show up, and a parameter declared as an array or function has
+/// the pointer it decays to as an unexposed type. Elaborated types are left to
+/// [`lower_kind`], since a qualified name like `ns::Foo` is more than spelling.
+fn strip_ignored(mut ty: Type<'_>) -> (Type<'_>, Vec<&'static str>) {
+    let mut stripped = vec![];
+    loop {
+        if ty.is_restrict_qualified() && !stripped.contains(&"`__restrict`") {
+            stripped.push("`__restrict`");
+        }
+        let inner = match ty.get_kind() {
+            TypeKind::Attributed => {
+                stripped.push("attributes");
+                ty.get_modified_type()
+            }
+            TypeKind::Unexposed if ty.get_canonical_type().get_kind() == TypeKind::Pointer => {
+                stripped.push("parameter decay");
+                Some(ty.get_canonical_type())
+            }
+            _ => None,
+        };
+        match inner {
+            Some(inner) => ty = inner,
+            None => return (ty, stripped),
+        }
     }
 }
 
+/// Lowers a type that [`strip_ignored`] has already been applied to.
+fn lower_kind<'tu, DB: CcSourceIr>(ty: Type<'tu>, ctx: &LowerCtx<'_, 'tu, DB>) -> Outcome<Ty> {
+    use TypeKind::*;
+    ok(match ty.get_kind() {
+        Void => Ty::Void,
+        Bool => Ty::Bool,
+        Short => Ty::Short,
+        UShort => Ty::UShort,
+        Int => Ty::Int,
+        UInt => Ty::UInt,
+        Long => Ty::Long,
+        ULong => Ty::ULong,
+        LongLong => Ty::LongLong,
+        ULongLong => Ty::ULongLong,
+        CharS => Ty::CharS,
+        SChar => Ty::SChar,
+        CharU => Ty::CharU,
+        UChar => Ty::UChar,
+        Float => Ty::Float,
+        Double => Ty::Double,
+        Typedef => {
+            // Exported aliases are bound too, so types spelled with them
+            // refer to their bindings instead of the aliased type.
+            if let Some(decl) = ty.get_declaration() {
+                // `size_t` is bound as `usize`, whatever integer type it
+                // is on the target.
+                if decl.get_name().as_deref() == Some("size_t") {
+                    return ok(Ty::Size);
+                }
+                if ctx.is_exported_alias(decl) {
+                    return ctx
+                        .lower_alias(decl)
+                        .map(|alias| alias.map_or(Ty::Error, Ty::Alias));
+                }
+            }
+            return ty.get_canonical_type().lower(ctx);
+        }
+        Pointer => {
+            let pointee = ty.get_pointee_type().unwrap();
+            Ty::Ptr {
+                pointee: ctx.mk_type_ref(pointee),
+                is_const: pointee.is_const_qualified(),
+            }
+        }
+        LValueReference => {
+            let pointee = ty.get_pointee_type().unwrap();
+            Ty::Ref {
+                pointee: ctx.mk_type_ref(pointee),
+                is_const: pointee.is_const_qualified(),
+            }
+        }
+        ConstantArray => Ty::Array {
+            elem: ctx.mk_type_ref(ty.get_element_type().unwrap()),
+            len: ty.get_size().unwrap() as u64,
+        },
+        Record => {
+            let decl = ty.get_declaration().unwrap();
+            if decl.get_kind() == EntityKind::UnionDecl {
+                return err(
+                    Ty::Error,
+                    Diagnostic::error(
+                        "unions are not supported",
+                        ctx.span(decl)
+                            .label("only anonymous unions inside structs are supported"),
+                    ),
+                );
+            }
+            // The display name of an anonymous struct is like
+            // `(unnamed struct at foo.h:3:5)`, which can't be bound.
+            let name = match record_name(ctx.db, decl) {
+                Some(name) => name,
+                None => match ctx.ident(&ty.get_display_name(), decl, "struct") {
+                    Ok(name) => name,
+                    Err(diag) => return err(Ty::Error, diag),
+                },
+            };
+            if decl.get_definition().is_none() {
+                return ctx.lower_opaque(&name, decl).map(Ty::Opaque);
+            }
+            return ctx
+                .lower_struct(&name, decl)
+                .map(|st| st.map_or(Ty::Error, Ty::Struct));
+        }
+        // Naming a struct as `struct Foo`, as C code has to, is only
+        // spelling. Structs are bound at the top of the crate whatever
+        // namespace they're in, so qualified names like `ns::Foo` stay
+        // unsupported until struct paths are generated.
+        Elaborated if !ty.get_display_name().contains("::") => {
+            return ty.get_elaborated_type().unwrap().lower(ctx);
+        }
+        _ => {
+            let message = format!("unsupported type `{}`", ty.get_display_name());
+            let diag = match ty.get_declaration() {
+                Some(decl) => Diagnostic::error(
+                    message,
+                    ctx.span(decl).label("types like this can't be bound yet"),
+                ),
+                None => Diagnostic::new(Severity::Error, message),
+            };
+            return err(Ty::Error, diag);
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::Session;
+    use super::{LOWER_EXPORT_CALLS, LOWER_STRUCT_CALLS, LOWER_TY_CALLS};
+    use crate::{
+        cc_use::RsSource,
+        ir::rs::{self, RsTargetBindings},
+        libclang::{self, CcSourceIr},
+        test_util, Language, Session, SessionOptions,
+    };
+    use std::path::Path;
 
     #[test]
     fn multiple_exports() {
+        // Exporting the same item again is harmless, so it's only exported
+        // once.
         let mut sess = Session::test();
         cpp_lower!(sess, {
             struct Foo {
@@ -659,8 +2218,123 @@ mod tests {
             namespace rust_export {
                 using ::Foo;
             }
+        });
+    }
+
+    #[test]
+    fn invalid_idents() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct Pod {
+                int a;
+                bool operator==(const Pod& other) const;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
         } => [
-            "multiple exports of the same item are not supported"
+            "`operator==` is not a valid identifier"
+        ]);
+
+        // Names given in the options that aren't paths are just not found.
+        let mut sess = Session::with_options(SessionOptions {
+            exports: vec!["Box<int>".into(), "".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            template <typename T>
+            struct Box {
+                T value;
+            };
+        } => [
+            "exported item not found: `Box<int>`",
+            "exported item not found: ``"
+        ]);
+    }
+
+    #[test]
+    fn undeduced_return_types() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct Counter {
+                int n;
+                auto later() const;
+            };
+            namespace rust_export {
+                using ::Counter;
+            }
+        } => [
+            "cannot deduce the return type of `later`"
+        ]);
+    }
+
+    #[test]
+    fn unsupported_bases() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct Point {
+                int x;
+                int y;
+            };
+            struct Empty {};
+            struct Tagged : Point, Empty {};
+            class Hidden : Point {};
+            namespace rust_export {
+                using ::Tagged;
+                using ::Hidden;
+            }
+        } => [
+            "multiple inheritance is not supported",
+            "unsupported base class"
+        ]);
+    }
+
+    #[test]
+    fn non_pod_fields() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            namespace std {
+                template <typename T>
+                struct atomic {
+                    atomic() {}
+                    T value;
+                };
+            }
+            struct Name {
+                ~Name();
+                char* str;
+            };
+            struct Counter {
+                std::atomic<int> count;
+                Name name;
+                int plain;
+            };
+            namespace rust_export {
+                using ::Counter;
+            }
+        } => [
+            "unsupported type",
+            "field `count` has non-trivial type `std::atomic<int>`",
+            "field `name` has non-trivial type `Name`"
+        ]);
+    }
+
+    #[test]
+    fn non_pod_members() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            struct Shape {
+                Shape(int sides);
+                virtual int area();
+                int sides;
+            };
+            namespace rust_export {
+                using ::Shape;
+            }
+        } => [
+            "unsupported type",
+            "`Shape` has a user-provided constructor",
+            "`Shape` has virtual method `area`"
         ]);
     }
 
@@ -682,10 +2356,143 @@ mod tests {
     }
 
     #[test]
-    fn packed() {
+    fn extern_c_block() {
+        let mut sess = Session::test();
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                extern "C" {
+                    struct Pod {
+                        int a;
+                    };
+                }
+                namespace rust_export {
+                    using ::Pod;
+                }
+            }),
+        );
+        assert!(rs.contains("pub use crate::bind::Pod;"), "{}", rs);
+    }
+
+    #[test]
+    fn inline_namespace() {
+        let mut sess = Session::test();
+        let (rs, cc) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                namespace lib {
+                    inline namespace v2 {
+                        struct Foo {
+                            int a;
+                            int get() const;
+                        };
+                    }
+                }
+                namespace rust_export {
+                    using ::lib::Foo;
+                }
+            }),
+        );
+        assert!(rs.contains("pub use crate::bind::lib::Foo;"), "{}", rs);
+        assert!(!rs.contains("v2"), "{}", rs);
+        assert!(cc.contains("::lib::Foo::get()"), "{}", cc);
+    }
+
+    #[test]
+    fn c_header() {
+        let mut sess = Session::with_options(SessionOptions {
+            language: Language::C,
+            exports: vec!["Point".into(), "Size".into(), "Node".into()],
+            ..Default::default()
+        });
+        let (rs, _) = test_util::gen_code(
+            &mut sess,
+            stringify!({
+                typedef struct Point {
+                    int x, y;
+                } Point;
+                typedef struct {
+                    unsigned w, h;
+                } Size;
+                typedef struct Node {
+                    struct Point pos;
+                    struct Node *next;
+                } Node;
+            }),
+        );
+        assert!(rs.contains("pub use crate::bind::Point;"), "{}", rs);
+        assert!(rs.contains("pub use crate::bind::Size;"), "{}", rs);
+        assert!(rs.contains("pub use crate::bind::Node;"), "{}", rs);
+        assert!(rs.contains("pos: Point,"), "{}", rs);
+        assert!(rs.contains("next: *mut Node,"), "{}", rs);
+    }
+
+    #[test]
+    fn qualified_field_type() {
+        let mut sess = Session::test();
+        let (_, errs) = test_util::lower_modules(
+            &mut sess,
+            &[stringify!({
+                namespace ns {
+                    struct Foo {
+                        int a;
+                    };
+                }
+                struct Bar {
+                    ns::Foo foo;
+                };
+                namespace rust_export {
+                    using ::Bar;
+                }
+            })],
+        );
+        assert!(
+            errs.iter().any(|err| err == "unsupported type `ns::Foo`"),
+            "{:?}",
+            errs
+        );
+    }
+
+    #[test]
+    fn missing_option_export() {
+        let mut sess = Session::with_options(SessionOptions {
+            language: Language::C,
+            exports: vec!["Piont".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct Point {
+                int x, y;
+            };
+        } => [
+            "exported item not found: `Piont`"
+        ]);
+    }
+
+    #[test]
+    fn anonymous_namespace() {
+        let mut sess = Session::test();
+        cpp_lower!(sess, {
+            namespace detail {
+                namespace {
+                    struct Helper {
+                        int a;
+                    };
+                }
+            }
+            namespace rust_export {
+                using ::detail::Helper;
+            }
+        } => [
+            "`Helper` has internal linkage"
+        ]);
+    }
+
+    #[test]
+    fn packed_and_aligned() {
         let mut sess = Session::test();
         cpp_lower!(sess, {
-            struct __attribute__((__packed__)) Pod {
+            struct __attribute__((__packed__, aligned(4))) Pod {
                 int a, b;
                 char c, d;
                 double e, f;
@@ -694,7 +2501,7 @@ mod tests {
                 using ::Pod;
             }
         } => [
-            "packed structs not supported"
+            "`Pod` is both packed and aligned"
         ]);
     }
 
@@ -712,4 +2519,532 @@ mod tests {
             "bitfields are not supported"
         ]);
     }
+
+    #[test]
+    fn skip_field() {
+        let mut sess = Session::with_options(SessionOptions {
+            skip_members: vec!["Widget::bits".into()],
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            union Bits {
+                int i;
+                float f;
+            };
+            struct Widget {
+                char tag;
+                Bits bits;
+                int id;
+            };
+            namespace rust_export {
+                using ::Widget;
+            }
+        } => [
+            "skipping field `bits`"
+        ]);
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(st.layout.field_offsets, vec![0, 4, 8]);
+        assert_eq!(st.layout.size, rs::Size::new(12));
+        assert_eq!(st.fields[1].vis, rs::Visibility::Private);
+        assert_eq!(st.fields[2].vis, rs::Visibility::Public);
+    }
+
+    #[test]
+    fn over_aligned_opaque_field() {
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["Block".into()],
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            struct alignas(64) Block {
+                int id;
+            };
+            struct Frame {
+                char tag;
+                Block block;
+            };
+            namespace rust_export {
+                using ::Frame;
+            }
+        });
+        let db = &sess.db;
+        let frame = ir
+            .visible_structs(db)
+            .iter()
+            .map(|id| id.lookup(db))
+            .find(|st| &*st.name.as_str(db) == "Frame")
+            .unwrap();
+        // The opaque struct has no fields to compute its alignment from, so
+        // it comes from clang.
+        assert_eq!(frame.fields[1].ty(db).align(db), rs::Align::new(64));
+        assert_eq!(frame.layout.field_offsets, vec![0, 64]);
+        assert_eq!(frame.layout.size, rs::Size::new(128));
+        assert_eq!(frame.layout.align, rs::Align::new(64));
+    }
+
+    #[test]
+    fn skip_method() {
+        let mut sess = Session::with_options(SessionOptions {
+            skip_members: vec!["ui::Widget::debug_dump".into()],
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            namespace ui {
+                struct Widget {
+                    int id;
+                    int get_id() const;
+                    void debug_dump() const;
+                };
+            }
+            namespace rust_export {
+                using ::ui::Widget;
+            }
+        } => [
+            "skipping method `debug_dump`"
+        ]);
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        let names: Vec<_> = st
+            .methods
+            .iter()
+            .map(|meth| meth.name.as_str(db).to_string())
+            .collect();
+        assert_eq!(names, ["get_id"]);
+    }
+
+    #[test]
+    fn skip_unknown_member() {
+        let mut sess = Session::with_options(SessionOptions {
+            skip_members: vec!["Widget::idd".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct Widget {
+                int id;
+            };
+            namespace rust_export {
+                using ::Widget;
+            }
+        } => errors: [], warnings: [
+            "skipped member not found: `Widget::idd`"
+        ]);
+    }
+
+    #[test]
+    fn opaque_from_options() {
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["cfg::BigConfig".into()],
+            ..Default::default()
+        });
+        let ir = cpp_lower!(sess, {
+            namespace cfg {
+                struct BigConfig {
+                    int flags;
+                    char name[100];
+                    int get_flags() const;
+                };
+            }
+            namespace rust_export {
+                using ::cfg::BigConfig;
+            }
+        } => [
+            "no accessors generated for field `name`"
+        ]);
+        let db = &sess.db;
+        let st = ir.visible_structs(db)[0].lookup(db);
+        assert_eq!(st.repr, rs::Repr::Opaque);
+        assert!(st.fields.is_empty());
+        assert_eq!(st.layout.size, rs::Size::new(104));
+        assert_eq!(st.layout.align, rs::Align::new(4));
+        let names: Vec<_> = st
+            .methods
+            .iter()
+            .map(|meth| meth.name.as_str(db).to_string())
+            .collect();
+        assert_eq!(names, ["flags", "set_flags", "get_flags"]);
+    }
+
+    #[test]
+    fn opaque_nested_in_exported_struct() {
+        let mut sess = Session::new();
+        let ir = cpp_lower!(sess, {
+            struct BigConfig {
+                char tag;
+                double scale;
+                int counts[5];
+            };
+            struct Service {
+                char id;
+                BigConfig config;
+                int port;
+            };
+            namespace rust_export {
+                using ::Service;
+                namespace opaque {
+                    using ::BigConfig;
+                }
+            }
+        } => [
+            "no accessors generated for field `counts`"
+        ]);
+        let db = &sess.db;
+        let structs: Vec<_> = ir
+            .visible_structs(db)
+            .iter()
+            .map(|id| id.lookup(db))
+            .collect();
+        let config = structs
+            .iter()
+            .find(|st| &*st.name.as_str(db) == "BigConfig")
+            .unwrap();
+        assert_eq!(config.repr, rs::Repr::Opaque);
+        assert!(config.fields.is_empty());
+        assert_eq!(config.layout.size, rs::Size::new(40));
+        assert_eq!(config.layout.align, rs::Align::new(8));
+        // Lowering checks the outer layout against clang's, so the opaque
+        // struct must take up the same space as the real one.
+        let service = structs
+            .iter()
+            .find(|st| &*st.name.as_str(db) == "Service")
+            .unwrap();
+        assert_eq!(service.repr, rs::Repr::C);
+        assert_eq!(service.layout.field_offsets, vec![0, 8, 48]);
+        assert_eq!(service.layout.size, rs::Size::new(56));
+    }
+
+    #[test]
+    fn opaque_unknown_type() {
+        let mut sess = Session::with_options(SessionOptions {
+            opaque: vec!["BigConfg".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct BigConfig {
+                int flags;
+            };
+            namespace rust_export {
+                using ::BigConfig;
+            }
+        } => errors: [], warnings: [
+            "opaque type not found: `BigConfg`"
+        ]);
+    }
+
+    #[test]
+    fn field_types_from_other_headers() {
+        let mut sess = Session::new();
+        let ir = cpp_lower_files!(sess, {
+            "point.h" => {
+                struct Point {
+                    int x, y;
+                };
+            },
+            "rect.h" => r#"
+                #include "point.h"
+                struct Rect {
+                    Point min, max;
+                };
+            "#,
+            "main.cc" => r#"
+                #include "rect.h"
+                namespace rust_export {
+                    using ::Rect;
+                }
+            "#
+        });
+        let db = &sess.db;
+        let rect = ir
+            .visible_structs(db)
+            .iter()
+            .map(|id| id.lookup(db))
+            .find(|st| &*st.name.as_str(db) == "Rect")
+            .unwrap();
+        assert_eq!(rect.fields.len(), 2);
+    }
+
+    #[test]
+    fn repeated_export_blocks() {
+        let mut sess = Session::new();
+        let ir = cpp_lower_files!(sess, {
+            "point.h" => {
+                struct Point {
+                    int x, y;
+                };
+                namespace rust_export {
+                    using ::Point;
+                }
+            },
+            "main.cc" => r#"
+                #include "point.h"
+                struct Size {
+                    int w, h;
+                };
+                namespace rust_export {
+                    using ::Point;
+                    using ::Size;
+                }
+            "#
+        });
+        let (rs, _) = test_util::codegen(&sess, &ir);
+        for name in &["Point", "Size"] {
+            let reexport = format!("pub use crate::bind::{};", name);
+            assert_eq!(rs.matches(&reexport).count(), 1, "{}", rs);
+        }
+    }
+
+    #[test]
+    fn conflicting_exports() {
+        let mut sess = Session::with_options(SessionOptions {
+            exports: vec!["b::Point".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            namespace a {
+                struct Point {
+                    int x, y;
+                };
+            }
+            namespace b {
+                struct Point {
+                    double x, y;
+                };
+            }
+            namespace rust_export {
+                using ::a::Point;
+            }
+        } => ["conflicting name in exported items"]);
+    }
+
+    #[test]
+    fn errors_in_included_header() {
+        let mut sess = Session::test();
+        let (_, errs) = test_util::lower_files(
+            &mut sess,
+            &[
+                (
+                    "widget.h",
+                    test_source!({
+                        struct Widget {
+                            int flags : 3;
+                        };
+                    }),
+                ),
+                (
+                    "main.cc",
+                    test_source!(
+                        "#include \"widget.h\"\nnamespace rust_export { using ::Widget; }"
+                    ),
+                ),
+            ],
+        );
+        let errs: Vec<_> = errs.iter().collect();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].message(), "bitfields are not supported");
+        let span = errs[0].primary_span().unwrap();
+        assert_eq!(span.file_id().name(&sess.db), "__test__/widget.h");
+    }
+
+    #[test]
+    fn shared_field_struct_lowered_once() {
+        let mut sess = Session::test();
+        let before = LOWER_STRUCT_CALLS.with(|calls| calls.get());
+        cpp_lower!(sess, {
+            struct Inner {
+                int a;
+            };
+            struct A {
+                Inner x;
+            };
+            struct B {
+                Inner x, y;
+            };
+            typedef Inner InnerAlias;
+            struct C {
+                InnerAlias x;
+                char c;
+            };
+            namespace rust_export {
+                using ::Inner;
+                using ::A;
+                using ::B;
+                using ::C;
+            }
+        });
+        assert_eq!(LOWER_STRUCT_CALLS.with(|calls| calls.get()) - before, 4);
+    }
+
+    #[test]
+    fn reparse_only_when_tokens_change() {
+        let src = |field_ty: &str, comment: &str| {
+            format!(
+                "struct Foo {{ {} a; }};\nnamespace rust_export {{ using ::Foo; }}\n// {}\n",
+                field_ty, comment
+            )
+        };
+        let mut sess = Session::test();
+        let index = libclang::create_index();
+        let path = Path::new(test_util::TEST_FILENAME);
+        let (ast, _) = libclang::parse_with(
+            &sess.db,
+            &index,
+            libclang::ModuleId::new(0),
+            path,
+            Some(src("int", "first")),
+        )
+        .unwrap();
+        sess.db.set_rs_source_root(None);
+        libclang::set_ast(&mut sess.db, vec![ast], |db| {
+            let lower = |db: &crate::Database| {
+                assert!(db.rs_bindings().is_ok());
+                LOWER_STRUCT_CALLS.with(|calls| calls.get())
+            };
+            let first = lower(db);
+            assert!(first > 0);
+
+            db.set_file_contents(path, Some(src("int", "second")));
+            assert_eq!(lower(db), first, "changing a comment should not relower");
+
+            db.set_file_contents(path, Some(src("long", "second")));
+            assert_eq!(lower(db), 2 * first, "changing a field should relower");
+        });
+    }
+
+    #[test]
+    fn relower_only_edited_module() {
+        let src = |name: &str, field_ty: &str| {
+            format!(
+                "struct {0} {{ {1} a; }};\nnamespace rust_export {{ using ::{0}; }}\n",
+                name, field_ty
+            )
+        };
+        let mut sess = Session::test();
+        let index = libclang::create_index();
+        let paths = [
+            Path::new("__test__/test0.cc"),
+            Path::new("__test__/test1.cc"),
+        ];
+        let asts = vec![
+            libclang::parse_with(
+                &sess.db,
+                &index,
+                libclang::ModuleId::new(0),
+                paths[0],
+                Some(src("Foo", "int")),
+            )
+            .unwrap()
+            .0,
+            libclang::parse_with(
+                &sess.db,
+                &index,
+                libclang::ModuleId::new(1),
+                paths[1],
+                Some(src("Bar", "int")),
+            )
+            .unwrap()
+            .0,
+        ];
+        sess.db.set_rs_source_root(None);
+        libclang::set_ast(&mut sess.db, asts, |db| {
+            let lower = |db: &crate::Database| {
+                assert!(db.rs_bindings().is_ok());
+                LOWER_EXPORT_CALLS.with(|calls| calls.get())
+            };
+            assert_eq!(lower(db), 2);
+            assert_eq!(lower(db), 2, "nothing changed");
+
+            db.set_file_contents(paths[0], Some(src("Foo", "long")));
+            assert_eq!(
+                lower(db),
+                3,
+                "only the export of the edited module is relowered"
+            );
+        });
+    }
+
+    #[test]
+    fn edit_one_of_several_exports() {
+        let src = |c_ty: &str| {
+            format!(
+                "struct A {{ int a; }};\n\
+                 struct B {{ double b; }};\n\
+                 struct C {{ {} c; }};\n\
+                 namespace rust_export {{ using ::A; using ::B; using ::C; }}\n",
+                c_ty
+            )
+        };
+        let mut sess = Session::test();
+        let index = libclang::create_index();
+        let path = Path::new(test_util::TEST_FILENAME);
+        let (ast, _) = libclang::parse_with(
+            &sess.db,
+            &index,
+            libclang::ModuleId::new(0),
+            path,
+            Some(src("char")),
+        )
+        .unwrap();
+        libclang::set_ast(&mut sess.db, vec![ast], |db| {
+            let items = |db: &crate::Database| {
+                let exports = db
+                    .cc_exports(libclang::ModuleId::new(0))
+                    .to_ref()
+                    .skip_errs()
+                    .clone();
+                exports
+                    .iter()
+                    .map(|id| db.cc_item_for_export(*id).skip_errs().unwrap().def)
+                    .collect::<Vec<_>>()
+            };
+            let before = items(db);
+            let calls = LOWER_EXPORT_CALLS.with(|calls| calls.get());
+            assert_eq!(before.len(), 3);
+
+            db.set_file_contents(path, Some(src("long")));
+            let after = items(db);
+            assert_eq!(
+                LOWER_EXPORT_CALLS.with(|calls| calls.get()) - calls,
+                3,
+                "every export of the reparsed module is lowered again"
+            );
+            assert_eq!(after[..2], before[..2], "unedited exports are unchanged");
+            assert_ne!(after[2], before[2]);
+        });
+    }
+
+    #[test]
+    fn relowering_is_memoized() {
+        let src = "
+            struct Inner { int a; double b; };
+            struct Outer { Inner x, y; long z; };
+            namespace rust_export { using ::Outer; }
+        ";
+        let mut sess = Session::test();
+        let index = libclang::create_index();
+        let (ast, _) = libclang::parse_with(
+            &sess.db,
+            &index,
+            libclang::ModuleId::new(0),
+            Path::new(test_util::TEST_FILENAME),
+            Some(src.to_string()),
+        )
+        .unwrap();
+        sess.db.set_rs_source_root(None);
+        libclang::set_ast(&mut sess.db, vec![ast], |db| {
+            let lower = |db: &crate::Database| {
+                assert!(db.rs_bindings().is_ok());
+                (
+                    LOWER_TY_CALLS.with(|calls| calls.get()),
+                    LOWER_STRUCT_CALLS.with(|calls| calls.get()),
+                )
+            };
+            let first = lower(db);
+            assert!(first.0 > 0 && first.1 > 0);
+            assert_eq!(lower(db), first);
+
+            // Reparsing is checked for, but the module does not change.
+            db.set_file_contents(Path::new("__test__/unrelated.h"), Some(String::new()));
+            assert_eq!(lower(db), first);
+        });
+    }
 }
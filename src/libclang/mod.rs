@@ -11,21 +11,29 @@
 mod diagnostics;
 mod index;
 mod lowering;
+mod pch;
 
 use crate::{
-    diagnostics::{db::SourceFileCache, Outcome},
+    diagnostics::{db::SourceFileCache, Diagnostic, Outcome, Severity, Span, VirtualFile},
     ir::{self, cc},
+    timings::Instrumented,
+    ConfigSet, Language, Options, SessionOptions,
 };
 use clang::{self, source, Entity, Parser, TranslationUnit, Type};
 use clang_sys::SharedLibrary;
 use core::hash::Hasher;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{hash_map::DefaultHasher, BTreeSet, HashMap, HashSet};
 use std::hash::Hash;
-use std::path;
-use std::sync::Arc;
+use std::path::{self, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::{fs, io};
 
 pub(crate) use diagnostics::{ParseErrors, SourceFile};
+pub use index::{SearchOptions, SymbolHit, SymbolKind};
 use ir::DefIr;
 
 pub(crate) fn create_index() -> Index {
@@ -36,38 +44,218 @@ fn create_index_with(clang: Arc<clang::Clang>) -> Index {
     Index::new(clang, false, false)
 }
 
+/// The result of parsing a module.
+///
+/// Errors in the source are returned in [`ParseErrors`]; `Err` means clang
+/// couldn't produce an AST at all, like when the file doesn't exist.
+pub(crate) type ParseResult = Result<ParsedModule, Diagnostic>;
+
+/// A parsed module, along with the errors clang found in its source.
+pub(crate) type ParsedModule = (ModuleContext, ParseErrors);
+
+#[cfg(test)]
 pub(crate) fn parse(
-    db: &impl SourceFileCache,
+    db: &impl Options,
     index: &Index,
     module_id: ModuleId,
     filename: &path::Path,
-) -> (ModuleContext, ParseErrors) {
-    parse_with(db, index, module_id, |index| {
-        let parser = index.parser(filename);
-        configure(parser).parse().unwrap()
-    })
+) -> ParseResult {
+    if let Err(err) = fs::File::open(filename) {
+        return Err(unreadable_file(filename, err));
+    }
+    parse_with(db, index, module_id, filename, None)
+}
+
+/// Like [`parse`], but defines the macros in `config` first. The module is
+/// bound under the config set's name.
+pub(crate) fn parse_configured(
+    db: &(impl SourceFileCache + Options),
+    index: &Index,
+    module_id: ModuleId,
+    filename: &path::Path,
+    config: Option<ConfigSet>,
+) -> ParseResult {
+    if let Err(err) = fs::File::open(filename) {
+        return Err(unreadable_file(filename, err));
+    }
+    parse_with_unsaved(db, index, module_id, filename, None, config, vec![])
 }
 
+/// Parses `filename`, using `contents` instead of the file on disk if given.
 pub(crate) fn parse_with(
-    db: &impl SourceFileCache,
+    db: &impl Options,
+    index: &Index,
+    module_id: ModuleId,
+    filename: &path::Path,
+    contents: Option<String>,
+) -> ParseResult {
+    parse_with_unsaved(db, index, module_id, filename, contents, None, vec![])
+}
+
+/// Like [`parse_with`], but starts out with the file overrides set in `db`,
+/// so the module can include headers that only exist in memory.
+#[cfg(test)]
+pub(crate) fn parse_with_overrides(
+    db: &impl CcSource,
+    index: &Index,
+    module_id: ModuleId,
+    filename: &path::Path,
+    contents: Option<String>,
+) -> ParseResult {
+    let unsaved = db
+        .overridden_files()
+        .iter()
+        .filter_map(|path| {
+            db.file_override(path.clone())
+                .map(|contents| (path.clone(), contents))
+        })
+        .collect();
+    parse_with_unsaved(db, index, module_id, filename, contents, None, unsaved)
+}
+
+fn parse_with_unsaved(
+    db: &impl Options,
     index: &Index,
     module_id: ModuleId,
-    parse_fn: impl for<'i, 'tu> FnOnce(&'tu clang::Index<'i>) -> clang::TranslationUnit<'tu>,
-) -> (ModuleContext, ParseErrors) {
-    let tu = index.clone().parse_with(parse_fn);
-    let ctx = ModuleContext::new(db, tu);
-    (ctx, ParseErrors(module_id))
-}
-
-pub(crate) fn configure(mut parser: Parser<'_>) -> Parser<'_> {
-    parser.skip_function_bodies(true).arguments(&[
-        "-x",
-        "c++",
-        "-std=c++17",
-        "-isysroot",
-        "/Library/Developer/CommandLineTools/SDKs/MacOSX.sdk",
-    ]);
+    filename: &path::Path,
+    contents: Option<String>,
+    config: Option<ConfigSet>,
+    unsaved: Vec<(PathBuf, Arc<String>)>,
+) -> ParseResult {
+    let source = ParseSource {
+        path: filename.to_owned(),
+        contents: contents.map(Arc::new),
+        config,
+    };
+    let ctx = ModuleContext::parse(db, index, source, unsaved).map_err(|err| {
+        Diagnostic::new(
+            Severity::Error,
+            format!("clang could not parse `{}`", filename.display()),
+        )
+        .with_note(format!("libclang reported: {}", err))
+    })?;
+    Ok((ctx, ParseErrors(module_id, vec![])))
+}
+
+fn unreadable_file(path: &path::Path, err: io::Error) -> Diagnostic {
+    let diag = match err.kind() {
+        io::ErrorKind::NotFound => Diagnostic::new(
+            Severity::Error,
+            format!("header not found: `{}`", path.display()),
+        ),
+        _ => Diagnostic::new(
+            Severity::Error,
+            format!("could not read header `{}`", path.display()),
+        )
+        .with_note(err.to_string()),
+    };
+    // Relative paths are only looked up in the working directory.
+    match (path.is_relative(), std::env::current_dir()) {
+        (true, Ok(dir)) => diag.with_note(cc_use_common::describe_search(&[dir])),
+        _ => diag,
+    }
+}
+
+/// Parses a module for each of `inputs` on a pool of `jobs` threads.
+///
+/// Each thread parses with its own index, since an index can only be used
+/// from one thread at a time. The results are in the same order as `inputs`.
+/// If parsing one input panics, the others are still parsed, and its result
+/// holds the panic message instead.
+pub(crate) fn parse_in_parallel<T: Sync>(
+    db: &crate::Database,
+    jobs: usize,
+    inputs: &[T],
+    parse: impl Fn(&crate::Database, &Index, &T) -> ParseResult + Sync,
+) -> Result<Vec<Result<ParseResult, String>>, rayon::ThreadPoolBuildError> {
+    use rayon::prelude::*;
+    use salsa::ParallelDatabase;
+    use std::panic::{self, AssertUnwindSafe};
+
+    /// A parsed module, on its way back to the thread that will lower it.
+    struct Parsed(Result<ParseResult, String>);
+    // SAFETY: Nothing in a parsed module is shared with anything that stays
+    // behind on the worker thread:
+    // - Its index and translation unit are only reachable through the module.
+    //   The worker drops its own handle to the index before sending it, and
+    //   the reference counts on both are atomic.
+    // - The entities, types and files it holds point into its own translation
+    //   unit, and the `RefCell`s caching things about them move along with it.
+    // libclang allows a translation unit to be used by a thread other than the
+    // one that created it, as long as only one thread uses it at a time. That
+    // thread does need the library loaded, which is done below.
+    unsafe impl Send for Parsed {}
+
+    // The modules are lowered and dropped on this thread.
+    clang();
+    // Snapshots can't be shared between threads, so give each input its own.
+    let work: Vec<_> = inputs.iter().map(|input| (db.snapshot(), input)).collect();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    let parsed: Vec<Parsed> = pool.install(|| {
+        work.into_par_iter()
+            .map(|(db, input)| {
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| parse(&db, &create_index(), input)));
+                Parsed(result.map_err(|payload| {
+                    payload
+                        .downcast_ref::<&str>()
+                        .map(|msg| msg.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown error".to_string())
+                }))
+            })
+            .collect()
+    });
+    Ok(parsed.into_iter().map(|parsed| parsed.0).collect())
+}
+
+#[cfg(test)]
+pub(crate) fn configure<'i>(parser: Parser<'i>, opts: &SessionOptions) -> Parser<'i> {
+    configure_with(parser, opts, &[])
+}
+
+/// Like [`configure`], but passes `extra_args` to clang too.
+fn configure_with<'i>(
+    mut parser: Parser<'i>,
+    opts: &SessionOptions,
+    extra_args: &[String],
+) -> Parser<'i> {
+    let mut args = clang_args(opts);
+    args.extend_from_slice(extra_args);
     parser
+        .skip_function_bodies(!opts.full_parse)
+        .arguments(&args);
+    parser
+}
+
+/// The arguments every module is parsed with.
+fn clang_args(opts: &SessionOptions) -> Vec<String> {
+    let (lang, std) = match opts.language {
+        Language::Cxx => ("c++", "-std=c++17"),
+        Language::C => ("c", "-std=c11"),
+    };
+    let mut args: Vec<String> = vec![
+        "-x".into(),
+        lang.into(),
+        std.into(),
+        "-isysroot".into(),
+        "/Library/Developer/CommandLineTools/SDKs/MacOSX.sdk".into(),
+    ];
+    if let Some(target) = &opts.target {
+        args.push("-target".into());
+        args.push(target.clone());
+        // Older clangs delay parsing templates on MSVC targets, which would
+        // leave us with less to look at than on other targets.
+        if target.ends_with("-msvc") {
+            args.push("-fno-delayed-template-parsing".into());
+        }
+    }
+    args.extend(
+        opts.include_dirs
+            .iter()
+            .map(|dir| format!("-I{}", dir.display())),
+    );
+    args
 }
 
 intern_key!(pub ModuleId);
@@ -77,9 +265,47 @@ impl ModuleId {
     }
 }
 
+intern_key!(pub ExportId);
+
+/// Identifies an item in the `rust_export` namespace of a module.
+///
+/// Besides the export's position in the namespace, this holds everything
+/// lowering takes from the export itself, so an export that moves or changes
+/// gets a new key.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ExportKey {
+    pub mdl: ModuleId,
+    pub index: usize,
+    pub name: cc::Ident,
+    pub path: Option<ir::bindings::Path>,
+    pub span: Span,
+}
+
 thread_local! {
     // Use thread-local storage so we can fully control the lifetime of our TranslationUnit.
     static AST_CONTEXT: RefCell<Option<Vec<ModuleContext>>> = RefCell::new(None);
+
+    // Set on threads that must only read query results memoized by the main thread.
+    static AST_FORBIDDEN: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the current thread as one that must never touch the AST.
+///
+/// The AST lives in thread-local storage on the main thread, so any query that
+/// reaches it from another thread must already have been memoized there.
+pub(crate) fn forbid_ast_access() {
+    AST_FORBIDDEN.with(|forbidden| forbidden.set(true));
+}
+
+/// Panics if the current thread was marked by [`forbid_ast_access`].
+///
+/// This is checked in release builds too: a thread that got past it would
+/// see no AST at all, or the AST of a different session.
+fn assert_ast_allowed() {
+    assert!(
+        !AST_FORBIDDEN.with(Cell::get),
+        "AST accessed from a lowering thread; resolve the query on the main thread first"
+    );
 }
 
 struct AstContextDropGuard;
@@ -89,17 +315,70 @@ impl Drop for AstContextDropGuard {
     }
 }
 
+/// Sets the initial values of the [`CcSource`] inputs.
+pub(crate) fn init(db: &mut crate::Database) {
+    db.set_ast_generation(0);
+    db.set_overridden_files(Default::default());
+}
+
 pub(crate) fn set_ast<R>(
     db: &mut crate::Database,
     ctx: Vec<ModuleContext>,
-    f: impl FnOnce(&crate::Database) -> R,
+    f: impl FnOnce(&mut crate::Database) -> R,
 ) -> R {
-    use salsa::Database;
-    db.query_mut(AstContextQuery).invalidate(&());
+    let generation = db.ast_generation();
+    db.set_ast_generation(generation + 1);
     let _guard = AstContextDropGuard;
+    let modules = ctx.len();
     AST_CONTEXT.with(|cx| *cx.borrow_mut() = Some(ctx));
-    let res = f(db);
-    res
+    // Files may have been overridden since the modules were parsed.
+    reparse_overridden(db);
+    for id in 0..modules {
+        let version = AST_CONTEXT.with(|ctx| ctx.borrow().as_ref().unwrap()[id].version);
+        db.set_ast_version(ModuleId::new(id as u32), version);
+    }
+    f(db)
+}
+
+/// Reparses every module that includes a file whose override changed since
+/// the module was last parsed.
+///
+/// This is the only place modules are reparsed. It needs a mutable database,
+/// so it can't happen inside a query; queries only ever read the AST that's
+/// already there.
+pub(crate) fn reparse_overridden(db: &mut crate::Database) {
+    let overridden = db.overridden_files();
+    let changed: Vec<(ModuleId, u64)> = AST_CONTEXT.with(|ctx| {
+        let mut ctx = ctx.borrow_mut();
+        let modules = match ctx.as_mut() {
+            Some(modules) => modules,
+            // The override is picked up when the modules are set.
+            None => return vec![],
+        };
+        let mut changed = vec![];
+        for (id, module) in modules.iter_mut().enumerate() {
+            let unsaved: Vec<_> = overridden
+                .iter()
+                .filter(|path| **path == module.source.path || module.includes_file(path))
+                .filter_map(|path| {
+                    db.file_override(path.clone())
+                        .map(|contents| (path.clone(), contents))
+                })
+                .collect();
+            if unsaved == module.unsaved {
+                continue;
+            }
+            let version = module.version;
+            module.reparse(&*db, unsaved);
+            if module.version != version {
+                changed.push((ModuleId::new(id as u32), module.version));
+            }
+        }
+        changed
+    });
+    for (mdl, version) in changed {
+        db.set_ast_version(mdl, version);
+    }
 }
 
 fn with_ast_module<R>(
@@ -107,11 +386,12 @@ fn with_ast_module<R>(
     mdl: ModuleId,
     f: impl for<'tu> FnOnce(&'tu TranslationUnit<'tu>, &'_ ModuleContextInner<'tu>) -> R,
 ) -> R {
-    // Report that we're reading the ast context.
-    db.ast_context();
+    assert_ast_allowed();
+    // Report that we're reading the module's AST.
+    db.ast_version(mdl);
     AST_CONTEXT.with(move |ctx| {
-        ctx.borrow_mut()
-            .as_mut()
+        ctx.borrow()
+            .as_ref()
             .expect("with_ast_module called with no ast defined")[mdl.0.as_usize()]
         .with(f)
     })
@@ -119,47 +399,126 @@ fn with_ast_module<R>(
 
 /// Provides access to C++ source files and their AST.
 #[salsa::query_group(CcSourceStorage)]
-pub trait CcSource {
-    /// The [`ModuleContext`] holds the source file contents and their AST.
+pub trait CcSource: Options + Instrumented {
+    /// Changes every time [`set_ast`] replaces the set of modules.
+    #[salsa::input]
+    fn ast_generation(&self) -> u64;
+
+    /// Paths of every file with an override set by [`CcSource::file_override`].
+    #[salsa::input]
+    fn overridden_files(&self) -> Arc<BTreeSet<PathBuf>>;
+
+    /// Contents to use for a file instead of what's on disk, if any.
+    ///
+    /// Use [`crate::Database::set_file_contents`] to set this.
+    #[salsa::input]
+    fn file_override(&self, path: PathBuf) -> Option<Arc<String>>;
+
+    /// Identifies the AST held by a module's [`ModuleContext`].
+    ///
+    /// This input exists to track uses of the context from [`with_ast_module`]. Use that
+    /// function to gain access to the context.
     ///
-    /// This query does not actually return the context; instead, it exists to track uses of the
-    /// context from [`with_ast_module`]. Use that function to gain access to the context.
-    #[salsa::dependencies]
-    fn ast_context(&self) -> ();
+    /// It is set by [`set_ast`], and again by [`reparse_overridden`] when a file in the module
+    /// is overridden. It only changes if the new AST differs from the old one in more than
+    /// comments, so queries that read the AST are not re-executed otherwise.
+    #[salsa::input]
+    fn ast_version(&self, mdl: ModuleId) -> u64;
 }
 
 /// Generates source IR for C++.
 #[salsa::query_group(CcSourceIrStorage)]
-pub trait CcSourceIr: CcSource + DefIr + SourceFileCache {
+pub trait CcSourceIr: CcSource + DefIr + SourceFileCache + Options {
     fn cc_module_ids(&self) -> Vec<ModuleId>;
 
+    /// The name of the config set a module was parsed under, if any.
+    fn cc_module_config(&self, mdl: ModuleId) -> Option<String>;
+
     #[salsa::invoke(lowering::cc_exported_items)]
     fn cc_exported_items(&self, mdl: ModuleId) -> Outcome<Arc<[ir::CcSourceImport]>>;
 
+    /// The items in the `rust_export` namespace of a module.
+    #[salsa::invoke(lowering::cc_exports)]
+    fn cc_exports(&self, mdl: ModuleId) -> Outcome<Arc<[ExportId]>>;
+
+    /// Lowers a single item in the `rust_export` namespace.
+    ///
+    /// Types are numbered per AST, so this runs again for every export of a
+    /// module that gets reparsed. Exports that lower to the same item as
+    /// before don't invalidate anything that uses them.
+    #[salsa::invoke(lowering::cc_item_for_export)]
+    fn cc_item_for_export(&self, export: ExportId) -> Outcome<Option<ir::CcSourceImport>>;
+
+    /// Checks that every item named in `SessionOptions::exports` exists.
+    #[salsa::invoke(lowering::check_option_exports)]
+    fn check_option_exports(&self) -> Outcome<()>;
+
+    /// Checks that every member named in `SessionOptions::skip_members`
+    /// exists.
+    #[salsa::invoke(lowering::check_skip_members)]
+    fn check_skip_members(&self) -> Outcome<()>;
+
+    /// Checks that every struct named in `SessionOptions::opaque` exists.
+    #[salsa::invoke(lowering::check_opaque_types)]
+    fn check_opaque_types(&self) -> Outcome<()>;
+
+    /// Checks that every alias named in `SessionOptions::newtypes` exists.
+    #[salsa::invoke(lowering::check_newtypes)]
+    fn check_newtypes(&self) -> Outcome<()>;
+
     #[salsa::invoke(lowering::cc_item)]
     fn cc_item(&self, import: ir::bindings::Import) -> Outcome<Option<ir::CcSourceImport>>;
 
     #[salsa::invoke(lowering::lower_ty)]
     fn type_of(&self, mdl: ModuleId, id: TypeId) -> Outcome<ir::cc::Ty>;
 
+    /// Lowers a struct declaration, so a struct used as a field of several
+    /// other structs is only lowered once.
+    ///
+    /// `fallback_name` names the struct if the declaration doesn't.
+    #[salsa::invoke(lowering::lower_struct)]
+    fn cc_struct(
+        &self,
+        mdl: ModuleId,
+        ent: EntityId,
+        fallback_name: ir::cc::Ident,
+    ) -> Outcome<Option<ir::cc::StructId>>;
+
+    #[salsa::interned]
+    fn intern_cc_export(&self, export: ExportKey) -> ExportId;
+
     #[salsa::interned]
     fn intern_cc_namespace(&self, st: ir::cc::Namespace) -> ir::cc::NamespaceId;
 
     #[salsa::interned]
-    fn intern_cc_struct(&self, st: ir::cc::Struct) -> ir::cc::StructId;
+    fn intern_cc_struct(&self, st: Arc<ir::cc::Struct>) -> ir::cc::StructId;
+
+    #[salsa::interned]
+    fn intern_cc_opaque(&self, ty: ir::cc::Opaque) -> ir::cc::OpaqueId;
+
+    #[salsa::interned]
+    fn intern_cc_alias(&self, alias: ir::cc::Alias) -> ir::cc::AliasId;
 
     #[salsa::interned]
     fn intern_cc_fn(&self, func: Arc<Outcome<ir::cc::Function>>) -> ir::cc::FunctionId;
 }
 
-fn ast_context(db: &(impl CcSource + salsa::Database)) {
-    db.salsa_runtime()
-        .report_synthetic_read(salsa::Durability::LOW);
+fn cc_module_config(db: &impl CcSourceIr, mdl: ModuleId) -> Option<String> {
+    db.ast_generation();
+    AST_CONTEXT.with(|ctx| {
+        let ctx = ctx.borrow();
+        let module = &ctx.as_ref().unwrap()[mdl.0.as_usize()];
+        module
+            .source
+            .config
+            .as_ref()
+            .map(|config| config.name.clone())
+    })
 }
 
 fn cc_module_ids(db: &impl CcSourceIr) -> Vec<ModuleId> {
-    // Report that we're reading the ast context.
-    db.ast_context();
+    // Report that we're reading the set of modules.
+    db.ast_generation();
     AST_CONTEXT.with(|ctx| {
         (0..ctx.borrow().as_ref().unwrap().len())
             .map(|id| ModuleId::new(id as u32))
@@ -178,26 +537,202 @@ impl Index {
 
     fn parse_with(
         self,
-        parse_fn: impl for<'i, 'tu> FnOnce(&'tu clang::Index<'i>) -> clang::TranslationUnit<'tu>,
-    ) -> AstTu {
-        AstTu(Arc::new(rent::Tu::new(self.0, |rent_index| {
-            parse_fn(&rent_index.index)
-        })))
+        parse_fn: impl for<'i, 'tu> FnOnce(
+            &'tu clang::Index<'i>,
+        )
+            -> Result<clang::TranslationUnit<'tu>, clang::SourceError>,
+    ) -> Result<AstTu, clang::SourceError> {
+        let tu = rent::Tu::try_new(self.0, |rent_index| parse_fn(&rent_index.index))
+            .map_err(|err| err.0)?;
+        Ok(AstTu(Arc::new(tu)))
+    }
+}
+
+/// Where a module's translation unit was parsed from, so it can be reparsed.
+#[derive(Clone)]
+struct ParseSource {
+    path: PathBuf,
+    /// Contents to use instead of the file on disk, if any.
+    contents: Option<Arc<String>>,
+    /// The config set to define macros from, if any.
+    config: Option<ConfigSet>,
+}
+impl ParseSource {
+    /// The `-D` arguments for the config set.
+    fn define_args(&self) -> Vec<String> {
+        self.config
+            .iter()
+            .flat_map(|config| &config.defines)
+            .map(|define| format!("-D{}", define))
+            .collect()
     }
 }
 
-pub struct ModuleContext(rent::ModuleContext);
+static NEXT_MODULE_VERSION: AtomicU64 = AtomicU64::new(0);
+
+pub struct ModuleContext {
+    inner: rent::ModuleContext,
+    source: ParseSource,
+    /// The file overrides this module was parsed with.
+    unsaved: Vec<(PathBuf, Arc<String>)>,
+    /// Unique to each parse that produced a different AST.
+    version: u64,
+}
 impl ModuleContext {
-    fn new(db: &impl SourceFileCache, tu: AstTu) -> Self {
-        ModuleContext(rent::ModuleContext::new(tu.0, |tu| {
-            ModuleContextInner::new(db, tu.tu)
-        }))
+    fn parse(
+        db: &impl Options,
+        index: &Index,
+        source: ParseSource,
+        unsaved: Vec<(PathBuf, Arc<String>)>,
+    ) -> Result<Self, clang::SourceError> {
+        let opts = db.options();
+        let pch = match &opts.pch_dir {
+            // Overridden files change too often for a PCH of them to be useful.
+            Some(dir) if unsaved.is_empty() => Some(pch::Pch::new(dir, &source, &opts)),
+            _ => None,
+        };
+        let tu = index.clone().parse_with(|index| {
+            let mut files: Vec<_> = unsaved
+                .iter()
+                .map(|(path, contents)| clang::Unsaved::new(path, &**contents))
+                .collect();
+            if let Some(contents) = &source.contents {
+                if !unsaved.iter().any(|(path, _)| *path == source.path) {
+                    files.push(clang::Unsaved::new(&source.path, &**contents));
+                }
+            }
+            if let Some(tu) = pch
+                .as_ref()
+                .and_then(|pch| pch.parse(index, &source, &opts, &files))
+            {
+                return Ok(tu);
+            }
+            // Errors in the source are reported separately, so this only fails
+            // if clang couldn't produce an AST at all.
+            let mut parser =
+                configure_with(index.parser(&source.path), &opts, &source.define_args());
+            parser.unsaved(&files).parse()
+        })?;
+        // Overrides come last, so they replace the source's own contents like
+        // they do above.
+        let virtual_files = source
+            .contents
+            .as_ref()
+            .map(|contents| (&source.path, contents))
+            .into_iter()
+            .chain(unsaved.iter().map(|(path, contents)| (path, contents)))
+            .map(|(path, contents)| {
+                let file = VirtualFile {
+                    name: path.to_string_lossy().into(),
+                    contents: contents.as_str().into(),
+                };
+                (path.clone(), file)
+            })
+            .collect();
+        Ok(ModuleContext {
+            inner: rent::ModuleContext::new(tu.0, |tu| {
+                ModuleContextInner::new(db, tu.tu, virtual_files)
+            }),
+            source,
+            unsaved,
+            version: NEXT_MODULE_VERSION.fetch_add(1, Ordering::Relaxed),
+        })
     }
-    fn with<R>(
+
+    /// Reparses the module with a new set of file overrides.
+    ///
+    /// The old AST is kept if the only changes are to comments, so that
+    /// anything derived from it stays valid.
+    fn reparse(&mut self, db: &impl Options, unsaved: Vec<(PathBuf, Arc<String>)>) {
+        let changed: Vec<PathBuf> = self
+            .unsaved
+            .iter()
+            .chain(&unsaved)
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut new = ModuleContext::parse(db, &create_index(), self.source.clone(), unsaved)
+            .expect("clang could not reparse a module it parsed before");
+        if new.token_hash(&changed) == self.token_hash(&changed) {
+            self.unsaved = new.unsaved;
+        } else {
+            *self = new;
+        }
+    }
+
+    /// Returns the file the module was parsed from and every file it
+    /// includes, leaving out system headers unless `include_system` is set.
+    pub(crate) fn input_files(&mut self, include_system: bool) -> Vec<PathBuf> {
+        let main = self.source.path.clone();
+        let mut files = self.with(|tu, _| {
+            let mut files = pch::included_files(tu, &main);
+            if !include_system {
+                files.retain(|path| !tu.get_file(path).is_some_and(is_system_file));
+            }
+            files
+        });
+        files.insert(0, main);
+        files
+    }
+
+    /// Searches the module's index for items whose name contains `query`.
+    pub(crate) fn search_symbols(
         &mut self,
+        db: &impl DefIr,
+        query: &str,
+        kind_filter: Option<SymbolKind>,
+        opts: &SearchOptions,
+    ) -> Vec<SymbolHit> {
+        self.with(|_, inner| {
+            inner
+                .path_index
+                .borrow_mut()
+                .search_symbols(db, query, kind_filter, opts)
+        })
+    }
+
+    fn includes_file(&mut self, path: &path::Path) -> bool {
+        self.with(|tu, _| tu.get_file(path).is_some())
+    }
+
+    /// Hashes the tokens of the given files, ignoring comments.
+    ///
+    /// Token offsets are included, since spans derived from the AST would be
+    /// invalidated by anything that moves tokens around.
+    fn token_hash(&mut self, paths: &[PathBuf]) -> u64 {
+        self.with(|tu, _| {
+            let mut hasher = DefaultHasher::new();
+            for path in paths {
+                let file = match tu.get_file(path) {
+                    Some(file) => file,
+                    None => continue,
+                };
+                path.hash(&mut hasher);
+                let len = file.get_contents().map_or(0, |contents| contents.len());
+                let range = source::SourceRange::new(
+                    file.get_offset_location(0),
+                    file.get_offset_location(len as u32),
+                );
+                for token in range.tokenize() {
+                    if token.get_kind() == clang::token::TokenKind::Comment {
+                        continue;
+                    }
+                    token.get_spelling().hash(&mut hasher);
+                    token
+                        .get_location()
+                        .get_file_location()
+                        .offset
+                        .hash(&mut hasher);
+                }
+            }
+            hasher.finish()
+        })
+    }
+
+    fn with<R>(
+        &self,
         f: impl for<'tu> FnOnce(&'tu clang::TranslationUnit<'tu>, &ModuleContextInner<'tu>) -> R,
     ) -> R {
-        self.0.rent_all(|r| f(r.tu.tu, r.result))
+        self.inner.rent_all(|r| f(r.tu.tu, r.result))
     }
 }
 
@@ -214,23 +749,42 @@ struct ModuleContextInner<'tu> {
     root: clang::Entity<'tu>,
 
     files: Interner<source::File<'tu>, LocalFileId>,
-    #[allow(dead_code)]
+    /// Files clang read from memory instead of disk, by path. Spans in these
+    /// point to the stored contents instead of the AST.
+    virtual_files: HashMap<PathBuf, VirtualFile>,
     entities: Interner<Entity<'tu>, EntityId>,
     types: Interner<HashType<'tu>, TypeId>,
 
     path_index: RefCell<index::PathIndex<'tu>>,
+    /// The exports of this AST, in the order [`CcSourceIr::cc_exports`]
+    /// numbers them. Found the first time they're needed.
+    exports: RefCell<Option<Vec<lowering::Export<'tu>>>>,
+    /// Structs exported from a `rust_export::opaque` namespace, by canonical
+    /// declaration.
+    opaque_exports: HashSet<Entity<'tu>>,
+    /// Aliases of builtin types exported from `rust_export`, by canonical
+    /// declaration. Types spelled with them are lowered to `cc::Ty::Alias`.
+    alias_exports: HashSet<Entity<'tu>>,
 }
 
 impl<'tu> ModuleContextInner<'tu> {
-    fn new(_db: &impl SourceFileCache, tu: &'tu TranslationUnit<'tu>) -> Self {
+    fn new(
+        _db: &impl Options,
+        tu: &'tu TranslationUnit<'tu>,
+        virtual_files: HashMap<PathBuf, VirtualFile>,
+    ) -> Self {
         ModuleContextInner {
             root: tu.get_entity(),
 
             files: Interner::new(),
+            virtual_files,
             entities: Interner::new(),
             types: Interner::new(),
 
             path_index: RefCell::new(index::PathIndex::new(tu)),
+            exports: RefCell::new(None),
+            opaque_exports: lowering::opaque_exports(tu),
+            alias_exports: lowering::alias_exports(tu),
         }
     }
 
@@ -268,6 +822,15 @@ impl<T: Hash + Eq + Clone, Id: salsa::InternKey + Copy> Interner<T, Id> {
     }
 }
 
+/// Returns true if clang treats `file` as a system header, either because it
+/// was found in a system include directory or because it says it is one.
+fn is_system_file(file: source::File<'_>) -> bool {
+    // `#pragma clang system_header` only applies after the pragma, so check
+    // the end of the file.
+    let len = file.get_contents().map_or(0, |contents| contents.len());
+    file.get_offset_location(len as u32).is_in_system_header()
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct HashType<'tu>(Type<'tu>);
 impl<'tu> Hash for HashType<'tu> {
@@ -283,18 +846,40 @@ mod lib {
     type ClangWithLib = (Arc<clang::Clang>, Arc<SharedLibrary>);
 
     lazy_static! {
-        static ref CLANG: ClangWithLib = load();
+        static ref CLANG: Result<ClangWithLib, String> = load();
     }
 
-    fn load() -> ClangWithLib {
-        clang_sys::load().unwrap();
-        let lib = clang_sys::get_library().unwrap();
-        let clang = clang::Clang::new().unwrap();
-        (Arc::new(clang), lib)
+    fn load() -> Result<ClangWithLib, String> {
+        clang_sys::load().map_err(|err| {
+            format!(
+                "could not load libclang: {}\n\
+                 set LIBCLANG_PATH to the directory containing the libclang \
+                 shared library, or put llvm-config on PATH",
+                err
+            )
+        })?;
+        let lib = clang_sys::get_library().expect("libclang was just loaded");
+        let clang = clang::Clang::new().map_err(|err| {
+            format!(
+                "could not initialize libclang: {}\n\
+                 only one `clang::Clang` can exist in a process at a time",
+                err
+            )
+        })?;
+        Ok((Arc::new(clang), lib))
+    }
+
+    /// Loads libclang if it isn't loaded yet, returning an explanation if it
+    /// can't be.
+    pub(crate) fn load_clang() -> Result<(), String> {
+        CLANG.as_ref().map(|_| ()).map_err(Clone::clone)
     }
 
     pub(crate) fn clang() -> Arc<clang::Clang> {
-        let clang_lib = CLANG.clone();
+        let clang_lib = match &*CLANG {
+            Ok(clang_lib) => clang_lib.clone(),
+            Err(msg) => panic!("{}", msg),
+        };
         // Ensure that the library is loaded on this thread.
         if !clang_sys::is_loaded() {
             clang_sys::set_library(Some(clang_lib.1.clone()));
@@ -302,7 +887,7 @@ mod lib {
         clang_lib.0
     }
 }
-pub(crate) use lib::clang;
+pub(crate) use lib::{clang, load_clang};
 
 // All of the clang types have a lifetime parameter, but salsa doesn't support
 // those today. Work around this with some structs that contain an Arc to the
@@ -363,3 +948,78 @@ impl Hash for AstFile {
         self.0.rent(|f| f.hash(state));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cc_use::RsSource, diagnostics::DiagnosticsCtx, Database};
+
+    #[test]
+    fn parse_modules_in_parallel() {
+        let db = Database::new();
+        let srcs: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|name| {
+                format!(
+                    "struct {0} {{ int a; }};\nnamespace rust_export {{ using ::{0}; }}\n",
+                    name
+                )
+            })
+            .enumerate()
+            .collect();
+        let parsed = parse_in_parallel(&db, 3, &srcs, |db, index, (idx, src)| {
+            parse_with(
+                db,
+                index,
+                ModuleId::new(*idx as u32),
+                path::Path::new(&format!("__test__/test{}.cc", idx)),
+                Some(src.clone()),
+            )
+        })
+        .unwrap();
+        let modules: Vec<_> = parsed
+            .into_iter()
+            .map(|result| result.unwrap().map(|(ctx, _)| ctx).unwrap())
+            .collect();
+
+        let mut db = db;
+        db.set_rs_source_root(None);
+        set_ast(&mut db, modules, |db| {
+            let ids = db.cc_module_ids();
+            assert_eq!(ids.len(), 3);
+            for (id, name) in ids.into_iter().zip(&["A", "B", "C"]) {
+                let exports = db.cc_exported_items(id);
+                assert!(exports.is_ok());
+                let exports = exports.to_ref().skip_errs();
+                assert_eq!(exports.len(), 1);
+                assert_eq!(&*exports[0].def.name(db).as_str(db), *name);
+            }
+        });
+    }
+
+    #[test]
+    fn snippet_from_unsaved_file() {
+        let mut db = Database::new();
+        let (ctx, errs) = parse_with(
+            &db,
+            &create_index(),
+            ModuleId::new(0),
+            path::Path::new(crate::test_util::TEST_FILENAME),
+            Some("struct S {\n    Missing field;\n};\n".to_string()),
+        )
+        .unwrap();
+        let diags = DiagnosticsCtx::buffered();
+        db.set_rs_source_root(None);
+        set_ast(&mut db, vec![ctx], |db| {
+            errs.to_diagnostics(db).emit(db, &diags)
+        });
+        let out = diags.captured().unwrap();
+        assert!(
+            out.starts_with("__test__/test.cc:2:5: error: unknown type name 'Missing'"),
+            "{}",
+            out
+        );
+        assert!(out.contains("__test__/test.cc:2:5"), "{}", out);
+        assert!(out.contains("Missing field;"), "{}", out);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Precompiled headers, so repeated runs don't parse the same headers again.
+//!
+//! A module is precompiled to `<name>-<key>.pch` in the PCH directory, where
+//! the key is a hash of the module source and the clang arguments. Next to it,
+//! `<name>-<key>.pch.deps` holds a hash of the contents of every file the
+//! module included, followed by their paths, one per line. The PCH is rebuilt
+//! whenever that hash changes.
+
+use super::{clang_args, configure_with, ParseSource};
+use crate::SessionOptions;
+use clang::{Index, TranslationUnit, Unsaved};
+use std::collections::{hash_map::DefaultHasher, HashSet};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+/// The precompiled header for a single module.
+pub(super) struct Pch {
+    path: PathBuf,
+}
+
+impl Pch {
+    pub(super) fn new(dir: &Path, source: &ParseSource, opts: &SessionOptions) -> Self {
+        let mut hasher = DefaultHasher::new();
+        source.path.hash(&mut hasher);
+        source.contents.hash(&mut hasher);
+        clang_args(opts).hash(&mut hasher);
+        opts.full_parse.hash(&mut hasher);
+        source.define_args().hash(&mut hasher);
+        clang::get_version().hash(&mut hasher);
+        let stem = source
+            .path
+            .file_stem()
+            .map_or("module".into(), |stem| stem.to_string_lossy());
+        Pch {
+            path: dir.join(format!("{}-{:016x}.pch", stem, hasher.finish())),
+        }
+    }
+
+    fn deps_path(&self) -> PathBuf {
+        self.path.with_extension("pch.deps")
+    }
+
+    /// Parses the module against its PCH, building the PCH first if it's
+    /// missing or out of date.
+    ///
+    /// Returns `None` if the PCH can't be built, e.g. because the headers have
+    /// errors. The module should then be parsed normally, so the errors are
+    /// reported.
+    pub(super) fn parse<'tu>(
+        &self,
+        index: &'tu Index<'tu>,
+        source: &ParseSource,
+        opts: &SessionOptions,
+        files: &[Unsaved],
+    ) -> Option<TranslationUnit<'tu>> {
+        if !self.is_valid() {
+            self.build(index, source, opts, files).ok()?;
+        }
+        // Everything the module includes is in the PCH, so the translation
+        // unit itself is empty. We check that the PCH is up to date ourselves,
+        // since clang would compare the main file on disk with what the PCH
+        // was built from, and for cc_use! modules those always differ.
+        let path = self.path.with_extension("cc");
+        let args = [
+            "-include-pch".to_string(),
+            self.path.display().to_string(),
+            "-Xclang".to_string(),
+            "-fno-validate-pch".to_string(),
+        ];
+        let mut parser = configure_with(index.parser(&path), opts, &args);
+        parser.unsaved(&[Unsaved::new(&path, "")]).parse().ok()
+    }
+
+    /// Returns whether the PCH exists and none of the files it was built from
+    /// have changed.
+    fn is_valid(&self) -> bool {
+        let deps = match fs::read_to_string(self.deps_path()) {
+            Ok(deps) => deps,
+            Err(_) => return false,
+        };
+        let mut lines = deps.lines();
+        let expected = lines.next();
+        let files: Vec<_> = lines.map(PathBuf::from).collect();
+        let actual = hash_files(&files).ok().map(|hash| hash.to_string());
+        self.path.is_file() && expected.is_some() && expected == actual.as_deref()
+    }
+
+    fn build(
+        &self,
+        index: &Index<'_>,
+        source: &ParseSource,
+        opts: &SessionOptions,
+        files: &[Unsaved],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut parser = configure_with(index.parser(&source.path), opts, &source.define_args());
+        let tu = parser
+            .incomplete(true)
+            .detailed_preprocessing_record(true)
+            .unsaved(files)
+            .parse()?;
+        let mut deps = included_files(&tu, &source.path);
+        // The contents of a module given as a string are part of the key.
+        if source.contents.is_none() {
+            deps.insert(0, source.path.clone());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        tu.save(&self.path)?;
+        let mut out = format!("{}\n", hash_files(&deps)?);
+        for dep in &deps {
+            out += &format!("{}\n", dep.display());
+        }
+        fs::write(self.deps_path(), out)?;
+        Ok(())
+    }
+}
+
+/// Returns every file `main` includes, directly or indirectly.
+pub(super) fn included_files(tu: &TranslationUnit<'_>, main: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    let mut stack: Vec<_> = tu.get_file(main).into_iter().collect();
+    while let Some(file) = stack.pop() {
+        for include in file.get_includes() {
+            if let Some(included) = include.get_file() {
+                if seen.insert(included.get_path()) {
+                    files.push(included.get_path());
+                    stack.push(included);
+                }
+            }
+        }
+    }
+    files
+}
+
+fn hash_files(paths: &[PathBuf]) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        path.hash(&mut hasher);
+        fs::read(path)?.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cc_use::RsSource, ir::rs::RsTargetBindings, libclang, Session, SessionOptions};
+    use std::fs;
+    use std::path::Path;
+
+    /// Generates bindings for `header`, and returns the number of fields in
+    /// the struct it exports.
+    fn field_count(header: &Path, pch_dir: &Path) -> usize {
+        let mut sess = Session::with_options(SessionOptions {
+            pch_dir: Some(pch_dir.to_owned()),
+            ..Default::default()
+        });
+        let index = libclang::create_index();
+        let (ast, _) =
+            libclang::parse(&sess.db, &index, libclang::ModuleId::new(0), header).unwrap();
+        sess.db.set_rs_source_root(None);
+        libclang::set_ast(&mut sess.db, vec![ast], |db| {
+            let krate = db.rs_bindings();
+            assert!(krate.is_ok());
+            let krate = krate.to_ref().skip_errs();
+            krate.visible_structs(db)[0].lookup(db).fields.len()
+        })
+    }
+
+    fn pch_files(pch_dir: &Path) -> Vec<String> {
+        let mut files: Vec<_> = fs::read_dir(pch_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn build_and_reuse() {
+        let dir = tempfile::tempdir().unwrap();
+        let pch_dir = dir.path().join("pch");
+        let header = dir.path().join("pod.h");
+        let inner = dir.path().join("inner.h");
+        fs::write(&inner, "struct Inner { int a; };\n").unwrap();
+        fs::write(
+            &header,
+            "#include \"inner.h\"\n\
+             struct Pod { Inner x; };\n\
+             namespace rust_export { using ::Pod; }\n",
+        )
+        .unwrap();
+
+        assert_eq!(field_count(&header, &pch_dir), 1);
+        let files = pch_files(&pch_dir);
+        assert_eq!(files.len(), 2, "{:?}", files);
+        assert!(files[0].ends_with(".pch") && files[1].ends_with(".pch.deps"));
+        let deps = fs::read_to_string(pch_dir.join(&files[1])).unwrap();
+        assert!(deps.contains("inner.h"), "{}", deps);
+
+        // Nothing changed, so the same PCH is used.
+        assert_eq!(field_count(&header, &pch_dir), 1);
+        assert_eq!(fs::read_to_string(pch_dir.join(&files[1])).unwrap(), deps);
+
+        // Changing an included header rebuilds it.
+        fs::write(&inner, "struct Inner { int a; };\nstruct Unused {};\n").unwrap();
+        fs::write(
+            &header,
+            "#include \"inner.h\"\n\
+             struct Pod { Inner x; int y; };\n\
+             namespace rust_export { using ::Pod; }\n",
+        )
+        .unwrap();
+        assert_eq!(field_count(&header, &pch_dir), 2);
+        assert_ne!(fs::read_to_string(pch_dir.join(&files[1])).unwrap(), deps);
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Chooses the Rust names of bound C++ items.
+//!
+//! By default items keep their C++ names. A [`NamingPolicy`] can convert them
+//! to Rust conventions, and a [`RenameHook`] can override any name after
+//! that. Exported names are left alone, since `cc_use!` refers to items by
+//! the names they are exported under.
+
+use crate::{
+    ir::{
+        cc::{Ident, InvalidIdent},
+        DefIr,
+    },
+    SessionOptions,
+};
+use std::{fmt, hash};
+
+/// Built-in conversions applied to the names of bound items.
+///
+/// The default keeps every name as it is in C++.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct NamingPolicy {
+    /// Prefixes to strip from type names, like the `C` in `CFoo`.
+    ///
+    /// A prefix is only stripped if the rest of the name starts a new word:
+    /// with the prefix `C`, `CFoo` becomes `Foo` but `Circle` is kept. The
+    /// first prefix that applies is used.
+    pub strip_type_prefixes: Vec<String>,
+    /// Convert type names to `CamelCase`, so `tag_bar_t` becomes `TagBarT`.
+    pub camel_case_types: bool,
+    /// Convert field and method names to `snake_case`, so `getName` becomes
+    /// `get_name`.
+    pub snake_case_members: bool,
+}
+
+/// The kind of item being named.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum NameKind {
+    Struct,
+    Field,
+    Method,
+}
+
+/// Describes an item whose Rust name is being chosen, for a [`RenameHook`].
+#[derive(Clone, Copy, Debug)]
+pub struct NameContext<'a> {
+    pub kind: NameKind,
+    /// The name of the item in C++.
+    pub cc_name: &'a str,
+    /// For fields and methods, the C++ name of the struct they belong to.
+    pub parent: Option<&'a str>,
+    /// The name chosen by the [`NamingPolicy`].
+    pub name: &'a str,
+}
+
+/// A function that can override the Rust name of any bound item, by
+/// returning `Some` with the new name.
+///
+/// Runs after the [`NamingPolicy`].
+#[derive(Clone, Copy)]
+pub struct RenameHook(pub fn(&NameContext<'_>) -> Option<String>);
+
+impl PartialEq for RenameHook {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 as usize == other.0 as usize
+    }
+}
+impl Eq for RenameHook {}
+impl hash::Hash for RenameHook {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        (self.0 as usize).hash(state)
+    }
+}
+impl fmt::Debug for RenameHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RenameHook({:#x})", self.0 as usize)
+    }
+}
+
+/// Returns the Rust name of an item, or `Err` if the chosen name isn't a
+/// valid identifier.
+pub(crate) fn rust_name(
+    db: &impl DefIr,
+    opts: &SessionOptions,
+    kind: NameKind,
+    cc_name: &str,
+    parent: Option<&str>,
+) -> Result<Ident, InvalidIdent> {
+    let policy = &opts.naming;
+    let name = match kind {
+        NameKind::Struct => {
+            let name = strip_prefix(cc_name, &policy.strip_type_prefixes);
+            match policy.camel_case_types {
+                true => to_camel_case(name),
+                false => name.to_string(),
+            }
+        }
+        NameKind::Field | NameKind::Method => match policy.snake_case_members {
+            true => to_snake_case(cc_name),
+            false => cc_name.to_string(),
+        },
+    };
+    let name = match opts.rename_hook {
+        Some(RenameHook(hook)) => {
+            let ctx = NameContext {
+                kind,
+                cc_name,
+                parent,
+                name: &name,
+            };
+            hook(&ctx).unwrap_or(name)
+        }
+        None => name,
+    };
+    Ident::new(db, &name)
+}
+
+fn strip_prefix<'a>(name: &'a str, prefixes: &[String]) -> &'a str {
+    for prefix in prefixes {
+        let rest = match name.strip_prefix(prefix.as_str()) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let starts_word = match rest.chars().next() {
+            Some(c) if c.is_ascii_digit() => false,
+            Some(c) => c.is_uppercase() || !prefix.ends_with(char::is_alphanumeric),
+            None => false,
+        };
+        if starts_word {
+            return rest;
+        }
+    }
+    name
+}
+
+/// Converts `camelCase` or `PascalCase` to `snake_case`, keeping acronyms
+/// together: `getHTTPResponse` becomes `get_http_response`.
+fn to_snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::with_capacity(name.len() + 4);
+    for (idx, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let starts_word = match idx.checked_sub(1).map(|prev| chars[prev]) {
+                None | Some('_') => false,
+                Some(prev) if prev.is_uppercase() => {
+                    chars.get(idx + 1).is_some_and(|next| next.is_lowercase())
+                }
+                Some(_) => true,
+            };
+            if starts_word {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Converts `snake_case` or `camelCase` to `CamelCase`. Leading underscores
+/// are kept.
+fn to_camel_case(name: &str) -> String {
+    let body = name.trim_start_matches('_');
+    let mut out = name[..name.len() - body.len()].to_string();
+    for word in body.split('_').filter(|word| !word.is_empty()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            out.extend(first.to_uppercase());
+            out.push_str(chars.as_str());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    fn session(naming: NamingPolicy) -> Session {
+        Session::with_options(SessionOptions {
+            naming,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn case_conversions() {
+        assert_eq!(to_snake_case("getHTTPResponse"), "get_http_response");
+        assert_eq!(to_snake_case("GetName"), "get_name");
+        assert_eq!(to_snake_case("x2Value"), "x2_value");
+        assert_eq!(to_snake_case("already_snake"), "already_snake");
+        assert_eq!(to_snake_case("URL"), "url");
+        assert_eq!(to_camel_case("tag_bar_t"), "TagBarT");
+        assert_eq!(to_camel_case("fooBar"), "FooBar");
+        assert_eq!(to_camel_case("_private_t"), "_PrivateT");
+
+        let prefixes = vec!["C".to_string(), "tag_".to_string()];
+        assert_eq!(strip_prefix("CFoo", &prefixes), "Foo");
+        assert_eq!(strip_prefix("Circle", &prefixes), "Circle");
+        assert_eq!(strip_prefix("C", &prefixes), "C");
+        assert_eq!(strip_prefix("tag_bar_t", &prefixes), "bar_t");
+        assert_eq!(strip_prefix("tag_1", &prefixes), "tag_1");
+    }
+
+    #[test]
+    fn snake_case_members() {
+        let mut sess = session(NamingPolicy {
+            snake_case_members: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct Point {
+                int xPos, y;
+                int getHTTPCode() const;
+            };
+            namespace rust_export {
+                using ::Point;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Point;
+            }
+            pub(crate) mod bind {
+                #[repr(C, align(4))]
+                pub struct Point {
+                    #[doc(alias = "xPos")]
+                    pub x_pos: i32,
+                    pub y: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Point>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Point>() == 4);
+                impl Point {
+                    #[doc(alias = "getHTTPCode")]
+                    pub fn get_http_code(&mut self, ) -> i32 {
+                        ::core::ptr::NonNull::from(self).get_http_code()
+                    }
+                }
+                pub trait Point_get_http_code_Ext {
+                    fn get_http_code(self, ) -> i32;
+                }
+                impl Point_get_http_code_Ext for ::core::ptr::NonNull<Point> {
+                    fn get_http_code(self, ) -> i32 {
+                        unsafe { _bind_Point__getHTTPCode(self.as_ptr(), ) }
+                    }
+                }
+                extern "C" {
+                    fn _bind_Point__getHTTPCode(this: *mut Point, ) -> i32;
+                }
+            }
+        "#, r#"
+            extern "C" int _bind_Point__getHTTPCode(::Point* self, ) {
+                return self->::Point::getHTTPCode();
+            }
+        "#);
+    }
+
+    #[test]
+    fn strip_type_prefix() {
+        let mut sess = session(NamingPolicy {
+            strip_type_prefixes: vec!["C".into()],
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct CPoint {
+                int x, y;
+            };
+            struct Circle {
+                CPoint center;
+                int radius;
+            };
+            namespace rust_export {
+                using ::CPoint;
+                using ::Circle;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::Point as CPoint;
+                pub use crate::bind::Circle;
+            }
+            pub(crate) mod bind {
+                #[doc(alias = "CPoint")]
+                #[repr(C, align(4))]
+                pub struct Point {
+                    pub x: i32,
+                    pub y: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Point>() == 8);
+                const _: () = assert!(::core::mem::align_of::<Point>() == 4);
+                #[repr(C, align(4))]
+                pub struct Circle {
+                    pub center: Point,
+                    pub radius: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<Circle>() == 12);
+                const _: () = assert!(::core::mem::align_of::<Circle>() == 4);
+            }
+        "#);
+    }
+
+    #[test]
+    fn camel_case_types() {
+        let mut sess = session(NamingPolicy {
+            camel_case_types: true,
+            ..Default::default()
+        });
+        cpp_to_rs!(sess, {
+            struct tag_bar_t {
+                int a, b;
+            };
+            namespace rust_export {
+                using ::tag_bar_t;
+            }
+        } => r#"
+            pub mod export {
+                pub use crate::bind::TagBarT as tag_bar_t;
+            }
+            pub(crate) mod bind {
+                #[doc(alias = "tag_bar_t")]
+                #[repr(C, align(4))]
+                pub struct TagBarT {
+                    pub a: i32,
+                    pub b: i32,
+                }
+                const _: () = assert!(::core::mem::size_of::<TagBarT>() == 8);
+                const _: () = assert!(::core::mem::align_of::<TagBarT>() == 4);
+            }
+        "#);
+    }
+
+    #[test]
+    fn renamed_fields_collide() {
+        let mut sess = session(NamingPolicy {
+            snake_case_members: true,
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct Pod {
+                int fooBar;
+                int foo_bar;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        } => [
+            "multiple fields named `foo_bar` in the generated bindings"
+        ]);
+    }
+
+    #[test]
+    fn renamed_types_collide() {
+        let mut sess = session(NamingPolicy {
+            strip_type_prefixes: vec!["C".into()],
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct Foo {
+                int a;
+            };
+            struct CFoo {
+                int b;
+            };
+            namespace rust_export {
+                using ::Foo;
+                using ::CFoo;
+            }
+        } => [
+            "multiple items named `Foo` in the generated bindings"
+        ]);
+    }
+
+    #[test]
+    fn hook_must_return_identifier() {
+        let mut sess = Session::with_options(SessionOptions {
+            rename_hook: Some(RenameHook(|ctx| match ctx.kind {
+                NameKind::Field => Some(format!("{}-field", ctx.name)),
+                _ => None,
+            })),
+            ..Default::default()
+        });
+        cpp_lower!(sess, {
+            struct Pod {
+                int a;
+            };
+            namespace rust_export {
+                using ::Pod;
+            }
+        } => [
+            "`a-field` is not a valid Rust identifier"
+        ]);
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Information about the target we're generating bindings for.
+
+use crate::{
+    diagnostics::{err, ok, Diagnostic, Outcome, Severity},
+    Options,
+};
+use std::{mem, sync::Arc};
+
+/// Sizes and alignments of builtin types that vary between targets.
+///
+/// Sizes are in bytes. Types not listed here have the same size on every
+/// supported target, and are aligned to their size.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TargetInfo {
+    pub pointer_size: u64,
+    pub long_size: u64,
+    pub i64_align: u32,
+    pub f64_align: u32,
+}
+
+impl TargetInfo {
+    /// The target that libclang parses for when no triple is given.
+    pub fn host() -> TargetInfo {
+        TargetInfo {
+            pointer_size: mem::size_of::<usize>() as u64,
+            long_size: if cfg!(windows) {
+                4
+            } else {
+                mem::size_of::<usize>() as u64
+            },
+            i64_align: mem::align_of::<u64>() as u32,
+            f64_align: mem::align_of::<f64>() as u32,
+        }
+    }
+
+    /// Looks up the target info for a target triple like
+    /// `armv7-unknown-linux-gnueabihf`. Returns `None` for unknown
+    /// architectures.
+    pub fn from_triple(triple: &str) -> Option<TargetInfo> {
+        let mut components = triple.split('-');
+        let arch = components.next()?;
+        let windows = components.any(|c| c == "windows" || c == "win32");
+
+        let info = match arch {
+            "x86_64" | "aarch64" | "arm64" | "powerpc64" | "powerpc64le" | "mips64"
+            | "mips64el" | "s390x" | "sparc64" | "wasm64" => TargetInfo {
+                pointer_size: 8,
+                long_size: if windows { 4 } else { 8 },
+                i64_align: 8,
+                f64_align: 8,
+            },
+            // The i386 System V ABI only aligns 8-byte scalars to 4 bytes
+            // inside structs. Windows does not.
+            "i386" | "i486" | "i586" | "i686" => TargetInfo {
+                pointer_size: 4,
+                long_size: 4,
+                i64_align: if windows { 8 } else { 4 },
+                f64_align: if windows { 8 } else { 4 },
+            },
+            _ if arch.starts_with("arm")
+                || arch.starts_with("thumb")
+                || arch.starts_with("riscv32") =>
+            {
+                TargetInfo::ilp32()
+            }
+            "wasm32" | "mips" | "mipsel" | "powerpc" | "sparc" => TargetInfo::ilp32(),
+            _ if arch.starts_with("riscv64") => TargetInfo {
+                pointer_size: 8,
+                long_size: 8,
+                i64_align: 8,
+                f64_align: 8,
+            },
+            _ => return None,
+        };
+        Some(info)
+    }
+
+    fn ilp32() -> TargetInfo {
+        TargetInfo {
+            pointer_size: 4,
+            long_size: 4,
+            i64_align: 8,
+            f64_align: 8,
+        }
+    }
+}
+
+/// Returns the target info for the target in the options, or reports an
+/// error and falls back to the host for a triple that isn't known.
+pub(crate) fn target_info(db: &impl Options) -> Outcome<Arc<TargetInfo>> {
+    match &db.options().target {
+        Some(triple) => match TargetInfo::from_triple(triple) {
+            Some(info) => ok(Arc::new(info)),
+            None => err(
+                Arc::new(TargetInfo::host()),
+                Diagnostic::new(Severity::Error, format!("unsupported target `{}`", triple)),
+            ),
+        },
+        None => ok(Arc::new(TargetInfo::host())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triples() {
+        let x86_64 = TargetInfo::from_triple("x86_64-unknown-linux-gnu").unwrap();
+        assert_eq!(x86_64.pointer_size, 8);
+        assert_eq!(x86_64.long_size, 8);
+        assert_eq!(x86_64.f64_align, 8);
+
+        let win64 = TargetInfo::from_triple("x86_64-pc-windows-msvc").unwrap();
+        assert_eq!(win64.long_size, 4);
+
+        let i686 = TargetInfo::from_triple("i686-unknown-linux-gnu").unwrap();
+        assert_eq!(i686.pointer_size, 4);
+        assert_eq!(i686.f64_align, 4);
+
+        let armv7 = TargetInfo::from_triple("armv7-unknown-linux-gnueabihf").unwrap();
+        assert_eq!(armv7.pointer_size, 4);
+        assert_eq!(armv7.f64_align, 8);
+
+        assert_eq!(
+            TargetInfo::from_triple("wasm32-unknown-unknown"),
+            Some(armv7)
+        );
+        assert_eq!(TargetInfo::from_triple("m68k-unknown-linux-gnu"), None);
+    }
+}
@@ -6,22 +6,95 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::{codegen, diagnostics::Outcome, ir, libclang, Session};
+use crate::{
+    codegen,
+    diagnostics::{db::SourceFileCache, Diagnostics, Severity},
+    ir, libclang, Session, SessionOptions,
+};
 use clang::{self, TranslationUnit, Unsaved};
 use pretty_assertions::assert_eq;
 use std::fmt;
 use std::path::Path;
 
 macro_rules! cpp_parse {
-    { $clang:expr, $src:tt } => { $crate::test_util::parse($clang, stringify!($src)) }
+    { $clang:expr, $src:tt } => {
+        $crate::test_util::parse($clang, stringify!($src), &Default::default())
+    }
 }
 
+/// Parses and lowers a C++ source, checking the diagnostics it produces.
+///
+/// Expected diagnostics are given by message, either as a single list of
+/// errors or as separate `errors: [..], warnings: [..]` lists.
 macro_rules! cpp_lower {
+    { $sess:expr, $src:tt => errors: [ $( $errs:expr ),* ], warnings: [ $( $warns:expr ),* ] } => {
+        $crate::test_util::parse_and_lower(
+            &mut $sess,
+            stringify!($src),
+            $crate::test_util::Expected { errors: vec![$($errs),*], warnings: vec![$($warns),*] },
+        )
+    };
     { $sess:expr, $src:tt => [ $( $errs:expr ),* ] } => {
-        $crate::test_util::parse_and_lower(&mut $sess, stringify!($src), vec![$($errs),*])
+        cpp_lower!($sess, $src => errors: [$($errs),*], warnings: [])
     };
     { $sess:expr, $src:tt } => {
-        $crate::test_util::parse_and_lower(&mut $sess, stringify!($src), vec![])
+        cpp_lower!($sess, $src => errors: [], warnings: [])
+    };
+}
+
+/// Like `cpp_lower!`, but parses each source as a separate module.
+macro_rules! cpp_lower_modules {
+    {
+        $sess:expr, [ $( $src:tt ),* ] =>
+            errors: [ $( $errs:expr ),* ], warnings: [ $( $warns:expr ),* ]
+    } => {
+        $crate::test_util::parse_and_lower_modules(
+            &mut $sess,
+            &[$(stringify!($src)),*],
+            $crate::test_util::Expected { errors: vec![$($errs),*], warnings: vec![$($warns),*] },
+        )
+    };
+    { $sess:expr, [ $( $src:tt ),* ] => [ $( $errs:expr ),* ] } => {
+        cpp_lower_modules!($sess, [$($src),*] => errors: [$($errs),*], warnings: [])
+    };
+    { $sess:expr, [ $( $src:tt ),* ] } => {
+        cpp_lower_modules!($sess, [$($src),*] => errors: [], warnings: [])
+    };
+}
+
+/// Like `cpp_lower!`, but for a module made of several files, each named
+/// relative to the directory test sources are in.
+///
+/// The last file is parsed and can include the others. Sources that need
+/// preprocessor directives can be given as string literals, since those need
+/// to be on lines of their own.
+macro_rules! cpp_lower_files {
+    {
+        $sess:expr, { $( $name:literal => $src:tt ),* } =>
+            errors: [ $( $errs:expr ),* ], warnings: [ $( $warns:expr ),* ]
+    } => {
+        $crate::test_util::parse_and_lower_files(
+            &mut $sess,
+            &[$(($name, test_source!($src))),*],
+            $crate::test_util::Expected { errors: vec![$($errs),*], warnings: vec![$($warns),*] },
+        )
+    };
+    { $sess:expr, { $( $name:literal => $src:tt ),* } => [ $( $errs:expr ),* ] } => {
+        cpp_lower_files!($sess, { $($name => $src),* } => errors: [$($errs),*], warnings: [])
+    };
+    { $sess:expr, { $( $name:literal => $src:tt ),* } } => {
+        cpp_lower_files!($sess, { $($name => $src),* } => errors: [], warnings: [])
+    };
+}
+
+/// Turns a test source, either a `{...}` block or a string literal, into the
+/// form `cpp_lower!` passes around.
+macro_rules! test_source {
+    ({ $($src:tt)* }) => {
+        stringify!({ $($src)* })
+    };
+    ($src:literal) => {
+        concat!("{", $src, "}")
     };
 }
 
@@ -35,6 +108,20 @@ macro_rules! cpp_to_rs {
     };
 }
 
+/// Like `cpp_lower!`, but also checks the generated Rust code against
+/// `$rs_out`, and returns the lowered bindings for further checks.
+macro_rules! cpp_codegen {
+    { $sess:expr, $src:tt => $rs_out:expr } => {
+        $crate::test_util::lower_and_check_codegen(&mut $sess, stringify!($src), $rs_out)
+    };
+}
+
+/// The path test sources are parsed as.
+pub(crate) const TEST_FILENAME: &str = "__test__/test.cc";
+
+/// The directory test sources are in.
+const TEST_DIR: &str = "__test__";
+
 fn strip_tt(src: &str) -> &str {
     assert!(src.starts_with('{'));
     assert!(src.ends_with('}'));
@@ -56,10 +143,14 @@ fn strip_indent(src: &str) -> String {
     out
 }
 
-pub(crate) fn parse<'c>(index: &'c clang::Index, src: &str) -> TranslationUnit<'c> {
+pub(crate) fn parse<'c>(
+    index: &'c clang::Index,
+    src: &str,
+    opts: &SessionOptions,
+) -> TranslationUnit<'c> {
     let src = strip_tt(src);
-    let test_filename = Path::new("__test__/test.cc");
-    let mut parser = libclang::configure(index.parser(&test_filename));
+    let test_filename = Path::new(TEST_FILENAME);
+    let mut parser = libclang::configure(index.parser(&test_filename), opts);
     let unsaved = Unsaved::new(&test_filename, src);
     parser
         .unsaved(&[unsaved])
@@ -67,38 +158,168 @@ pub(crate) fn parse<'c>(index: &'c clang::Index, src: &str) -> TranslationUnit<'
         .expect("test input failed to parse")
 }
 
+/// The diagnostics a test expects lowering to produce, by message.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct Expected<'a> {
+    /// Errors, including bugs.
+    pub(crate) errors: Vec<&'a str>,
+    pub(crate) warnings: Vec<&'a str>,
+}
+
 pub(crate) fn parse_and_lower(
     sess: &mut Session,
     src: &str,
-    expected: Vec<&str>,
+    expected: Expected<'_>,
 ) -> ir::rs::BindingsCrate {
+    parse_and_lower_modules(sess, &[src], expected)
+}
+
+pub(crate) fn parse_and_lower_modules(
+    sess: &mut Session,
+    srcs: &[&str],
+    expected: Expected<'_>,
+) -> ir::rs::BindingsCrate {
+    let (rust_ir, errs) = lower_modules_with_diagnostics(sess, srcs);
+    check_diagnostics(sess, &errs, expected);
+    rust_ir
+}
+
+pub(crate) fn parse_and_lower_files(
+    sess: &mut Session,
+    files: &[(&str, &str)],
+    expected: Expected<'_>,
+) -> ir::rs::BindingsCrate {
+    let (rust_ir, errs) = lower_files(sess, files);
+    check_diagnostics(sess, &errs, expected);
+    rust_ir
+}
+
+fn check_diagnostics(sess: &Session, errs: &Diagnostics, expected: Expected<'_>) {
+    let mut actual = Expected::default();
+    for diag in errs.iter() {
+        match diag.severity() {
+            Severity::Warning => actual.warnings.push(diag.message()),
+            _ => actual.errors.push(diag.message()),
+        }
+    }
+    assert_eq!(
+        expected,
+        actual,
+        "did not get the expected set of lowering diagnostics:\n{}",
+        describe_diagnostics(&sess.db, errs)
+    );
+}
+
+/// Lists `errs` one per line, with the severity and location of each.
+fn describe_diagnostics(db: &impl SourceFileCache, errs: &Diagnostics) -> String {
+    let mut out = String::new();
+    for diag in errs.iter() {
+        let severity = match diag.severity() {
+            Severity::Bug => "bug",
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+            Severity::Help => "help",
+        };
+        out += &format!("    {}: {}", severity, diag.message());
+        if let Some(span) = diag.primary_span() {
+            let (line, col) = span.start_position(db);
+            out += &format!(" at {}:{}:{}", span.file_id().name(db), line, col);
+        }
+        out += "\n";
+    }
+    out
+}
+
+/// Lowers `srcs`, returning the bindings and the messages of any lowering
+/// errors.
+pub(crate) fn lower_modules(
+    sess: &mut Session,
+    srcs: &[&str],
+) -> (ir::rs::BindingsCrate, Vec<String>) {
+    let (rust_ir, errs) = lower_modules_with_diagnostics(sess, srcs);
+    let errs = errs.iter().map(|diag| diag.message().to_string()).collect();
+    (rust_ir, errs)
+}
+
+fn lower_modules_with_diagnostics(
+    sess: &mut Session,
+    srcs: &[&str],
+) -> (ir::rs::BindingsCrate, Diagnostics) {
     assert!(!sess.diags.has_errors()); // TODO has_diags()
 
     let index = libclang::create_index();
-    let module_id = libclang::ModuleId::new(0);
-    let (ast, errs) = libclang::parse_with(&sess.db, &index, module_id, |index| parse(index, src));
+    let (asts, parse_errs): (Vec<_>, Vec<_>) = srcs
+        .iter()
+        .enumerate()
+        .map(|(idx, src)| {
+            let filename = match srcs.len() {
+                1 => TEST_FILENAME.to_string(),
+                _ => format!("__test__/test{}.cc", idx),
+            };
+            libclang::parse_with(
+                &sess.db,
+                &index,
+                libclang::ModuleId::new(idx as u32),
+                Path::new(&filename),
+                Some(strip_tt(src).to_string()),
+            )
+            .unwrap()
+        })
+        .unzip();
+    lower_parsed(sess, asts, parse_errs)
+}
+
+/// Lowers a module made of `files`, each a name and a source, returning the
+/// bindings and any diagnostics.
+///
+/// The last file is parsed. The others are set as file overrides, so they can
+/// be included without existing on disk.
+pub(crate) fn lower_files(
+    sess: &mut Session,
+    files: &[(&str, &str)],
+) -> (ir::rs::BindingsCrate, Diagnostics) {
+    assert!(!sess.diags.has_errors()); // TODO has_diags()
+
+    let ((main, main_src), headers) = files.split_last().expect("no test files given");
+    for (name, src) in headers {
+        let path = Path::new(TEST_DIR).join(name);
+        sess.db
+            .set_file_contents(path, Some(strip_tt(src).to_string()));
+    }
+    let (ast, parse_errs) = libclang::parse_with_overrides(
+        &sess.db,
+        &libclang::create_index(),
+        libclang::ModuleId::new(0),
+        &Path::new(TEST_DIR).join(main),
+        Some(strip_tt(main_src).to_string()),
+    )
+    .unwrap();
+    lower_parsed(sess, vec![ast], vec![parse_errs])
+}
+
+fn lower_parsed(
+    sess: &mut Session,
+    asts: Vec<libclang::ModuleContext>,
+    parse_errs: Vec<libclang::ParseErrors>,
+) -> (ir::rs::BindingsCrate, Diagnostics) {
     use crate::cc_use::RsSource;
     sess.db.set_rs_source_root(None);
-    let (rust_ir, errs) = libclang::set_ast(&mut sess.db, vec![ast], |db| {
-        use ir::rs::RsTargetBindings;
-        Outcome::from_parts((), errs.to_diagnostics(db)).then(|_| Outcome::clone(&db.rs_bindings()))
+    let (rust_ir, errs) = libclang::set_ast(&mut sess.db, asts, |db| {
+        crate::lower_bindings(db, parse_errs)
     })
     .split();
-    assert_eq!(
-        expected,
-        errs.iter().map(|diag| diag.message()).collect::<Vec<_>>(),
-        "did not get the expected set of lowering errors"
-    );
-    rust_ir.clone()
+    (rust_ir.clone(), errs)
 }
 
-pub(crate) fn check_codegen(
-    sess: &mut Session,
-    src: &str,
-    rs_expected: &str,
-    cc_expected: Option<&str>,
-) {
-    let rs_module = parse_and_lower(sess, src, vec![]);
+/// Lowers `src` and returns the generated Rust and C++ code.
+pub(crate) fn gen_code(sess: &mut Session, src: &str) -> (String, String) {
+    let rs_module = parse_and_lower(sess, src, Expected::default());
+    codegen(sess, &rs_module)
+}
+
+/// Generates code for `rs_module`, and checks that the Rust code parses.
+pub(crate) fn codegen(sess: &Session, rs_module: &ir::rs::BindingsCrate) -> (String, String) {
     let mut rs_out = vec![];
     let mut cc_out = vec![];
     let mut rs_writer = codegen::CodeWriter::new(&mut rs_out);
@@ -113,23 +334,50 @@ pub(crate) fn check_codegen(
         is_system: false,
         span: None,
     };
-    codegen::perform_codegen(&sess.db, &rs_module, &[header], true, outputs)
+    codegen::perform_codegen(&sess.db, rs_module, &[header], true, outputs)
         .expect("Codegen failed");
+    let to_string = |out| String::from_utf8(out).expect("Generated code is not UTF-8");
+    let (rs_out, cc_out) = (to_string(rs_out), to_string(cc_out));
+    if let Err(err) = syn::parse_file(&rs_out) {
+        panic!("Generated Rust does not parse: {}\n{}", err, rs_out);
+    }
+    (rs_out, cc_out)
+}
 
-    let check = |lang, out, expected| {
-        let output = String::from_utf8(out).expect("Generated code is not UTF-8");
-        let output = output.trim_matches('\n');
-        let expected = strip_indent(expected);
-        let expected = expected.trim_matches('\n');
-        assert_eq!(
-            MultilineStr(&expected),
-            MultilineStr(&output),
-            "Generated {} did not match",
-            lang
-        );
-    };
-    check("Rust", rs_out, rs_expected);
-    check("C++", cc_out, cc_expected.unwrap_or(""));
+pub(crate) fn check_codegen(
+    sess: &mut Session,
+    src: &str,
+    rs_expected: &str,
+    cc_expected: Option<&str>,
+) {
+    let (rs_out, cc_out) = gen_code(sess, src);
+    check_output("Rust", &rs_out, rs_expected);
+    check_output("C++", &cc_out, cc_expected.unwrap_or(""));
+}
+
+pub(crate) fn lower_and_check_codegen(
+    sess: &mut Session,
+    src: &str,
+    rs_expected: &str,
+) -> ir::rs::BindingsCrate {
+    let rs_module = parse_and_lower(sess, src, Expected::default());
+    let (rs_out, _) = codegen(sess, &rs_module);
+    check_output("Rust", &rs_out, rs_expected);
+    rs_module
+}
+
+/// Compares generated code to the expected code, ignoring leading and
+/// trailing blank lines and the common indentation of `expected`.
+pub(crate) fn check_output(lang: &str, output: &str, expected: &str) {
+    let output = output.trim_matches('\n');
+    let expected = strip_indent(expected);
+    let expected = expected.trim_matches('\n');
+    assert_eq!(
+        MultilineStr(expected),
+        MultilineStr(output),
+        "Generated {} did not match",
+        lang
+    );
 }
 
 #[derive(Eq, PartialEq)]
@@ -139,3 +387,49 @@ impl fmt::Debug for MultilineStr<'_> {
         write!(f, "{}", self.0)
     }
 }
+
+/// Builds C++ test sources programmatically, for tests that generate their
+/// inputs. The output can be passed anywhere a stringified `cpp_lower!`
+/// source is expected.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SourceBuilder {
+    src: String,
+    exports: Vec<String>,
+}
+
+impl SourceBuilder {
+    /// Adds a struct with the given fields, each a `(type, name)` pair.
+    pub(crate) fn add_struct<'a>(
+        &mut self,
+        name: &str,
+        align: Option<u32>,
+        fields: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> &mut Self {
+        self.src += "struct ";
+        if let Some(align) = align {
+            self.src += &format!("alignas({}) ", align);
+        }
+        self.src += name;
+        self.src += " {\n";
+        for (ty, field) in fields {
+            self.src += &format!("    {} {};\n", ty, field);
+        }
+        self.src += "};\n";
+        self
+    }
+
+    /// Exports `name` from the `rust_export` namespace.
+    pub(crate) fn export(&mut self, name: &str) -> &mut Self {
+        self.exports.push(name.to_string());
+        self
+    }
+
+    pub(crate) fn build(&self) -> String {
+        let mut out = format!("{{\n{}namespace rust_export {{\n", self.src);
+        for name in &self.exports {
+            out += &format!("    using ::{};\n", name);
+        }
+        out += "}\n}";
+        out
+    }
+}
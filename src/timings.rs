@@ -0,0 +1,222 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Records where the time of a session goes, for `--timings`.
+//!
+//! Each phase is timed where it starts, and the time is added up over every
+//! call. Lowering runs on several threads with `--jobs`, so the time of a
+//! phase is the sum over all threads, and can be more than the wall time of
+//! the whole run.
+
+use std::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Gives queries access to the metrics of the session.
+pub trait Instrumented {
+    fn metrics(&self) -> &Metrics;
+}
+
+/// A part of generating bindings that is timed on its own.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Phase {
+    /// Parsing headers with libclang.
+    Parse,
+    /// Finding the exported items in the parsed headers.
+    Exports,
+    /// Lowering C++ structs to the source IR.
+    Lower,
+    /// Computing the Rust layout of structs and checking it against C++.
+    Layout,
+    /// Writing the Rust and C++ code.
+    Codegen,
+}
+const PHASES: usize = 5;
+
+/// Something counted during a session.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Counter {
+    StructsLowered,
+    FunctionsLowered,
+    /// Queries executed instead of answered from the database.
+    QueriesExecuted,
+}
+const COUNTERS: usize = 3;
+
+thread_local! {
+    /// The phases being timed on this thread, one bit each.
+    static ACTIVE: Cell<u8> = const { Cell::new(0) };
+}
+
+/// Timings and counts shared by a database and its snapshots.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    nanos: [AtomicU64; PHASES],
+    counts: [AtomicU64; COUNTERS],
+}
+
+impl Metrics {
+    /// Runs `f`, adding the time it takes to `phase`.
+    ///
+    /// Lowering a struct can lower the structs it uses, so a phase that is
+    /// already being timed on this thread is only counted once.
+    pub(crate) fn time<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let bit = 1 << phase as u8;
+        if ACTIVE.with(|active| active.get() & bit != 0) {
+            return f();
+        }
+        ACTIVE.with(|active| active.set(active.get() | bit));
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed().as_nanos() as u64;
+        ACTIVE.with(|active| active.set(active.get() & !bit));
+        self.nanos[phase as usize].fetch_add(elapsed, Ordering::Relaxed);
+        result
+    }
+
+    pub(crate) fn count(&self, counter: Counter) {
+        self.counts[counter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn duration(&self, phase: Phase) -> Duration {
+        Duration::from_nanos(self.nanos[phase as usize].load(Ordering::Relaxed))
+    }
+
+    fn get(&self, counter: Counter) -> u64 {
+        self.counts[counter as usize].load(Ordering::Relaxed)
+    }
+
+    /// Takes a snapshot of the metrics, with the number of diagnostics of
+    /// each severity reported so far.
+    pub(crate) fn report(&self, errors: u32, warnings: u32, notes: u32) -> TimingReport {
+        TimingReport {
+            parse: self.duration(Phase::Parse),
+            exports: self.duration(Phase::Exports),
+            lower: self.duration(Phase::Lower),
+            layout: self.duration(Phase::Layout),
+            codegen: self.duration(Phase::Codegen),
+            structs_lowered: self.get(Counter::StructsLowered),
+            functions_lowered: self.get(Counter::FunctionsLowered),
+            queries_executed: self.get(Counter::QueriesExecuted),
+            errors,
+            warnings,
+            notes,
+        }
+    }
+}
+
+/// How long each phase of a [`Session`](crate::Session) took, and how much
+/// it did, since the session was created.
+///
+/// Printed as a table with `Display`, or as JSON with
+/// [`TimingReport::to_json`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TimingReport {
+    /// Parsing headers.
+    pub parse: Duration,
+    /// Finding the exported items.
+    pub exports: Duration,
+    /// Lowering structs, summed over every struct.
+    pub lower: Duration,
+    /// Computing and checking struct layouts.
+    pub layout: Duration,
+    /// Writing the bindings.
+    pub codegen: Duration,
+    pub structs_lowered: u64,
+    /// Free functions and methods.
+    pub functions_lowered: u64,
+    /// Salsa queries that were executed, rather than reused.
+    pub queries_executed: u64,
+    pub errors: u32,
+    pub warnings: u32,
+    pub notes: u32,
+}
+
+impl TimingReport {
+    fn phases(&self) -> [(&'static str, Duration); PHASES] {
+        [
+            ("parse", self.parse),
+            ("exports", self.exports),
+            ("lower", self.lower),
+            ("layout", self.layout),
+            ("codegen", self.codegen),
+        ]
+    }
+
+    /// Writes the report as a JSON object, with the time of each phase in
+    /// seconds:
+    ///
+    /// ```json
+    /// {
+    ///   "phases": { "parse": 0.0125, "exports": 0.0003, ... },
+    ///   "counts": { "structs_lowered": 2, "functions_lowered": 5, "queries_executed": 140 },
+    ///   "diagnostics": { "errors": 0, "warnings": 1, "notes": 0 }
+    /// }
+    /// ```
+    pub fn to_json(&self) -> String {
+        let phases: serde_json::Map<_, _> = self
+            .phases()
+            .iter()
+            .map(|(name, time)| (name.to_string(), time.as_secs_f64().into()))
+            .collect();
+        let doc = serde_json::json!({
+            "phases": phases,
+            "counts": {
+                "structs_lowered": self.structs_lowered,
+                "functions_lowered": self.functions_lowered,
+                "queries_executed": self.queries_executed,
+            },
+            "diagnostics": {
+                "errors": self.errors,
+                "warnings": self.warnings,
+                "notes": self.notes,
+            },
+        });
+        serde_json::to_string_pretty(&doc).unwrap()
+    }
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, time) in self.phases().iter() {
+            writeln!(f, "{:<18} {:>10.3}ms", name, time.as_secs_f64() * 1000.0)?;
+        }
+        let counts = [
+            ("structs lowered", self.structs_lowered),
+            ("functions lowered", self.functions_lowered),
+            ("queries executed", self.queries_executed),
+            ("errors", self.errors.into()),
+            ("warnings", self.warnings.into()),
+            ("notes", self.notes.into()),
+        ];
+        for (name, count) in counts.iter() {
+            writeln!(f, "{:<18} {:>12}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// How `--timings` prints the report.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TimingsFormat {
+    Table,
+    Json,
+}
+impl std::str::FromStr for TimingsFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(TimingsFormat::Table),
+            "json" => Ok(TimingsFormat::Json),
+            _ => Err(format!("invalid timings format `{}`", s)),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs each `.rs` and `.cc` file in `test/ui` through the bindings generator,
+//! and compares the diagnostics to the `.stderr` file next to it.
+//!
+//! Headers used by the tests go in `test/ui/fixtures`, which is on the include
+//! path. Run with `MOSAIC_BLESS=1` to update the `.stderr` files instead.
+
+use crate::Session;
+use itertools::Itertools;
+use std::{env, fs, io, path::Path};
+
+/// Generates bindings for `path` and returns the rendered diagnostics.
+fn run(path: &Path, fixtures: &Path) -> String {
+    let mut sess = Session::builder()
+        .include_dir(fixtures)
+        .capture_diagnostics()
+        .build();
+    if path.extension() == Some("rs".as_ref()) {
+        sess.parse_rust(path).expect("failed to read test input");
+    } else {
+        sess.parse_header(path).expect("failed to read test input");
+    }
+    sess.generate(io::sink(), io::sink());
+    sess.diagnostics().unwrap()
+}
+
+/// Replaces the test directory in `output` with `$DIR`, so expectations don't
+/// depend on where the repository is checked out.
+fn normalize(output: &str, ui_dir: &str) -> String {
+    output
+        .lines()
+        .map(|line| {
+            // Files are shown by name only, since headers can come from
+            // anywhere on the include path.
+            const PAT: &str = "┌─ ";
+            if line.trim_start().starts_with(PAT) {
+                let cut_start = line.find(PAT).unwrap() + PAT.len();
+                if let Some(cut_end) = line.rfind(&['/', '\\'][..]) {
+                    return String::from(&line[..cut_start]) + "$DIR/" + &line[cut_end + 1..];
+                }
+            }
+            line.replace(ui_dir, "$DIR").trim_end().to_string()
+        })
+        .join("\n")
+}
+
+#[test]
+fn ui() {
+    let ui_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test")
+        .join("ui");
+    let fixtures = ui_dir.join("fixtures");
+    let bless = env::var_os("MOSAIC_BLESS").is_some();
+
+    let mut tests: Vec<_> = fs::read_dir(&ui_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("rs") | Some("cc")
+            )
+        })
+        .collect();
+    tests.sort();
+    assert!(!tests.is_empty(), "no tests found in {}", ui_dir.display());
+
+    let mut failures = vec![];
+    for test in &tests {
+        let actual = normalize(&run(test, &fixtures), &ui_dir.to_string_lossy());
+        let stderr_path = test.with_extension("stderr");
+        if bless {
+            fs::write(&stderr_path, actual + "\n").unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&stderr_path).unwrap_or_default();
+        if expected.trim_end() != actual.trim_end() {
+            failures.push((test, expected, actual));
+        }
+    }
+
+    for (test, expected, actual) in &failures {
+        eprintln!("---- {} ----", test.display());
+        eprintln!("expected:\n{}\n", expected.trim_end());
+        eprintln!("actual:\n{}\n", actual.trim_end());
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} ui tests failed; rerun with MOSAIC_BLESS=1 to update the expected output",
+        failures.len(),
+        tests.len()
+    );
+}
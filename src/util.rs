@@ -7,6 +7,7 @@
 // except according to those terms.
 
 use clang::{Entity, Type};
+use std::fmt::{self, Display, Formatter};
 
 macro_rules! intern_key {
     ($vis:vis $name:ident) => {
@@ -33,6 +34,14 @@ macro_rules! intern_key {
 
 pub(crate) const INDENT_SPACES: usize = 2;
 
+/// Adapts a function that prints something to `Display`.
+pub(crate) struct DisplayFn<F>(pub F);
+impl<F: Fn(&mut Formatter<'_>) -> fmt::Result> Display for DisplayFn<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        (self.0)(f)
+    }
+}
+
 macro_rules! print_indent {
     ($self: expr, $fmt:expr) => { print_indent!($self, $fmt,) };
     ($self: expr, $fmt:expr, $($arg:expr),*) => {
@@ -0,0 +1,334 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks the struct layouts in the bindings against a C++ compiler, for
+//! `--verify`.
+//!
+//! Lowering already compares our layouts with libclang's, but the compiler
+//! that builds the shims may be given different flags. Here each size,
+//! alignment and field offset the bindings assume becomes a `static_assert`
+//! in a C++ file, which is compiled on its own. Assertions that fail are
+//! reported at the struct they came from.
+
+use crate::{
+    diagnostics::{Diagnostic, Diagnostics, Severity, Span},
+    ir::{bindings, cc::RsTargetIr, rs},
+};
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// How to check the layouts, as set up by the command line tool.
+pub(crate) struct Verify {
+    /// Where to write the assertions.
+    pub(crate) path: PathBuf,
+    /// The compiler to check them with, if one was found. It's run like
+    /// `clang++` or `g++`.
+    pub(crate) compiler: Option<Command>,
+    /// Directories to search for the included headers.
+    pub(crate) include_dirs: Vec<PathBuf>,
+}
+
+/// Writes the layout assertions for `krate` and compiles them, if there is a
+/// compiler. Returns the failed assertions as errors.
+pub(crate) fn run(
+    db: &impl RsTargetIr,
+    krate: &rs::BindingsCrate,
+    headers: &[bindings::Header],
+    verify: Verify,
+) -> Diagnostics {
+    let asserts = LayoutAsserts::new(db, krate, headers);
+    if let Err(err) = crate::write_if_changed(&verify.path, asserts.text.as_bytes()) {
+        return Diagnostics::build(|errs| {
+            errs.add(Diagnostic::new(
+                Severity::Error,
+                format!("cannot write {}: {}", verify.path.display(), err),
+            ))
+        });
+    }
+    match verify.compiler {
+        Some(compiler) => asserts.check(compiler, &verify.path, &verify.include_dirs),
+        None => Diagnostics::build(|errs| {
+            errs.add(
+                Diagnostic::new(Severity::Warning, "no C++ compiler found to verify layouts")
+                    .with_note(format!(
+                        "the assertions were written to {}",
+                        verify.path.display()
+                    ))
+                    .with_note("pass `--cxx` to choose a compiler"),
+            )
+        }),
+    }
+}
+
+/// A C++ file asserting the layout of every struct in the bindings.
+pub(crate) struct LayoutAsserts {
+    pub(crate) text: String,
+    /// The number of lines in `text`.
+    lines: usize,
+    asserts: Vec<Assert>,
+}
+
+/// One `static_assert`, and where to report it if it fails.
+struct Assert {
+    /// The line of the file it's on, starting at 1.
+    line: usize,
+    /// The C++ path of the struct it checks.
+    path: String,
+    /// What it checks, like `size` or ``offset of `x` ``.
+    what: String,
+    expected: u64,
+    span: Span,
+}
+
+impl LayoutAsserts {
+    pub(crate) fn new(
+        db: &impl RsTargetIr,
+        krate: &rs::BindingsCrate,
+        headers: &[bindings::Header],
+    ) -> LayoutAsserts {
+        let mut text = String::new();
+        writeln!(
+            text,
+            "// Checks the struct layouts assumed by the bindings."
+        )
+        .unwrap();
+        writeln!(text, "#include <cstddef>").unwrap();
+        for hdr in headers {
+            match hdr.is_system {
+                true => writeln!(text, "#include <{}>", hdr.path).unwrap(),
+                false => writeln!(text, "#include \"{}\"", hdr.path).unwrap(),
+            }
+        }
+        let mut asserts = LayoutAsserts {
+            lines: text.lines().count(),
+            text,
+            asserts: vec![],
+        };
+        let mut structs = vec![];
+        collect_structs(db, krate.root, &mut structs);
+        for id in structs {
+            asserts.add_struct(db, &id.lookup(db));
+        }
+        asserts
+    }
+
+    fn add_struct(&mut self, db: &impl RsTargetIr, st: &rs::Struct) {
+        let path = st.cc_id.lookup(db).path(db).display(db).to_string();
+        self.text.push('\n');
+        self.lines += 1;
+        let layout = &st.layout;
+        self.add(
+            format!("sizeof({})", path),
+            &path,
+            "size".to_string(),
+            layout.size.get(),
+            &st.span,
+        );
+        self.add(
+            format!("alignof({})", path),
+            &path,
+            "alignment".to_string(),
+            layout.align.get() as u64,
+            &st.span,
+        );
+        for (field, &offset) in st.fields.iter().zip(&layout.field_offsets) {
+            self.add(
+                format!("offsetof({}, {})", path, field.cc_name.as_str(db)),
+                &path,
+                format!("offset of `{}`", field.cc_name.as_str(db)),
+                offset,
+                &field.span,
+            );
+        }
+    }
+
+    fn add(&mut self, expr: String, path: &str, what: String, expected: u64, span: &Span) {
+        writeln!(
+            self.text,
+            "static_assert({} == {}, \"{} of {}\");",
+            expr, expected, what, path
+        )
+        .unwrap();
+        self.lines += 1;
+        self.asserts.push(Assert {
+            line: self.lines,
+            path: path.to_string(),
+            what,
+            expected,
+            span: span.clone(),
+        });
+    }
+
+    /// Compiles the assertions written to `path`, and returns the ones that
+    /// failed.
+    fn check(&self, mut compiler: Command, path: &Path, include_dirs: &[PathBuf]) -> Diagnostics {
+        compiler.arg("-std=c++17").arg("-fsyntax-only");
+        for dir in include_dirs {
+            compiler.arg("-I").arg(dir);
+        }
+        compiler.arg(path);
+        let output = match compiler.output() {
+            Ok(output) => output,
+            Err(err) => {
+                return Diagnostics::build(|errs| {
+                    errs.add(Diagnostic::new(
+                        Severity::Error,
+                        format!("cannot run the C++ compiler: {}", err),
+                    ))
+                })
+            }
+        };
+        if output.status.success() {
+            return Diagnostics::new();
+        }
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut errs = self.failures(&file_name, &format!("{}{}", stderr, stdout));
+        if !errs.has_errors() {
+            // Something other than an assertion failed, like a missing
+            // header.
+            errs.add(
+                Diagnostic::new(Severity::Error, "layout assertions failed to compile")
+                    .with_notes(stderr.lines().take(10).map(str::to_string)),
+            );
+        }
+        errs
+    }
+
+    /// Maps the errors a compiler reported for the file named `file_name` to
+    /// the assertions on those lines.
+    pub(crate) fn failures(&self, file_name: &str, output: &str) -> Diagnostics {
+        let mut failed: Vec<(&Assert, &str)> = vec![];
+        for msg in output.lines().filter(|msg| msg.contains("error")) {
+            let line = match error_line(msg, file_name) {
+                Some(line) => line,
+                None => continue,
+            };
+            // Compilers may report a failed assertion more than once.
+            if failed.iter().any(|(assert, _)| assert.line == line) {
+                continue;
+            }
+            if let Some(assert) = self.asserts.iter().find(|assert| assert.line == line) {
+                failed.push((assert, msg));
+            }
+        }
+        Diagnostics::build(|errs| {
+            for (assert, msg) in failed {
+                errs.add(
+                    Diagnostic::error(
+                        format!(
+                            "the {} of `{}` doesn't match the C++ compiler",
+                            assert.what, assert.path
+                        ),
+                        assert
+                            .span
+                            .label(format!("the bindings assume {}", assert.expected)),
+                    )
+                    .with_note(msg.trim().to_string()),
+                );
+            }
+        })
+    }
+}
+
+/// Returns the structs in `id` and its submodules, in order.
+///
+/// Modules for config sets are left out, since their layouts depend on
+/// definitions the assertions aren't compiled with.
+fn collect_structs(db: &impl RsTargetIr, id: rs::ModuleId, structs: &mut Vec<rs::StructId>) {
+    for item in id.lookup(db).children {
+        match item {
+            rs::ItemKind::Module(id) if id.lookup(db).feature.is_some() => {}
+            rs::ItemKind::Module(id) => collect_structs(db, id, structs),
+            rs::ItemKind::Struct(id) => structs.push(id),
+            rs::ItemKind::Reexport(..) | rs::ItemKind::Opaque(_) | rs::ItemKind::Alias(_) => {}
+        }
+    }
+}
+
+/// Returns the line number of a compiler message about `file_name`, written
+/// as `file:line:col:` by Clang and GCC, or `file(line):` by MSVC.
+fn error_line(msg: &str, file_name: &str) -> Option<usize> {
+    let rest = &msg[msg.find(file_name)? + file_name.len()..];
+    let digits = rest.strip_prefix(':').or_else(|| rest.strip_prefix('('))?;
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    digits[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    fn asserts(sess: &mut Session, src: &str) -> LayoutAsserts {
+        let ir = crate::test_util::parse_and_lower(sess, src, Default::default());
+        let header = bindings::Header {
+            path: "test.h".to_string(),
+            is_system: false,
+            span: None,
+        };
+        LayoutAsserts::new(&sess.db, &ir, &[header])
+    }
+
+    const POD: &str = stringify!({
+        struct Pod {
+            char a;
+            double b;
+        };
+        namespace rust_export {
+            using ::Pod;
+        }
+    });
+
+    #[test]
+    fn assertion_text() {
+        let mut sess = Session::test();
+        let asserts = asserts(&mut sess, POD);
+        assert_eq!(
+            asserts.text,
+            "// Checks the struct layouts assumed by the bindings.\n\
+             #include <cstddef>\n\
+             #include \"test.h\"\n\
+             \n\
+             static_assert(sizeof(::Pod) == 16, \"size of ::Pod\");\n\
+             static_assert(alignof(::Pod) == 8, \"alignment of ::Pod\");\n\
+             static_assert(offsetof(::Pod, a) == 0, \"offset of `a` of ::Pod\");\n\
+             static_assert(offsetof(::Pod, b) == 8, \"offset of `b` of ::Pod\");\n"
+        );
+    }
+
+    #[test]
+    fn failure_mapped_to_field() {
+        let mut sess = Session::test();
+        let asserts = asserts(&mut sess, POD);
+        let output = "\
+            In file included from pod_bind.layout.cc:3:\n\
+            pod_bind.layout.cc:8:1: error: static assertion failed due to requirement \
+            'offsetof(::Pod, b) == 8': offset of `b` of ::Pod\n\
+            1 error generated.\n";
+        let errs = asserts.failures("pod_bind.layout.cc", output);
+        let msgs: Vec<_> = errs.iter().map(|diag| diag.message()).collect();
+        assert_eq!(
+            msgs,
+            ["the offset of `b` of `::Pod` doesn't match the C++ compiler"]
+        );
+        let span = errs.iter().next().unwrap().primary_span().unwrap();
+        assert_eq!(span, asserts.asserts[3].span);
+
+        let msvc = "pod_bind.layout.cc(5): error C2338: size of ::Pod";
+        let errs = asserts.failures("pod_bind.layout.cc", msvc);
+        let msgs: Vec<_> = errs.iter().map(|diag| diag.message()).collect();
+        assert_eq!(msgs, ["the size of `::Pod` doesn't match the C++ compiler"]);
+    }
+}
@@ -0,0 +1,16 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+include!(concat!(env!("OUT_DIR"), "/widget_glue.rs"));
+
+use export::Widget;
+
+fn main() {
+    let mut widget = Widget { id: 7, size: 3 };
+    widget.greet();
+}
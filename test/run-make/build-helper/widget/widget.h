@@ -0,0 +1,23 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#include <iostream>
+
+struct Widget
+{
+  int id, size;
+  void greet()
+  {
+    std::cout << "hello from C++. id: " << id << ", size: " << size << "\n";
+  }
+};
+
+namespace rust_export
+{
+  using ::Widget;
+}
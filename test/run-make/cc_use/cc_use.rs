@@ -6,10 +6,9 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-// TODO: Make paths like Foo::Pod work
 extern crate cc_use_bind;
 use cc_use::cc_use;
-cc_use!("cc_use.h", Foo, nested::Bar);
+cc_use!("cc_use.h", Foo, nested::Bar, nested::Bar as Baz);
 
 fn main() {
     let mut foo = Foo {
@@ -17,10 +16,14 @@ fn main() {
         b: 2,
         c: 0.1,
         d: 10.0,
-        e: '!' as i8,
+        e: '!' as core::ffi::c_char,
     };
     foo.say_hello(42);
 
     let mut bar = Bar { x: 1337 };
     bar.say_goodbye(84);
+
+    // A renamed import is the same type as the original.
+    let baz: Baz = bar;
+    assert_eq!(baz.x, 1337);
 }
@@ -0,0 +1,29 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+#include <cstddef>
+
+struct Triple
+{
+  int values[3];
+  int& operator[](std::size_t i) { return values[i]; }
+  const int& operator[](std::size_t i) const { return values[i]; }
+};
+
+struct Frozen
+{
+  int values[2];
+  const int& operator[](std::size_t i) const { return values[i]; }
+};
+
+namespace rust_export
+{
+  using ::Triple;
+  using ::Frozen;
+}
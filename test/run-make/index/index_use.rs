@@ -0,0 +1,21 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+extern crate index_bind;
+use index_bind::export::{Frozen, Triple};
+
+fn main() {
+    let mut triple = Triple { values: [1, 2, 3] };
+    triple[1] = 20;
+    triple[2] += 10;
+    println!("{} {} {}", triple[0], triple[1], triple[2]);
+
+    let frozen = Frozen { values: [4, 5] };
+    println!("{}", frozen[0] + frozen[1]);
+}
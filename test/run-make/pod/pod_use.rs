@@ -15,7 +15,7 @@ fn main() {
         b: 2,
         c: 0.1,
         d: 10.0,
-        e: '!' as i8,
+        e: '!' as core::ffi::c_char,
     };
     pod.foo(42);
 }
@@ -21,3 +21,17 @@ cc_use!("cc_use.h" in "libfoo", Templated<i32>);
 cc_use!("cc_use.h" in "libfoo", Templated<'a>);
 cc_use!("cc_use.h" in "libfoo", Templated<&i32>);
 cc_use!("cc_use.h" in "libfoo", Templated<<Foo as Deref>::Target>);
+
+cc_use!(<>, A);
+cc_use!(<cc_use.h, A);
+cc_use!(<"cc_use.h">, A);
+cc_use!("<cc_use.h>", B);
+cc_use!("cc_use.h", A as Alpha);
+cc_use!("cc_use.h", A as B, B);
+cc_use!(["cc_use.h", "cc_use_outer.h"], Outer);
+cc_use!(["cc_use.h", "cc_use.h"], A);
+cc_use!([], A);
+cc_use!(["cc_use.h", "cc_use_redef.h"], B);
+cc_use!(["cc_use.h", "missing.h"], A);
+cc_use!("cc_use_sub/sub.h", Sub);
+cc_use!(<nonexistent_system.h>, A);
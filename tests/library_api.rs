@@ -0,0 +1,318 @@
+// Copyright (c) 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Generates bindings using only the public API of the library.
+
+use mosaic::{NameKind, NamingPolicy, SearchOptions, Session, SessionOptions, SymbolKind};
+use std::fs;
+
+#[test]
+fn bind_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let include_dir = dir.path().join("include");
+    fs::create_dir(&include_dir).unwrap();
+    fs::write(
+        include_dir.join("point.h"),
+        "
+        struct Point {
+            int x;
+            int y;
+            int norm() const;
+        };
+        ",
+    )
+    .unwrap();
+    let header = dir.path().join("shapes.h");
+    fs::write(
+        &header,
+        r#"
+        #include "point.h"
+        struct Rect {
+            Point origin;
+            double width;
+        };
+        namespace rust_export {
+            using ::Point;
+            using ::Rect;
+        }
+        "#,
+    )
+    .unwrap();
+
+    let mut sess = Session::builder()
+        .options(SessionOptions {
+            inline_crate: true,
+            ..Default::default()
+        })
+        .include_dir(&include_dir)
+        .capture_diagnostics()
+        .build();
+    sess.parse_header(&header).unwrap();
+    let (mut rs_out, mut cc_out) = (vec![], vec![]);
+    let bindings = sess.generate(&mut rs_out, &mut cc_out);
+    let diags = sess.diagnostics().unwrap();
+    let bindings = bindings.unwrap_or_else(|| panic!("generating bindings failed:\n{}", diags));
+    assert!(!sess.has_errors(), "{}", diags);
+
+    let rs_out = String::from_utf8(rs_out).unwrap();
+    let cc_out = String::from_utf8(cc_out).unwrap();
+    assert!(rs_out.contains("pub struct Rect"), "{}", rs_out);
+    assert!(cc_out.contains("#include \"shapes.h\""), "{}", cc_out);
+
+    let paths: Vec<_> = bindings.structs().map(|st| st.path.as_str()).collect();
+    assert_eq!(paths, ["export::Point", "export::Rect"]);
+
+    let point = bindings.get_struct("export::Point").unwrap();
+    assert_eq!((point.size, point.align), (8, 4));
+    let fields: Vec<_> = point
+        .fields
+        .iter()
+        .map(|f| (f.name.as_str(), f.ty.as_str(), f.offset))
+        .collect();
+    assert_eq!(fields, [("x", "i32", 0), ("y", "i32", 4)]);
+    assert_eq!(point.methods.len(), 1);
+    assert_eq!(point.methods[0].name, "norm");
+    assert!(point.methods[0].param_tys.is_empty());
+    assert_eq!(point.methods[0].return_ty, "i32");
+
+    let rect = bindings.get_struct("export::Rect").unwrap();
+    assert_eq!((rect.size, rect.align), (16, 8));
+    assert_eq!(rect.fields[0].ty, "Point");
+    assert_eq!(rect.fields[1].offset, 8);
+}
+
+#[test]
+fn errors_are_captured() {
+    let dir = tempfile::tempdir().unwrap();
+    let header = dir.path().join("broken.h");
+    fs::write(
+        &header,
+        "
+        namespace rust_export {
+            using ::Missing;
+        }
+        ",
+    )
+    .unwrap();
+
+    let mut sess = Session::builder().capture_diagnostics().build();
+    sess.parse_header(&header).unwrap();
+    assert!(sess.generate(std::io::sink(), std::io::sink()).is_none());
+    assert!(sess.has_errors());
+    assert!(sess.diagnostics().unwrap().contains("error"));
+}
+
+#[test]
+fn unsupported_target() {
+    let sess = Session::builder()
+        .options(SessionOptions {
+            target: Some("m68k-unknown-linux-gnu".into()),
+            ..Default::default()
+        })
+        .capture_diagnostics()
+        .build();
+    assert!(sess.has_errors());
+    let diags = sess.diagnostics().unwrap();
+    assert!(
+        diags.contains("unsupported target `m68k-unknown-linux-gnu`"),
+        "{}",
+        diags
+    );
+}
+
+#[test]
+fn rename_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    let header = dir.path().join("legacy.h");
+    fs::write(
+        &header,
+        "
+        struct LegacyPoint {
+            int m_x;
+            int m_y;
+            int GetLength() const;
+        };
+        namespace rust_export {
+            using ::LegacyPoint;
+        }
+        ",
+    )
+    .unwrap();
+
+    let mut sess = Session::builder()
+        .options(SessionOptions {
+            naming: NamingPolicy {
+                snake_case_members: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .rename(|ctx| match ctx.kind {
+            NameKind::Field => ctx.name.strip_prefix("m_").map(str::to_string),
+            NameKind::Struct | NameKind::Method => None,
+        })
+        .capture_diagnostics()
+        .build();
+    sess.parse_header(&header).unwrap();
+    let mut rs_out = vec![];
+    let bindings = sess.generate(&mut rs_out, std::io::sink());
+    let diags = sess.diagnostics().unwrap();
+    let bindings = bindings.unwrap_or_else(|| panic!("generating bindings failed:\n{}", diags));
+
+    let point = bindings.get_struct("export::LegacyPoint").unwrap();
+    let fields: Vec<_> = point.fields.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(fields, ["x", "y"]);
+    assert_eq!(point.methods[0].name, "get_length");
+
+    let rs_out = String::from_utf8(rs_out).unwrap();
+    assert!(rs_out.contains("#[doc(alias = \"m_x\")]"), "{}", rs_out);
+}
+
+#[test]
+fn search_symbols() {
+    let dir = tempfile::tempdir().unwrap();
+    let include_dir = dir.path().join("include");
+    fs::create_dir(&include_dir).unwrap();
+    fs::write(
+        include_dir.join("sys_widget.h"),
+        "
+        #pragma clang system_header
+        struct SysWidget {};
+        ",
+    )
+    .unwrap();
+    let header = dir.path().join("widgets.h");
+    fs::write(
+        &header,
+        r#"
+        #include "sys_widget.h"
+        namespace ui {
+            struct Widget {};
+            Widget make_widget();
+        }
+        "#,
+    )
+    .unwrap();
+
+    let mut sess = Session::builder().include_dir(&include_dir).build();
+    sess.parse_header(&header).unwrap();
+    let paths = |hits: Vec<mosaic::SymbolHit>| -> Vec<String> {
+        hits.into_iter().map(|hit| hit.path).collect()
+    };
+
+    let hits = sess.search_symbols("wid", None, &SearchOptions::default());
+    assert_eq!(paths(hits), ["ui::Widget", "ui::make_widget"]);
+
+    let hits = sess.search_symbols("wid", Some(SymbolKind::Struct), &SearchOptions::default());
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].file, header);
+    let contents = fs::read_to_string(&header).unwrap();
+    assert!(contents[hits[0].span.clone()].starts_with("struct Widget"));
+
+    let opts = SearchOptions {
+        include_system_headers: true,
+        ..Default::default()
+    };
+    let hits = sess.search_symbols("wid", Some(SymbolKind::Struct), &opts);
+    assert_eq!(paths(hits), ["SysWidget", "ui::Widget"]);
+}
+
+#[test]
+fn dependencies() {
+    let dir = tempfile::tempdir().unwrap();
+    let include_dir = dir.path().join("include");
+    fs::create_dir(&include_dir).unwrap();
+    fs::write(
+        include_dir.join("sys_point.h"),
+        "
+        #pragma clang system_header
+        struct SysPoint { int x, y; };
+        ",
+    )
+    .unwrap();
+    fs::write(dir.path().join("point.h"), "struct Point { int x, y; };").unwrap();
+    let header = dir.path().join("shapes.h");
+    fs::write(
+        &header,
+        r#"
+        #include "point.h"
+        #include "sys_point.h"
+        struct Rect { Point min, max; };
+        "#,
+    )
+    .unwrap();
+
+    let mut sess = Session::builder().include_dir(&include_dir).build();
+    sess.parse_header(&header).unwrap();
+    assert_eq!(
+        sess.dependencies(false),
+        [dir.path().join("point.h"), header.clone()]
+    );
+    assert_eq!(
+        sess.dependencies(true),
+        [
+            include_dir.join("sys_point.h"),
+            dir.path().join("point.h"),
+            header.clone(),
+        ]
+    );
+}
+
+#[test]
+fn generated_symbols() {
+    let dir = tempfile::tempdir().unwrap();
+    let header = dir.path().join("counters.h");
+    fs::write(
+        &header,
+        "
+        struct Counter {
+            int count;
+            void add(int n);
+            int get() const;
+            static int limit();
+            static constexpr int step() { return 1; }
+        };
+        namespace ns {
+            struct Gauge {
+                int level;
+                int read() const;
+            };
+        }
+        namespace rust_export {
+            using ::Counter;
+            using ns::Gauge;
+        }
+        ",
+    )
+    .unwrap();
+
+    let mut sess = Session::builder().capture_diagnostics().build();
+    sess.parse_header(&header).unwrap();
+    let mut rs_out = vec![];
+    let bindings = sess.generate(&mut rs_out, std::io::sink());
+    let diags = sess.diagnostics().unwrap();
+    let bindings = bindings.unwrap_or_else(|| panic!("generating bindings failed:\n{}", diags));
+
+    let rs_out = String::from_utf8(rs_out).unwrap();
+    let externs: Vec<_> = rs_out
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("fn _bind_"))
+        .map(|decl| format!("_bind_{}", decl.split('(').next().unwrap()))
+        .collect();
+    let symbols = bindings.generated_symbols();
+    assert_eq!(externs.len(), 4, "{}", rs_out);
+    for name in &externs {
+        assert!(
+            symbols.contains(name),
+            "`{}` missing from {:?}",
+            name,
+            symbols
+        );
+    }
+    assert_eq!(symbols.len(), externs.len(), "{:?}", symbols);
+}